@@ -0,0 +1,49 @@
+/* C interface of the simple-risc assembler and emulator, see src/ffi.rs.
+ * Build the library with
+ *     cargo rustc --lib --release --features cdylib --crate-type cdylib
+ */
+#ifndef SIMPLE_RISC_H
+#define SIMPLE_RISC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status returned by srisc_emulator_step and srisc_emulator_run */
+#define SRISC_RUNNING 0
+#define SRISC_BREAK 1
+#define SRISC_STOPPED 2
+#define SRISC_FAILED (-1)
+
+typedef struct SriscEmulator SriscEmulator;
+
+/* Returns 0 and the instructions, to be freed with srisc_binary_free, or the line of
+ * the error with its message written to error if it is not NULL. */
+int srisc_assemble(const char *source, uint32_t **binary, size_t *len, char *error,
+                   size_t error_len);
+void srisc_binary_free(uint32_t *binary, size_t len);
+
+SriscEmulator *srisc_emulator_new(const uint32_t *binary, size_t len);
+void srisc_emulator_free(SriscEmulator *emul);
+
+int srisc_emulator_step(SriscEmulator *emul);
+int srisc_emulator_run(SriscEmulator *emul, uint64_t max_steps);
+void srisc_emulator_add_breakpoint(SriscEmulator *emul, int32_t pc);
+
+int32_t srisc_emulator_reg(const SriscEmulator *emul, int reg);
+int32_t srisc_emulator_pc(const SriscEmulator *emul);
+/* Returns 0, or -1 if the bytes are outside the memory */
+int srisc_emulator_read_mem(SriscEmulator *emul, size_t addr, uint8_t *buf, size_t len);
+/* Returns 1 and stores the code if the program used the exit system call, else 0 */
+int srisc_emulator_exit_code(const SriscEmulator *emul, int32_t *code);
+/* Message of the last failure owned by the emulator, NULL if nothing failed */
+const char *srisc_emulator_error(const SriscEmulator *emul);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,160 @@
+"""Python bindings to the simple-risc assembler and emulator, for graders and notebooks.
+
+They load the C interface of the library, built with
+    cargo rustc --lib --release --features cdylib --crate-type cdylib
+from the path in SIMPLE_RISC_LIB, or else from target/release of the repository.
+
+    >>> import simple_risc
+    >>> emul = simple_risc.Emulator(simple_risc.assemble("mov r1, 7\\n"))
+    >>> emul.run()
+    'stopped'
+    >>> emul.registers[1]
+    7
+"""
+
+import ctypes
+import os
+import sys
+
+__all__ = ["AssemblyError", "Emulator", "EmulatorError", "assemble"]
+
+_STATUS = {0: "running", 1: "break", 2: "stopped"}
+_FAILED = -1
+
+
+def _library_path():
+    if "SIMPLE_RISC_LIB" in os.environ:
+        return os.environ["SIMPLE_RISC_LIB"]
+    name = {"win32": "simple_risc.dll", "darwin": "libsimple_risc.dylib"}.get(
+        sys.platform, "libsimple_risc.so"
+    )
+    root = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+    return os.path.join(root, "target", "release", name)
+
+
+def _load():
+    lib = ctypes.CDLL(_library_path())
+    emul = ctypes.c_void_p
+    signatures = {
+        "srisc_assemble": (
+            ctypes.c_int,
+            [
+                ctypes.c_char_p,
+                ctypes.POINTER(ctypes.POINTER(ctypes.c_uint32)),
+                ctypes.POINTER(ctypes.c_size_t),
+                ctypes.c_char_p,
+                ctypes.c_size_t,
+            ],
+        ),
+        "srisc_binary_free": (None, [ctypes.POINTER(ctypes.c_uint32), ctypes.c_size_t]),
+        "srisc_emulator_new": (emul, [ctypes.POINTER(ctypes.c_uint32), ctypes.c_size_t]),
+        "srisc_emulator_free": (None, [emul]),
+        "srisc_emulator_step": (ctypes.c_int, [emul]),
+        "srisc_emulator_run": (ctypes.c_int, [emul, ctypes.c_uint64]),
+        "srisc_emulator_add_breakpoint": (None, [emul, ctypes.c_int32]),
+        "srisc_emulator_reg": (ctypes.c_int32, [emul, ctypes.c_int]),
+        "srisc_emulator_pc": (ctypes.c_int32, [emul]),
+        "srisc_emulator_read_mem": (
+            ctypes.c_int,
+            [emul, ctypes.c_size_t, ctypes.c_char_p, ctypes.c_size_t],
+        ),
+        "srisc_emulator_exit_code": (ctypes.c_int, [emul, ctypes.POINTER(ctypes.c_int32)]),
+        "srisc_emulator_error": (ctypes.c_char_p, [emul]),
+    }
+    for name, (restype, argtypes) in signatures.items():
+        function = getattr(lib, name)
+        function.restype = restype
+        function.argtypes = argtypes
+    return lib
+
+
+_lib = _load()
+
+
+class AssemblyError(Exception):
+    """The source could not be assembled, `line` starts from 1"""
+
+    def __init__(self, line, message):
+        super().__init__(message)
+        self.line = line
+        self.message = message
+
+
+class EmulatorError(Exception):
+    """An instruction failed"""
+
+
+def assemble(source):
+    """Returns the instructions of `source` as a list of ints, raises AssemblyError"""
+    binary = ctypes.POINTER(ctypes.c_uint32)()
+    length = ctypes.c_size_t()
+    error = ctypes.create_string_buffer(256)
+    line = _lib.srisc_assemble(
+        source.encode(), ctypes.byref(binary), ctypes.byref(length), error, len(error)
+    )
+    if line != 0:
+        raise AssemblyError(line, error.value.decode())
+    try:
+        return binary[: length.value]
+    finally:
+        _lib.srisc_binary_free(binary, length)
+
+
+class Emulator:
+    """Runs a program, using the standard input and output of the process.
+
+    Running returns "running" if it can execute more instructions, "break" when paused
+    at a breakpoint and "stopped" once the program stopped, and raises EmulatorError if
+    an instruction failed.
+    """
+
+    def __init__(self, binary):
+        words = (ctypes.c_uint32 * len(binary))(*binary)
+        self._emul = _lib.srisc_emulator_new(words, len(binary))
+
+    def __del__(self):
+        if getattr(self, "_emul", None):
+            _lib.srisc_emulator_free(self._emul)
+            self._emul = None
+
+    def _status(self, status):
+        if status == _FAILED:
+            raise EmulatorError(_lib.srisc_emulator_error(self._emul).decode())
+        return _STATUS[status]
+
+    def step(self):
+        """Executes a single instruction"""
+        return self._status(_lib.srisc_emulator_step(self._emul))
+
+    def run(self, max_steps=2**64 - 1):
+        """Executes at most `max_steps` instructions, stopping at breakpoints"""
+        return self._status(_lib.srisc_emulator_run(self._emul, max_steps))
+
+    def add_breakpoint(self, pc):
+        _lib.srisc_emulator_add_breakpoint(self._emul, pc)
+
+    def reg(self, reg):
+        return _lib.srisc_emulator_reg(self._emul, reg)
+
+    @property
+    def registers(self):
+        return [self.reg(reg) for reg in range(16)]
+
+    @property
+    def pc(self):
+        return _lib.srisc_emulator_pc(self._emul)
+
+    def read_memory(self, addr, length):
+        """Returns `length` bytes at `addr`, raises ValueError if they are outside the memory"""
+        buf = ctypes.create_string_buffer(length)
+        if _lib.srisc_emulator_read_mem(self._emul, addr, buf, length) != 0:
+            raise ValueError(f"Memory at 0x{addr:x}..0x{addr + length:x} is out of bounds")
+        return buf.raw
+
+    @property
+    def exit_code(self):
+        """Code passed to the exit system call, None if it was not used"""
+        code = ctypes.c_int32()
+        if _lib.srisc_emulator_exit_code(self._emul, ctypes.byref(code)):
+            return code.value
+        return None
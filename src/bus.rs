@@ -0,0 +1,360 @@
+//! Memory-mapped I/O.
+//!
+//! A [`Bus`] wraps a [`Memory`] and routes accesses within the address ranges of
+//! registered [`Device`]s to those devices, everything else goes to the memory.
+//! The bus itself implements [`Memory`], so it can be given to the emulator as is.
+
+use crate::{emulator::EmulatorErr, interrupt::IRQ_LINES, memory::Memory};
+
+/// Start of the address range conventionally reserved for devices.
+/// It lies above any reasonable memory size and can be loaded with a single `movh`.
+pub const MMIO_BASE: usize = 0x7fff_0000;
+
+/// A peripheral which responds to loads and stores within its address range.
+///
+/// Offsets are relative to the address the device is mapped at and an access
+/// always lies completely within `[0, size())`.
+pub trait Device {
+    /// Size of the address range occupied by the device in bytes
+    fn size(&self) -> usize;
+
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr>;
+
+    /// Writes `bytes` starting at `offset`
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr>;
+
+    /// Advances the device by one instruction
+    fn tick(&mut self) {}
+
+    /// Advances the device by `ticks` instructions at once
+    fn advance(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Number of upcoming ticks after which [`Device::irq`] still returns the same,
+    /// so that the emulator need not check it in between.
+    /// Devices whose `tick` can change their interrupt request have to override it.
+    fn idle_ticks(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Returns true while the device requests an interrupt
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+impl<D: Device + ?Sized> Device for Box<D> {
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        (**self).read(offset, buf)
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        (**self).write(offset, bytes)
+    }
+
+    fn tick(&mut self) {
+        (**self).tick()
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        (**self).advance(ticks)
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        (**self).idle_ticks()
+    }
+
+    fn irq(&self) -> bool {
+        (**self).irq()
+    }
+}
+
+struct Mapping {
+    base: usize,
+    device: Box<dyn Device>,
+    /// Interrupt line on which the device requests interrupts
+    irq_line: Option<u8>,
+}
+
+impl Mapping {
+    fn end(&self) -> usize {
+        self.base + self.device.size()
+    }
+}
+
+/// Memory with devices mapped over parts of its address space
+pub struct Bus<M: Memory> {
+    memory: M,
+    /// Sorted by base address, ranges never overlap
+    mappings: Vec<Mapping>,
+}
+
+impl<M: Memory> Bus<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Maps `device` at `[base, base + device.size())`, accesses to that range
+    /// go to the device even if they also lie in the memory.
+    ///
+    /// # Panics
+    /// If the range overlaps a device mapped earlier or does not fit in the address space.
+    pub fn map(&mut self, base: usize, device: Box<dyn Device>) {
+        self.insert(base, device, None);
+    }
+
+    /// Same as [`Bus::map`] but interrupts requested by the device are raised on `line`
+    ///
+    /// # Panics
+    /// Also if `line` is not less than [`IRQ_LINES`].
+    pub fn map_irq(&mut self, base: usize, device: Box<dyn Device>, line: u8) {
+        assert!(line < IRQ_LINES, "Invalid interrupt line");
+        self.insert(base, device, Some(line));
+    }
+
+    fn insert(&mut self, base: usize, device: Box<dyn Device>, irq_line: Option<u8>) {
+        let end = base
+            .checked_add(device.size())
+            .expect("Device range does not fit in the address space");
+        let pos = self.mappings.partition_point(|m| m.base < base);
+        let overlaps = self.mappings.get(pos).is_some_and(|m| m.base < end)
+            || pos > 0 && self.mappings[pos - 1].end() > base;
+        assert!(!overlaps, "Device range overlaps another device");
+        self.mappings.insert(
+            pos,
+            Mapping {
+                base,
+                device,
+                irq_line,
+            },
+        );
+    }
+
+    fn read_array<const N: usize>(&mut self, addr: usize) -> Result<[u8; N], EmulatorErr> {
+        let mut buf = [0; N];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// Finds the device whose range contains `[addr, addr + len)`.
+    /// Returns `Ok(None)` if the access does not touch any device and
+    /// an error if it only partially lies within a device.
+    fn route(&mut self, addr: usize, len: usize) -> Result<Option<&mut Mapping>, EmulatorErr> {
+        let end = addr.checked_add(len).ok_or(EmulatorErr::InvalidMemAddr)?;
+        // Last mapping starting at or before addr, or the first one if none
+        let pos = self.mappings.partition_point(|m| m.base <= addr);
+        let candidates = [pos.checked_sub(1), Some(pos)];
+        for idx in candidates.into_iter().flatten() {
+            let Some(m) = self.mappings.get(idx) else {
+                continue;
+            };
+            if m.base < end && addr < m.end() {
+                if m.base <= addr && end <= m.end() {
+                    return Ok(Some(&mut self.mappings[idx]));
+                }
+                return Err(EmulatorErr::InvalidMemAddr);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<M: Memory> Memory for Bus<M> {
+    /// Size of the memory or the end of the highest device, whichever is larger
+    fn size(&self) -> usize {
+        let dev_end = self.mappings.last().map_or(0, Mapping::end);
+        self.memory.size().max(dev_end)
+    }
+
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        match self.route(addr, buf.len())? {
+            Some(m) => m.device.read(addr - m.base, buf),
+            None => self.memory.read_bytes(addr, buf),
+        }
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        match self.route(addr, bytes.len())? {
+            Some(m) => m.device.write(addr - m.base, bytes),
+            None => self.memory.write_bytes(addr, bytes),
+        }
+    }
+
+    // Accesses to the memory keep its own accessors, which may be faster
+
+    fn read_byte(&mut self, addr: usize) -> Result<u8, EmulatorErr> {
+        match self.route(addr, 1)? {
+            Some(_) => self.read_array(addr).map(|[byte]| byte),
+            None => self.memory.read_byte(addr),
+        }
+    }
+
+    fn read_half(&mut self, addr: usize) -> Result<u16, EmulatorErr> {
+        match self.route(addr, 2)? {
+            Some(_) => self.read_array(addr).map(u16::from_le_bytes),
+            None => self.memory.read_half(addr),
+        }
+    }
+
+    fn read_word(&mut self, addr: usize) -> Result<u32, EmulatorErr> {
+        match self.route(addr, 4)? {
+            Some(_) => self.read_array(addr).map(u32::from_le_bytes),
+            None => self.memory.read_word(addr),
+        }
+    }
+
+    fn write_byte(&mut self, addr: usize, value: u8) -> Result<(), EmulatorErr> {
+        match self.route(addr, 1)? {
+            Some(_) => self.write_bytes(addr, &[value]),
+            None => self.memory.write_byte(addr, value),
+        }
+    }
+
+    fn write_half(&mut self, addr: usize, value: u16) -> Result<(), EmulatorErr> {
+        match self.route(addr, 2)? {
+            Some(_) => self.write_bytes(addr, &value.to_le_bytes()),
+            None => self.memory.write_half(addr, value),
+        }
+    }
+
+    fn write_word(&mut self, addr: usize, value: u32) -> Result<(), EmulatorErr> {
+        match self.route(addr, 4)? {
+            Some(_) => self.write_bytes(addr, &value.to_le_bytes()),
+            None => self.memory.write_word(addr, value),
+        }
+    }
+
+    fn bytes_mut(&mut self, addr: usize, len: usize) -> Option<&mut [u8]> {
+        match self.is_mmio(addr, len) {
+            true => None,
+            false => self.memory.bytes_mut(addr, len),
+        }
+    }
+
+    fn contents(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.memory.contents()
+    }
+
+    fn is_mmio(&self, addr: usize, len: usize) -> bool {
+        let end = addr.saturating_add(len);
+        // Mappings do not overlap, so the last one starting before end ends last
+        let pos = self.mappings.partition_point(|m| m.base < end);
+        pos > 0 && addr < self.mappings[pos - 1].end()
+    }
+
+    fn clear(&mut self) {
+        self.memory.clear();
+    }
+
+    fn tick(&mut self) {
+        self.memory.tick();
+        for m in &mut self.mappings {
+            m.device.tick();
+        }
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.memory.advance(ticks);
+        for m in &mut self.mappings {
+            m.device.advance(ticks);
+        }
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        let devices = self.mappings.iter().map(|m| m.device.idle_ticks());
+        devices.fold(self.memory.idle_ticks(), u64::min)
+    }
+
+    fn pending_irqs(&self) -> u32 {
+        let devices = self.mappings.iter().filter(|m| m.device.irq());
+        let lines = devices.filter_map(|m| m.irq_line);
+        lines.fold(self.memory.pending_irqs(), |acc, line| acc | 1 << line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Device};
+    use crate::{
+        emulator::EmulatorErr,
+        memory::{FlatMemory, Memory},
+    };
+
+    /// Device with a few bytes of storage, reads return the stored bytes plus one
+    struct Regs([u8; 4]);
+
+    impl Device for Regs {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.0[offset + i] + 1;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+            self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut bus = Bus::new(FlatMemory::new(32));
+        bus.map(8, Box::new(Regs([0; 4])));
+        bus.map(64, Box::new(Regs([0; 4])));
+        assert_eq!(bus.size(), 68);
+
+        bus.write_word(4, 0x11111111).unwrap();
+        bus.write_word(8, 0x04030201).unwrap();
+        assert_eq!(bus.read_word(8).unwrap(), 0x05040302);
+        assert_eq!(bus.read_byte(65).unwrap(), 1);
+        // Memory beneath the device is untouched
+        assert_eq!(bus.memory_mut().read_word(8).unwrap(), 0);
+        assert_eq!(bus.read_word(4).unwrap(), 0x11111111);
+        assert!(bus.is_mmio(6, 4));
+        assert!(!bus.is_mmio(12, 4));
+
+        // Partially within a device
+        assert!(matches!(bus.read_word(6), Err(EmulatorErr::InvalidMemAddr)));
+        assert!(matches!(
+            bus.write_bytes(66, &[0; 4]),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+        // Neither in memory nor in a device
+        assert!(matches!(
+            bus.read_word(40),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_overlap() {
+        let mut bus = Bus::new(FlatMemory::new(32));
+        bus.map(8, Box::new(Regs([0; 4])));
+        bus.map(10, Box::new(Regs([0; 4])));
+    }
+}
@@ -0,0 +1,314 @@
+//! Models of set-associative caches, which count the hits and misses of the instruction
+//! fetches or the loads and stores of a run, see [`Emulator::set_icache`] and
+//! [`Emulator::set_dcache`]. They only keep the tags, the data always comes from the memory.
+//!
+//! A cache is configured from a list like `size=1K,assoc=2,line=16,policy=lru,write=back`
+//! where every entry is optional:
+//! - `size`: capacity in bytes, which may have a 'K' suffix, 1K by default
+//! - `assoc`: lines in each set, 1(direct mapped) by default
+//! - `line`: bytes in a line, 16 by default
+//! - `policy`: line replaced on a miss, `lru`(default), `fifo` or `random`
+//! - `write`: `back`(default) allocates a line on a write miss and writes it back once it
+//!   is replaced, `through` writes to the memory right away and does not allocate
+//!
+//! The number of sets, `size / (assoc * line)`, and the line size must be powers of two.
+//! Instructions are 4 bytes at the address `4 * pc` in a separate address space.
+//! Accesses made by system calls and to memory-mapped devices bypass the cache.
+//!
+//! [`Emulator::set_icache`]: crate::emulator::Emulator::set_icache
+//! [`Emulator::set_dcache`]: crate::emulator::Emulator::set_dcache
+
+use crate::rng::Rng;
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// Least recently used
+    Lru,
+    /// Oldest line
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Capacity in bytes
+    pub size: usize,
+    /// Lines in each set
+    pub assoc: usize,
+    /// Bytes in a line
+    pub line: usize,
+    pub policy: Replacement,
+    /// Write back and allocate on write misses, or write through without allocating
+    pub write_back: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 1024,
+            assoc: 1,
+            line: 16,
+            policy: Replacement::Lru,
+            write_back: true,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Returns the number of sets, or `None` if the sizes do not describe a cache
+    pub fn sets(&self) -> Option<usize> {
+        let set_bytes = self.assoc.checked_mul(self.line).filter(|&b| b > 0)?;
+        let sets = self.size / set_bytes;
+        let valid = self.size.is_multiple_of(set_bytes) && sets.is_power_of_two();
+        (valid && self.line.is_power_of_two()).then_some(sets)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheErr(String);
+
+impl fmt::Display for CacheErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid cache configuration '{}'", self.0)
+    }
+}
+
+impl core::error::Error for CacheErr {}
+
+impl FromStr for CacheConfig {
+    type Err = CacheErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for entry in s.split(',').filter(|e| !e.is_empty()) {
+            let err = || CacheErr(entry.to_string());
+            let (key, value) = entry.split_once('=').ok_or_else(err)?;
+            let size = || match value.strip_suffix(['K', 'k']) {
+                Some(kib) => kib.parse::<usize>().ok()?.checked_mul(1024),
+                None => value.parse().ok(),
+            };
+            match key {
+                "size" => config.size = size().ok_or_else(err)?,
+                "assoc" => config.assoc = value.parse().map_err(|_| err())?,
+                "line" => config.line = value.parse().map_err(|_| err())?,
+                "policy" => {
+                    config.policy = match value {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        _ => return Err(err()),
+                    }
+                }
+                "write" => {
+                    config.write_back = match value {
+                        "back" => true,
+                        "through" => false,
+                        _ => return Err(err()),
+                    }
+                }
+                _ => return Err(err()),
+            }
+        }
+        match config.sets() {
+            Some(_) => Ok(config),
+            None => Err(CacheErr(s.to_string())),
+        }
+    }
+}
+
+/// Counts of the accesses made to a cache
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    /// Dirty lines written to the memory when they were replaced
+    pub write_backs: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.accesses() - self.misses()
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = match self.accesses() {
+            0 => 0.0,
+            n => 100.0 * self.hits() as f64 / n as f64,
+        };
+        write!(
+            f,
+            "{} accesses, {} hits, {} misses({} reads, {} writes), {:.2}% hit rate, {} write backs",
+            self.accesses(),
+            self.hits(),
+            self.misses(),
+            self.read_misses,
+            self.write_misses,
+            rate,
+            self.write_backs
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    tag: usize,
+    dirty: bool,
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    /// Valid lines of each set, in the order they are replaced for LRU and FIFO
+    sets: Vec<Vec<Line>>,
+    /// Chooses the line replaced by the random policy, always seeded the same
+    rng: Rng,
+    stats: CacheStats,
+}
+
+impl Cache {
+    /// Creates an empty cache, panics if `config` does not describe a cache,
+    /// which is checked when parsing it
+    pub fn new(config: CacheConfig) -> Self {
+        let sets = config.sets().expect("Invalid cache configuration");
+        Self {
+            sets: vec![Vec::with_capacity(config.assoc); sets],
+            config,
+            rng: Rng::new(0),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Accesses the line holding `addr` and returns true on a hit
+    pub fn access(&mut self, addr: usize, write: bool) -> bool {
+        let block = addr / self.config.line;
+        let nsets = self.sets.len();
+        let (set, tag) = (block % nsets, block / nsets);
+        let lines = &mut self.sets[set];
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+
+        if let Some(pos) = lines.iter().position(|l| l.tag == tag) {
+            lines[pos].dirty |= write && self.config.write_back;
+            if self.config.policy == Replacement::Lru {
+                let line = lines.remove(pos);
+                lines.push(line);
+            }
+            return true;
+        }
+
+        if write {
+            self.stats.write_misses += 1;
+            if !self.config.write_back {
+                return false;
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+        if lines.len() == self.config.assoc {
+            let victim = match self.config.policy {
+                Replacement::Random => (self.rng.next_u64() % lines.len() as u64) as usize,
+                _ => 0,
+            };
+            if lines.remove(victim).dirty {
+                self.stats.write_backs += 1;
+            }
+        }
+        lines.push(Line {
+            tag,
+            dirty: write && self.config.write_back,
+        });
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheConfig, Replacement};
+
+    #[test]
+    fn test_parse_config() {
+        let config: CacheConfig = "size=2K,assoc=4,line=32,policy=fifo,write=through"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            CacheConfig {
+                size: 2048,
+                assoc: 4,
+                line: 32,
+                policy: Replacement::Fifo,
+                write_back: false,
+            }
+        );
+        assert_eq!(config.sets(), Some(16));
+        assert_eq!("".parse::<CacheConfig>().unwrap(), CacheConfig::default());
+        assert!("size=1000".parse::<CacheConfig>().is_err());
+        assert!("line=0".parse::<CacheConfig>().is_err());
+        assert!("ways=2".parse::<CacheConfig>().is_err());
+    }
+
+    #[test]
+    fn test_replacement() {
+        // 2 sets of 2 lines of 4 bytes, addresses 0, 8 and 16 map to set 0
+        let config = |policy| CacheConfig {
+            size: 16,
+            assoc: 2,
+            line: 4,
+            policy,
+            write_back: true,
+        };
+        let mut lru = Cache::new(config(Replacement::Lru));
+        let mut fifo = Cache::new(config(Replacement::Fifo));
+        for cache in [&mut lru, &mut fifo] {
+            assert!(!cache.access(0, false));
+            assert!(cache.access(2, false));
+            assert!(!cache.access(8, true));
+            assert!(cache.access(0, false));
+            // Replaces 8 for LRU and 0 for FIFO
+            assert!(!cache.access(16, false));
+        }
+        assert!(lru.access(0, false));
+        assert!(!fifo.access(0, false));
+        // 8 was written and is replaced in both
+        assert_eq!(lru.stats().write_backs, 1);
+        assert_eq!(fifo.stats().write_backs, 1);
+        assert_eq!((lru.stats().hits(), lru.stats().misses()), (3, 3));
+
+        let mut through = Cache::new(CacheConfig {
+            write_back: false,
+            ..config(Replacement::Lru)
+        });
+        assert!(!through.access(0, true));
+        assert!(!through.access(0, false));
+        assert!(through.access(0, true));
+        assert_eq!(through.stats().write_backs, 0);
+    }
+}
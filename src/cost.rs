@@ -0,0 +1,166 @@
+//! Cycle and energy costs of the instructions, which give the number of cycles taken
+//! by a run and an estimate of the energy it used.
+//!
+//! Costs are parsed from a list like `mul=3,div=10,memory=5` where each name is an
+//! instruction or one of the groups `memory`(loads, stores and atomics) and `branch`(branches,
+//! calls and returns). Later entries override earlier ones and every instruction not
+//! listed costs one cycle or one unit of energy. Energy costs may also have the entries
+//! `read` and `write`, the energy of each access made by a load or a store to the data
+//! memory in addition to the energy of the instruction, 0 by default.
+
+use crate::info::{self, Opcode, INSTRUCTIONS};
+use alloc::string::{String, ToString};
+use core::{fmt, str::FromStr};
+
+/// Cycles taken by each instruction, indexed by its opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleCosts([u32; INSTRUCTIONS.len()]);
+
+impl Default for CycleCosts {
+    fn default() -> Self {
+        Self([1; INSTRUCTIONS.len()])
+    }
+}
+
+impl CycleCosts {
+    pub fn get(&self, opcode: Opcode) -> u32 {
+        self.0[opcode as usize]
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cycles: u32) {
+        self.0[opcode as usize] = cycles;
+    }
+}
+
+/// Energy used by each instruction and memory access, in arbitrary units
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnergyCosts {
+    /// Energy of each instruction, indexed by its opcode
+    instructions: [u32; INSTRUCTIONS.len()],
+    /// Energy of reading the data memory
+    pub read: u32,
+    /// Energy of writing the data memory
+    pub write: u32,
+}
+
+impl Default for EnergyCosts {
+    fn default() -> Self {
+        Self {
+            instructions: [1; INSTRUCTIONS.len()],
+            read: 0,
+            write: 0,
+        }
+    }
+}
+
+impl EnergyCosts {
+    pub fn get(&self, opcode: Opcode) -> u32 {
+        self.instructions[opcode as usize]
+    }
+
+    pub fn set(&mut self, opcode: Opcode, energy: u32) {
+        self.instructions[opcode as usize] = energy;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostErr(String);
+
+impl fmt::Display for CostErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid cost '{}'", self.0)
+    }
+}
+
+impl core::error::Error for CostErr {}
+
+/// Sets the costs of the instructions listed in `s`, entries whose name is not an
+/// instruction or a group are passed to `other` which returns false if it is invalid
+fn parse_costs(
+    s: &str,
+    costs: &mut [u32; INSTRUCTIONS.len()],
+    mut other: impl FnMut(&str, u32) -> bool,
+) -> Result<(), CostErr> {
+    for entry in s.split(',') {
+        let err = || CostErr(entry.to_string());
+        let (name, cost) = entry.split_once('=').ok_or_else(err)?;
+        let cost = cost.trim().parse().map_err(|_| err())?;
+        let group: fn(u8) -> bool = match name.trim() {
+            "memory" => info::accesses_memory,
+            "branch" => info::is_branch,
+            name => {
+                match INSTRUCTIONS.iter().find(|ins| ins.name == name) {
+                    Some(ins) => costs[ins.opcode as usize] = cost,
+                    None if other(name, cost) => {}
+                    None => return Err(err()),
+                }
+                continue;
+            }
+        };
+        for ins in INSTRUCTIONS.iter().filter(|ins| group(ins.opcode as u8)) {
+            costs[ins.opcode as usize] = cost;
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for CycleCosts {
+    type Err = CostErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut costs = Self::default();
+        parse_costs(s, &mut costs.0, |_, _| false)?;
+        Ok(costs)
+    }
+}
+
+impl FromStr for EnergyCosts {
+    type Err = CostErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut costs = Self::default();
+        let (mut read, mut write) = (costs.read, costs.write);
+        parse_costs(s, &mut costs.instructions, |name, energy| match name {
+            "read" => {
+                read = energy;
+                true
+            }
+            "write" => {
+                write = energy;
+                true
+            }
+            _ => false,
+        })?;
+        (costs.read, costs.write) = (read, write);
+        Ok(costs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CycleCosts, EnergyCosts};
+    use crate::info::Opcode;
+
+    #[test]
+    fn test_parse_costs() {
+        let costs: CycleCosts = "memory=5, mul=3,div=10,ld=7".parse().unwrap();
+        assert_eq!(costs.get(Opcode::MUL), 3);
+        assert_eq!(costs.get(Opcode::DIV), 10);
+        assert_eq!(costs.get(Opcode::STB), 5);
+        assert_eq!(costs.get(Opcode::LD), 7);
+        assert_eq!(costs.get(Opcode::ADD), 1);
+        let costs: CycleCosts = "branch=2".parse().unwrap();
+        assert_eq!(costs.get(Opcode::RET), 2);
+        assert_eq!(costs.get(Opcode::SYS), 1);
+
+        assert!("mul".parse::<CycleCosts>().is_err());
+        assert!("mul=-1".parse::<CycleCosts>().is_err());
+        assert!("fma=2".parse::<CycleCosts>().is_err());
+        assert!("read=2".parse::<CycleCosts>().is_err());
+
+        let energy: EnergyCosts = "mul=4,read=10,write=12".parse().unwrap();
+        assert_eq!(energy.get(Opcode::MUL), 4);
+        assert_eq!(energy.get(Opcode::LD), 1);
+        assert_eq!((energy.read, energy.write), (10, 12));
+    }
+}
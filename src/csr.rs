@@ -0,0 +1,259 @@
+//! Control and status registers(CSRs).
+//!
+//! They are read and written using `csrr` and `csrw`, which like the other
+//! privileged instructions can only be executed in supervisor mode, except for
+//! accesses to `FLAGS` which is as visible to the program as the registers.
+//!
+//! | Number | Name      | Contents                                                    |
+//! | ------ | --------- | ----------------------------------------------------------- |
+//! | 0      | `STATUS`  | Interrupt enable, mode and their values before the handler  |
+//! | 1      | `EPC`     | `pc` to which `reti` returns                                |
+//! | 2      | `CAUSE`   | Reason for entering the handler                             |
+//! | 3      | `SCRATCH` | Free for use by handlers, e.g. to save a register           |
+//! | 4      | `BADADDR` | Memory address accessed by the instruction causing a trap   |
+//! | 5      | `HARTID`  | Index of the hart running the program, read-only            |
+//! | 6      | `PTBR`    | Page directory and whether translation is enabled, see [`crate::mmu`] |
+//! | 7      | `FLAGS`   | Flags N, Z, C and V set by `adds`, `subs`, `cmp` and `cmpu` |
+
+use crate::emulator::EmulatorErr;
+
+pub const STATUS: u32 = 0;
+pub const EPC: u32 = 1;
+pub const CAUSE: u32 = 2;
+pub const SCRATCH: u32 = 3;
+pub const BADADDR: u32 = 4;
+pub const HARTID: u32 = 5;
+pub const PTBR: u32 = 6;
+pub const FLAGS: u32 = 7;
+
+// Bits of STATUS
+/// Interrupts are enabled
+pub const IE: u32 = 1 << 0;
+/// Running in supervisor mode, otherwise in user mode
+pub const SUPERVISOR: u32 = 1 << 1;
+/// Value of `IE` before entering the handler
+pub const PREV_IE: u32 = 1 << 2;
+/// Value of `SUPERVISOR` before entering the handler
+pub const PREV_SUPERVISOR: u32 = 1 << 3;
+/// Value of `flags.E` before entering the handler
+pub const PREV_FLAG_E: u32 = 1 << 4;
+/// Value of `flags.GT` before entering the handler
+pub const PREV_FLAG_GT: u32 = 1 << 5;
+/// Value of `FLAGS` before entering the handler, in bits 6-9
+pub const PREV_FLAGS: u32 = 0xf << PREV_FLAGS_OFF;
+const PREV_FLAGS_OFF: u32 = 6;
+
+// Bits of FLAGS, like the NZCV flags of ARM
+/// Signed overflow
+pub const FLAG_V: u32 = 1 << 0;
+/// Carry out of an addition, or no borrow by a subtraction
+pub const FLAG_C: u32 = 1 << 1;
+/// The result is zero
+pub const FLAG_Z: u32 = 1 << 2;
+/// The result is negative
+pub const FLAG_N: u32 = 1 << 3;
+
+/// Set in `CAUSE` when the handler was entered due to an interrupt,
+/// the lower bits then contain the interrupt line
+pub const CAUSE_IRQ: u32 = 1 << 31;
+
+// Causes of traps
+/// Invalid opcode, modifier or CSR number
+pub const CAUSE_ILLEGAL_INS: u32 = 1;
+/// Privileged instruction executed in user mode
+pub const CAUSE_PRIVILEGED: u32 = 2;
+pub const CAUSE_DIVIDE_BY_ZERO: u32 = 3;
+/// Address out of range, `BADADDR` contains the address
+pub const CAUSE_INVALID_ADDR: u32 = 4;
+/// Address not aligned to the access size, `BADADDR` contains the address
+pub const CAUSE_UNALIGNED_ADDR: u32 = 5;
+/// Non-existent system call number in r0
+pub const CAUSE_INVALID_SYSCALL: u32 = 6;
+/// Access not allowed by a protected region, `BADADDR` contains the address
+pub const CAUSE_PROTECTION: u32 = 7;
+/// Page not mapped or access not allowed by its entry, `BADADDR` contains the address
+pub const CAUSE_PAGE_FAULT: u32 = 8;
+/// Signed overflow of `add`, `sub` or `mul`, see [`EmulatorConfig::trap_overflow`]
+///
+/// [`EmulatorConfig::trap_overflow`]: crate::emulator::EmulatorConfig::trap_overflow
+pub const CAUSE_OVERFLOW: u32 = 9;
+
+/// Returns the `CAUSE` of the trap for `err`, or `None` if it is not caused
+/// by the program and cannot be handled by it
+pub fn fault_cause(err: &EmulatorErr) -> Option<u32> {
+    match err {
+        EmulatorErr::InvalidOpcode | EmulatorErr::InvalidModbits | EmulatorErr::InvalidCsr => {
+            Some(CAUSE_ILLEGAL_INS)
+        }
+        EmulatorErr::PrivilegedIns => Some(CAUSE_PRIVILEGED),
+        EmulatorErr::DivideByZero => Some(CAUSE_DIVIDE_BY_ZERO),
+        EmulatorErr::InvalidMemAddr => Some(CAUSE_INVALID_ADDR),
+        EmulatorErr::UnalignedMemAddr => Some(CAUSE_UNALIGNED_ADDR),
+        EmulatorErr::InvalidSyscall => Some(CAUSE_INVALID_SYSCALL),
+        EmulatorErr::ProtectionFault => Some(CAUSE_PROTECTION),
+        EmulatorErr::PageFault => Some(CAUSE_PAGE_FAULT),
+        EmulatorErr::Overflow(..) => Some(CAUSE_OVERFLOW),
+        EmulatorErr::ClobberedRetReg
+        | EmulatorErr::UnalignedStack(_)
+        | EmulatorErr::PcOutOfBounds(_)
+        | EmulatorErr::ReplayDiverged
+        | EmulatorErr::InfiniteLoop => None,
+        #[cfg(feature = "std")]
+        EmulatorErr::Io(_) => None,
+        EmulatorErr::Fault { err, .. } => fault_cause(err),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrFile {
+    pub status: u32,
+    pub epc: u32,
+    pub cause: u32,
+    pub scratch: u32,
+    pub badaddr: u32,
+    pub hartid: u32,
+    pub ptbr: u32,
+    pub flags: u32,
+}
+
+impl Default for CsrFile {
+    /// Starts in supervisor mode with interrupts disabled
+    fn default() -> Self {
+        Self {
+            status: SUPERVISOR,
+            epc: 0,
+            cause: 0,
+            scratch: 0,
+            badaddr: 0,
+            hartid: 0,
+            ptbr: 0,
+            flags: 0,
+        }
+    }
+}
+
+impl CsrFile {
+    pub fn read(&self, num: u32) -> Result<u32, EmulatorErr> {
+        match num {
+            STATUS => Ok(self.status),
+            EPC => Ok(self.epc),
+            CAUSE => Ok(self.cause),
+            SCRATCH => Ok(self.scratch),
+            BADADDR => Ok(self.badaddr),
+            HARTID => Ok(self.hartid),
+            PTBR => Ok(self.ptbr),
+            FLAGS => Ok(self.flags),
+            _ => Err(EmulatorErr::InvalidCsr),
+        }
+    }
+
+    pub fn write(&mut self, num: u32, value: u32) -> Result<(), EmulatorErr> {
+        let csr = match num {
+            STATUS => &mut self.status,
+            EPC => &mut self.epc,
+            CAUSE => &mut self.cause,
+            SCRATCH => &mut self.scratch,
+            BADADDR => &mut self.badaddr,
+            PTBR => &mut self.ptbr,
+            FLAGS => &mut self.flags,
+            _ => return Err(EmulatorErr::InvalidCsr),
+        };
+        *csr = match num {
+            FLAGS => value & (FLAG_N | FLAG_Z | FLAG_C | FLAG_V),
+            _ => value,
+        };
+        Ok(())
+    }
+
+    /// Returns true if CSR `num` can be accessed in user mode
+    pub fn user_accessible(num: u32) -> bool {
+        num == FLAGS
+    }
+
+    pub fn is_supervisor(&self) -> bool {
+        self.status & SUPERVISOR != 0
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.status & IE != 0
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.status = set_bit(self.status, IE, enabled);
+    }
+
+    /// Enters a handler in supervisor mode with interrupts disabled,
+    /// saving `pc`, the flags(E, GT) and `FLAGS` for [`CsrFile::leave`]
+    pub fn enter(&mut self, cause: u32, pc: i32, flags: (bool, bool)) {
+        let saved = PREV_IE | PREV_SUPERVISOR | PREV_FLAG_E | PREV_FLAG_GT | PREV_FLAGS;
+        let mut status = (self.status & !saved) | self.flags << PREV_FLAGS_OFF;
+        status = set_bit(status, PREV_IE, self.status & IE != 0);
+        status = set_bit(status, PREV_SUPERVISOR, self.is_supervisor());
+        status = set_bit(status, PREV_FLAG_E, flags.0);
+        status = set_bit(status, PREV_FLAG_GT, flags.1);
+        self.status = (status & !IE) | SUPERVISOR;
+        self.epc = pc as u32;
+        self.cause = cause;
+    }
+
+    /// Restores the interrupt enable, mode and `FLAGS` saved by [`CsrFile::enter`],
+    /// returns the `pc` and flags to continue with
+    pub fn leave(&mut self) -> (i32, (bool, bool)) {
+        let prev = |bit| self.status & bit != 0;
+        let flags = (prev(PREV_FLAG_E), prev(PREV_FLAG_GT));
+        let mut status = set_bit(self.status, IE, prev(PREV_IE));
+        status = set_bit(status, SUPERVISOR, prev(PREV_SUPERVISOR));
+        self.status = status;
+        self.flags = (status & PREV_FLAGS) >> PREV_FLAGS_OFF;
+        (self.epc as i32, flags)
+    }
+}
+
+fn set_bit(value: u32, bit: u32, set: bool) -> u32 {
+    if set {
+        value | bit
+    } else {
+        value & !bit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enter_leave() {
+        let mut csrs = CsrFile::default();
+        assert!(csrs.is_supervisor());
+        assert!(!csrs.interrupts_enabled());
+
+        // Switch to user mode with interrupts enabled, like an OS would
+        csrs.write(STATUS, PREV_IE).unwrap();
+        csrs.write(EPC, 42).unwrap();
+        assert_eq!(csrs.leave(), (42, (false, false)));
+        assert!(!csrs.is_supervisor());
+        assert!(csrs.interrupts_enabled());
+
+        csrs.write(FLAGS, FLAG_N | FLAG_C | 0x30).unwrap();
+        csrs.enter(CAUSE_IRQ | 3, 50, (true, false));
+        csrs.flags = FLAG_Z;
+        assert!(csrs.is_supervisor());
+        assert!(!csrs.interrupts_enabled());
+        assert_eq!(csrs.read(CAUSE).unwrap(), CAUSE_IRQ | 3);
+        assert_eq!(
+            csrs.read(STATUS).unwrap(),
+            SUPERVISOR | PREV_IE | PREV_FLAG_E | (FLAG_N | FLAG_C) << 6
+        );
+
+        assert_eq!(csrs.leave(), (50, (true, false)));
+        assert_eq!(csrs.read(FLAGS).unwrap(), FLAG_N | FLAG_C);
+        assert!(!csrs.is_supervisor());
+        assert!(csrs.interrupts_enabled());
+        assert_eq!(csrs.read(HARTID).unwrap(), 0);
+        assert!(matches!(
+            csrs.write(HARTID, 1),
+            Err(EmulatorErr::InvalidCsr)
+        ));
+        assert!(matches!(csrs.read(8), Err(EmulatorErr::InvalidCsr)));
+    }
+}
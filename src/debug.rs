@@ -0,0 +1,155 @@
+//! Debugging aids which pause the execution started by [`Emulator::run_for`] or
+//! [`Emulator::step`] when something interesting happens.
+//!
+//! [`Emulator::run_for`]: crate::emulator::Emulator::run_for
+//! [`Emulator::step`]: crate::emulator::Emulator::step
+
+use crate::expr::Expr;
+use alloc::boxed::Box;
+use core::ops::Range;
+
+/// Kind of memory access a watchpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Either a read or a write
+    Any,
+}
+
+impl Access {
+    /// Returns true if a watchpoint for `self` triggers on `access`
+    fn covers(self, access: Access) -> bool {
+        self == Access::Any || self == access
+    }
+}
+
+/// Memory range which pauses the execution when it is accessed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemWatch {
+    pub addrs: Range<usize>,
+    pub access: Access,
+}
+
+impl MemWatch {
+    /// Returns true if an `access` to `[addr, addr + size)` triggers this watchpoint
+    pub(crate) fn matches(&self, addr: usize, size: usize, access: Access) -> bool {
+        self.access.covers(access) && addr < self.addrs.end && self.addrs.start < addr + size
+    }
+}
+
+/// Memory access which triggered a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemHit {
+    /// Index of the accessing instruction
+    pub pc: i32,
+    pub addr: usize,
+    /// Size of the access in bytes
+    pub size: usize,
+    /// Either [`Access::Read`] or [`Access::Write`]
+    pub access: Access,
+    /// Value before the access, equal to `new` for reads
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Register which pauses the execution when an instruction changes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegWatch {
+    pub reg: usize,
+    /// Only trigger when the register changes to this value
+    pub value: Option<i32>,
+}
+
+impl RegWatch {
+    /// Returns true if changing `reg` from `old` to `new` triggers this watchpoint
+    pub(crate) fn matches(&self, reg: usize, old: i32, new: i32) -> bool {
+        self.reg == reg && old != new && self.value.is_none_or(|v| v == new)
+    }
+}
+
+/// Register change made by an instruction, which triggered a watchpoint
+/// or is passed to a [`RegObserver`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegHit {
+    /// Index of the instruction which changed the register
+    pub pc: i32,
+    pub reg: usize,
+    pub old: i32,
+    pub new: i32,
+}
+
+/// Called with each change of the register it observes, see [`Emulator::observe_reg`]
+///
+/// [`Emulator::observe_reg`]: crate::emulator::Emulator::observe_reg
+pub type RegObserver = Box<dyn FnMut(&RegHit)>;
+
+/// Instruction before which the execution is paused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub pc: i32,
+    /// Only pause if this evaluates to non-zero, see [`crate::expr`]
+    pub condition: Option<Expr>,
+}
+
+/// A call which has not returned yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    /// Index of the `call` instruction
+    pub call_pc: i32,
+    /// Index of the called function
+    pub target: i32,
+}
+
+/// Why the execution was paused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    MemWatch(MemHit),
+    RegWatch(RegHit),
+    /// Reached the breakpoint at this `pc`, the instruction there has not been executed yet
+    Breakpoint(i32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, MemWatch, RegWatch};
+
+    #[test]
+    fn test_mem_watch() {
+        let watch = MemWatch {
+            addrs: 8..12,
+            access: Access::Write,
+        };
+        assert!(watch.matches(8, 4, Access::Write));
+        assert!(watch.matches(11, 1, Access::Write));
+        assert!(watch.matches(6, 4, Access::Write));
+        assert!(!watch.matches(4, 4, Access::Write));
+        assert!(!watch.matches(12, 2, Access::Write));
+        assert!(!watch.matches(8, 4, Access::Read));
+
+        let watch = MemWatch {
+            addrs: 0..4,
+            access: Access::Any,
+        };
+        assert!(watch.matches(2, 2, Access::Read));
+        assert!(watch.matches(0, 1, Access::Write));
+    }
+
+    #[test]
+    fn test_reg_watch() {
+        let watch = RegWatch {
+            reg: 15,
+            value: None,
+        };
+        assert!(watch.matches(15, 0, 4));
+        assert!(!watch.matches(15, 4, 4));
+        assert!(!watch.matches(14, 0, 4));
+
+        let watch = RegWatch {
+            reg: 1,
+            value: Some(0),
+        };
+        assert!(watch.matches(1, 3, 0));
+        assert!(!watch.matches(1, 3, 2));
+    }
+}
@@ -0,0 +1,620 @@
+//! Interactive command line debugger built on [`Emulator::run_for`] and the
+//! debugging aids in [`crate::debug`].
+//!
+//! Commands, most of which have a one letter abbreviation:
+//! ```text
+//! step [n]                 Execute n(default 1) instructions
+//! back [n]                 Undo n(default 1) steps, see EmulatorConfig::history_depth
+//! checkpoints              List the periodic checkpoints, see EmulatorConfig::checkpoint_interval
+//! rollback <step>          Go back to the checkpoint taken at step
+//! continue                 Run until a breakpoint, watchpoint or the end of the program, or run
+//! break <loc> [if <expr>]  Pause before the instruction at loc, a label or an index
+//! delete <loc>             Remove the breakpoints at loc
+//! watch <addr> [len]       Pause when the program writes to [addr, addr + len)
+//! watch <reg> [value]      Pause when an instruction changes reg(to value)
+//! who <addr>               Show the last write to addr, see Emulator::set_write_log
+//! regs                     Print the registers
+//! print <expr>             Evaluate an expression, see crate::expr
+//! x <addr> [n]             Examine n(default 4) words of memory starting at addr
+//! dump <addr> <len>        Show len bytes of memory starting at addr as a hex dump
+//! find <expr>              List the word aligned addresses holding the value of expr
+//! find bytes <b> [b...]    List the addresses of a sequence of hex bytes, like `de ad`
+//! find "text"              List the addresses of an ASCII string
+//! list [n]                 Disassemble n(default 5) instructions around pc
+//! help                     Print the list of commands
+//! quit
+//! ```
+//! An empty line repeats the previous command. Several commands can be given on a line
+//! separated by `;`, like `break main; run; print r1`, and [`Debugger::run_script`]
+//! executes a script of them, like the probes of a grader.
+
+use crate::{
+    debug::{Access, StopReason},
+    disasm::disassemble,
+    emulator::{Emulator, ExitStatus, RunStatus},
+    expr::{Env, Expr},
+    memory::{find, hexdump, Endian, Memory},
+    program::Program,
+    trace,
+};
+use std::io::{self, BufRead, Write};
+
+/// Matches listed by `find`, the others are only counted
+const MAX_MATCHES: usize = 16;
+
+const HELP: &str = "\
+step [n]                 Execute n(default 1) instructions
+back [n]                 Undo n(default 1) steps
+checkpoints              List the periodic checkpoints by step
+rollback <step>          Go back to the checkpoint taken at step
+continue                 Run until a breakpoint, watchpoint or the end of the program, or run
+break <loc> [if <expr>]  Pause before the instruction at loc, a label or an index
+delete <loc>             Remove the breakpoints at loc
+watch <addr> [len]       Pause when the program writes to [addr, addr + len)
+watch <reg> [value]      Pause when an instruction changes reg(to value)
+who <addr>               Show the instruction which last wrote to addr
+regs                     Print the registers
+print <expr>             Evaluate an expression like 'r1 + [sp]'
+x <addr> [n]             Examine n(default 4) words of memory starting at addr
+dump <addr> <len>        Show len bytes of memory starting at addr as a hex dump
+find <expr>              List the aligned addresses of a word in memory
+find bytes <b> [b...]    List the addresses of a sequence of hex bytes
+find \"text\"              List the addresses of an ASCII string
+list [n]                 Disassemble n(default 5) instructions around pc
+quit";
+
+pub struct Debugger<'p, R, W> {
+    program: &'p Program,
+    /// Lines of the source the program was assembled from
+    source: Vec<&'p str>,
+    input: R,
+    output: W,
+    last_cmd: String,
+}
+
+impl<'p, R: BufRead, W: Write> Debugger<'p, R, W> {
+    /// Creates a debugger for `program` assembled from `source`,
+    /// reading commands from `input` and writing to `output`
+    pub fn new(program: &'p Program, source: &'p str, input: R, output: W) -> Self {
+        Self {
+            program,
+            source: source.lines().collect(),
+            input,
+            output,
+            last_cmd: String::new(),
+        }
+    }
+
+    /// Reads and executes commands until `quit` or the end of input
+    pub fn run<M: Memory>(&mut self, emul: &mut Emulator<M>) -> io::Result<()> {
+        self.show_location(emul)?;
+        loop {
+            write!(self.output, "(srdb) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 || self.execute(emul, &line)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes the commands of `script`, one or more per line like typed ones, and
+    /// echoes each line after the prompt. Empty lines and lines starting with `#` are
+    /// skipped. Returns true if the debugger should quit.
+    pub fn run_script<M: Memory>(
+        &mut self,
+        emul: &mut Emulator<M>,
+        script: &str,
+    ) -> io::Result<bool> {
+        for line in script.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            writeln!(self.output, "(srdb) {}", line)?;
+            if self.execute(emul, line)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Executes a command line, an empty one repeats the previous command.
+    /// Returns true if the debugger should quit.
+    pub fn execute<M: Memory>(&mut self, emul: &mut Emulator<M>, line: &str) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => std::mem::take(&mut self.last_cmd),
+            cmd => cmd.to_string(),
+        };
+        let mut quit = false;
+        for cmd in line.split(';').map(str::trim) {
+            quit = match self.command(emul, cmd) {
+                Ok(quit) => quit,
+                Err(Error::Io(err)) => return Err(err),
+                Err(Error::Usage(msg)) => {
+                    writeln!(self.output, "{}", msg)?;
+                    false
+                }
+            };
+            if quit {
+                break;
+            }
+        }
+        self.last_cmd = line;
+        Ok(quit)
+    }
+
+    /// Stream the output of the commands is written to
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Executes a command, returns true if the debugger should quit
+    fn command<M: Memory>(&mut self, emul: &mut Emulator<M>, line: &str) -> Result<bool, Error> {
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match cmd {
+            "" => {}
+            "s" | "step" => {
+                let n = if args.is_empty() { 1 } else { parse_num(args)? };
+                self.resume(emul, n)?;
+            }
+            "bs" | "back" => {
+                let n: u64 = if args.is_empty() { 1 } else { parse_num(args)? };
+                let undone = (0..n).take_while(|_| emul.step_back()).count();
+                if undone == 0 {
+                    return Err(Error::Usage("No steps to undo"));
+                }
+                self.show_location(emul)?;
+            }
+            "checkpoints" => {
+                let steps: Vec<String> = emul.checkpoints().map(|s| s.steps.to_string()).collect();
+                match steps.is_empty() {
+                    true => writeln!(self.output, "No checkpoints")?,
+                    false => writeln!(self.output, "Checkpoints at steps {}", steps.join(" "))?,
+                }
+            }
+            "rollback" => {
+                if !emul.rollback_to(parse_num(args)?) {
+                    return Err(Error::Usage(
+                        "No checkpoint at that step, see 'checkpoints'",
+                    ));
+                }
+                self.show_location(emul)?;
+            }
+            "c" | "continue" | "run" => self.resume(emul, u64::MAX)?,
+            "b" | "break" => {
+                let (loc, cond) = match args.split_once(" if ") {
+                    Some((loc, cond)) => (loc, Some(parse_expr(cond)?)),
+                    None => (args, None),
+                };
+                let pc = self.location(loc)?;
+                emul.add_breakpoint(pc, cond);
+                writeln!(self.output, "Breakpoint at {}", self.describe(pc))?;
+            }
+            "d" | "delete" => emul.remove_breakpoint(self.location(args)?),
+            "w" | "watch" => {
+                let mut args = args.split_whitespace();
+                let target = args
+                    .next()
+                    .ok_or(Error::Usage("Address or register expected"))?;
+                let arg = args.next().map(parse_num).transpose()?;
+                match parse_expr(target)? {
+                    Expr::Reg(reg) => emul.watch_reg(reg, arg),
+                    addr => {
+                        let addr = addr
+                            .eval(emul)
+                            .map_err(|_| Error::Usage("Invalid address"))?;
+                        let start =
+                            usize::try_from(addr).map_err(|_| Error::Usage("Invalid address"))?;
+                        let len = arg.unwrap_or(4).max(1) as usize;
+                        emul.watch_memory(start..start + len, Access::Write);
+                    }
+                }
+                writeln!(self.output, "Watching {}", target)?;
+            }
+            "who" => {
+                let addr = parse_expr(args)?
+                    .eval(emul)
+                    .map_err(|_| Error::Usage("Invalid address"))?;
+                let addr = usize::try_from(addr).map_err(|_| Error::Usage("Invalid address"))?;
+                let log = emul
+                    .write_log()
+                    .ok_or(Error::Usage("Writes are not being logged"))?;
+                let endian = emul.config().endian;
+                let show = |bytes: &[u8]| format_bytes(bytes, endian);
+                match log.last_write(addr) {
+                    Some(write) => writeln!(
+                        self.output,
+                        "{} byte(s) at 0x{:x} written by {} at step {}, {} -> {}",
+                        write.new.len(),
+                        write.addr,
+                        self.describe(write.pc),
+                        write.step,
+                        write.old.as_deref().map_or(String::from("?"), show),
+                        show(&write.new)
+                    )?,
+                    None => writeln!(self.output, "0x{:x} was not written", addr)?,
+                }
+            }
+            "r" | "regs" => self.print_regs(emul)?,
+            "p" | "print" => match parse_expr(args)?.eval(emul) {
+                Ok(value) => writeln!(self.output, "{} (0x{:x})", value, value)?,
+                Err(err) => writeln!(self.output, "{}", err)?,
+            },
+            "x" => {
+                let mut args = args.split_whitespace();
+                let addr = args.next().ok_or(Error::Usage("Address expected"))?;
+                let addr = parse_expr(addr)?
+                    .eval(emul)
+                    .map_err(|_| Error::Usage("Invalid address"))?;
+                let count = args.next().map(parse_num).transpose()?.unwrap_or(4);
+                self.examine(emul, addr, count)?;
+            }
+            "dump" => {
+                let mut args = args.split_whitespace();
+                let (Some(addr), Some(len)) = (args.next(), args.next()) else {
+                    return Err(Error::Usage("Address and length expected"));
+                };
+                let addr = parse_expr(addr)?
+                    .eval(emul)
+                    .map_err(|_| Error::Usage("Invalid address"))?;
+                let start = usize::try_from(addr).map_err(|_| Error::Usage("Invalid address"))?;
+                let len: usize = parse_num(len)?;
+                match emul.read_mem(start..start.saturating_add(len)) {
+                    Ok(bytes) => write!(self.output, "{}", hexdump(start, &bytes))?,
+                    Err(err) => writeln!(self.output, "{}", err)?,
+                }
+            }
+            "find" => {
+                let (pattern, align) = parse_pattern(emul, args)?;
+                let matches = find(emul.memory_mut(), &pattern, align);
+                for addr in matches.iter().take(MAX_MATCHES) {
+                    writeln!(self.output, "0x{:08x}", addr)?;
+                }
+                match matches.len() {
+                    0 => writeln!(self.output, "Not found")?,
+                    n if n > MAX_MATCHES => writeln!(self.output, "... {} matches", n)?,
+                    _ => {}
+                }
+            }
+            "l" | "list" => {
+                let n = if args.is_empty() { 5 } else { parse_num(args)? };
+                self.list(emul.pc(), n)?;
+            }
+            "h" | "help" => writeln!(self.output, "{}", HELP)?,
+            "q" | "quit" => return Ok(true),
+            _ => return Err(Error::Usage("Unknown command, try 'help'")),
+        }
+        Ok(false)
+    }
+
+    /// Executes at most `max_steps` instructions and reports why the execution paused
+    fn resume<M: Memory>(&mut self, emul: &mut Emulator<M>, max_steps: u64) -> io::Result<()> {
+        match emul.run_for(max_steps) {
+            Ok(RunStatus::Running) => {}
+            Ok(RunStatus::Stopped(ExitStatus::Finished)) => {
+                return writeln!(self.output, "Program finished");
+            }
+            Ok(RunStatus::Stopped(ExitStatus::Exited(code))) => {
+                return writeln!(self.output, "Program exited with code {}", code);
+            }
+            Ok(RunStatus::Stopped(ExitStatus::BudgetExceeded)) => {
+                return writeln!(self.output, "Program exceeded its instruction budget");
+            }
+            Ok(RunStatus::Break(reason)) => self.show_reason(reason)?,
+            Err(err) => writeln!(self.output, "Program execution failed: {}", err)?,
+        }
+        self.show_location(emul)
+    }
+
+    fn show_reason(&mut self, reason: StopReason) -> io::Result<()> {
+        match reason {
+            StopReason::Breakpoint(pc) => {
+                writeln!(self.output, "Breakpoint at {}", self.describe(pc))
+            }
+            StopReason::MemWatch(hit) => writeln!(
+                self.output,
+                "Watchpoint: {} byte(s) at 0x{:x} written by {}, 0x{:x} -> 0x{:x}",
+                hit.size,
+                hit.addr,
+                self.describe(hit.pc),
+                hit.old,
+                hit.new
+            ),
+            StopReason::RegWatch(hit) => writeln!(
+                self.output,
+                "Watchpoint: r{} changed from {} to {} by {}",
+                hit.reg,
+                hit.old,
+                hit.new,
+                self.describe(hit.pc)
+            ),
+        }
+    }
+
+    /// Prints the instruction at `pc` along with its source line
+    fn show_location<M: Memory>(&mut self, emul: &Emulator<M>) -> io::Result<()> {
+        self.list(emul.pc(), 1)
+    }
+
+    /// Prints `n` instructions centered around `pc`
+    fn list(&mut self, pc: i32, n: usize) -> io::Result<()> {
+        let len = self.program.text.len() as i64;
+        let start = (pc as i64 - (n as i64 - 1) / 2).clamp(0, len);
+        let end = (start + n as i64).min(len);
+        if start >= end {
+            return writeln!(self.output, "pc = {} is outside the program", pc);
+        }
+        for idx in start as usize..end as usize {
+            let marker = if idx as i64 == pc as i64 { "=>" } else { "  " };
+            let asm = disassemble(self.program.text[idx], idx);
+            let line = self.program.lines.get(idx).copied().unwrap_or(0);
+            let source = self
+                .source
+                .get(line.wrapping_sub(1))
+                .map_or("", |s| s.trim());
+            writeln!(
+                self.output,
+                "{marker} {idx:>4}: {asm:<24} @ {line}: {source}"
+            )?;
+        }
+        Ok(())
+    }
+
+    fn print_regs<M: Memory>(&mut self, emul: &Emulator<M>) -> io::Result<()> {
+        write!(self.output, "{}", emul.machine_state())
+    }
+
+    fn examine<M: Memory>(
+        &mut self,
+        emul: &mut Emulator<M>,
+        addr: i32,
+        count: usize,
+    ) -> io::Result<()> {
+        for i in 0..count as i32 {
+            let at = addr.wrapping_add(4 * i);
+            match Env::load(emul, at) {
+                Ok(word) => writeln!(self.output, "0x{:08x}: 0x{:08x} {}", at, word, word)?,
+                Err(err) => return writeln!(self.output, "0x{:08x}: {}", at, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts a label or an instruction index to an index
+    fn location(&self, loc: &str) -> Result<i32, Error> {
+        match self.program.labels.get(loc) {
+            Some(&at) => Ok(at as i32),
+            None => parse_num(loc),
+        }
+    }
+
+    /// Formats `pc` along with its label, if any
+    fn describe(&self, pc: i32) -> String {
+        let label = usize::try_from(pc)
+            .ok()
+            .and_then(|pc| self.program.label_at(pc));
+        match label {
+            Some(label) => format!("{} <{}>", pc, label),
+            None => pc.to_string(),
+        }
+    }
+}
+
+enum Error {
+    Io(io::Error),
+    /// Command was used incorrectly
+    Usage(&'static str),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn parse_num<T: TryFrom<i32>>(s: &str) -> Result<T, Error> {
+    let Ok(Expr::Num(n)) = s.parse::<Expr>() else {
+        return Err(Error::Usage("Number expected"));
+    };
+    T::try_from(n).map_err(|_| Error::Usage("Number out of range"))
+}
+
+/// Formats up to 4 bytes as a number in the order `endian`, longer writes as their bytes
+fn format_bytes(bytes: &[u8], endian: Endian) -> String {
+    match bytes.len() {
+        1..=4 => format!("0x{:x}", trace::to_number(bytes, endian)),
+        _ => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+/// Parses the arguments of `find` into the bytes to search for and their alignment
+fn parse_pattern<M: Memory>(emul: &mut Emulator<M>, args: &str) -> Result<(Vec<u8>, usize), Error> {
+    if let Some(bytes) = args.strip_prefix("bytes ") {
+        let pattern = bytes
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| Error::Usage("Hex bytes expected"))?;
+        return Ok((pattern, 1));
+    }
+    if let Some(text) = args.strip_prefix('"') {
+        let text = text
+            .strip_suffix('"')
+            .ok_or(Error::Usage("Unterminated string"))?;
+        return match text.is_ascii() {
+            true => Ok((text.as_bytes().to_vec(), 1)),
+            false => Err(Error::Usage("ASCII string expected")),
+        };
+    }
+    let value = parse_expr(args)?
+        .eval(emul)
+        .map_err(|_| Error::Usage("Invalid value"))?;
+    Ok((emul.config().endian.word_bytes(value as u32).to_vec(), 4))
+}
+
+fn parse_expr(s: &str) -> Result<Expr, Error> {
+    s.parse()
+        .map_err(|_| Error::Usage("Invalid expression, see 'help'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::{
+        emulator::{Emulator, EmulatorConfig},
+        parser::assemble,
+        writelog::WriteLog,
+    };
+
+    fn session(code: &str, commands: &str) -> String {
+        let program = assemble(code).unwrap();
+        let config = EmulatorConfig {
+            history_depth: 100,
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&program.text, config);
+        emul.set_write_log(WriteLog::new());
+        let mut output = Vec::new();
+        let mut dbg = Debugger::new(&program, code, commands.as_bytes(), &mut output);
+        dbg.run(&mut emul).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let code =
+            "mov r1, 3\nloop:\nsub r1, r1, 1\ncmp r1, 0\nbgt loop\nmov r2, 7\nst r2, 8[r0]\n";
+        let out = session(
+            code,
+            "b loop if r1 == 1\nc\np r1 * 10\ns 2\n\nw 8\nl 3\nc\nx 8 1\nc\n",
+        );
+        let expected = [
+            "=>    0: mov r1, 3                @ 1: mov r1, 3",
+            "Breakpoint at 1 <loop>",
+            "=>    1: sub r1, r1, 1            @ 3: sub r1, r1, 1",
+            "10 (0xa)",
+            "=>    3: bgt 1                    @ 5: bgt loop",
+            "=>    5: st r2, 8[r0]             @ 7: st r2, 8[r0]",
+            "Watching 8",
+            "      4: mov r2, 7                @ 6: mov r2, 7",
+            "Watchpoint: 4 byte(s) at 0x8 written by 5, 0x0 -> 0x7",
+            "pc = 6 is outside the program",
+            "0x00000008: 0x00000007 7",
+            "Program finished",
+        ];
+        let mut lines = out.lines();
+        for exp in expected {
+            assert!(lines.any(|l| l.contains(exp)), "'{exp}' not in\n{out}");
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let code = "mov r1, 5\nst r1, 4[r0]\nadd r1, r1, 1\n";
+        let out = session(
+            code,
+            "s 3\nwho 6\nback 2\nwho 6\nx 4 1\np r1\nback 5\nback\n",
+        );
+        let expected = [
+            "Program finished",
+            "4 byte(s) at 0x4 written by 1 at step 1, 0x0 -> 0x5",
+            "=>    1: st r1, 4[r0]",
+            "0x6 was not written",
+            "0x00000004: 0x00000000 0",
+            "5 (0x5)",
+            "=>    0: mov r1, 5",
+            "No steps to undo",
+        ];
+        let mut lines = out.lines();
+        for exp in expected {
+            assert!(lines.any(|l| l.contains(exp)), "'{exp}' not in\n{out}");
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let code = "mov r1, 0x6261\nst r1, 8[r0]\nst r1, 20[r0]\n";
+        let out = session(
+            code,
+            "c\nfind 0x6261\nfind bytes 61 62 00\nfind \"ab\"\nfind \"ba\"\nfind bytes zz\n",
+        );
+        let expected = [
+            "0x00000008",
+            "0x00000008",
+            "0x00000014",
+            "0x00000008",
+            "0x00000014",
+            "Not found",
+            "Hex bytes expected",
+        ];
+        let mut lines = out.lines();
+        for exp in expected {
+            assert!(lines.any(|l| l.contains(exp)), "'{exp}' not in\n{out}");
+        }
+    }
+
+    #[test]
+    fn test_rollback() {
+        let code = "mov r1, 1\nadd r1, r1, 1\nadd r1, r1, 1\nadd r1, r1, 1\n";
+        let program = assemble(code).unwrap();
+        let config = EmulatorConfig {
+            checkpoint_interval: 2,
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&program.text, config);
+        let mut output = Vec::new();
+        let commands = "checkpoints\ns 3\ncheckpoints\nrollback 2\np r1\nrollback 1\n";
+        let mut dbg = Debugger::new(&program, code, commands.as_bytes(), &mut output);
+        dbg.run(&mut emul).unwrap();
+        let out = String::from_utf8(output).unwrap();
+        let expected = [
+            "No checkpoints",
+            "Checkpoints at steps 0 2",
+            "=>    2: add r1, r1, 1",
+            "2 (0x2)",
+            "No checkpoint at that step",
+        ];
+        let mut lines = out.lines();
+        for exp in expected {
+            assert!(lines.any(|l| l.contains(exp)), "'{exp}' not in\n{out}");
+        }
+    }
+
+    #[test]
+    fn test_script() {
+        let code = "mov r1, 0x41\nmain:\nst r1, 4[r0]\nadd r1, r1, 1\n";
+        let program = assemble(code).unwrap();
+        let mut emul = Emulator::new(&program.text);
+        let mut output = Vec::new();
+        let mut dbg = Debugger::new(&program, code, "p r1\n".as_bytes(), &mut output);
+        let script = "# Probe\nbreak main; run\n\nprint r1; dump 4 4\n";
+        assert!(!dbg.run_script(&mut emul, script).unwrap());
+        assert!(dbg.run_script(&mut emul, "run; quit; print r1").unwrap());
+        let out = String::from_utf8(output).unwrap();
+        let expected = [
+            "(srdb) break main; run",
+            "Breakpoint at 1 <main>",
+            "(srdb) print r1; dump 4 4",
+            "65 (0x41)",
+            "00000004  00 00 00 00",
+            "(srdb) run; quit; print r1",
+            "Program finished",
+        ];
+        let mut lines = out.lines();
+        for exp in expected {
+            assert!(lines.any(|l| l.contains(exp)), "'{exp}' not in\n{out}");
+        }
+        assert!(!out.contains("66 (0x42)"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let out = session("nop\n", "b nowhere\nfoo\nprint r1 +\nq\nregs\n");
+        assert!(out.contains("Number expected"));
+        assert!(out.contains("Unknown command"));
+        assert!(out.contains("Invalid expression"));
+        // Nothing is executed after quit
+        assert!(!out.contains("r0 "));
+    }
+}
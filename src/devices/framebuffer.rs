@@ -0,0 +1,130 @@
+//! Display with 64x64 pixels.
+//!
+//! Each pixel is a byte whose lower 4 bits select a color from [`PALETTE`],
+//! pixel `(x, y)` is at offset `y * WIDTH + x`. Pixels are read by a frontend
+//! through a [`Screen`] which shares the pixel buffer with the device.
+
+use crate::{bus::Device, emulator::EmulatorErr};
+use std::{cell::RefCell, io, rc::Rc};
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 64;
+
+/// RGB colors selected by the pixel values 0 to 15
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], // Black
+    [0x00, 0x00, 0xaa], // Blue
+    [0x00, 0xaa, 0x00], // Green
+    [0x00, 0xaa, 0xaa], // Cyan
+    [0xaa, 0x00, 0x00], // Red
+    [0xaa, 0x00, 0xaa], // Magenta
+    [0xaa, 0x55, 0x00], // Brown
+    [0xaa, 0xaa, 0xaa], // Light gray
+    [0x55, 0x55, 0x55], // Dark gray
+    [0x55, 0x55, 0xff], // Light blue
+    [0x55, 0xff, 0x55], // Light green
+    [0x55, 0xff, 0xff], // Light cyan
+    [0xff, 0x55, 0x55], // Light red
+    [0xff, 0x55, 0xff], // Light magenta
+    [0xff, 0xff, 0x55], // Yellow
+    [0xff, 0xff, 0xff], // White
+];
+
+/// Memory-mapped pixel buffer
+pub struct Framebuffer {
+    pixels: Rc<RefCell<Vec<u8>>>,
+}
+
+/// Read access to the pixels of a [`Framebuffer`] for a frontend
+#[derive(Clone)]
+pub struct Screen {
+    pixels: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Framebuffer {
+    /// Creates a black framebuffer
+    pub fn new() -> Self {
+        Self {
+            pixels: Rc::new(RefCell::new(vec![0; WIDTH * HEIGHT])),
+        }
+    }
+
+    /// Returns a view of the pixels which stays valid after the device is mapped
+    pub fn screen(&self) -> Screen {
+        Screen {
+            pixels: Rc::clone(&self.pixels),
+        }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        WIDTH * HEIGHT
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        buf.copy_from_slice(&self.pixels.borrow()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        self.pixels.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl Screen {
+    /// Returns the palette index of the pixel at `(x, y)`
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels.borrow()[y * WIDTH + x] & 0xf
+    }
+
+    /// Returns the color of the pixel at `(x, y)`
+    pub fn rgb(&self, x: usize, y: usize) -> [u8; 3] {
+        PALETTE[self.pixel(x, y) as usize]
+    }
+
+    /// Writes the current frame as a binary PPM(P6) image
+    pub fn write_ppm(&self, out: &mut impl io::Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                out.write_all(&self.rgb(x, y))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framebuffer, PALETTE, WIDTH};
+    use crate::{bus::Bus, memory::FlatMemory, memory::Memory};
+
+    #[test]
+    fn test_framebuffer() {
+        let fb = Framebuffer::new();
+        let screen = fb.screen();
+        let mut bus = Bus::new(FlatMemory::new(0));
+        bus.map(0x1000, Box::new(fb));
+
+        bus.write_byte(0x1000 + 2 * WIDTH + 1, 4).unwrap();
+        bus.write_word(0x1000 + 4, 0x0f0e_1f00).unwrap();
+        assert_eq!(screen.pixel(1, 2), 4);
+        assert_eq!(screen.rgb(1, 2), PALETTE[4]);
+        // Only the lower 4 bits select the color
+        assert_eq!(screen.pixel(5, 0), 0xf);
+        assert_eq!(bus.read_byte(0x1005).unwrap(), 0x1f);
+
+        let mut ppm = Vec::new();
+        screen.write_ppm(&mut ppm).unwrap();
+        assert!(ppm.starts_with(b"P6\n64 64\n255\n"));
+        assert_eq!(ppm.len(), 13 + 64 * 64 * 3);
+    }
+}
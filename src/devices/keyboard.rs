@@ -0,0 +1,249 @@
+//! Keyboard which queues key presses without blocking the emulator.
+//!
+//! Keys are sent to the device through a channel, by the frontend using
+//! [`Keyboard::sender`] or by a thread reading a stream([`Keyboard::from_reader`]).
+//! Keys from a thread arrive whenever the host reads them, [`Keyboard::paced`] instead
+//! reads one every fixed number of ticks so that their timing can be reproduced.
+//!
+//! | Offset | Register | Access                                                 |
+//! | ------ | -------- | ------------------------------------------------------ |
+//! | 0      | `DATA`   | Read takes the next key(0 if none), never waits        |
+//! | 4      | `STATUS` | Read only, see [`KEY_READY`]                           |
+//! | 8      | `CTRL`   | See [`IRQ_ENABLE`]                                     |
+
+use super::{read_reg_bytes, write_reg_bytes};
+use crate::{bus::Device, emulator::EmulatorErr};
+use std::{
+    collections::VecDeque,
+    io::Read,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+/// Offset of the data register
+pub const DATA: usize = 0;
+/// Offset of the status register
+pub const STATUS: usize = 4;
+/// Offset of the control register
+pub const CTRL: usize = 8;
+
+/// Status bit set when a key is available in `DATA`
+pub const KEY_READY: u32 = 1 << 0;
+/// Control bit which makes the keyboard request an interrupt while a key is available
+pub const IRQ_ENABLE: u32 = 1 << 0;
+
+/// Memory-mapped keyboard with a queue of pressed keys
+pub struct Keyboard {
+    sender: Sender<u8>,
+    receiver: Receiver<u8>,
+    keys: VecDeque<u8>,
+    ctrl: u32,
+    paced: Option<Paced>,
+}
+
+/// Stream read by a [`Keyboard::paced`] keyboard
+struct Paced {
+    input: Box<dyn Read>,
+    /// Ticks between two keys
+    interval: u64,
+    ticks: u64,
+    eof: bool,
+}
+
+impl Paced {
+    /// Adds the keys due in the next `ticks` ticks to `keys`, waiting for them
+    fn advance(&mut self, ticks: u64, keys: &mut VecDeque<u8>) {
+        let due = (self.ticks + ticks) / self.interval - self.ticks / self.interval;
+        self.ticks += ticks;
+        for _ in 0..due {
+            let mut byte = [0u8; 1];
+            match self.eof || self.input.read(&mut byte).ok() != Some(1) {
+                true => self.eof = true,
+                false => keys.push_back(byte[0]),
+            }
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver,
+            keys: VecDeque::new(),
+            ctrl: 0,
+            paced: None,
+        }
+    }
+
+    /// Creates a keyboard whose keys are the bytes of `input`, one is read every
+    /// `interval` ticks, which must not be 0, waiting for it if needed
+    pub fn paced(input: Box<dyn Read>, interval: u64) -> Self {
+        assert!(interval > 0, "Keys must be at least one tick apart");
+        Self {
+            paced: Some(Paced {
+                input,
+                interval,
+                ticks: 0,
+                eof: false,
+            }),
+            ..Self::new()
+        }
+    }
+
+    /// Creates a keyboard whose keys are the bytes of `input`,
+    /// they are read by a separate thread so that the emulator never waits.
+    pub fn from_reader(mut input: impl Read + Send + 'static) -> Self {
+        let kbd = Self::new();
+        let sender = kbd.sender();
+        thread::spawn(move || {
+            let mut byte = [0u8; 1];
+            while let Ok(1) = input.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        kbd
+    }
+
+    /// Returns a handle through which keys can be pressed
+    pub fn sender(&self) -> Sender<u8> {
+        self.sender.clone()
+    }
+
+    /// Moves the keys sent so far into the queue
+    fn poll(&mut self) {
+        self.keys.extend(self.receiver.try_iter());
+    }
+
+    fn read_reg(&mut self, reg: usize) -> u32 {
+        self.poll();
+        match reg {
+            DATA => self.keys.pop_front().map_or(0, u32::from),
+            STATUS if !self.keys.is_empty() => KEY_READY,
+            STATUS => 0,
+            _ => self.ctrl,
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Keyboard {
+    fn size(&self) -> usize {
+        12
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        let reg = offset & !3;
+        let value = self.read_reg(reg);
+        read_reg_bytes(value, offset - reg, buf);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        let reg = offset & !3;
+        if reg == CTRL {
+            self.ctrl = write_reg_bytes(self.ctrl, offset - reg, bytes);
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.advance(1);
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        if let Some(paced) = &mut self.paced {
+            paced.advance(ticks, &mut self.keys);
+        }
+        self.poll();
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        // Keys from a thread are only seen when polled, so they can wait until the next advance
+        match &self.paced {
+            Some(paced) if !paced.eof && self.ctrl & IRQ_ENABLE != 0 && self.keys.is_empty() => {
+                paced.interval - paced.ticks % paced.interval - 1
+            }
+            _ => u64::MAX,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ctrl & IRQ_ENABLE != 0 && !self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keyboard, CTRL, DATA, IRQ_ENABLE, KEY_READY, STATUS};
+    use crate::bus::Device;
+
+    fn read_word(kbd: &mut Keyboard, offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        kbd.read(offset, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_keyboard() {
+        let mut kbd = Keyboard::new();
+        assert_eq!(read_word(&mut kbd, STATUS), 0);
+        assert_eq!(read_word(&mut kbd, DATA), 0);
+
+        let keys = kbd.sender();
+        keys.send(b'w').unwrap();
+        keys.send(b'a').unwrap();
+        kbd.write(CTRL, &IRQ_ENABLE.to_le_bytes()).unwrap();
+        kbd.tick();
+        assert!(kbd.irq());
+        assert_eq!(read_word(&mut kbd, STATUS), KEY_READY);
+        assert_eq!(read_word(&mut kbd, DATA), b'w' as u32);
+        assert_eq!(read_word(&mut kbd, DATA), b'a' as u32);
+        assert_eq!(read_word(&mut kbd, STATUS), 0);
+        assert!(!kbd.irq());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let mut kbd = Keyboard::from_reader(&b"xy"[..]);
+        let mut keys = Vec::new();
+        // The reader thread delivers the keys eventually
+        while keys.len() < 2 {
+            match read_word(&mut kbd, DATA) {
+                0 => std::thread::yield_now(),
+                key => keys.push(key as u8),
+            }
+        }
+        assert_eq!(keys, b"xy");
+    }
+
+    #[test]
+    fn test_paced() {
+        let mut kbd = Keyboard::paced(Box::new(&b"xy"[..]), 3);
+        let mut keys = Vec::new();
+        for tick in 1..=9 {
+            kbd.tick();
+            if read_word(&mut kbd, STATUS) == KEY_READY {
+                keys.push((tick, read_word(&mut kbd, DATA) as u8));
+            }
+        }
+        assert_eq!(keys, [(3, b'x'), (6, b'y')]);
+
+        let mut kbd = Keyboard::paced(Box::new(&b"xyz"[..]), 3);
+        kbd.write(CTRL, &IRQ_ENABLE.to_le_bytes()).unwrap();
+        kbd.tick();
+        assert_eq!(kbd.idle_ticks(), 1);
+        kbd.advance(6);
+        assert_eq!(read_word(&mut kbd, DATA), b'x' as u32);
+        assert_eq!(read_word(&mut kbd, DATA), b'y' as u32);
+        assert_eq!(read_word(&mut kbd, STATUS), 0);
+    }
+}
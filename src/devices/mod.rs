@@ -0,0 +1,47 @@
+//! Peripherals which can be mapped on a [`Bus`](crate::bus::Bus).
+//!
+//! Default addresses of the devices, all of them lie in the reserved MMIO range:
+//! | Device      | Base address | Interrupt line |
+//! | ----------- | ------------ | -------------- |
+//! | UART        | `0x7fff0000` | -              |
+//! | Timer       | `0x7fff0100` | 0              |
+//! | Keyboard    | `0x7fff0200` | 1              |
+//! | Framebuffer | `0x7fff1000` | -              |
+
+use crate::bus::MMIO_BASE;
+
+pub mod framebuffer;
+pub mod keyboard;
+pub mod timer;
+pub mod uart;
+
+pub use framebuffer::{Framebuffer, Screen};
+pub use keyboard::Keyboard;
+pub use timer::Timer;
+pub use uart::Uart;
+
+/// Default base address of the [`Uart`]
+pub const UART_BASE: usize = MMIO_BASE;
+/// Default base address of the [`Timer`]
+pub const TIMER_BASE: usize = MMIO_BASE + 0x100;
+/// Default base address of the [`Keyboard`]
+pub const KEYBOARD_BASE: usize = MMIO_BASE + 0x200;
+/// Default base address of the [`Framebuffer`]
+pub const FRAMEBUFFER_BASE: usize = MMIO_BASE + 0x1000;
+
+/// Default interrupt line of the [`Timer`]
+pub const TIMER_IRQ: u8 = 0;
+/// Default interrupt line of the [`Keyboard`]
+pub const KEYBOARD_IRQ: u8 = 1;
+
+/// Copies the bytes of the 4 byte register `value` starting at `offset` into `buf`
+fn read_reg_bytes(value: u32, offset: usize, buf: &mut [u8]) {
+    buf.copy_from_slice(&value.to_le_bytes()[offset..offset + buf.len()]);
+}
+
+/// Returns `value` with its bytes starting at `offset` replaced by `bytes`
+fn write_reg_bytes(value: u32, offset: usize, bytes: &[u8]) -> u32 {
+    let mut reg = value.to_le_bytes();
+    reg[offset..offset + bytes.len()].copy_from_slice(bytes);
+    u32::from_le_bytes(reg)
+}
@@ -0,0 +1,202 @@
+//! Countdown timer which can reload itself to fire periodically.
+//!
+//! Time is measured in executed instructions, the count decrements once per instruction
+//! while the timer is enabled. When it reaches 0 the `EXPIRED` flag is set, then the count
+//! is reloaded from `RELOAD` or the timer is disabled if `RELOAD` is 0.
+//!
+//! | Offset | Register | Access                                                   |
+//! | ------ | -------- | -------------------------------------------------------- |
+//! | 0      | `COUNT`  | Instructions left until the timer expires               |
+//! | 4      | `RELOAD` | Count loaded on expiry, 0 for a one-shot timer           |
+//! | 8      | `CTRL`   | See [`ENABLE`] and [`IRQ_ENABLE`]                        |
+//! | 12     | `STATUS` | See [`EXPIRED`], writing a set bit clears it             |
+
+use super::{read_reg_bytes, write_reg_bytes};
+use crate::{bus::Device, emulator::EmulatorErr};
+
+/// Offset of the count register
+pub const COUNT: usize = 0;
+/// Offset of the reload register
+pub const RELOAD: usize = 4;
+/// Offset of the control register
+pub const CTRL: usize = 8;
+/// Offset of the status register
+pub const STATUS: usize = 12;
+
+/// Control bit which starts the countdown
+pub const ENABLE: u32 = 1 << 0;
+/// Control bit which makes the timer request an interrupt while `EXPIRED` is set
+pub const IRQ_ENABLE: u32 = 1 << 1;
+/// Status bit set when the count reaches 0
+pub const EXPIRED: u32 = 1 << 0;
+
+/// Countdown timer with 4 registers, see the module documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timer {
+    count: u32,
+    reload: u32,
+    ctrl: u32,
+    status: u32,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reg_mut(&mut self, reg: usize) -> &mut u32 {
+        match reg {
+            COUNT => &mut self.count,
+            RELOAD => &mut self.reload,
+            CTRL => &mut self.ctrl,
+            _ => &mut self.status,
+        }
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> usize {
+        16
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        let reg = offset & !3;
+        read_reg_bytes(*self.reg_mut(reg), offset - reg, buf);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        let reg = offset & !3;
+        let value = write_reg_bytes(*self.reg_mut(reg), offset - reg, bytes);
+        match reg {
+            STATUS => self.status &= !value,
+            _ => *self.reg_mut(reg) = value,
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        if self.ctrl & ENABLE == 0 {
+            return;
+        }
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0 {
+            self.status |= EXPIRED;
+            self.count = self.reload;
+            if self.reload == 0 {
+                self.ctrl &= !ENABLE;
+            }
+        }
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        if self.ctrl & ENABLE == 0 {
+            return;
+        }
+        // A count of 0 expires on the next tick like a count of 1
+        let count = self.count.max(1) as u64;
+        if ticks < count {
+            self.count -= ticks as u32;
+            return;
+        }
+        self.status |= EXPIRED;
+        self.count = self.reload;
+        match self.reload {
+            0 => self.ctrl &= !ENABLE,
+            reload => self.count -= ((ticks - count) % reload as u64) as u32,
+        }
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        // Only an expiry can raise the request, and only a write can lower it
+        let counting = self.ctrl & (ENABLE | IRQ_ENABLE) == ENABLE | IRQ_ENABLE;
+        match counting && self.status & EXPIRED == 0 {
+            true => self.count.saturating_sub(1) as u64,
+            false => u64::MAX,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ctrl & IRQ_ENABLE != 0 && self.status & EXPIRED != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Timer, COUNT, CTRL, ENABLE, EXPIRED, IRQ_ENABLE, RELOAD, STATUS};
+    use crate::bus::Device;
+
+    fn write_word(timer: &mut Timer, offset: usize, value: u32) {
+        timer.write(offset, &value.to_le_bytes()).unwrap();
+    }
+
+    fn read_word(timer: &mut Timer, offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        timer.read(offset, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut timer = Timer::new();
+        write_word(&mut timer, COUNT, 3);
+        timer.tick();
+        assert_eq!(read_word(&mut timer, COUNT), 3);
+
+        write_word(&mut timer, CTRL, ENABLE);
+        timer.tick();
+        timer.tick();
+        assert_eq!(read_word(&mut timer, STATUS), 0);
+        timer.tick();
+        assert_eq!(read_word(&mut timer, STATUS), EXPIRED);
+        assert_eq!(read_word(&mut timer, CTRL), 0);
+        assert!(!timer.irq());
+
+        write_word(&mut timer, STATUS, EXPIRED);
+        assert_eq!(read_word(&mut timer, STATUS), 0);
+    }
+
+    #[test]
+    fn test_periodic() {
+        let mut timer = Timer::new();
+        write_word(&mut timer, COUNT, 2);
+        write_word(&mut timer, RELOAD, 2);
+        write_word(&mut timer, CTRL, ENABLE | IRQ_ENABLE);
+        let mut fired = 0;
+        for _ in 0..10 {
+            timer.tick();
+            if timer.irq() {
+                fired += 1;
+                write_word(&mut timer, STATUS, EXPIRED);
+            }
+        }
+        assert_eq!(fired, 5);
+        assert_eq!(read_word(&mut timer, CTRL), ENABLE | IRQ_ENABLE);
+        // Byte access to a register
+        timer.write(RELOAD + 1, &[1]).unwrap();
+        assert_eq!(read_word(&mut timer, RELOAD), 0x102);
+    }
+
+    #[test]
+    fn test_advance() {
+        for (count, reload, ticks) in [(5, 0, 4), (5, 0, 9), (0, 3, 1), (4, 3, 11), (2, 7, 2)] {
+            let mut ticked = Timer::new();
+            write_word(&mut ticked, COUNT, count);
+            write_word(&mut ticked, RELOAD, reload);
+            write_word(&mut ticked, CTRL, ENABLE | IRQ_ENABLE);
+            let mut advanced = Timer::new();
+            write_word(&mut advanced, COUNT, count);
+            write_word(&mut advanced, RELOAD, reload);
+            write_word(&mut advanced, CTRL, ENABLE | IRQ_ENABLE);
+            assert_eq!(advanced.idle_ticks(), count.saturating_sub(1) as u64);
+
+            for _ in 0..ticks {
+                ticked.tick();
+            }
+            advanced.advance(ticks);
+            for reg in [COUNT, CTRL, STATUS] {
+                assert_eq!(read_word(&mut advanced, reg), read_word(&mut ticked, reg));
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Serial console with a data and a status register.
+//!
+//! | Offset | Register | Access                                                         |
+//! | ------ | -------- | -------------------------------------------------------------- |
+//! | 0      | `DATA`   | Read takes the next input byte(0 if none), write outputs a byte |
+//! | 4      | `STATUS` | Read only, see [`RX_READY`] and [`TX_READY`]                    |
+//!
+//! Reading a register waits until the input stream has a byte or reaches its end.
+
+use super::read_reg_bytes;
+use crate::{bus::Device, emulator::EmulatorErr};
+use std::io::{Read, Write};
+
+/// Offset of the data register
+pub const DATA: usize = 0;
+/// Offset of the status register
+pub const STATUS: usize = 4;
+
+/// Status bit set when an input byte is available in `DATA`
+pub const RX_READY: u32 = 1 << 0;
+/// Status bit set when a byte can be written to `DATA`
+pub const TX_READY: u32 = 1 << 1;
+
+/// UART connected to an input and an output stream
+pub struct Uart<R: Read, W: Write> {
+    input: R,
+    output: W,
+    /// Byte read ahead from the input to answer `STATUS` reads
+    pending: Option<u8>,
+    /// Set once the input stream reaches its end
+    eof: bool,
+}
+
+impl<R: Read, W: Write> Uart<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            pending: None,
+            eof: false,
+        }
+    }
+
+    /// Reads ahead one byte from the input if none is pending
+    fn fill(&mut self) -> Result<(), EmulatorErr> {
+        if self.pending.is_some() || self.eof {
+            return Ok(());
+        }
+        let mut byte = [0u8; 1];
+        match self.input.read(&mut byte)? {
+            0 => self.eof = true,
+            _ => self.pending = Some(byte[0]),
+        }
+        Ok(())
+    }
+
+    fn read_reg(&mut self, reg: usize) -> Result<u32, EmulatorErr> {
+        self.fill()?;
+        Ok(match reg {
+            DATA => self.pending.take().map_or(0, u32::from),
+            STATUS if self.pending.is_some() => RX_READY | TX_READY,
+            _ => TX_READY,
+        })
+    }
+}
+
+impl<R: Read, W: Write> Device for Uart<R, W> {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        // Registers are 4 bytes wide, a narrower read returns part of the register
+        let reg = offset & !3;
+        let value = self.read_reg(reg)?;
+        read_reg_bytes(value, offset - reg, buf);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        // Only a write to the lowest byte of DATA transmits, rest is ignored
+        if offset == DATA {
+            self.output.write_all(&bytes[..1])?;
+            self.output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Uart, DATA, RX_READY, STATUS, TX_READY};
+    use crate::bus::Device;
+    use std::io::Cursor;
+
+    fn read_word(uart: &mut impl Device, offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        uart.read(offset, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_uart() {
+        let mut out = Vec::new();
+        let mut uart = Uart::new(Cursor::new(b"hi".to_vec()), &mut out);
+        assert_eq!(read_word(&mut uart, STATUS), RX_READY | TX_READY);
+        assert_eq!(read_word(&mut uart, DATA), b'h' as u32);
+        assert_eq!(read_word(&mut uart, DATA), b'i' as u32);
+        assert_eq!(read_word(&mut uart, STATUS), TX_READY);
+        assert_eq!(read_word(&mut uart, DATA), 0);
+
+        uart.write(DATA, &0x141u32.to_le_bytes()).unwrap();
+        uart.write(DATA, b"B").unwrap();
+        uart.write(STATUS, b"C").unwrap();
+        drop(uart);
+        assert_eq!(out, b"AB");
+    }
+}
@@ -0,0 +1,123 @@
+//! Converts encoded instructions back to assembly, see src/info.rs for the encoding.
+
+use crate::info::{self, bits::*};
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+
+fn get_bits(bits: u32, n: u8, offset: u8) -> u32 {
+    (bits >> offset) & ((1 << n) - 1)
+}
+
+/// Returns the assembly for `inst` located at index `pc`, branch targets are shown
+/// as instruction indices. Words which are not valid instructions are shown as `.word`.
+pub fn disassemble(inst: u32, pc: usize) -> String {
+    let invalid = format!(".word 0x{:08x}", inst);
+    let opcode = info::opcode_of(inst);
+    let Some(ins) = info::instruction(opcode) else {
+        return invalid;
+    };
+    let is_imm = info::supports_imm(opcode) && get_bits(inst, IMMBIT_BITS, IMMBIT_OFF) == 1;
+    let modbits = get_bits(inst, MOD_BITS, MOD_OFF) as u8;
+    let dst = get_bits(inst, REG_BITS, DST_OFF);
+    let src1 = get_bits(inst, REG_BITS, SRC1_OFF);
+    let imm = get_bits(inst, IMM_BITS, 0);
+
+    let suffix = match modbits {
+        _ if !is_imm => "",
+        MOD_DEF => "",
+        MOD_U if info::supports_mod(opcode) || info::is_subword_load(opcode) => "u",
+        MOD_H if info::supports_mod(opcode) => "h",
+        MOD_W if info::supports_mod(opcode) => "w",
+        _ => return invalid,
+    };
+    // Sub-word loads zero extend the loaded value with 'u', not the offset
+    let src2 = if !is_imm {
+        format!("r{}", get_bits(inst, REG_BITS, SRC2_OFF))
+    } else if modbits == MOD_DEF || info::is_subword_load(opcode) {
+        (imm as u16 as i16).to_string()
+    } else {
+        imm.to_string()
+    };
+    let name = format!("{}{}", ins.name, suffix);
+
+    if info::is_atomic(opcode) {
+        return match ins.nsrc {
+            2 => format!("{name} r{dst}, {src2}, [r{src1}]"),
+            _ => format!("{name} r{dst}, [r{src1}]"),
+        };
+    }
+    if info::is_mem_access(opcode) {
+        return match is_imm {
+            true => format!("{name} r{dst}, {src2}[r{src1}]"),
+            false => format!("{name} r{dst}, [r{src1} + {src2}]"),
+        };
+    }
+    match (ins.ndst, ins.nsrc) {
+        (1, 2) => format!("{name} r{dst}, r{src1}, {src2}"),
+        (0, 2) => format!("{name} r{src1}, {src2}"),
+        (1, 1) => format!("{name} r{dst}, {src2}"),
+        (0, 1) => format!("{name} {}", pc as i64 + info::branch_offset(inst) as i64),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+    use crate::{
+        emulator::Xlen,
+        memory::Endian,
+        parser::{assemble_for, parse_and_assemble},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let code = [
+            "add r1, r2, r3",
+            "sub r1, r2, -5",
+            "movu r4, 65535",
+            "movh r4, 1",
+            "movw r4, 2",
+            "cmp r1, 10",
+            "not r2, r3",
+            "ld r1, -4[r14]",
+            "ldbu r2, 3[r0]",
+            "sth r5, 2[r1]",
+            "csrw r1, 3",
+            "nop",
+            "sys",
+            "swap r1, [r2]",
+            "cas r3, r4, [r14]",
+            "xor r5, r6, r7",
+            "mulhu r1, r2, r3",
+            "divu r4, r5, r6",
+            "cmpu r7, r8",
+            "mla r9, r10, r11",
+            "popcnt r12, r13",
+            "sxth r14, r15",
+            "maxu r1, r2, r3",
+            "subs r4, r5, r6",
+        ];
+        let code_text = code.join("\n") + "\n";
+        let bin = assemble_for(&code_text, Endian::Little, Xlen::X64)
+            .unwrap()
+            .text;
+        for (i, (&inst, &line)) in bin.iter().zip(&code).enumerate() {
+            assert_eq!(disassemble(inst, i), line);
+        }
+    }
+
+    #[test]
+    fn test_branches() {
+        let bin = parse_and_assemble("start: nop\nnop\nbeq start\ncall end\nend:\n").unwrap();
+        assert_eq!(disassemble(bin[2], 2), "beq 0");
+        assert_eq!(disassemble(bin[3], 3), "call 4");
+        let bin = parse_and_assemble("start: blo end\nbvs start\nend:\n").unwrap();
+        assert_eq!(disassemble(bin[0], 0), "blo 2");
+        assert_eq!(disassemble(bin[1], 1), "bvs 0");
+        assert_eq!(disassemble(0xffff_ffff, 0), ".word 0xffffffff");
+        assert_eq!(disassemble(0xf800_0000 | 40, 0), ".word 0xf8000028");
+    }
+}
@@ -1,10 +1,472 @@
 //! Implements a basic emulator for simpleRISC.
 //! It uses 2's complement wrap-around arithmetic for all calculations.
+//! Memory is byte addressable and multi-byte values are stored in little-endian order.
 
-use crate::info::{self, bits::*, Opcode};
-use std::{fmt, num::Wrapping};
+#[cfg(feature = "jit")]
+use crate::jit;
+use crate::{
+    cache::Cache,
+    cost::{CycleCosts, EnergyCosts},
+    csr::{self, CsrFile},
+    debug::{
+        Access, Breakpoint, CallFrame, MemHit, MemWatch, RegHit, RegObserver, RegWatch, StopReason,
+    },
+    disasm::disassemble,
+    expr::{self, Expr},
+    heatmap::Heatmap,
+    history::{LoggedMemory, MemWrite, Undo},
+    info::{self, bits::*, Opcode},
+    interrupt,
+    memcheck::MemCheck,
+    memory::{Endian, Memory, PagedMemory, Protection, Region},
+    mmu,
+    pipeline::{self, Pipeline},
+    profile::Profiler,
+    program::{Program, DATA_BASE},
+    regstats::RegStats,
+    state::{MachineState, State},
+    syscall::{SyscallContext, SyscallHandler},
+    writelog::{WriteLog, WriteRecord},
+};
+#[cfg(feature = "std")]
+use crate::{
+    replay::{Recorder, Recording},
+    syscall::DefaultSyscalls,
+    trace::{TraceEvent, TracedStep, Tracer},
+};
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, vec, vec::Vec};
+use core::{fmt, mem, num::Wrapping, ops::Range, str::FromStr};
+#[cfg(feature = "std")]
+use std::io;
+
+/// Default size of the memory in bytes
+pub const DEFAULT_MEM_SIZE: usize = 16 * 1024;
+/// Default of [`EmulatorConfig::checkpoints_kept`]
+pub const DEFAULT_CHECKPOINTS_KEPT: usize = 8;
+
+/// Instructions which take a millisecond on the fake clock, see [`EmulatorConfig::fake_clock`]
+pub const FAKE_CLOCK_STEPS_PER_MS: u64 = 1000;
+
+/// Options for creating an [`Emulator`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmulatorConfig {
+    /// Size of the memory in bytes, addresses lie in `[0, mem_bytes)`.
+    /// Memory is allocated lazily so large sizes are fine.
+    /// Not used if the memory is supplied using [`Emulator::with_memory`].
+    pub mem_bytes: usize,
+    /// Convert faults like dividing by zero into traps handled by the program
+    /// instead of stopping the execution with an error, see [`csr::fault_cause`]
+    pub trap_faults: bool,
+    /// Seed of the generator used by the random system call,
+    /// if `None` it is seeded with the current time
+    pub seed: Option<u64>,
+    /// Make the time system call return a clock derived from the number of executed
+    /// instructions instead of the real time, so that runs are reproducible
+    pub fake_clock: bool,
+    /// Number of steps which can be undone using [`Emulator::step_back`],
+    /// 0 disables recording the undo information
+    pub history_depth: usize,
+    /// Cycles taken by each instruction, see [`Emulator::cycles`]
+    pub cycle_costs: CycleCosts,
+    /// Energy used by each instruction and data access, see [`Emulator::energy`]
+    pub energy_costs: EnergyCosts,
+    /// Stop with [`EmulatorErr::InfiniteLoop`] when a backward branch is taken twice in
+    /// a row with the same registers, flags and CSRs, without accessing the memory or a
+    /// device in between and with interrupts disabled, so nothing can ever change
+    pub detect_loops: bool,
+    /// Stop with [`ExitStatus::BudgetExceeded`] once this many instructions are executed
+    pub max_steps: Option<u64>,
+    /// Decode the program once when it is loaded instead of decoding every instruction
+    /// each time it is executed. Straight-line code is then compiled into blocks, which are
+    /// run at once when no debugging aid or observer needs to see every step,
+    /// see [`Emulator::run_for`].
+    pub predecode: bool,
+    /// Byte order of the words and halfwords loaded and stored by the program, and of the
+    /// vector table and the page tables it writes
+    pub endian: Endian,
+    /// Load the program into memory at this address and fetch every instruction from
+    /// there, so that it can modify its own code or load code at run time. `pc` still
+    /// counts instructions, the one at `pc` being the word at `text_base + 4 * pc`, and
+    /// the program ends when `pc` leaves the loaded instructions. That address is
+    /// translated like a load when the MMU is enabled. Such programs are never
+    /// predecoded.
+    pub text_base: Option<usize>,
+    /// Make two runs of a program with the same input identical: the random system call
+    /// is seeded with [`DETERMINISTIC_SEED`] unless `seed` is set, the time system call uses
+    /// the fake clock, and the input system calls wait for the bytes of a non-blocking
+    /// input instead of returning before they arrive. Devices only depend on the executed
+    /// instructions unless they are fed by the host, like a [`Keyboard::from_reader`].
+    ///
+    /// [`DETERMINISTIC_SEED`]: crate::syscall::DETERMINISTIC_SEED
+    /// [`Keyboard::from_reader`]: crate::devices::Keyboard::from_reader
+    pub deterministic: bool,
+    /// Snapshot the state every this many instructions so that the execution can be
+    /// rolled back using [`Emulator::rollback_to`], 0 disables the checkpoints
+    pub checkpoint_interval: u64,
+    /// Number of periodic checkpoints kept, the oldest ones are dropped
+    pub checkpoints_kept: usize,
+    /// Stop with [`EmulatorErr::Overflow`] when the result of `add`, `sub` or `mul` does
+    /// not fit in a signed word instead of wrapping around, or trap if `trap_faults` is set
+    pub trap_overflow: bool,
+    /// Enforce the register conventions: stop with [`EmulatorErr::ClobberedRetReg`] when an
+    /// instruction other than `call` writes r15, except `ld` restoring a saved return
+    /// address, and with [`EmulatorErr::UnalignedStack`] when `sp` is set to an address
+    /// which is not a multiple of 4
+    pub strict: bool,
+    /// What happens when `pc` leaves the program without the exit system call
+    pub pc_bounds: PcBounds,
+    /// Width of the registers and of the words loaded and stored by `ld` and `st`
+    pub xlen: Xlen,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        Self {
+            mem_bytes: DEFAULT_MEM_SIZE,
+            trap_faults: false,
+            seed: None,
+            fake_clock: false,
+            history_depth: 0,
+            cycle_costs: CycleCosts::default(),
+            energy_costs: EnergyCosts::default(),
+            detect_loops: false,
+            max_steps: None,
+            predecode: true,
+            endian: Endian::Little,
+            text_base: None,
+            deterministic: false,
+            checkpoint_interval: 0,
+            checkpoints_kept: DEFAULT_CHECKPOINTS_KEPT,
+            trap_overflow: false,
+            strict: false,
+            pc_bounds: PcBounds::Halt,
+            xlen: Xlen::X32,
+        }
+    }
+}
+
+/// State at a backward branch, see [`EmulatorConfig::detect_loops`]
+#[derive(PartialEq, Eq)]
+struct LoopState {
+    pc: i32,
+    regs: [Wrapping<i32>; 16],
+    regs_hi: [i32; 16],
+    flags: (bool, bool),
+    csrs: CsrFile,
+}
+
+/// Second source operand of an instruction
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(usize),
+    /// Immediate after applying the modifier
+    Imm(i32),
+}
+
+/// Ticks owed to the devices by [`Emulator::run_blocks`]
+struct DeviceTicks {
+    /// Instructions executed since the devices were last ticked
+    pending: u64,
+    /// Instructions which can be executed before the devices have to be ticked,
+    /// as they may then request an interrupt
+    quiet: u64,
+}
+
+impl DeviceTicks {
+    /// Starts owing nothing to devices which are idle for `idle_ticks`
+    fn new(idle_ticks: u64) -> Self {
+        Self {
+            pending: 0,
+            quiet: idle_ticks.saturating_add(1),
+        }
+    }
+}
+
+/// Operation of a compiled [`Block`], one or two instructions which only use the
+/// registers and flags
+#[derive(Clone, Copy)]
+pub(crate) enum BlockOp {
+    Add {
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    Sub {
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    And {
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    Or {
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    Xor {
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    Mov {
+        dst: u8,
+        src2: Src,
+    },
+    /// One of the others computed by [`alu`]
+    Alu {
+        opcode: Opcode,
+        dst: u8,
+        src1: u8,
+        src2: Src,
+    },
+    /// Load of `size` bytes at `base + offset`, the instruction `at` in the block
+    Load {
+        size: u8,
+        zero_ext: bool,
+        dst: u8,
+        base: u8,
+        offset: Src,
+        at: u16,
+    },
+    /// Store of `size` bytes at `base + offset`, the instruction `at` in the block
+    Store {
+        size: u8,
+        src: u8,
+        base: u8,
+        offset: Src,
+        at: u16,
+    },
+    Cmp {
+        src1: u8,
+        src2: Src,
+    },
+    /// Branch to `target` if `cond`, a branch opcode, is taken
+    Branch {
+        cond: Opcode,
+        target: i32,
+    },
+    /// `cmp` fused with the conditional branch after it
+    CmpBranch {
+        src1: u8,
+        src2: Src,
+        cond: Opcode,
+        target: i32,
+    },
+}
+
+/// Second source operand of a [`BlockOp`]
+#[derive(Clone, Copy)]
+pub(crate) enum Src {
+    Reg(u8),
+    Imm(i32),
+}
+
+impl From<Operand> for Src {
+    fn from(operand: Operand) -> Self {
+        match operand {
+            Operand::Reg(reg) => Self::Reg(reg as u8),
+            Operand::Imm(imm) => Self::Imm(imm),
+        }
+    }
+}
+
+/// Straight-line code up to and including its first branch, compiled the first time
+/// `pc` reaches its start. It has no instruction which can fault or leave the program,
+/// except for loads and stores: they are left to [`Emulator::exec_inst`] if they cannot
+/// be done directly in the memory, which ends the block early.
+#[derive(Clone, Copy)]
+struct Block {
+    /// Range of its operations in [`BlockCache::ops`]
+    ops: (u32, u32),
+    /// Number of instructions
+    len: u64,
+    /// `pc` after the block when its branch is not taken
+    next_pc: i32,
+    cycles: u64,
+    energy: u64,
+    /// Native code run instead of the operations
+    #[cfg(feature = "jit")]
+    code: Option<jit::Code>,
+}
+
+/// Blocks compiled so far, indexed by the `pc` they start at
+#[derive(Default)]
+struct BlockCache {
+    /// Index in `blocks` plus one, 0 if not compiled yet and [`BlockCache::NONE`]
+    /// if the instruction at `pc` cannot start a block
+    index: Vec<u32>,
+    blocks: Vec<Block>,
+    ops: Vec<BlockOp>,
+    #[cfg(feature = "jit")]
+    jit: jit::Jit,
+}
+
+impl BlockCache {
+    const NONE: u32 = u32::MAX;
+
+    fn new(len: usize) -> Self {
+        Self {
+            index: vec![0; len],
+            ..Default::default()
+        }
+    }
+
+    /// Returns the block starting at `pc`, compiling it from `decoded` if needed.
+    /// None if the instruction at `pc` has to be executed by [`Emulator::exec_inst`].
+    #[inline]
+    fn get(
+        &mut self,
+        pc: usize,
+        decoded: &[Option<DecodedIns>],
+        config: &EmulatorConfig,
+    ) -> Option<Block> {
+        match self.index.get(pc).copied()? {
+            0 => {
+                let block = self.compile(pc, decoded, config);
+                self.index[pc] = block.map_or(Self::NONE, |_| self.blocks.len() as u32);
+                block
+            }
+            Self::NONE => None,
+            i => Some(self.blocks[i as usize - 1]),
+        }
+    }
+
+    #[cold]
+    fn compile(
+        &mut self,
+        start: usize,
+        decoded: &[Option<DecodedIns>],
+        config: &EmulatorConfig,
+    ) -> Option<Block> {
+        use Opcode::*;
+
+        let first = self.ops.len();
+        let mut block = Block {
+            ops: (first as u32, first as u32),
+            len: 0,
+            next_pc: start as i32,
+            cycles: 0,
+            energy: 0,
+            #[cfg(feature = "jit")]
+            code: None,
+        };
+        // Leaving the program is reported by exec_inst when it is an error
+        let end = match config.pc_bounds {
+            PcBounds::Error => decoded.len().saturating_sub(1),
+            _ => decoded.len(),
+        };
+        let in_program = |target: i32| {
+            config.pc_bounds != PcBounds::Error || (0..decoded.len() as i32).contains(&target)
+        };
+        // The instructions of a block are counted by `at`
+        for (pc, ins) in decoded
+            .iter()
+            .enumerate()
+            .take(end.min(start + u16::MAX as usize))
+            .skip(start)
+        {
+            let Some(ins) = *ins else { break };
+            let target = pc as i32 + ins.offset;
+            let (dst, src1, src2) = (ins.dst_reg as u8, ins.src1_reg as u8, ins.src2.into());
+            let (size, at) = (access_size(ins.opcode), block.len as u16);
+            let op = match ins.opcode {
+                // Left to exec_inst to report the overflow
+                ADD | SUB | MUL if config.trap_overflow => break,
+                CMP => Some(BlockOp::Cmp { src1, src2 }),
+                NOP => None,
+                B | BEQ | BGT if in_program(target) => Some(BlockOp::Branch {
+                    cond: ins.opcode,
+                    target,
+                }),
+                _ if info::is_nzcv_branch(ins.opcode as u8) && in_program(target) => {
+                    Some(BlockOp::Branch {
+                        cond: ins.opcode,
+                        target,
+                    })
+                }
+                ST | STB | STH => Some(BlockOp::Store {
+                    size,
+                    src: dst,
+                    base: src1,
+                    offset: src2,
+                    at,
+                }),
+                // The others write dst, left to exec_inst to check the conventions
+                _ if config.strict && ins.dst_reg >= info::SP_REG => break,
+                ADD => Some(BlockOp::Add { dst, src1, src2 }),
+                SUB => Some(BlockOp::Sub { dst, src1, src2 }),
+                AND => Some(BlockOp::And { dst, src1, src2 }),
+                OR => Some(BlockOp::Or { dst, src1, src2 }),
+                XOR => Some(BlockOp::Xor { dst, src1, src2 }),
+                MOV => Some(BlockOp::Mov { dst, src2 }),
+                opcode if is_alu(opcode) => Some(BlockOp::Alu {
+                    opcode,
+                    dst,
+                    src1,
+                    src2,
+                }),
+                LD | LDB | LDH => Some(BlockOp::Load {
+                    size,
+                    zero_ext: ins.zero_ext,
+                    dst,
+                    base: src1,
+                    offset: src2,
+                    at,
+                }),
+                _ => break,
+            };
+            block.len += 1;
+            block.next_pc += 1;
+            block.cycles += config.cycle_costs.get(ins.opcode) as u64;
+            block.energy += config.energy_costs.get(ins.opcode) as u64;
+            match (op, self.ops[first..].last_mut()) {
+                (Some(BlockOp::Branch { cond, target }), Some(last @ BlockOp::Cmp { .. })) => {
+                    let BlockOp::Cmp { src1, src2 } = *last else {
+                        unreachable!()
+                    };
+                    *last = BlockOp::CmpBranch {
+                        src1,
+                        src2,
+                        cond,
+                        target,
+                    };
+                }
+                (Some(op), _) => self.ops.push(op),
+                (None, _) => {}
+            }
+            if info::is_branch(ins.opcode as u8) {
+                break;
+            }
+        }
+        block.ops.1 = self.ops.len() as u32;
+        #[cfg(feature = "jit")]
+        if block.len > 0 {
+            block.code = self.jit.compile(&self.ops[first..], block.next_pc);
+        }
+        (block.len > 0).then(|| {
+            self.blocks.push(block);
+            block
+        })
+    }
+}
+
+/// Fields of an instruction which do not depend on the machine state
+#[derive(Clone, Copy)]
+struct DecodedIns {
+    opcode: Opcode,
+    dst_reg: usize,
+    src1_reg: usize,
+    src2: Operand,
+    /// Sign extended branch offset
+    offset: i32,
+    zero_ext: bool,
+    /// The instruction writes its result to `dst_reg`, see [`pipeline::operands`]
+    writes_dst: bool,
+}
 
-const MEM_WORD_MAX: usize = 4096;
 struct UnpackedIns {
     dst_reg: usize,
     src1: Wrapping<i32>,
@@ -12,200 +474,2605 @@ struct UnpackedIns {
     memaddr: i32,
     new_pc: i32,
     opcode: Opcode,
+    /// Zero extend the value read by a sub-word load instead of sign extending
+    zero_ext: bool,
+    writes_dst: bool,
+}
+
+pub struct Emulator<M: Memory = PagedMemory> {
+    /// Register file, r[0-15]
+    regs: [Wrapping<i32>; 16],
+    /// Upper halves of the registers, the sign of the lower halves unless a 64-bit
+    /// instruction wrote them, see [`Xlen::X64`]. Not kept up to date in 32-bit mode.
+    regs_hi: [i32; 16],
+    memory: M,
+    /// Copy of the program, so that the emulator does not borrow it
+    instructions: Vec<u32>,
+    /// Decoded program if [`EmulatorConfig::predecode`] is set, `None` for instructions
+    /// which fail to decode
+    decoded: Vec<Option<DecodedIns>>,
+    /// Blocks compiled from `decoded` for [`Emulator::run_blocks`]
+    blocks: BlockCache,
+    /// Initial contents of the memory from [`DATA_BASE`], see [`Emulator::load`]
+    data: Vec<u8>,
+    /// `pc` of the first instruction
+    entry: i32,
+    prog_cnt: i32,
+    flag_e: bool,
+    flag_g: bool,
+    csrs: CsrFile,
+    config: EmulatorConfig,
+    /// Set by the exit system call
+    exit_code: Option<i32>,
+    /// Number of instructions executed so far
+    steps: u64,
+    /// Cycles taken by the executed instructions
+    cycles: u64,
+    /// Estimated energy used by the executed instructions
+    energy: u64,
+    #[cfg(feature = "std")]
+    default_syscalls: DefaultSyscalls,
+    /// Consulted before the default system calls
+    syscall_handler: Option<Box<dyn SyscallHandler>>,
+    mem_watches: Vec<MemWatch>,
+    regions: Vec<Region>,
+    reg_watches: Vec<RegWatch>,
+    /// Observers and the register each one observes
+    reg_observers: Vec<(usize, RegObserver)>,
+    breakpoints: Vec<Breakpoint>,
+    /// Calls made by the program which have not returned yet, innermost last
+    call_stack: Vec<CallFrame>,
+    /// Set after pausing at a breakpoint so that resuming executes its instruction
+    resume_pc: Option<i32>,
+    /// Set when the current instruction triggers a debugging aid
+    stop_reason: Option<StopReason>,
+    /// Undo records of the last steps, latest last
+    history: VecDeque<Undo>,
+    /// Periodic checkpoints, see [`EmulatorConfig::checkpoint_interval`]
+    checkpoints: VecDeque<State>,
+    /// Writes made by the current step, logged if the history or the trace needs them
+    mem_log: Vec<MemWrite>,
+    #[cfg(feature = "std")]
+    tracer: Option<Tracer>,
+    pipeline: Option<Pipeline>,
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    profiler: Option<Profiler>,
+    heatmap: Option<Heatmap>,
+    reg_stats: Option<RegStats>,
+    memcheck: Option<MemCheck>,
+    write_log: Option<WriteLog>,
+    /// State at the last backward branch
+    loop_state: Option<LoopState>,
+    /// Set when the memory or a device was accessed after the last backward branch
+    loop_effects: bool,
+}
+
+/// How the execution of a program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// `pc` went outside the program
+    Finished,
+    /// The program used the exit system call with this code
+    Exited(i32),
+    /// The program executed [`EmulatorConfig::max_steps`] instructions without stopping
+    BudgetExceeded,
+}
+
+/// What happens when `pc` leaves the program, like by falling off the end of the last
+/// function, while the exit system call was not used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PcBounds {
+    /// Stop with [`ExitStatus::Finished`]
+    #[default]
+    Halt,
+    /// Stop like [`PcBounds::Halt`], the host warns that the program did not exit
+    Warn,
+    /// Stop with [`EmulatorErr::PcOutOfBounds`] at the instruction which left
+    Error,
+}
+
+/// Parses `halt`, `warn` or `error`
+impl FromStr for PcBounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(Self::Halt),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("Invalid pc bounds policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for PcBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Halt => "halt",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// Width of the registers, see [`EmulatorConfig::xlen`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Xlen {
+    #[default]
+    X32,
+    /// Registers hold 64 bits and the arithmetic, logical, shift and compare instructions
+    /// work on all of them, `ld` and `st` access 8 aligned bytes and the `w` modifier
+    /// shifts an immediate into bits 32-47. Other instructions produce 32-bit values
+    /// which are sign extended, and the debugging aids, traces, system calls and
+    /// [`Emulator::get_reg_val`] see the lower halves of the registers. Programs are
+    /// not predecoded and [`EmulatorConfig::trap_overflow`] is not supported.
+    X64,
 }
 
-pub struct Emulator<'a> {
-    /// Register file, r[0-15]
-    regs: [Wrapping<i32>; 16],
-    /// Stores words(=4bytes) instead of storing each byte seperately.
-    /// Only for aligned(by 4 bytes) access, `word_index = memaddr/4`
-    wmemory: [Wrapping<i32>; MEM_WORD_MAX],
-    instructions: &'a [u32],
-    prog_cnt: i32,
-    flag_e: bool,
-    flag_g: bool,
-}
+/// Parses `32` or `64`
+impl FromStr for Xlen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "32" => Ok(Self::X32),
+            "64" => Ok(Self::X64),
+            _ => Err(format!("Invalid register width '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Xlen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::X32 => "32",
+            Self::X64 => "64",
+        })
+    }
+}
+
+/// Outcome of [`Emulator::run_for`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The instruction budget ran out before the program stopped
+    Running,
+    /// The program stopped within the budget
+    Stopped(ExitStatus),
+    /// The execution was paused by a debugging aid after executing the
+    /// instruction which triggered it, it can be resumed
+    Break(StopReason),
+}
+
+#[derive(Debug)]
+pub enum EmulatorErr {
+    InvalidModbits,
+    InvalidMemAddr,
+    InvalidOpcode,
+    DivideByZero,
+    /// Signed overflow of an arithmetic instruction on its two source operands,
+    /// see [`EmulatorConfig::trap_overflow`]
+    Overflow(i32, i32),
+    UnalignedMemAddr,
+    /// Privileged instruction executed in user mode
+    PrivilegedIns,
+    /// Access to a protected region which it does not allow, see [`Emulator::protect`]
+    ProtectionFault,
+    /// Access to a virtual page which is not mapped or does not allow it, see [`crate::mmu`]
+    PageFault,
+    /// r15 written by an instruction other than `call`, see [`EmulatorConfig::strict`]
+    ClobberedRetReg,
+    /// `sp` set to this address which is not word aligned, see [`EmulatorConfig::strict`]
+    UnalignedStack(i32),
+    /// `pc` left the program for this one, see [`EmulatorConfig::pc_bounds`]
+    PcOutOfBounds(i32),
+    /// The program can never leave the loop it is in, see [`EmulatorConfig::detect_loops`]
+    InfiniteLoop,
+    InvalidCsr,
+    InvalidSyscall,
+    /// The program asked for inputs which were not recorded, see [`crate::replay`]
+    ReplayDiverged,
+    /// A device failed to read or write its stream
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// An error which stopped the program, along with where it happened and the calls
+    /// which led to it
+    Fault {
+        err: Box<EmulatorErr>,
+        /// `pc` of the faulting instruction, the first one of the loop for
+        /// [`EmulatorErr::InfiniteLoop`]
+        pc: i32,
+        /// Encoding of the instruction, `None` if `pc` is outside the program
+        inst: Option<u32>,
+        /// Calls which had not returned, innermost first
+        backtrace: Vec<CallFrame>,
+    },
+}
+
+impl EmulatorErr {
+    /// Returns the error without the context of a [`EmulatorErr::Fault`]
+    pub fn kind(&self) -> &EmulatorErr {
+        match self {
+            Self::Fault { err, .. } => err.kind(),
+            err => err,
+        }
+    }
+
+    /// `pc` of the instruction which caused the error, if known
+    pub fn pc(&self) -> Option<i32> {
+        match self {
+            Self::Fault { pc, .. } => Some(*pc),
+            _ => None,
+        }
+    }
+
+    /// Calls which had not returned when the error occurred, innermost first
+    pub fn backtrace(&self) -> &[CallFrame] {
+        match self {
+            Self::Fault { backtrace, .. } => backtrace,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for EmulatorErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidModbits => write!(f, "Invalid immediate modifier bits"),
+            Self::InvalidMemAddr => write!(f, "Memory address out of range"),
+            Self::InvalidOpcode => write!(f, "Non-existent instruction"),
+            Self::DivideByZero => write!(f, "Divide by 0 error"),
+            Self::Overflow(lhs, rhs) => write!(f, "Signed overflow of {} and {}", lhs, rhs),
+            Self::UnalignedMemAddr => write!(f, "Memory address not aligned to the access size"),
+            Self::PrivilegedIns => write!(f, "Privileged instruction executed in user mode"),
+            Self::ProtectionFault => write!(f, "Memory access not allowed by its protection"),
+            Self::PageFault => write!(f, "Page fault"),
+            Self::ClobberedRetReg => write!(f, "Return address in r15 overwritten outside a call"),
+            Self::UnalignedStack(sp) => {
+                write!(f, "Stack pointer set to unaligned address {:#x}", sp)
+            }
+            Self::PcOutOfBounds(pc) => write!(f, "Jump to {} outside the program", pc),
+            Self::InfiniteLoop => write!(f, "Program is stuck in an infinite loop"),
+            Self::InvalidCsr => write!(f, "Non-existent control/status register"),
+            Self::InvalidSyscall => write!(f, "Non-existent system call"),
+            Self::ReplayDiverged => write!(f, "Execution diverged from the recorded run"),
+            #[cfg(feature = "std")]
+            Self::Io(err) => write!(f, "Device I/O failed: {}", err),
+            Self::Fault {
+                err,
+                pc,
+                inst: Some(inst),
+                ..
+            } => write!(
+                f,
+                "{} at {}: {} ({:#010x})",
+                err,
+                pc,
+                disassemble(*inst, *pc as usize),
+                inst
+            ),
+            Self::Fault { err, pc, .. } => write!(f, "{} at {}", err, pc),
+        }
+    }
+}
+
+impl core::error::Error for EmulatorErr {}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for EmulatorErr {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn get_bits(bits: u32, n: u8, offset: u8) -> u32 {
+    (bits >> offset) & (!0u32 >> (32 - n))
+}
+
+/// Returns the flags N, Z, C and V of the `FLAGS` CSR for `lhs + rhs`, or `lhs - rhs`
+/// if `sub` is set, where C is set if the subtraction does not borrow like on ARM
+#[inline]
+fn nzcv(lhs: i32, rhs: i32, sub: bool) -> u32 {
+    let (result, carry, overflow) = match sub {
+        false => {
+            let (result, carry) = (lhs as u32).overflowing_add(rhs as u32);
+            (result as i32, carry, lhs.checked_add(rhs).is_none())
+        }
+        true => (
+            lhs.wrapping_sub(rhs),
+            lhs as u32 >= rhs as u32,
+            lhs.checked_sub(rhs).is_none(),
+        ),
+    };
+    pack_nzcv(result < 0, result == 0, carry, overflow)
+}
+
+/// Returns the `FLAGS` CSR with the flags N, Z, C and V set as given
+#[inline]
+fn pack_nzcv(n: bool, z: bool, c: bool, v: bool) -> u32 {
+    let mut flags = 0;
+    for (set, flag) in [
+        (n, csr::FLAG_N),
+        (z, csr::FLAG_Z),
+        (c, csr::FLAG_C),
+        (v, csr::FLAG_V),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+    flags
+}
+
+/// Like [`nzcv`] for the 64-bit registers of [`Xlen::X64`]
+fn nzcv_wide(lhs: i64, rhs: i64, sub: bool) -> u32 {
+    let (result, carry, overflow) = match sub {
+        false => {
+            let (result, carry) = (lhs as u64).overflowing_add(rhs as u64);
+            (result as i64, carry, lhs.checked_add(rhs).is_none())
+        }
+        true => (
+            lhs.wrapping_sub(rhs),
+            lhs as u64 >= rhs as u64,
+            lhs.checked_sub(rhs).is_none(),
+        ),
+    };
+    pack_nzcv(result < 0, result == 0, carry, overflow)
+}
+
+/// Returns true if the branch at `opcode` on the flags N, Z, C and V is taken with
+/// the `FLAGS` CSR set to `flags`
+#[inline]
+fn nzcv_taken(opcode: Opcode, flags: u32) -> bool {
+    let [n, z, c, v] =
+        [csr::FLAG_N, csr::FLAG_Z, csr::FLAG_C, csr::FLAG_V].map(|flag| flags & flag != 0);
+    match opcode {
+        Opcode::BNE => !z,
+        Opcode::BMI => n,
+        Opcode::BPL => !n,
+        Opcode::BVS => v,
+        Opcode::BVC => !v,
+        Opcode::BHS => c,
+        Opcode::BLO => !c,
+        Opcode::BHI => c && !z,
+        Opcode::BLS => !c || z,
+        Opcode::BGE => n == v,
+        Opcode::BLT => n != v,
+        Opcode::BLE => z || n != v,
+        _ => false,
+    }
+}
+
+/// Returns true for the instructions which only compute a register from their operands
+/// and cannot fault, see [`alu`]
+fn is_alu(opcode: Opcode) -> bool {
+    use Opcode::*;
+
+    matches!(
+        opcode,
+        ADD | SUB
+            | MUL
+            | MULH
+            | MULHU
+            | AND
+            | OR
+            | XOR
+            | NOT
+            | MOV
+            | LSL
+            | LSR
+            | ASR
+            | CLZ
+            | CTZ
+            | POPCNT
+            | SXTB
+            | SXTH
+            | UXTB
+            | UXTH
+            | MIN
+            | MAX
+            | MINU
+            | MAXU
+    )
+}
+
+/// Value computed by an instruction for which [`is_alu`] is true
+#[inline]
+fn alu(opcode: Opcode, src1: Wrapping<i32>, src2: Wrapping<i32>) -> Wrapping<i32> {
+    use Opcode::*;
+
+    // Only the lower 5 bits of a shift amount count, that is at most 31
+    let shift = src2.0 & 0b11111;
+    match opcode {
+        ADD => src1 + src2,
+        SUB => src1 - src2,
+        MUL => src1 * src2,
+        MULH => Wrapping(((src1.0 as i64 * src2.0 as i64) >> 32) as i32),
+        MULHU => Wrapping(((src1.0 as u32 as u64 * src2.0 as u32 as u64) >> 32) as i32),
+        AND => src1 & src2,
+        OR => src1 | src2,
+        XOR => src1 ^ src2,
+        NOT => !src2,
+        MOV => src2,
+        LSL => Wrapping(src1.0 << shift),
+        LSR => Wrapping(((src1.0 as u32) >> shift) as i32),
+        ASR => Wrapping(src1.0 >> shift),
+        CLZ => Wrapping(src2.0.leading_zeros() as i32),
+        CTZ => Wrapping(src2.0.trailing_zeros() as i32),
+        POPCNT => Wrapping(src2.0.count_ones() as i32),
+        SXTB => Wrapping(src2.0 as i8 as i32),
+        SXTH => Wrapping(src2.0 as i16 as i32),
+        UXTB => Wrapping(src2.0 as u8 as i32),
+        UXTH => Wrapping(src2.0 as u16 as i32),
+        MIN => src1.min(src2),
+        MAX => src1.max(src2),
+        MINU => Wrapping((src1.0 as u32).min(src2.0 as u32) as i32),
+        MAXU => Wrapping((src1.0 as u32).max(src2.0 as u32) as i32),
+        _ => unreachable!("{:?} is not computed by the ALU", opcode),
+    }
+}
+
+/// Load called by the native code of blocks, see [`jit::Helpers`]
+#[cfg(feature = "jit")]
+extern "sysv64" fn jit_load<M: Memory>(emul: *mut Emulator<M>, addr: i32, size: u32) -> u64 {
+    // SAFETY: the emulator running the block passes itself, see Emulator::exec_native
+    let emul = unsafe { &mut *emul };
+    emul.load_direct(addr, size as usize)
+        .map_or(0, |value| jit::Jit::DEOPT | value as u64)
+}
+
+/// Store called by the native code of blocks, see [`jit::Helpers`]
+#[cfg(feature = "jit")]
+extern "sysv64" fn jit_store<M: Memory>(
+    emul: *mut Emulator<M>,
+    addr: i32,
+    size: u32,
+    value: u32,
+) -> u32 {
+    // SAFETY: the emulator running the block passes itself, see Emulator::exec_native
+    let emul = unsafe { &mut *emul };
+    emul.store_direct(addr, size as usize, value) as u32
+}
+
+/// Number of bytes accessed by a load or a store
+fn access_size(opcode: Opcode) -> u8 {
+    match opcode {
+        Opcode::LDB | Opcode::STB => 1,
+        Opcode::LDH | Opcode::STH => 2,
+        _ => 4,
+    }
+}
+
+/// Returns the flags E and G and the `FLAGS` CSR set by `cmp` of `lhs` and `rhs`
+#[inline]
+fn compare(lhs: Wrapping<i32>, rhs: Wrapping<i32>) -> (bool, bool, u32) {
+    (lhs == rhs, lhs > rhs, nzcv(lhs.0, rhs.0, true))
+}
+
+/// Returns true if the branch at `opcode` is taken with the flags E and G set as in
+/// `flags` and the `FLAGS` CSR set to `nzcv`
+#[inline]
+fn branch_taken(opcode: Opcode, (flag_e, flag_g): (bool, bool), nzcv: u32) -> bool {
+    match opcode {
+        Opcode::B => true,
+        Opcode::BEQ => flag_e,
+        Opcode::BGT => flag_g,
+        _ => nzcv_taken(opcode, nzcv),
+    }
+}
+
+/// Returns true if `add`, `sub` or `mul` of `lhs` and `rhs` overflows as signed words
+fn overflows(opcode: Opcode, lhs: Wrapping<i32>, rhs: Wrapping<i32>) -> bool {
+    let result = match opcode {
+        Opcode::ADD => lhs.0.checked_add(rhs.0),
+        Opcode::SUB => lhs.0.checked_sub(rhs.0),
+        Opcode::MUL => lhs.0.checked_mul(rhs.0),
+        _ => return false,
+    };
+    result.is_none()
+}
+
+fn sign_extend(num: u32, nbits: u8) -> i32 {
+    if num >> (nbits - 1) != 0 {
+        (num | (!0u32 << nbits)) as i32
+    } else {
+        num as i32
+    }
+}
+
+impl Emulator {
+    pub fn new(instructions: &[u32]) -> Self {
+        Self::with_config(instructions, EmulatorConfig::default())
+    }
+
+    pub fn with_config(instructions: &[u32], config: EmulatorConfig) -> Self {
+        let memory = PagedMemory::new(config.mem_bytes);
+        Self::with_memory_and_config(instructions, memory, config)
+    }
+}
+
+impl<M: Memory> expr::Env for Emulator<M> {
+    fn reg(&self, reg: usize) -> i32 {
+        self.regs[reg].0
+    }
+
+    fn pc(&self) -> i32 {
+        self.prog_cnt
+    }
+
+    fn load(&mut self, addr: i32) -> Result<i32, EmulatorErr> {
+        let addr = usize::try_from(addr).map_err(|_| EmulatorErr::InvalidMemAddr)?;
+        Ok(self.config.endian.word(self.memory.read_word(addr)?) as i32)
+    }
+}
+
+impl<M: Memory> Emulator<M> {
+    /// Creates an emulator using `memory` as its memory
+    pub fn with_memory(instructions: &[u32], memory: M) -> Self {
+        Self::with_memory_and_config(instructions, memory, EmulatorConfig::default())
+    }
+
+    /// Creates an emulator using `memory` as its memory, `config.mem_bytes` is ignored
+    pub fn with_memory_and_config(instructions: &[u32], memory: M, config: EmulatorConfig) -> Self {
+        let decoded = predecode(instructions, &config);
+        let mut emul = Self {
+            regs: [Wrapping(0); 16],
+            regs_hi: [0; 16],
+            memory,
+            instructions: instructions.to_vec(),
+            blocks: Self::new_blocks(decoded.len()),
+            decoded,
+            data: Vec::new(),
+            entry: 0,
+            prog_cnt: 0,
+            flag_e: false,
+            flag_g: false,
+            csrs: CsrFile::default(),
+            #[cfg(feature = "std")]
+            default_syscalls: DefaultSyscalls::new(&config),
+            syscall_handler: None,
+            config,
+            exit_code: None,
+            steps: 0,
+            cycles: 0,
+            energy: 0,
+            mem_watches: Vec::new(),
+            regions: Vec::new(),
+            reg_watches: Vec::new(),
+            reg_observers: Vec::new(),
+            breakpoints: Vec::new(),
+            call_stack: Vec::new(),
+            resume_pc: None,
+            stop_reason: None,
+            history: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            mem_log: Vec::new(),
+            #[cfg(feature = "std")]
+            tracer: None,
+            pipeline: None,
+            icache: None,
+            dcache: None,
+            profiler: None,
+            heatmap: None,
+            reg_stats: None,
+            memcheck: None,
+            write_log: None,
+            loop_state: None,
+            loop_effects: false,
+        };
+        emul.load_text();
+        emul
+    }
+
+    /// Instructions of the program being executed, as they were loaded when they are
+    /// fetched from memory, see [`EmulatorConfig::text_base`]
+    pub fn instructions(&self) -> &[u32] {
+        &self.instructions
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// Reads the bytes in `addrs`, see [`crate::memory::hexdump`] for showing them.
+    /// Reading the registers of a device may have side effects.
+    pub fn read_mem(&mut self, addrs: Range<usize>) -> Result<Vec<u8>, EmulatorErr> {
+        let mut bytes = vec![0; addrs.len()];
+        self.memory.read_bytes(addrs.start, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Writes `bytes` starting at `addr`, bypassing protected regions and watchpoints.
+    /// The bytes count as initialized for [`Emulator::set_memcheck`].
+    pub fn write_mem(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        self.memory.write_bytes(addr, bytes)?;
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.initialize(addr..addr + bytes.len());
+        }
+        self.loop_effects = true;
+        Ok(())
+    }
+
+    /// Reads the word at `addr`, which need not be aligned, like [`Emulator::read_mem`]
+    /// in the byte order of [`EmulatorConfig::endian`]
+    pub fn read_word(&mut self, addr: usize) -> Result<i32, EmulatorErr> {
+        Ok(self.config.endian.word(self.memory.read_word(addr)?) as i32)
+    }
+
+    /// Writes the word at `addr`, which need not be aligned, like [`Emulator::write_mem`]
+    /// in the byte order of [`EmulatorConfig::endian`]
+    pub fn write_word(&mut self, addr: usize, value: i32) -> Result<(), EmulatorErr> {
+        self.write_mem(addr, &self.config.endian.word_bytes(value as u32))
+    }
+
+    /// Registers and flags, whose `Display` shows one register per line
+    pub fn machine_state(&self) -> MachineState {
+        MachineState {
+            regs: self.regs.map(|r| r.0),
+            regs_hi: self.wide_regs_hi(),
+            pc: self.prog_cnt,
+            flag_e: self.flag_e,
+            flag_g: self.flag_g,
+            nzcv: self.csrs.flags,
+        }
+    }
+
+    /// Prints [`Emulator::machine_state`] to the standard output
+    #[cfg(feature = "std")]
+    pub fn debug(&self) {
+        print!("{}", self.machine_state());
+    }
+
+    /// Installs `handler` which can add or override system calls, see [`crate::syscall`]
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscall_handler = Some(handler);
+    }
+
+    /// Sets the stream read by the input system calls, the standard input by default
+    #[cfg(feature = "std")]
+    pub fn set_input(&mut self, input: Box<dyn io::Read>) {
+        self.default_syscalls.set_input(input);
+    }
+
+    /// Sets the stream written by the output system calls, the standard output by default
+    #[cfg(feature = "std")]
+    pub fn set_output(&mut self, output: Box<dyn io::Write>) {
+        self.default_syscalls.set_output(output);
+    }
+
+    /// Logs every instruction executed from now on using `tracer`, see [`crate::trace`]
+    #[cfg(feature = "std")]
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Feeds every instruction executed from now on to the timing model `pipeline`,
+    /// see [`crate::pipeline`]. Stepping back is not reflected in the model.
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipeline = Some(pipeline);
+    }
+
+    pub fn pipeline(&self) -> Option<&Pipeline> {
+        self.pipeline.as_ref()
+    }
+
+    /// Models `cache` as the instruction cache, see [`crate::cache`]
+    pub fn set_icache(&mut self, cache: Cache) {
+        self.icache = Some(cache);
+    }
+
+    /// Models `cache` as the data cache used by loads and stores, see [`crate::cache`]
+    pub fn set_dcache(&mut self, cache: Cache) {
+        self.dcache = Some(cache);
+    }
+
+    pub fn icache(&self) -> Option<&Cache> {
+        self.icache.as_ref()
+    }
+
+    pub fn dcache(&self) -> Option<&Cache> {
+        self.dcache.as_ref()
+    }
+
+    /// Counts the executions of every instruction from now on using `profiler`,
+    /// see [`crate::profile`]
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Counts the loads and stores to each range of addresses from now on using `heatmap`,
+    /// see [`crate::heatmap`]
+    pub fn set_heatmap(&mut self, heatmap: Heatmap) {
+        self.heatmap = Some(heatmap);
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    /// Counts the reads and writes of every register from now on using `reg_stats`,
+    /// see [`crate::regstats`]
+    pub fn set_reg_stats(&mut self, reg_stats: RegStats) {
+        self.reg_stats = Some(reg_stats);
+    }
+
+    pub fn reg_stats(&self) -> Option<&RegStats> {
+        self.reg_stats.as_ref()
+    }
+
+    /// Reports the loads reading memory which was not written from now on using
+    /// `memcheck`, see [`crate::memcheck`]
+    pub fn set_memcheck(&mut self, mut memcheck: MemCheck) {
+        memcheck.initialize(DATA_BASE..DATA_BASE + self.data.len());
+        if let Some(base) = self.config.text_base {
+            memcheck.initialize(base..base + 4 * self.instructions.len());
+        }
+        self.memcheck = Some(memcheck);
+    }
+
+    pub fn memcheck(&self) -> Option<&MemCheck> {
+        self.memcheck.as_ref()
+    }
+
+    /// Logs every write made to the memory from now on in `log`, see [`crate::writelog`].
+    /// Writes undone by [`Emulator::step_back`] are dropped from the log.
+    pub fn set_write_log(&mut self, log: WriteLog) {
+        self.write_log = Some(log);
+    }
+
+    pub fn write_log(&self) -> Option<&WriteLog> {
+        self.write_log.as_ref()
+    }
+
+    /// Records the inputs read by the system calls from now on, see [`crate::replay`].
+    /// Inputs set later using [`Emulator::set_input`] are not recorded.
+    #[cfg(feature = "std")]
+    pub fn record(&mut self, recorder: Recorder) {
+        self.default_syscalls.record(recorder);
+    }
+
+    /// Replays the inputs of the system calls from `recording`, see [`crate::replay`]
+    #[cfg(feature = "std")]
+    pub fn replay(&mut self, recording: &Recording) {
+        self.default_syscalls.replay(recording);
+    }
+
+    pub fn get_reg_val(&self, reg_num: usize) -> i32 {
+        self.regs[reg_num].0
+    }
+
+    /// Sets a register without triggering its watches
+    pub fn set_reg_val(&mut self, reg_num: usize, value: i32) {
+        self.set_reg_val64(reg_num, value as i64);
+    }
+
+    /// Value of the whole register in 64-bit mode, see [`EmulatorConfig::xlen`]
+    pub fn get_reg_val64(&self, reg_num: usize) -> i64 {
+        match self.config.xlen {
+            Xlen::X32 => self.regs[reg_num].0 as i64,
+            Xlen::X64 => (self.regs_hi[reg_num] as i64) << 32 | self.regs[reg_num].0 as u32 as i64,
+        }
+    }
+
+    /// Sets the whole register in 64-bit mode without triggering its watches,
+    /// only the lower half is kept in 32-bit mode
+    pub fn set_reg_val64(&mut self, reg_num: usize, value: i64) {
+        self.regs[reg_num] = Wrapping(value as i32);
+        self.regs_hi[reg_num] = match self.config.xlen {
+            Xlen::X32 => value as i32 >> 31,
+            Xlen::X64 => (value >> 32) as i32,
+        };
+    }
+
+    /// Upper halves of the registers which are part of the state in 64-bit mode
+    fn wide_regs_hi(&self) -> Option<[i32; 16]> {
+        (self.config.xlen == Xlen::X64).then_some(self.regs_hi)
+    }
+
+    /// Index of the next instruction to execute
+    pub fn pc(&self) -> i32 {
+        self.prog_cnt
+    }
+
+    /// Continues the execution at `pc`, a breakpoint there is hit before executing it
+    pub fn set_pc(&mut self, pc: i32) {
+        self.prog_cnt = pc;
+    }
+
+    /// Flags set by `cmp` and `cas` as `(E, GT)`
+    pub fn flags(&self) -> (bool, bool) {
+        (self.flag_e, self.flag_g)
+    }
+
+    pub fn set_flags(&mut self, flag_e: bool, flag_g: bool) {
+        (self.flag_e, self.flag_g) = (flag_e, flag_g);
+    }
+
+    /// Sets the index of the hart read from the `HARTID` CSR, kept by [`Emulator::reset`]
+    pub fn set_hart_id(&mut self, hart: u32) {
+        self.csrs.hartid = hart;
+    }
+
+    pub fn config(&self) -> &EmulatorConfig {
+        &self.config
+    }
+
+    /// Code passed to the exit system call, `None` if the program has not used it
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Cycles taken by the instructions executed so far, see [`EmulatorConfig::cycle_costs`]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Estimated energy used by the instructions executed so far, in the units of
+    /// [`EmulatorConfig::energy_costs`]. Accesses made by system calls are not included.
+    pub fn energy(&self) -> u64 {
+        self.energy
+    }
+
+    /// Makes loads and stores to `addrs` fail with [`EmulatorErr::ProtectionFault`] unless
+    /// `protection` allows them. Accesses made by system calls are not checked.
+    pub fn protect(&mut self, addrs: Range<usize>, protection: Protection) {
+        self.regions.push(Region { addrs, protection });
+    }
+
+    /// Removes the protection of the regions added for exactly `addrs`
+    pub fn unprotect(&mut self, addrs: Range<usize>) {
+        self.regions.retain(|r| r.addrs != addrs);
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Pauses the execution when a load or store instruction makes an `access` to `addrs`.
+    /// Watching the registers of a device reads them before each write.
+    pub fn watch_memory(&mut self, addrs: Range<usize>, access: Access) {
+        self.mem_watches.push(MemWatch { addrs, access });
+    }
+
+    /// Removes the watchpoints added for exactly `addrs`
+    pub fn unwatch_memory(&mut self, addrs: Range<usize>) {
+        self.mem_watches.retain(|w| w.addrs != addrs);
+    }
+
+    pub fn mem_watches(&self) -> &[MemWatch] {
+        &self.mem_watches
+    }
+
+    /// Pauses the execution when an instruction changes `reg`,
+    /// if `value` is given only when it changes to that value
+    pub fn watch_reg(&mut self, reg: usize, value: Option<i32>) {
+        assert!(reg < self.regs.len(), "Invalid register");
+        self.reg_watches.push(RegWatch { reg, value });
+    }
+
+    /// Removes the watchpoints added for `reg`
+    pub fn unwatch_reg(&mut self, reg: usize) {
+        self.reg_watches.retain(|w| w.reg != reg);
+    }
+
+    pub fn reg_watches(&self) -> &[RegWatch] {
+        &self.reg_watches
+    }
+
+    /// Calls `observer` whenever an instruction or a system call changes `reg`, after the
+    /// change. Changes made by [`Emulator::set_reg_val`], restoring a state or stepping
+    /// back are not observed.
+    pub fn observe_reg(&mut self, reg: usize, observer: RegObserver) {
+        assert!(reg < self.regs.len(), "Invalid register");
+        self.reg_observers.push((reg, observer));
+    }
+
+    /// Removes the observers of `reg`
+    pub fn unobserve_reg(&mut self, reg: usize) {
+        self.reg_observers.retain(|(r, _)| *r != reg);
+    }
+
+    /// Pauses the execution before the instruction at `pc`, if a `condition` is given only
+    /// when it evaluates to non-zero. A condition which fails to evaluate also pauses it.
+    pub fn add_breakpoint(&mut self, pc: i32, condition: Option<Expr>) {
+        self.breakpoints.push(Breakpoint { pc, condition });
+    }
+
+    /// Removes the breakpoints at `pc`
+    pub fn remove_breakpoint(&mut self, pc: i32) {
+        self.breakpoints.retain(|b| b.pc != pc);
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Captures the state of the machine so that it can be restored using [`Emulator::restore`]
+    pub fn snapshot(&mut self) -> State {
+        State {
+            regs: self.regs.map(|r| r.0),
+            regs_hi: self.wide_regs_hi(),
+            pc: self.prog_cnt,
+            flag_e: self.flag_e,
+            flag_g: self.flag_g,
+            csrs: self.csrs.clone(),
+            exit_code: self.exit_code,
+            steps: self.steps,
+            cycles: self.cycles,
+            energy: self.energy,
+            call_stack: self.call_stack.clone(),
+            memory: self.memory.contents(),
+        }
+    }
+
+    /// Restores a state captured by [`Emulator::snapshot`], possibly of another emulator.
+    /// Steps recorded for [`Emulator::step_back`] are forgotten. Fails without changing
+    /// anything if the memory is too small for the state.
+    pub fn restore(&mut self, state: &State) -> Result<(), EmulatorErr> {
+        let size = self.memory.size();
+        if state
+            .memory
+            .iter()
+            .any(|(addr, bytes)| addr.checked_add(bytes.len()).is_none_or(|end| end > size))
+        {
+            return Err(EmulatorErr::InvalidMemAddr);
+        }
+        self.memory.clear();
+        for (addr, bytes) in &state.memory {
+            self.memory.write_bytes(*addr, bytes)?;
+        }
+        for (reg, &value) in state.regs.iter().enumerate() {
+            let hi = state.regs_hi.map_or(value >> 31, |hi| hi[reg]);
+            self.set_reg_val64(reg, (hi as i64) << 32 | value as u32 as i64);
+        }
+        self.prog_cnt = state.pc;
+        self.flag_e = state.flag_e;
+        self.flag_g = state.flag_g;
+        self.csrs = CsrFile {
+            hartid: self.csrs.hartid,
+            ..state.csrs.clone()
+        };
+        self.exit_code = state.exit_code;
+        self.steps = state.steps;
+        self.cycles = state.cycles;
+        self.energy = state.energy;
+        self.call_stack = state.call_stack.clone();
+        self.history.clear();
+        self.resume_pc = None;
+        self.stop_reason = None;
+        self.loop_state = None;
+        Ok(())
+    }
+
+    /// Periodic checkpoints taken with [`EmulatorConfig::checkpoint_interval`], oldest first
+    pub fn checkpoints(&self) -> impl Iterator<Item = &State> {
+        self.checkpoints.iter()
+    }
+
+    /// Restores the periodic checkpoint taken after `steps` instructions and drops the
+    /// ones taken after it, returns false if there is no such checkpoint. Like for
+    /// [`Emulator::step_back`], side effects outside the machine, like output or the
+    /// state of devices, are not undone.
+    pub fn rollback_to(&mut self, steps: u64) -> bool {
+        let Some(index) = self.checkpoints.iter().position(|s| s.steps == steps) else {
+            return false;
+        };
+        self.checkpoints.truncate(index + 1);
+        let state = self.checkpoints[index].clone();
+        self.restore(&state).is_ok()
+    }
+
+    /// Takes a periodic checkpoint if one is due before the next instruction
+    fn auto_checkpoint(&mut self) {
+        let interval = self.config.checkpoint_interval;
+        if interval == 0
+            || !self.steps.is_multiple_of(interval)
+            || self
+                .checkpoints
+                .back()
+                .is_some_and(|s| s.steps == self.steps)
+        {
+            return;
+        }
+        if self.checkpoints.len() >= self.config.checkpoints_kept {
+            self.checkpoints.pop_front();
+        }
+        if self.config.checkpoints_kept > 0 {
+            let state = self.snapshot();
+            self.checkpoints.push_back(state);
+        }
+    }
+
+    /// Puts the machine back in its initial state: zeroes the registers, flags and memory,
+    /// sets `pc` to 0, resets the CSRs and counters and restarts the clock and random
+    /// numbers of the system calls. Debugging aids, devices and observers are kept, set
+    /// new observers to collect the next run separately.
+    pub fn reset(&mut self) {
+        self.memory.clear();
+        self.load_text();
+        self.load_data();
+        self.regs = [Wrapping(0); 16];
+        self.regs_hi = [0; 16];
+        self.prog_cnt = self.entry;
+        self.flag_e = false;
+        self.flag_g = false;
+        self.csrs = CsrFile {
+            hartid: self.csrs.hartid,
+            ..Default::default()
+        };
+        self.exit_code = None;
+        self.steps = 0;
+        self.cycles = 0;
+        self.energy = 0;
+        self.call_stack.clear();
+        self.history.clear();
+        self.checkpoints.clear();
+        self.mem_log.clear();
+        self.resume_pc = None;
+        self.stop_reason = None;
+        self.loop_state = None;
+        self.loop_effects = false;
+        #[cfg(feature = "std")]
+        self.default_syscalls.reset();
+    }
+
+    /// Replaces the program and resets the machine, see [`Emulator::reset`].
+    /// Breakpoints are removed as they refer to the old program.
+    pub fn load_program(&mut self, instructions: &[u32]) {
+        self.data.clear();
+        self.entry = 0;
+        self.replace_text(instructions);
+    }
+
+    /// Replaces the program with an assembled one, whose data section is copied to memory
+    /// at [`DATA_BASE`] and which starts at its entry, then resets the machine like
+    /// [`Emulator::load_program`]. [`Emulator::reset`] copies the data again.
+    /// Fails without changing anything if the data does not fit in memory.
+    pub fn load(&mut self, program: &Program) -> Result<(), EmulatorErr> {
+        if DATA_BASE + program.data.len() > self.memory.size() {
+            return Err(EmulatorErr::InvalidMemAddr);
+        }
+        self.data = program.data.clone();
+        self.entry = program.entry as i32;
+        self.replace_text(&program.text);
+        Ok(())
+    }
+
+    /// Returns the [`BlockCache`] for a program of `len` instructions
+    fn new_blocks(len: usize) -> BlockCache {
+        let blocks = BlockCache::new(len);
+        #[cfg(feature = "jit")]
+        let blocks = BlockCache {
+            jit: jit::Jit::new(jit::Helpers {
+                load: jit_load::<M> as *const () as usize,
+                store: jit_store::<M> as *const () as usize,
+            }),
+            ..blocks
+        };
+        blocks
+    }
+
+    fn replace_text(&mut self, instructions: &[u32]) {
+        self.instructions = instructions.to_vec();
+        self.decoded = predecode(instructions, &self.config);
+        self.blocks = Self::new_blocks(self.decoded.len());
+        self.breakpoints.clear();
+        self.reset();
+    }
+
+    /// Copies the program to memory if instructions are fetched from there. Words which do
+    /// not fit fail when they are fetched.
+    fn load_text(&mut self) {
+        let Some(base) = self.config.text_base else {
+            return;
+        };
+        for (i, &inst) in self.instructions.iter().enumerate() {
+            let _ = self
+                .memory
+                .write_word(base + 4 * i, self.config.endian.word(inst));
+        }
+    }
+
+    /// Copies the data section of the program to memory, [`Emulator::load`] checked that
+    /// it fits
+    fn load_data(&mut self) {
+        let _ = self.memory.write_bytes(DATA_BASE, &self.data);
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.initialize(DATA_BASE..DATA_BASE + self.data.len());
+        }
+    }
+
+    /// Returns the instruction at `pc`, which is within the program. It is read from the
+    /// virtual address `text_base + 4 * pc` if instructions are fetched from memory.
+    fn fetch(&mut self) -> Result<u32, EmulatorErr> {
+        let pc = self.prog_cnt as usize;
+        match self.config.text_base {
+            Some(base) => {
+                let addr = self.translate(base + 4 * pc, false)?;
+                Ok(self.config.endian.word(self.memory.read_word(addr)?))
+            }
+            None => Ok(self.instructions[pc]),
+        }
+    }
+
+    /// Calls which have not returned yet, innermost last.
+    /// A `ret` to the instruction after a `call` unwinds the stack up to that call.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    /// Runs the program until it exits or `pc` goes outside it, debugging aids are ignored
+    pub fn exec(&mut self) -> Result<ExitStatus, EmulatorErr> {
+        loop {
+            if let RunStatus::Stopped(status) = self.run_for(u64::MAX)? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Executes at most `max_steps` instructions, so that the caller can do other
+    /// work in between and resume by calling this again.
+    pub fn run_for(&mut self, max_steps: u64) -> Result<RunStatus, EmulatorErr> {
+        if let Some(status) = self.stopped() {
+            return Ok(RunStatus::Stopped(status));
+        }
+        let mut left = max_steps;
+        while left > 0 {
+            if !self.instrumented() {
+                let executed = self.run_blocks(left)?;
+                if executed > 0 {
+                    left -= executed;
+                    if let Some(status) = self.stopped() {
+                        return Ok(RunStatus::Stopped(status));
+                    }
+                    continue;
+                }
+            }
+            match self.step()? {
+                RunStatus::Running => left -= 1,
+                status => return Ok(status),
+            }
+        }
+        Ok(RunStatus::Running)
+    }
+
+    /// Executes the compiled block at `pc` the way [`Emulator::run_for`] does, or a single
+    /// instruction with [`Emulator::step`] if there is none or it cannot be run at once.
+    /// Lets a caller see the state at the block boundaries, which is what
+    /// [`crate::lockstep::run_lockstep_blocks`] compares.
+    pub fn run_block(&mut self) -> Result<RunStatus, EmulatorErr> {
+        if let Some(status) = self.stopped() {
+            return Ok(RunStatus::Stopped(status));
+        }
+        if !self.instrumented() {
+            let pc = self.prog_cnt as usize;
+            let block = self.blocks.get(pc, &self.decoded, &self.config);
+            if let Some(block) = block {
+                if self.run_blocks(block.len)? > 0 {
+                    return Ok(self
+                        .stopped()
+                        .map_or(RunStatus::Running, RunStatus::Stopped));
+                }
+            }
+        }
+        self.step()
+    }
+
+    /// Returns true if anything has to be done for every instruction besides executing it,
+    /// which rules out [`Emulator::run_blocks`]
+    fn instrumented(&self) -> bool {
+        self.config.history_depth > 0
+            || self.config.detect_loops
+            || self.tracing()
+            || self.pipeline.is_some()
+            || self.icache.is_some()
+            || self.dcache.is_some()
+            || self.profiler.is_some()
+            || self.reg_stats.is_some()
+            || self.memcheck.is_some()
+            || self.write_log.is_some()
+            || !self.breakpoints.is_empty()
+            || !self.mem_watches.is_empty()
+            || !self.reg_watches.is_empty()
+            || !self.reg_observers.is_empty()
+    }
+
+    /// Executes compiled blocks, see [`Block`], and the instructions between them until
+    /// `max_steps` instructions are executed, without the bookkeeping done by
+    /// [`Emulator::step`]. Devices are ticked in batches, as long as they cannot request
+    /// an interrupt in between and are not accessed. Returns the number of executed
+    /// instructions, 0 if `step` has to execute the next one like when an interrupt is
+    /// pending or the program is not predecoded.
+    fn run_blocks(&mut self, max_steps: u64) -> Result<u64, EmulatorErr> {
+        if self.decoded.is_empty() || self.exit_code.is_some() || self.irq_pending() {
+            return Ok(0);
+        }
+        self.auto_checkpoint();
+        let mut budget = match self.config.max_steps {
+            Some(max) => max_steps.min(max.saturating_sub(self.steps)),
+            None => max_steps,
+        };
+        // Stop at the next checkpoint
+        let interval = self.config.checkpoint_interval;
+        if interval > 0 {
+            budget = budget.min(interval - self.steps % interval);
+        }
+        self.resume_pc = None;
+        let mut ticks = DeviceTicks::new(self.memory.idle_ticks());
+        let mut left = budget;
+        while left > 0 {
+            let Ok(pc) = usize::try_from(self.prog_cnt) else {
+                break;
+            };
+            match self.blocks.get(pc, &self.decoded, &self.config) {
+                Some(block) if block.len <= left.min(ticks.quiet) => {
+                    // A loop within the block runs again without looking it up
+                    let max = left.min(ticks.quiet);
+                    let mut count = 0;
+                    let left_early = loop {
+                        if let Err(executed) = self.exec_block(pc, &block) {
+                            count += executed;
+                            break true;
+                        }
+                        count += block.len;
+                        if self.prog_cnt != pc as i32 || count + block.len > max {
+                            break false;
+                        }
+                    };
+                    left -= count;
+                    ticks.pending += count;
+                    ticks.quiet -= count;
+                    if left_early {
+                        self.run_inst(&mut ticks)?;
+                        left -= 1;
+                    }
+                }
+                None if pc >= self.instructions.len() => break,
+                // The devices may be due before the end of the block
+                Some(_) if ticks.pending > 0 => ticks.quiet = 0,
+                _ => {
+                    self.run_inst(&mut ticks)?;
+                    left -= 1;
+                }
+            }
+            if self.exit_code.is_some() {
+                break;
+            }
+            if ticks.quiet == 0 {
+                self.memory.advance(ticks.pending);
+                ticks = DeviceTicks::new(self.memory.idle_ticks());
+                if self.irq_pending() {
+                    break;
+                }
+            }
+        }
+        self.memory.advance(ticks.pending);
+        Ok(budget - left)
+    }
+
+    /// Executes the instruction at `pc` for [`Emulator::run_blocks`], after ticking the
+    /// devices as it may access them
+    fn run_inst(&mut self, ticks: &mut DeviceTicks) -> Result<(), EmulatorErr> {
+        self.memory.advance(ticks.pending);
+        // They are checked right after it
+        *ticks = DeviceTicks {
+            pending: 1,
+            quiet: 0,
+        };
+        let inst = self.fetch().map_err(|err| self.fault(err))?;
+        self.prog_cnt = match self.exec_inst(inst) {
+            Ok(next_pc) => self.check_pc(next_pc).map_err(|err| self.fault(err))?,
+            Err(err) if self.config.trap_faults => {
+                self.enter_trap(err, inst).map_err(|err| self.fault(err))?
+            }
+            Err(err) => return Err(self.fault(err)),
+        };
+        self.steps += 1;
+        self.charge(inst);
+        Ok(())
+    }
+
+    /// Executes `block`, which starts at `start`. Returns the number of instructions
+    /// executed before a load or a store which has to be done by [`Emulator::exec_inst`],
+    /// `pc` is then left at it.
+    #[inline(always)]
+    fn exec_block(&mut self, start: usize, block: &Block) -> Result<(), u64> {
+        #[cfg(feature = "jit")]
+        if let Some(code) = block.code {
+            return self.exec_native(start, block, code);
+        }
+        let mut next_pc = block.next_pc;
+        let (first, end) = block.ops;
+        for i in first as usize..end as usize {
+            let reg = |reg: u8| self.regs[reg as usize % 16];
+            let operand = |src: Src| match src {
+                Src::Reg(src) => reg(src),
+                Src::Imm(imm) => Wrapping(imm),
+            };
+            let (dst, value) = match self.blocks.ops[i] {
+                BlockOp::Add { dst, src1, src2 } => (dst, reg(src1) + operand(src2)),
+                BlockOp::Sub { dst, src1, src2 } => (dst, reg(src1) - operand(src2)),
+                BlockOp::And { dst, src1, src2 } => (dst, reg(src1) & operand(src2)),
+                BlockOp::Or { dst, src1, src2 } => (dst, reg(src1) | operand(src2)),
+                BlockOp::Xor { dst, src1, src2 } => (dst, reg(src1) ^ operand(src2)),
+                BlockOp::Mov { dst, src2 } => (dst, operand(src2)),
+                BlockOp::Alu {
+                    opcode,
+                    dst,
+                    src1,
+                    src2,
+                } => (dst, alu(opcode, reg(src1), operand(src2))),
+                BlockOp::Load {
+                    size,
+                    zero_ext,
+                    dst,
+                    base,
+                    offset,
+                    at,
+                } => {
+                    let addr = reg(base) + operand(offset);
+                    let Some(value) = self.load_direct(addr.0, size as usize) else {
+                        self.leave_block(start, at as u64);
+                        return Err(at as u64);
+                    };
+                    let value = match (size, zero_ext) {
+                        (1, false) => value as u8 as i8 as i32,
+                        (2, false) => value as u16 as i16 as i32,
+                        _ => value as i32,
+                    };
+                    (dst, Wrapping(value))
+                }
+                BlockOp::Store {
+                    size,
+                    src,
+                    base,
+                    offset,
+                    at,
+                } => {
+                    let (addr, value) = (reg(base) + operand(offset), reg(src));
+                    if !self.store_direct(addr.0, size as usize, value.0 as u32) {
+                        self.leave_block(start, at as u64);
+                        return Err(at as u64);
+                    }
+                    continue;
+                }
+                BlockOp::Cmp { src1, src2 } => {
+                    let flags = compare(reg(src1), operand(src2));
+                    (self.flag_e, self.flag_g, self.csrs.flags) = flags;
+                    continue;
+                }
+                BlockOp::Branch { cond, target } => {
+                    if branch_taken(cond, (self.flag_e, self.flag_g), self.csrs.flags) {
+                        next_pc = target;
+                    }
+                    continue;
+                }
+                BlockOp::CmpBranch {
+                    src1,
+                    src2,
+                    cond,
+                    target,
+                } => {
+                    let flags = compare(reg(src1), operand(src2));
+                    (self.flag_e, self.flag_g, self.csrs.flags) = flags;
+                    if branch_taken(cond, (flags.0, flags.1), flags.2) {
+                        next_pc = target;
+                    }
+                    continue;
+                }
+            };
+            // Blocks only run in 32-bit mode, so regs_hi is left alone
+            self.regs[dst as usize % 16] = value;
+        }
+        self.prog_cnt = next_pc;
+        self.steps += block.len;
+        self.cycles += block.cycles;
+        self.energy += block.energy;
+        Ok(())
+    }
+
+    /// Runs `code`, the native code of `block`, like [`Emulator::exec_block`]
+    #[cfg(feature = "jit")]
+    fn exec_native(&mut self, start: usize, block: &Block, code: jit::Code) -> Result<(), u64> {
+        let mut flags = jit::Flags::new(self.flag_e, self.flag_g, self.csrs.flags);
+        let emul: *mut Self = self;
+        // SAFETY: the code only accesses the registers and the flags, and the emulator
+        // through the helpers of Emulator::new_blocks for the same memory type
+        let result = unsafe {
+            let regs = core::ptr::addr_of_mut!((*emul).regs).cast();
+            code(regs, &mut flags, emul.cast())
+        };
+        (self.flag_e, self.flag_g, self.csrs.flags) = flags.unpack();
+        if result >= jit::Jit::DEOPT {
+            let at = result - jit::Jit::DEOPT;
+            self.leave_block(start, at);
+            return Err(at);
+        }
+        self.prog_cnt = result as i32;
+        self.steps += block.len;
+        self.cycles += block.cycles;
+        self.energy += block.energy;
+        Ok(())
+    }
+
+    /// Accounts for the first `executed` instructions of the block at `start`
+    /// and leaves `pc` at the next one
+    #[cold]
+    fn leave_block(&mut self, start: usize, executed: u64) {
+        let end = start + executed as usize;
+        for pc in start..end {
+            self.charge(self.instructions[pc]);
+        }
+        self.steps += executed;
+        self.prog_cnt = end as i32;
+    }
+
+    /// Returns the `size` bytes at `memaddr` if a block can access them in place rather
+    /// than with [`Emulator::mem_load`] or [`Emulator::mem_store`], see
+    /// [`Memory::bytes_mut`]
+    #[inline]
+    fn direct_bytes(&mut self, memaddr: i32, size: usize) -> Option<&mut [u8]> {
+        let idx = self.mem_index(memaddr, size).ok()?;
+        let direct =
+            self.csrs.ptbr & mmu::ENABLE == 0 && self.regions.is_empty() && self.heatmap.is_none();
+        direct.then(|| self.memory.bytes_mut(idx, size)).flatten()
+    }
+
+    /// Loads like [`Emulator::mem_load`] if it can be done in place
+    #[inline]
+    fn load_direct(&mut self, memaddr: i32, size: usize) -> Option<u32> {
+        let endian = self.config.endian;
+        let value = match self.direct_bytes(memaddr, size)? {
+            [byte] => *byte as u32,
+            &mut [b0, b1] => endian.half(u16::from_le_bytes([b0, b1])) as u32,
+            bytes => endian.word(u32::from_le_bytes(bytes.try_into().ok()?)),
+        };
+        self.energy += self.config.energy_costs.read as u64;
+        Some(value)
+    }
+
+    /// Stores like [`Emulator::mem_store`] if it can be done in place, returns false
+    /// without doing anything otherwise
+    #[inline]
+    fn store_direct(&mut self, memaddr: i32, size: usize, value: u32) -> bool {
+        let endian = self.config.endian;
+        let Some(bytes) = self.direct_bytes(memaddr, size) else {
+            return false;
+        };
+        match bytes.len() {
+            1 => bytes[0] = value as u8,
+            2 => bytes.copy_from_slice(&endian.half(value as u16).to_le_bytes()),
+            _ => bytes.copy_from_slice(&endian.word(value).to_le_bytes()),
+        }
+        self.energy += self.config.energy_costs.write as u64;
+        true
+    }
+
+    /// Returns true if an interrupt would be taken before the next instruction,
+    /// devices are only asked if interrupts are enabled
+    fn irq_pending(&self) -> bool {
+        self.csrs.interrupts_enabled() && self.memory.pending_irqs() != 0
+    }
+
+    /// Executes a single instruction, delivering a pending interrupt before it.
+    /// Errors are returned as an [`EmulatorErr::Fault`] with the faulting instruction
+    /// and the backtrace of the calls.
+    pub fn step(&mut self) -> Result<RunStatus, EmulatorErr> {
+        let result = self.execute_step();
+        result.map_err(|err| self.fault(err))
+    }
+
+    /// Wraps `err`, raised by the instruction at `pc`, in an [`EmulatorErr::Fault`]
+    fn fault(&mut self, err: EmulatorErr) -> EmulatorErr {
+        let in_program =
+            usize::try_from(self.prog_cnt).is_ok_and(|pc| pc < self.instructions.len());
+        EmulatorErr::Fault {
+            err: Box::new(err),
+            pc: self.prog_cnt,
+            inst: in_program.then(|| self.fetch().ok()).flatten(),
+            backtrace: self.call_stack.iter().rev().copied().collect(),
+        }
+    }
 
-#[derive(Debug)]
-pub enum EmulatorErr {
-    InvalidModbits,
-    InvalidMemAddr,
-    InvalidOpcode,
-    DivideByZero,
-    UnalignedMemAddr,
-}
+    fn execute_step(&mut self) -> Result<RunStatus, EmulatorErr> {
+        if let Some(status) = self.stopped() {
+            return Ok(RunStatus::Stopped(status));
+        }
+        self.auto_checkpoint();
+        self.mem_log.clear();
+        let mut undo = self.save_undo();
+        let enabled = self.csrs.interrupts_enabled();
+        let pending = if enabled {
+            self.memory.pending_irqs()
+        } else {
+            0
+        };
+        let irq = interrupt::select(enabled, pending);
+        if let Some(line) = irq {
+            self.prog_cnt = self.enter_interrupt(line)?;
+            #[cfg(feature = "std")]
+            if let Some(tracer) = &mut self.tracer {
+                tracer.event(TraceEvent::Interrupt(line))?;
+            }
+            if let Some(status) = self.stopped() {
+                self.push_undo(undo);
+                return Ok(RunStatus::Stopped(status));
+            }
+        }
+        if self.resume_pc.take() != Some(self.prog_cnt) && self.breakpoint_hit() {
+            // Entering an interrupt still has to be undoable
+            if irq.is_some() {
+                self.push_undo(undo);
+            }
+            self.resume_pc = Some(self.prog_cnt);
+            return Ok(RunStatus::Break(StopReason::Breakpoint(self.prog_cnt)));
+        }
+        let inst = match self.fetch() {
+            // Like an interrupt, the handler starts right away. It returns to fetch again.
+            Err(err) if self.config.trap_faults => {
+                #[cfg(feature = "std")]
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.event(TraceEvent::Trap(&err))?;
+                }
+                self.prog_cnt = self.enter_fetch_trap(err)?;
+                if let Some(status) = self.stopped() {
+                    self.push_undo(undo);
+                    return Ok(RunStatus::Stopped(status));
+                }
+                self.fetch()?
+            }
+            result => result?,
+        };
+        if let Some(icache) = &mut self.icache {
+            icache.access(4 * self.prog_cnt as usize, false);
+        }
+        if let Some(undo) = &mut undo {
+            let opcode = info::opcode_of(inst);
+            if opcode == Opcode::CALL as u8 || opcode == Opcode::RET as u8 {
+                undo.call_stack = Some(self.call_stack.clone());
+            }
+        }
+        let pc = self.prog_cnt;
+        #[cfg(feature = "std")]
+        let regs = self.regs;
+        let result = self.exec_inst(inst);
+        #[cfg(feature = "std")]
+        if let Some(tracer) = &mut self.tracer {
+            let changed: Vec<_> = (0..16)
+                .filter(|&r| self.regs[r] != regs[r])
+                .map(|r| (r, self.regs[r].0))
+                .collect();
+            tracer.instruction(&TracedStep {
+                pc,
+                inst,
+                regs: &changed,
+                all_regs: &self.regs,
+                writes: &self.mem_log,
+                endian: self.config.endian,
+                flags: (self.flag_e, self.flag_g),
+                nzcv: self.csrs.flags,
+            })?;
+            match &result {
+                Err(err) if self.config.trap_faults => tracer.event(TraceEvent::Trap(err))?,
+                Err(_) => tracer.flush()?,
+                Ok(_) => {}
+            }
+        }
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.issue(pc, inst)?;
+            if result.is_err() && !self.config.trap_faults {
+                pipeline.finish()?;
+            }
+        }
+        let trapped = result.is_err();
+        self.prog_cnt = match result {
+            Err(err) if self.config.trap_faults => self.enter_trap(err, inst)?,
+            result => self.check_pc(result?)?,
+        };
+        self.steps += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc as usize);
+            if !trapped {
+                profiler.transfer(pc as usize, inst, self.prog_cnt);
+            }
+        }
+        if let Some(reg_stats) = &mut self.reg_stats {
+            if !trapped {
+                reg_stats.record(self.steps - 1, inst);
+            }
+        }
+        if let Some(memcheck) = &mut self.memcheck {
+            // Writes to devices do not initialize anything
+            for write in self.mem_log.iter().filter(|w| w.old.is_some()) {
+                memcheck.initialize(write.addr..write.addr + write.new.len());
+            }
+        }
+        if let Some(log) = &mut self.write_log {
+            for write in &self.mem_log {
+                log.push(WriteRecord {
+                    step: self.steps - 1,
+                    pc,
+                    addr: write.addr,
+                    old: write.old.clone(),
+                    new: write.new.clone(),
+                });
+            }
+        }
+        // Faulting instructions which trap still take their cycles
+        self.charge(inst);
+        self.memory.tick();
+        self.push_undo(undo);
+        if self.config.detect_loops && !trapped {
+            self.check_loop(pc, inst)?;
+        }
+        // A stop is reported even by the last instruction, the next step reports the exit
+        if let Some(reason) = self.stop_reason.take() {
+            return Ok(RunStatus::Break(reason));
+        }
+        Ok(match self.stopped() {
+            Some(status) => {
+                #[cfg(feature = "std")]
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.flush()?;
+                }
+                if let Some(pipeline) = &mut self.pipeline {
+                    pipeline.finish()?;
+                }
+                RunStatus::Stopped(status)
+            }
+            None => RunStatus::Running,
+        })
+    }
 
-impl fmt::Display for EmulatorErr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Self::InvalidModbits => write!(f, "Invalid immediate modifier bits"),
-            Self::InvalidMemAddr => write!(f, "Memory address out of range"),
-            Self::InvalidOpcode => write!(f, "Non-existent instruction"),
-            Self::DivideByZero => write!(f, "Divide by 0 error"),
-            Self::UnalignedMemAddr => write!(f, "Memory address not aligned by 4 bytes"),
+    /// Adds the cycles and energy taken by `inst`
+    fn charge(&mut self, inst: u32) {
+        if let Some(ins) = info::instruction(info::opcode_of(inst)) {
+            self.cycles += self.config.cycle_costs.get(ins.opcode) as u64;
+            self.energy += self.config.energy_costs.get(ins.opcode) as u64;
+        } else {
+            self.cycles += 1;
         }
     }
-}
 
-fn get_bits(bits: u32, n: u8, offset: u8) -> u32 {
-    (bits >> offset) & (!0u32 >> (32 - n))
-}
+    /// Saves the state before a step if the history is enabled
+    fn save_undo(&mut self) -> Option<Undo> {
+        if self.config.history_depth == 0 {
+            return None;
+        }
+        Some(Undo {
+            regs: self.regs,
+            regs_hi: self.regs_hi,
+            prog_cnt: self.prog_cnt,
+            flags: (self.flag_e, self.flag_g),
+            csrs: self.csrs.clone(),
+            exit_code: self.exit_code,
+            steps: self.steps,
+            cycles: self.cycles,
+            energy: self.energy,
+            call_stack: None,
+            writes: Vec::new(),
+        })
+    }
 
-fn sign_extend(num: u32, nbits: u8) -> i32 {
-    if num >> (nbits - 1) != 0 {
-        (num | (!0u32 << nbits)) as i32
-    } else {
-        num as i32
+    fn push_undo(&mut self, undo: Option<Undo>) {
+        let Some(mut undo) = undo else {
+            return;
+        };
+        undo.writes = self
+            .mem_log
+            .drain(..)
+            .filter_map(|w| Some((w.addr, w.old?)))
+            .collect();
+        if self.history.len() == self.config.history_depth {
+            self.history.pop_front();
+        }
+        self.history.push_back(undo);
     }
-}
 
-impl<'a> Emulator<'a> {
-    pub fn new(instructions: &'a [u32]) -> Self {
-        Self {
-            regs: [Wrapping(0); 16],
-            wmemory: [Wrapping(0); 4096],
-            instructions,
-            prog_cnt: 0,
-            flag_e: false,
-            flag_g: false,
+    /// Undoes the last step and returns true, or returns false if no steps are recorded.
+    /// At most [`EmulatorConfig::history_depth`] steps can be undone. Side effects outside
+    /// the registers and the memory, like output or the state of devices, are not undone.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.pop_back() else {
+            return false;
+        };
+        for (addr, old) in undo.writes.iter().rev() {
+            // Writing back the old contents of memory which was written cannot fail
+            let _ = self.memory.write_bytes(*addr, old);
+        }
+        self.regs = undo.regs;
+        self.regs_hi = undo.regs_hi;
+        self.prog_cnt = undo.prog_cnt;
+        (self.flag_e, self.flag_g) = undo.flags;
+        self.csrs = undo.csrs;
+        self.exit_code = undo.exit_code;
+        self.steps = undo.steps;
+        self.cycles = undo.cycles;
+        self.energy = undo.energy;
+        if let Some(call_stack) = undo.call_stack {
+            self.call_stack = call_stack;
         }
+        if let Some(log) = &mut self.write_log {
+            log.truncate(self.steps);
+        }
+        // Stepping forward again should not stop at a breakpoint right away
+        self.resume_pc = Some(self.prog_cnt);
+        self.stop_reason = None;
+        self.loop_state = None;
+        true
     }
 
-    pub fn debug(&self) {
-        for (i, &rval) in self.regs.iter().enumerate() {
-            println!("r{:<2} = {}", i, rval);
+    /// Fails if the step from `pc` repeated the last iteration of a loop without any effect
+    fn check_loop(&mut self, pc: i32, inst: u32) -> Result<(), EmulatorErr> {
+        let opcode = info::opcode_of(inst);
+        self.loop_effects |= info::accesses_memory(opcode) || opcode == Opcode::SYS as u8;
+        if self.prog_cnt > pc {
+            return Ok(());
+        }
+        let state = LoopState {
+            pc,
+            regs: self.regs,
+            regs_hi: self.regs_hi,
+            flags: (self.flag_e, self.flag_g),
+            csrs: self.csrs.clone(),
+        };
+        let stuck = !self.loop_effects
+            && !self.csrs.interrupts_enabled()
+            && self.loop_state.as_ref() == Some(&state);
+        self.loop_state = Some(state);
+        self.loop_effects = false;
+        match stuck {
+            true => Err(EmulatorErr::InfiniteLoop),
+            false => Ok(()),
         }
     }
 
-    pub fn get_reg_val(&self, reg_num: usize) -> i32 {
-        self.regs[reg_num].0
+    /// Returns true if a breakpoint at the current `pc` has its condition satisfied
+    fn breakpoint_hit(&mut self) -> bool {
+        let breakpoints = mem::take(&mut self.breakpoints);
+        let pc = self.prog_cnt;
+        let hit = breakpoints.iter().filter(|b| b.pc == pc).any(|b| {
+            b.condition
+                .as_ref()
+                .is_none_or(|cond| !matches!(cond.eval(self), Ok(0)))
+        });
+        self.breakpoints = breakpoints;
+        hit
     }
 
-    pub fn exec(&mut self) -> Result<(), EmulatorErr> {
-        while self.prog_cnt >= 0 && (self.prog_cnt as usize) < self.instructions.len() {
-            self.prog_cnt = self.exec_inst(self.instructions[self.prog_cnt as usize])?;
+    /// Returns the exit status if the program can not execute any further
+    fn stopped(&self) -> Option<ExitStatus> {
+        if let Some(code) = self.exit_code {
+            return Some(ExitStatus::Exited(code));
         }
-        Ok(())
+        let in_program = self.prog_cnt >= 0 && (self.prog_cnt as usize) < self.instructions.len();
+        if !in_program {
+            return Some(ExitStatus::Finished);
+        }
+        let exceeded = self.config.max_steps.is_some_and(|max| self.steps >= max);
+        exceeded.then_some(ExitStatus::BudgetExceeded)
+    }
+
+    /// Returns `next_pc`, or [`EmulatorErr::PcOutOfBounds`] if it leaves the program and
+    /// [`PcBounds::Error`] is set
+    fn check_pc(&self, next_pc: i32) -> Result<i32, EmulatorErr> {
+        let in_program = usize::try_from(next_pc).is_ok_and(|pc| pc < self.instructions.len());
+        match in_program || self.exit_code.is_some() || self.config.pc_bounds != PcBounds::Error {
+            true => Ok(next_pc),
+            false => Err(EmulatorErr::PcOutOfBounds(next_pc)),
+        }
+    }
+
+    /// Saves the state needed by `reti` and returns the `pc` of the handler for `line`
+    fn enter_interrupt(&mut self, line: u8) -> Result<i32, EmulatorErr> {
+        let handler = self.read_word(interrupt::irq_vector(line))?;
+        let cause = csr::CAUSE_IRQ | line as u32;
+        self.csrs
+            .enter(cause, self.prog_cnt, (self.flag_e, self.flag_g));
+        Ok(handler)
+    }
+
+    /// Enters the trap handler for the fault `err` caused by `inst` and returns its `pc`.
+    /// Returns `err` back if it cannot be handled by the program.
+    fn enter_trap(&mut self, err: EmulatorErr, inst: u32) -> Result<i32, EmulatorErr> {
+        let Some(cause) = csr::fault_cause(&err) else {
+            return Err(err);
+        };
+        // Registers are not modified by a faulting instruction, so it decodes the same.
+        // System calls take the address of their buffer in r1.
+        let badaddr = match (err, self.unpack(inst)) {
+            (
+                EmulatorErr::InvalidMemAddr
+                | EmulatorErr::UnalignedMemAddr
+                | EmulatorErr::ProtectionFault
+                | EmulatorErr::PageFault,
+                Ok(ins),
+            ) => {
+                if ins.opcode == Opcode::SYS {
+                    self.regs[1].0 as u32
+                } else {
+                    ins.memaddr as u32
+                }
+            }
+            _ => 0,
+        };
+        let handler = self.read_word(interrupt::TRAP_VECTOR)?;
+        self.csrs
+            .enter(cause, self.prog_cnt, (self.flag_e, self.flag_g));
+        self.csrs.badaddr = badaddr;
+        Ok(handler)
+    }
+
+    /// Enters the trap handler for the fault `err` raised when fetching the instruction at
+    /// `pc`, whose address is put in `BADADDR`, and returns the `pc` of the handler
+    fn enter_fetch_trap(&mut self, err: EmulatorErr) -> Result<i32, EmulatorErr> {
+        let (Some(cause), Some(base)) = (csr::fault_cause(&err), self.config.text_base) else {
+            return Err(err);
+        };
+        let handler = self.read_word(interrupt::TRAP_VECTOR)?;
+        self.csrs
+            .enter(cause, self.prog_cnt, (self.flag_e, self.flag_g));
+        self.csrs.badaddr = (base + 4 * self.prog_cnt as usize) as u32;
+        Ok(handler)
     }
 
     /// Executes the instruction contained in `bits` and returns the new `pc`
     fn exec_inst(&mut self, inst: u32) -> Result<i32, EmulatorErr> {
         use Opcode::*;
 
+        if self.config.xlen == Xlen::X64 && self.exec_wide(inst)? {
+            return Ok(self.prog_cnt + 1);
+        }
+
         let UnpackedIns {
             dst_reg,
             src1,
-            mut src2,
+            src2,
             memaddr,
             new_pc,
             mut opcode,
-        } = self.decode(inst)?;
+            zero_ext,
+            writes_dst,
+        } = self.unpack(inst)?;
 
         // Modify and verify fields as needed
         match opcode {
             // Convert BGT and BEQ to NOP if corresponding flags not set
             BEQ if !self.flag_e => opcode = NOP,
             BGT if !self.flag_g => opcode = NOP,
-            // Only consider the lower 5 bits for shift amount(that is max 31)
-            LSL | LSR | ASR => src2 = Wrapping(src2.0 & 0b11111),
-            DIV | MOD if src2.0 == 0 => return Err(EmulatorErr::DivideByZero),
+            _ if info::is_nzcv_branch(opcode as u8) && !nzcv_taken(opcode, self.csrs.flags) => {
+                opcode = NOP
+            }
+            DIV | MOD | DIVU | MODU if src2.0 == 0 => return Err(EmulatorErr::DivideByZero),
+            ADD | SUB | MUL if self.config.trap_overflow && overflows(opcode, src1, src2) => {
+                return Err(EmulatorErr::Overflow(src1.0, src2.0))
+            }
+            CSRR | CSRW if CsrFile::user_accessible(src2.0 as u32) => {}
+            _ if info::is_privileged(opcode as u8) && !self.csrs.is_supervisor() => {
+                return Err(EmulatorErr::PrivilegedIns)
+            }
             _ => {}
         };
 
-        self.regs[dst_reg] = match opcode {
-            ADD => src1 + src2,
-            SUB => src1 - src2,
-            MUL => src1 * src2,
+        let value = match opcode {
+            ADD | SUB | MUL | MULH | MULHU | AND | OR | XOR | NOT | MOV | LSL | LSR | ASR | CLZ
+            | CTZ | POPCNT | SXTB | SXTH | UXTB | UXTH | MIN | MAX | MINU | MAXU => {
+                alu(opcode, src1, src2)
+            }
             DIV => src1 / src2,
             MOD => src1 % src2,
+            DIVU => Wrapping((src1.0 as u32 / src2.0 as u32) as i32),
+            MODU => Wrapping((src1.0 as u32 % src2.0 as u32) as i32),
+            MLA => self.regs[dst_reg] + src1 * src2,
             CMP => {
                 self.flag_e = src1 == src2;
                 self.flag_g = src1 > src2;
+                self.csrs.flags = nzcv(src1.0, src2.0, true);
                 self.regs[dst_reg]
             }
-            AND => src1 & src2,
-            OR => src1 | src2,
-            NOT => !src2,
-            MOV => src2,
-            LSL => Wrapping(src1.0 << src2.0),
-            LSR => Wrapping(((src1.0 as u32) >> src2.0) as i32),
-            ASR => Wrapping(src1.0 >> src2.0),
+            CMPU => {
+                self.flag_e = src1 == src2;
+                self.flag_g = src1.0 as u32 > src2.0 as u32;
+                self.csrs.flags = nzcv(src1.0, src2.0, true);
+                self.regs[dst_reg]
+            }
+            ADDS | SUBS => {
+                self.csrs.flags = nzcv(src1.0, src2.0, opcode == SUBS);
+                match opcode {
+                    ADDS => src1 + src2,
+                    _ => src1 - src2,
+                }
+            }
             NOP => self.regs[dst_reg],
-            LD => self.wmemory[self.get_word_index(memaddr)?],
+            LD => Wrapping(self.mem_load(memaddr, 4)? as i32),
             ST => {
-                self.wmemory[self.get_word_index(memaddr)?] = self.regs[dst_reg];
+                self.mem_store(memaddr, 4, self.regs[dst_reg].0 as u32)?;
                 self.regs[dst_reg]
             }
             // Conditional branch instructions are already converted to NOPs if flags not set
-            BEQ | BGT | B => return Ok(new_pc),
+            BEQ | BGT | B | BNE | BMI | BPL | BVS | BVC | BHS | BLO | BHI | BLS | BGE | BLT
+            | BLE => return Ok(new_pc),
             CALL => {
-                self.regs[info::RET_REG] = Wrapping(self.prog_cnt + 1);
+                self.write_reg(info::RET_REG, Wrapping(self.prog_cnt + 1));
+                self.call_stack.push(CallFrame {
+                    call_pc: self.prog_cnt,
+                    target: new_pc,
+                });
                 return Ok(new_pc);
             }
-            RET => return Ok(self.regs[info::RET_REG].0),
+            RET => {
+                let target = self.regs[info::RET_REG].0;
+                let caller = self
+                    .call_stack
+                    .iter()
+                    .rposition(|f| f.call_pc + 1 == target);
+                if let Some(pos) = caller {
+                    self.call_stack.truncate(pos);
+                }
+                return Ok(target);
+            }
+            LDB => {
+                let byte = self.mem_load(memaddr, 1)?;
+                Wrapping(if zero_ext {
+                    byte as i32
+                } else {
+                    byte as u8 as i8 as i32
+                })
+            }
+            STB => {
+                self.mem_store(memaddr, 1, self.regs[dst_reg].0 as u32)?;
+                self.regs[dst_reg]
+            }
+            LDH => {
+                let half = self.mem_load(memaddr, 2)?;
+                Wrapping(if zero_ext {
+                    half as i32
+                } else {
+                    half as u16 as i16 as i32
+                })
+            }
+            STH => {
+                self.mem_store(memaddr, 2, self.regs[dst_reg].0 as u32)?;
+                self.regs[dst_reg]
+            }
+            // Harts are interleaved between instructions, so these are atomic
+            SWAP => {
+                let old = self.mem_load(memaddr, 4)?;
+                self.mem_store(memaddr, 4, self.regs[dst_reg].0 as u32)?;
+                Wrapping(old as i32)
+            }
+            CAS => {
+                let old = self.mem_load(memaddr, 4)?;
+                self.flag_e = old as i32 == self.regs[dst_reg].0;
+                if self.flag_e {
+                    self.mem_store(memaddr, 4, src2.0 as u32)?;
+                }
+                Wrapping(old as i32)
+            }
+            EI | DI => {
+                self.csrs.set_interrupts_enabled(opcode == EI);
+                self.regs[dst_reg]
+            }
+            RETI => {
+                let (pc, flags) = self.csrs.leave();
+                (self.flag_e, self.flag_g) = flags;
+                return Ok(pc);
+            }
+            CSRR => Wrapping(self.csrs.read(src2.0 as u32)? as i32),
+            CSRW => {
+                self.csrs
+                    .write(src2.0 as u32, self.regs[dst_reg].0 as u32)?;
+                self.regs[dst_reg]
+            }
+            SYS => {
+                let before = self.regs;
+                let retry = self.do_syscall()?;
+                // Replay the register changes so that watchpoints and observers see them
+                let after = mem::replace(&mut self.regs, before);
+                for (reg, value) in after.into_iter().enumerate() {
+                    if value != before[reg] {
+                        self.write_reg(reg, value);
+                    }
+                }
+                if retry {
+                    return Ok(self.prog_cnt);
+                }
+                self.regs[dst_reg]
+            }
         };
+        if writes_dst {
+            self.check_write(opcode, dst_reg, value.0)?;
+            self.write_reg(dst_reg, value);
+        }
 
         Ok(self.prog_cnt + 1)
     }
 
-    fn get_word_index(&self, memaddr: i32) -> Result<usize, EmulatorErr> {
+    /// Executes the instructions which work on whole registers or memory words in 64-bit
+    /// mode, see [`Xlen::X64`]. Returns false without doing anything for the others.
+    fn exec_wide(&mut self, inst: u32) -> Result<bool, EmulatorErr> {
+        use Opcode::*;
+
+        let opcode = info::opcode_of(inst);
+        let wide_imm = info::supports_mod(opcode)
+            && get_bits(inst, IMMBIT_BITS, IMMBIT_OFF) == 1
+            && get_bits(inst, MOD_BITS, MOD_OFF) as u8 == MOD_W;
+        // The decoder only knows the modifiers of 32-bit immediates
+        let ins = match wide_imm {
+            true => decode(inst & !((MOD_W as u32) << MOD_OFF))?,
+            false => decode(inst)?,
+        };
+        let dst = Wrapping(self.get_reg_val64(ins.dst_reg));
+        let src1 = Wrapping(self.get_reg_val64(ins.src1_reg));
+        let src2 = Wrapping(match ins.src2 {
+            Operand::Reg(reg) => self.get_reg_val64(reg),
+            Operand::Imm(_) if wide_imm => (get_bits(inst, IMM_BITS, 0) as i64) << 32,
+            Operand::Imm(imm) => imm as i64,
+        });
+        let memaddr = i32::try_from((src1 + src2).0).map_err(|_| EmulatorErr::InvalidMemAddr);
+
+        let value = match ins.opcode {
+            DIV | MOD | DIVU | MODU if src2.0 == 0 => return Err(EmulatorErr::DivideByZero),
+            ADD => src1 + src2,
+            SUB => src1 - src2,
+            MUL => src1 * src2,
+            DIV => src1 / src2,
+            MOD => src1 % src2,
+            DIVU => Wrapping((src1.0 as u64 / src2.0 as u64) as i64),
+            MODU => Wrapping((src1.0 as u64 % src2.0 as u64) as i64),
+            MLA => dst + src1 * src2,
+            CLZ => Wrapping(src2.0.leading_zeros() as i64),
+            CTZ => Wrapping(src2.0.trailing_zeros() as i64),
+            POPCNT => Wrapping(src2.0.count_ones() as i64),
+            SXTB => Wrapping(src2.0 as i8 as i64),
+            SXTH => Wrapping(src2.0 as i16 as i64),
+            UXTB => Wrapping(src2.0 as u8 as i64),
+            UXTH => Wrapping(src2.0 as u16 as i64),
+            MIN => src1.min(src2),
+            MAX => src1.max(src2),
+            MINU => Wrapping((src1.0 as u64).min(src2.0 as u64) as i64),
+            MAXU => Wrapping((src1.0 as u64).max(src2.0 as u64) as i64),
+            CMP | CMPU => {
+                self.flag_e = src1 == src2;
+                self.flag_g = match ins.opcode {
+                    CMP => src1 > src2,
+                    _ => src1.0 as u64 > src2.0 as u64,
+                };
+                self.csrs.flags = nzcv_wide(src1.0, src2.0, true);
+                return Ok(true);
+            }
+            ADDS | SUBS => {
+                self.csrs.flags = nzcv_wide(src1.0, src2.0, ins.opcode == SUBS);
+                match ins.opcode {
+                    ADDS => src1 + src2,
+                    _ => src1 - src2,
+                }
+            }
+            AND => src1 & src2,
+            OR => src1 | src2,
+            XOR => src1 ^ src2,
+            MULH => Wrapping(((src1.0 as i128 * src2.0 as i128) >> 64) as i64),
+            MULHU => Wrapping(((src1.0 as u64 as u128 * src2.0 as u64 as u128) >> 64) as i64),
+            NOT => !src2,
+            MOV => src2,
+            // Only the lower 6 bits are the shift amount
+            LSL => Wrapping(src1.0 << (src2.0 & 0b111111)),
+            LSR => Wrapping(((src1.0 as u64) >> (src2.0 & 0b111111)) as i64),
+            ASR => Wrapping(src1.0 >> (src2.0 & 0b111111)),
+            LD => Wrapping(self.mem_load_wide(memaddr?)?),
+            ST => {
+                self.mem_store_wide(memaddr?, dst.0)?;
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        self.check_write(ins.opcode, ins.dst_reg, value.0 as i32)?;
+        self.write_reg(ins.dst_reg, Wrapping(value.0 as i32));
+        self.regs_hi[ins.dst_reg] = (value.0 >> 32) as i32;
+        Ok(true)
+    }
+
+    /// Enforces the register conventions on writing `value` to `dst_reg`,
+    /// see [`EmulatorConfig::strict`]
+    fn check_write(&self, opcode: Opcode, dst_reg: usize, value: i32) -> Result<(), EmulatorErr> {
+        if !self.config.strict {
+            return Ok(());
+        }
+        match dst_reg {
+            info::RET_REG if opcode != Opcode::LD => Err(EmulatorErr::ClobberedRetReg),
+            info::SP_REG if value % 4 != 0 => Err(EmulatorErr::UnalignedStack(value)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets register `reg` to `value`, recording a stop if that triggers a watchpoint
+    fn write_reg(&mut self, reg: usize, value: Wrapping<i32>) {
+        let old = self.regs[reg].0;
+        self.regs[reg] = value;
+        self.regs_hi[reg] = value.0 >> 31;
+        if old == value.0 {
+            return;
+        }
+        let hit = RegHit {
+            pc: self.prog_cnt,
+            reg,
+            old,
+            new: value.0,
+        };
+        for (_, observer) in self.reg_observers.iter_mut().filter(|(r, _)| *r == reg) {
+            observer(&hit);
+        }
+        if self
+            .reg_watches
+            .iter()
+            .any(|w| w.matches(reg, old, value.0))
+        {
+            self.stop_reason = Some(StopReason::RegWatch(hit));
+        }
+    }
+
+    /// Executes the system call whose number is in r0, the arguments are in r[1-4].
+    /// The return value is stored in r0. Returns true if the `sys` instruction has to be
+    /// executed again, see [`SyscallContext::retry`].
+    fn do_syscall(&mut self) -> Result<bool, EmulatorErr> {
+        let mut memory = LoggedMemory {
+            log: self.logs_writes().then_some(&mut self.mem_log),
+            inner: &mut self.memory,
+        };
+        let mut ctx = SyscallContext {
+            regs: &mut self.regs,
+            memory: &mut memory,
+            steps: self.steps,
+            cycles: self.cycles,
+            exit_code: &mut self.exit_code,
+            retry: false,
+        };
+        if let Some(handler) = &mut self.syscall_handler {
+            if handler.syscall(&mut ctx)? {
+                return Ok(ctx.retry);
+            }
+        }
+        #[cfg(feature = "std")]
+        if self.default_syscalls.syscall(&mut ctx)? {
+            return Ok(ctx.retry);
+        }
+        Err(EmulatorErr::InvalidSyscall)
+    }
+
+    /// Returns the index in memory for an access of `size` bytes at `memaddr`,
+    /// the address must be aligned to `size`. Upper bound is checked by the memory.
+    fn mem_index(&self, memaddr: i32, size: usize) -> Result<usize, EmulatorErr> {
         if memaddr < 0 {
             return Err(EmulatorErr::InvalidMemAddr);
         }
-        if memaddr % 4 != 0 {
+        let idx = memaddr as usize;
+        // Sizes are powers of 2, which avoids a division
+        if idx & (size - 1) != 0 {
             return Err(EmulatorErr::UnalignedMemAddr);
         }
-        // A word(i32) is 4 bytes
-        let word_idx = (memaddr as usize) / 4;
-        if word_idx >= self.wmemory.len() {
-            return Err(EmulatorErr::InvalidMemAddr);
+        Ok(idx)
+    }
+
+    /// Returns the physical address of `idx` when the MMU is enabled by `PTBR`, an aligned
+    /// access never crosses a page so its first byte is enough
+    fn translate(&mut self, idx: usize, write: bool) -> Result<usize, EmulatorErr> {
+        let ptbr = self.csrs.ptbr;
+        if ptbr & mmu::ENABLE == 0 {
+            return Ok(idx);
         }
-        Ok(word_idx)
+        let user = !self.csrs.is_supervisor();
+        let endian = self.config.endian;
+        mmu::translate(&mut self.memory, endian, ptbr, idx, write, user)
     }
 
-    fn decode(&self, inst: u32) -> Result<UnpackedIns, EmulatorErr> {
-        // See src/info.rs for more info
-        let opcode = get_bits(inst, OPCODE_BITS, OPCODE_OFF) as u8;
-        let is_imm = info::supports_imm(opcode) && get_bits(inst, IMMBIT_BITS, IMMBIT_OFF) == 1;
-        let modbits = get_bits(inst, MOD_BITS, MOD_OFF) as u8;
-        let dst_reg = get_bits(inst, REG_BITS, DST_OFF) as usize;
-        let src1 = self.regs[get_bits(inst, REG_BITS, SRC1_OFF) as usize];
-        // src2 can be either a register or an immediate
-        let tmps2 = if is_imm {
-            let imm = get_bits(inst, IMM_BITS, 0);
-            match modbits {
-                MOD_DEF => sign_extend(imm, IMM_BITS),
-                MOD_U => imm as i32,
-                MOD_H => (imm << u16::BITS) as i32,
-                _ => return Err(EmulatorErr::InvalidModbits),
+    /// Fails if an `access` of `size` bytes at `idx` is not allowed by a protected region
+    fn check_protection(&self, idx: usize, size: usize, access: Access) -> Result<(), EmulatorErr> {
+        match self.regions.iter().any(|r| r.forbids(idx, size, access)) {
+            true => Err(EmulatorErr::ProtectionFault),
+            false => Ok(()),
+        }
+    }
+
+    /// Reads `size`(1, 2 or 4) bytes from memory as a zero extended value
+    fn mem_load(&mut self, memaddr: i32, size: usize) -> Result<u32, EmulatorErr> {
+        let idx = self.mem_index(memaddr, size)?;
+        let idx = self.translate(idx, false)?;
+        self.check_protection(idx, size, Access::Read)?;
+        self.data_access(idx, size, false);
+        let value = self.read_sized(idx, size)?;
+        if let Some(memcheck) = &mut self.memcheck {
+            if !self.memory.is_mmio(idx, size) {
+                memcheck.check_read(self.prog_cnt, idx, size);
             }
+        }
+        self.check_mem_watch(idx, size, Access::Read, value, value);
+        Ok(value)
+    }
+
+    fn read_sized(&mut self, idx: usize, size: usize) -> Result<u32, EmulatorErr> {
+        let endian = self.endian_at(idx, size);
+        match size {
+            1 => self.memory.read_byte(idx).map(u32::from),
+            2 => self
+                .memory
+                .read_half(idx)
+                .map(|half| endian.half(half) as u32),
+            _ => self.memory.read_word(idx).map(|word| endian.word(word)),
+        }
+    }
+
+    /// Byte order of an access of `size` bytes at `idx`. Device registers hold values
+    /// rather than bytes, so they are accessed the same way in either order.
+    fn endian_at(&self, idx: usize, size: usize) -> Endian {
+        match self.memory.is_mmio(idx, size) {
+            true => Endian::Little,
+            false => self.config.endian,
+        }
+    }
+
+    /// Loads 8 aligned bytes at `memaddr` in 64-bit mode, as two words in the byte order
+    fn mem_load_wide(&mut self, memaddr: i32) -> Result<i64, EmulatorErr> {
+        self.mem_index(memaddr, 8)?;
+        let first = self.mem_load(memaddr, 4)? as u64;
+        let second = self.mem_load(memaddr + 4, 4)? as u64;
+        Ok(match self.config.endian {
+            Endian::Little => second << 32 | first,
+            Endian::Big => first << 32 | second,
+        } as i64)
+    }
+
+    /// Stores `value` to 8 aligned bytes at `memaddr` in 64-bit mode
+    fn mem_store_wide(&mut self, memaddr: i32, value: i64) -> Result<(), EmulatorErr> {
+        self.mem_index(memaddr, 8)?;
+        let (lo, hi) = (value as u32, (value >> 32) as u32);
+        let (first, second) = match self.config.endian {
+            Endian::Little => (lo, hi),
+            Endian::Big => (hi, lo),
+        };
+        self.mem_store(memaddr, 4, first)?;
+        self.mem_store(memaddr + 4, 4, second)
+    }
+
+    /// Writes the lower `size`(1, 2 or 4) bytes of `value` to memory
+    fn mem_store(&mut self, memaddr: i32, size: usize, value: u32) -> Result<(), EmulatorErr> {
+        let idx = self.mem_index(memaddr, size)?;
+        let idx = self.translate(idx, true)?;
+        self.check_protection(idx, size, Access::Write)?;
+        self.data_access(idx, size, true);
+        let watched = self
+            .mem_watches
+            .iter()
+            .any(|w| w.matches(idx, size, Access::Write));
+        let old = if watched {
+            self.read_sized(idx, size)?
         } else {
-            self.regs[get_bits(inst, REG_BITS, SRC2_OFF) as usize].0
+            0
         };
-        let src2 = Wrapping(tmps2);
-        let new_pc = self.prog_cnt + sign_extend(get_bits(inst, OFFSET_BITS, 0), OFFSET_BITS);
-        // imm[reg] is understood as (reg + imm), where imm is always src2
-        let memaddr = src1 + src2;
+        let endian = self.endian_at(idx, size);
+        let mut memory = self.logged_memory();
+        match size {
+            1 => memory.write_byte(idx, value as u8)?,
+            2 => memory.write_half(idx, endian.half(value as u16))?,
+            _ => memory.write_word(idx, endian.word(value))?,
+        }
+        if watched {
+            let new = value & (u32::MAX >> (32 - 8 * size));
+            self.check_mem_watch(idx, size, Access::Write, old, new);
+        }
+        Ok(())
+    }
+
+    /// Accounts for an access made by a load or a store
+    fn data_access(&mut self, idx: usize, size: usize, write: bool) {
+        let costs = &self.config.energy_costs;
+        self.energy += if write { costs.write } else { costs.read } as u64;
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record(idx, write);
+        }
+        if let Some(dcache) = &mut self.dcache {
+            // Devices are not cached
+            if !self.memory.is_mmio(idx, size) {
+                dcache.access(idx, write);
+            }
+        }
+    }
+
+    /// Memory which logs the writes made by the current step if they are needed
+    fn logged_memory(&mut self) -> LoggedMemory<'_> {
+        LoggedMemory {
+            log: self.logs_writes().then_some(&mut self.mem_log),
+            inner: &mut self.memory,
+        }
+    }
+
+    /// Returns true if a tracer logs the executed instructions
+    fn tracing(&self) -> bool {
+        #[cfg(feature = "std")]
+        return self.tracer.is_some();
+        #[cfg(not(feature = "std"))]
+        false
+    }
+
+    /// Returns true if the writes have to be logged for the history, the trace, memcheck
+    /// or the write log
+    fn logs_writes(&self) -> bool {
+        self.config.history_depth > 0
+            || self.tracing()
+            || self.memcheck.is_some()
+            || self.write_log.is_some()
+    }
+
+    /// Records a stop if an `access` to `[idx, idx + size)` triggers a watchpoint
+    fn check_mem_watch(&mut self, idx: usize, size: usize, access: Access, old: u32, new: u32) {
+        if self
+            .mem_watches
+            .iter()
+            .any(|w| w.matches(idx, size, access))
+        {
+            self.stop_reason = Some(StopReason::MemWatch(MemHit {
+                pc: self.prog_cnt,
+                addr: idx,
+                size,
+                access,
+                old,
+                new,
+            }));
+        }
+    }
 
-        assert!(opcode <= Opcode::RET as u8, "Invalid opcode"); // RET is the last instruction
+    /// Reads the operands of `inst`, the instruction at `pc`
+    fn unpack(&self, inst: u32) -> Result<UnpackedIns, EmulatorErr> {
+        let decoded = match self.decoded.get(self.prog_cnt as usize) {
+            Some(Some(decoded)) => *decoded,
+            _ => decode(inst)?,
+        };
+        let src1 = self.regs[decoded.src1_reg];
+        // src2 can be either a register or an immediate
+        let src2 = match decoded.src2 {
+            Operand::Reg(reg) => self.regs[reg],
+            Operand::Imm(imm) => Wrapping(imm),
+        };
+        // imm[reg] is understood as (reg + imm), where imm is always src2,
+        // atomics take the value to store in src2 and the address alone in [reg]
+        let memaddr = match info::is_atomic(decoded.opcode as u8) {
+            true => src1,
+            false => src1 + src2,
+        };
         Ok(UnpackedIns {
-            dst_reg,
+            dst_reg: decoded.dst_reg,
             src1,
             src2,
             memaddr: memaddr.0,
-            new_pc,
-            opcode: unsafe { std::mem::transmute::<u8, Opcode>(opcode) },
+            new_pc: self.prog_cnt + decoded.offset,
+            opcode: decoded.opcode,
+            zero_ext: decoded.zero_ext,
+            writes_dst: decoded.writes_dst,
+        })
+    }
+}
+
+fn decode(inst: u32) -> Result<DecodedIns, EmulatorErr> {
+    // See src/info.rs for more info
+    let opcode = info::opcode_of(inst);
+    let ins = info::instruction(opcode).ok_or(EmulatorErr::InvalidOpcode)?;
+    let is_imm = info::supports_imm(opcode) && get_bits(inst, IMMBIT_BITS, IMMBIT_OFF) == 1;
+    let mut modbits = get_bits(inst, MOD_BITS, MOD_OFF) as u8;
+    // Modifier of sub-word loads applies to the loaded value, offset is always sign extended
+    let zero_ext = info::is_subword_load(opcode) && modbits == MOD_U;
+    if zero_ext {
+        modbits = MOD_DEF;
+    }
+    let src2 = if is_imm {
+        let imm = get_bits(inst, IMM_BITS, 0);
+        Operand::Imm(match modbits {
+            MOD_DEF => sign_extend(imm, IMM_BITS),
+            MOD_U => imm as i32,
+            MOD_H => (imm << u16::BITS) as i32,
+            _ => return Err(EmulatorErr::InvalidModbits),
         })
+    } else {
+        Operand::Reg(get_bits(inst, REG_BITS, SRC2_OFF) as usize)
+    };
+
+    let dst_reg = get_bits(inst, REG_BITS, DST_OFF) as usize;
+    Ok(DecodedIns {
+        opcode: ins.opcode,
+        dst_reg,
+        src1_reg: get_bits(inst, REG_BITS, SRC1_OFF) as usize,
+        src2,
+        offset: info::branch_offset(inst),
+        zero_ext,
+        // The others would write back the old value, which loses its upper half
+        writes_dst: pipeline::operands(inst).writes & (1 << dst_reg) != 0,
+    })
+}
+
+/// Decodes every instruction of the program if [`EmulatorConfig::predecode`] is set.
+/// Invalid words are left for [`decode`] to report when they are executed.
+fn predecode(instructions: &[u32], config: &EmulatorConfig) -> Vec<Option<DecodedIns>> {
+    if !config.predecode || config.text_base.is_some() || config.xlen == Xlen::X64 {
+        return Vec::new();
     }
+    instructions.iter().map(|&inst| decode(inst).ok()).collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::sign_extend;
+    use super::{sign_extend, Emulator, EmulatorConfig, EmulatorErr};
+    use crate::{
+        info::{self, Opcode},
+        memory::{FlatMemory, Memory},
+        parser::parse_and_assemble,
+        rng::Rng,
+    };
     #[test]
     fn test_sign_extent() {
         assert_eq!(sign_extend(0b11111, 5), -1);
         assert_eq!(sign_extend(0b10000, 5), -16);
         assert_eq!(sign_extend(0b01111, 5), 15);
     }
+
+    #[test]
+    fn test_memory() {
+        let mut emul = Emulator::new(&[]);
+        emul.mem_store(8, 4, 0x12345678).unwrap();
+        let mut bytes = [0u8; 4];
+        emul.memory.read_bytes(8, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(emul.mem_load(8, 4).unwrap(), 0x12345678);
+        assert_eq!(emul.mem_load(8, 2).unwrap(), 0x5678);
+        assert_eq!(emul.mem_load(11, 1).unwrap(), 0x12);
+
+        assert!(matches!(
+            emul.mem_load(6, 4),
+            Err(EmulatorErr::UnalignedMemAddr)
+        ));
+        assert!(matches!(
+            emul.mem_load(-4, 4),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+        let end = emul.memory.size() as i32;
+        assert!(matches!(
+            emul.mem_load(end, 1),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+        assert!(emul.mem_load(end - 4, 4).is_ok());
+    }
+
+    #[test]
+    fn test_mem_size() {
+        let config = EmulatorConfig {
+            mem_bytes: 64,
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&[], config);
+        assert!(emul.mem_load(60, 4).is_ok());
+        assert!(matches!(
+            emul.mem_load(64, 4),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+    }
+
+    /// Counts the number of writes done to the wrapped memory
+    struct CountingMemory(FlatMemory, usize);
+
+    impl Memory for CountingMemory {
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+
+        fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+            self.0.read_bytes(addr, buf)
+        }
+
+        fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+            self.1 += 1;
+            self.0.write_bytes(addr, bytes)
+        }
+    }
+
+    #[test]
+    fn test_custom_memory() {
+        let bin = parse_and_assemble("mov r1, 7\nst r1, 4[r0]\nstb r1, 9[r0]\nld r2, 4[r0]\n");
+        let bin = bin.unwrap();
+        let mut emul = Emulator::with_memory(&bin, CountingMemory(FlatMemory::new(16), 0));
+        emul.exec().unwrap();
+        assert_eq!(emul.get_reg_val(2), 7);
+        assert_eq!(emul.memory.1, 2);
+    }
+
+    #[test]
+    fn test_garbage_words() {
+        let mut rng = Rng::new(1);
+        let words = (0..2000).map(|_| rng.next_u64() as u32);
+        // Every extended opcode, most of which are invalid
+        let ext = (0..64).map(|funct| info::encode_opcode(info::EXT_OPCODE) | funct);
+        for word in words.chain(ext) {
+            let opcode = info::opcode_of(word);
+            // System calls would use the standard input and output
+            if opcode == Opcode::SYS as u8 {
+                continue;
+            }
+            for predecode in [true, false] {
+                let config = EmulatorConfig {
+                    predecode,
+                    ..Default::default()
+                };
+                let mut emul = Emulator::with_config(&[word], config);
+                let result = emul.step();
+                if info::instruction(opcode).is_none() {
+                    let err = result.unwrap_err();
+                    assert!(matches!(err.kind(), EmulatorErr::InvalidOpcode));
+                    assert!(err.to_string().contains(".word"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn test_native_blocks() {
+        // The first store to a page returns to the interpreter, which allocates it
+        let code = "
+            mov r1, 0
+            mov r2, r0
+        loop:
+            add r1, r1, 1
+            sub r2, r2, r1
+            mul r3, r2, 7
+            mul r4, r3, r1
+            and r5, r4, 0xf0f
+            or r5, r5, r1
+            xor r6, r5, r2
+            lsl r7, r6, r1
+            lsr r8, r6, 35
+            asr r9, r6, r1
+            lsl r13, r1, 2
+            st r3, 4096[r13]
+            sth r6, 8192[r0]
+            ldb r10, 4096[r13]
+            ldh r11, 8192[r0]
+            ldhu r12, 8192[r0]
+            ld r7, 4096[r13]
+            cmp r7, r3
+            b check
+        check:
+            beq same
+            mov r10, -1
+        same:
+            cmp r2, -100
+            blt done
+            cmp r1, 5
+            bls loop
+            cmp r3, r4
+            bvc loop
+        done:
+        ";
+        let bincode = parse_and_assemble(code).unwrap();
+        let config = |predecode| EmulatorConfig {
+            predecode,
+            ..Default::default()
+        };
+        let mut native = Emulator::with_config(&bincode, config(true));
+        let mut interpreted = Emulator::with_config(&bincode, config(false));
+        crate::lockstep::run_lockstep_blocks(&mut native, &mut interpreted, 10_000).unwrap();
+        assert_eq!(native.snapshot(), interpreted.snapshot());
+        assert_eq!(native.energy, interpreted.energy);
+        let blocks = &native.blocks.blocks;
+        assert!(blocks.len() > 3);
+        assert!(blocks.iter().all(|block| block.code.is_some()));
+    }
 }
@@ -0,0 +1,81 @@
+//! Crate level error type combining errors from all the stages.
+
+use crate::{emulator::EmulatorErr, parser::ParseErr};
+use std::{fmt, io};
+
+/// Any error that can occur while assembling or running a program
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    Parse(ParseErr),
+    Emulator(EmulatorErr),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(_) => write!(f, "Cannot assemble the program"),
+            Self::Emulator(_) => write!(f, "Program execution failed"),
+            Self::Io(_) => write!(f, "I/O error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            Self::Emulator(e) => Some(e),
+            Self::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<ParseErr> for Error {
+    fn from(e: ParseErr) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl From<EmulatorErr> for Error {
+    fn from(e: EmulatorErr) -> Self {
+        Self::Emulator(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use crate::{emulator::Emulator, parser::parse_and_assemble};
+    use std::error::Error as _;
+
+    fn run(code: &str) -> Result<i32, Error> {
+        let bin = parse_and_assemble(code)?;
+        let mut emul = Emulator::new(&bin);
+        emul.exec()?;
+        Ok(emul.get_reg_val(0))
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(run("mov r0, 7\n").unwrap(), 7);
+
+        let err = run("mov r0\n").unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        assert!(err.source().unwrap().to_string().starts_with("On line 1"));
+
+        let err = run("div r0, r0, 0\n").unwrap_err();
+        assert!(matches!(err, Error::Emulator(_)));
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "Divide by 0 error at 0: div r0, r0, 0 (0x1c000000)"
+        );
+    }
+}
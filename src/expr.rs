@@ -0,0 +1,342 @@
+//! Small expression language over the machine state, used by conditional breakpoints.
+//!
+//! ```text
+//! r1 == 0 && [0x100] > 5
+//! ```
+//! Operands are integers(decimal, `0x` hex or `0b` binary), registers(`r0`-`r15`, `sp`),
+//! `pc` and `[expr]` which is the word in memory at address `expr`.
+//! Operators, from the lowest precedence to the highest, are `||`, `&&`,
+//! comparisons(`== != < <= > >=`), `+ -`, `* / %` and the unary `- !`.
+//! All values are 32-bit signed integers, comparisons and logical operators give 0 or 1.
+
+use crate::emulator::EmulatorErr;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+};
+use core::{fmt, num::Wrapping, str::FromStr};
+
+/// State an expression is evaluated against
+pub trait Env {
+    fn reg(&self, reg: usize) -> i32;
+    fn pc(&self) -> i32;
+    /// Reads the word at `addr`
+    fn load(&mut self, addr: i32) -> Result<i32, EmulatorErr>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i32),
+    Reg(usize),
+    Pc,
+    /// Word in memory at the address
+    Mem(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, env: &mut dyn Env) -> Result<i32, EmulatorErr> {
+        Ok(match self {
+            Self::Num(n) => *n,
+            Self::Reg(r) => env.reg(*r),
+            Self::Pc => env.pc(),
+            Self::Mem(addr) => {
+                let addr = addr.eval(env)?;
+                env.load(addr)?
+            }
+            Self::Unary(UnOp::Neg, e) => e.eval(env)?.wrapping_neg(),
+            Self::Unary(UnOp::Not, e) => (e.eval(env)? == 0) as i32,
+            // Short circuit
+            Self::Binary(BinOp::Or, l, r) => (l.eval(env)? != 0 || r.eval(env)? != 0) as i32,
+            Self::Binary(BinOp::And, l, r) => (l.eval(env)? != 0 && r.eval(env)? != 0) as i32,
+            Self::Binary(op, l, r) => {
+                let (l, r) = (Wrapping(l.eval(env)?), Wrapping(r.eval(env)?));
+                match op {
+                    BinOp::Eq => (l == r) as i32,
+                    BinOp::Ne => (l != r) as i32,
+                    BinOp::Lt => (l < r) as i32,
+                    BinOp::Le => (l <= r) as i32,
+                    BinOp::Gt => (l > r) as i32,
+                    BinOp::Ge => (l >= r) as i32,
+                    BinOp::Add => (l + r).0,
+                    BinOp::Sub => (l - r).0,
+                    BinOp::Mul => (l * r).0,
+                    BinOp::Div | BinOp::Mod if r.0 == 0 => return Err(EmulatorErr::DivideByZero),
+                    BinOp::Div => (l / r).0,
+                    BinOp::Mod => (l % r).0,
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+/// An error encountered while parsing an expression
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExprErr {
+    pos: usize,
+    found: String,
+}
+
+impl ExprErr {
+    /// Byte offset(starting from 0) of the offending token
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// The offending token, it is empty if the error was caused by the end of input
+    pub fn found(&self) -> &str {
+        &self.found
+    }
+}
+
+impl core::error::Error for ExprErr {}
+
+impl fmt::Display for ExprErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.found.is_empty() {
+            write!(f, "Unexpected end of expression")
+        } else {
+            write!(f, "Unexpected '{}' at offset {}", self.found, self.pos)
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ExprErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { src: s, pos: 0 };
+        let expr = parser.expr(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(_) => Err(parser.error()),
+        }
+    }
+}
+
+/// Binary operators along with their precedence, longer lexemes come first
+const BIN_OPS: [(&str, BinOp, u8); 13] = [
+    ("||", BinOp::Or, 0),
+    ("&&", BinOp::And, 1),
+    ("==", BinOp::Eq, 2),
+    ("!=", BinOp::Ne, 2),
+    ("<=", BinOp::Le, 2),
+    (">=", BinOp::Ge, 2),
+    ("<", BinOp::Lt, 2),
+    (">", BinOp::Gt, 2),
+    ("+", BinOp::Add, 3),
+    ("-", BinOp::Sub, 3),
+    ("*", BinOp::Mul, 4),
+    ("/", BinOp::Div, 4),
+    ("%", BinOp::Mod, 4),
+];
+
+/// Precedence climbing parser
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// Parses operands joined by operators with a precedence of at least `min_prec`
+    fn expr(&mut self, min_prec: u8) -> Result<Expr, ExprErr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let rest = self.rest();
+            let Some(&(lexeme, op, prec)) = BIN_OPS.iter().find(|(l, ..)| rest.starts_with(l))
+            else {
+                return Ok(lhs);
+            };
+            if prec < min_prec {
+                return Ok(lhs);
+            }
+            self.pos += lexeme.len();
+            // Left associative
+            let rhs = self.expr(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprErr> {
+        let op = match self.peek() {
+            Some('-') => UnOp::Neg,
+            Some('!') if !self.rest().starts_with("!=") => UnOp::Not,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprErr> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.expr(0)?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some('[') => {
+                self.pos += 1;
+                let expr = self.expr(0)?;
+                self.expect(']')?;
+                Ok(Expr::Mem(Box::new(expr)))
+            }
+            Some(c) if c.is_ascii_alphanumeric() => {
+                let start = self.pos;
+                let len = self.src[start..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(self.src.len() - start);
+                let word = &self.src[start..start + len];
+                let expr = Self::operand(word).ok_or_else(|| ExprErr {
+                    pos: start,
+                    found: word.to_string(),
+                })?;
+                self.pos += len;
+                Ok(expr)
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Converts a number, register name or `pc` to an operand
+    fn operand(word: &str) -> Option<Expr> {
+        let lower = word.to_ascii_lowercase();
+        let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+            (hex, 16)
+        } else if let Some(bin) = lower.strip_prefix("0b") {
+            (bin, 2)
+        } else {
+            (lower.as_str(), 10)
+        };
+        if let Ok(n) = u32::from_str_radix(digits, radix) {
+            // Large literals like 0xffffffff wrap around
+            return Some(Expr::Num(n as i32));
+        }
+        match lower.as_str() {
+            "pc" => Some(Expr::Pc),
+            "sp" => Some(Expr::Reg(14)),
+            _ => {
+                let digits = lower.strip_prefix('r')?;
+                let reg: usize = digits.parse().ok()?;
+                (reg < 16 && reg.to_string() == digits).then_some(Expr::Reg(reg))
+            }
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ExprErr> {
+        if self.peek() != Some(c) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Skips whitespace and returns the remaining input
+    fn rest(&mut self) -> &str {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        &self.src[self.pos..]
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    /// Error for the character at the current position
+    fn error(&mut self) -> ExprErr {
+        let found = self.peek().map_or(String::new(), String::from);
+        ExprErr {
+            pos: self.pos,
+            found,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Env, Expr};
+    use crate::emulator::EmulatorErr;
+
+    struct TestEnv;
+
+    impl Env for TestEnv {
+        fn reg(&self, reg: usize) -> i32 {
+            reg as i32 * 10
+        }
+
+        fn pc(&self) -> i32 {
+            7
+        }
+
+        fn load(&mut self, addr: i32) -> Result<i32, EmulatorErr> {
+            match addr {
+                0..=1023 => Ok(addr + 1),
+                _ => Err(EmulatorErr::InvalidMemAddr),
+            }
+        }
+    }
+
+    fn eval(s: &str) -> i32 {
+        let expr: Expr = s.parse().unwrap();
+        expr.eval(&mut TestEnv).unwrap()
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("10 - 4 - 3"), 3);
+        assert_eq!(eval("-r1 + sp"), 130);
+        assert_eq!(eval("r15 % 4 == 2"), 1);
+        assert_eq!(eval("r0 == 0 && [0x100] > 5"), 1);
+        assert_eq!(eval("r1==0||[r2]!=21"), 0);
+        assert_eq!(eval("!(pc >= 7) || 0b11 < 2"), 0);
+        assert_eq!(eval("0xffffffff"), -1);
+        // The right operand is not evaluated
+        assert_eq!(eval("0 && [-1]"), 0);
+
+        let expr: Expr = "[r0 - 4]".parse().unwrap();
+        assert!(matches!(
+            expr.eval(&mut TestEnv),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |s: &str| {
+            let err = s.parse::<Expr>().unwrap_err();
+            (err.pos(), err.found().to_string())
+        };
+        assert_eq!(err("r1 == "), (6, String::new()));
+        assert_eq!(err("r16 > 1"), (0, "r16".to_string()));
+        assert_eq!(err("[r1 > 1"), (7, String::new()));
+        assert_eq!(err("r1 = 2"), (3, "=".to_string()));
+        assert_eq!(err("1 2"), (2, "2".to_string()));
+    }
+}
@@ -0,0 +1,311 @@
+//! C interface to the assembler and the emulator, for embedding them in programs written
+//! in other languages. Build it as a shared library with
+//! `cargo rustc --lib --release --features cdylib --crate-type cdylib`, the functions are
+//! declared in `include/simple_risc.h`.
+//!
+//! The emulator is an opaque pointer created by [`srisc_emulator_new`] and freed by
+//! [`srisc_emulator_free`]. Its program uses the standard input and output of the process.
+//! Functions returning a status use the `SRISC_*` constants.
+
+use crate::{
+    emulator::{Emulator, EmulatorErr, ExitStatus, RunStatus},
+    parser::assemble,
+};
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr, slice,
+};
+
+/// The emulator can execute more instructions
+pub const SRISC_RUNNING: c_int = 0;
+/// The emulator paused at a breakpoint
+pub const SRISC_BREAK: c_int = 1;
+/// The program stopped, see [`srisc_emulator_exit_code`]
+pub const SRISC_STOPPED: c_int = 2;
+/// An instruction failed, see [`srisc_emulator_error`]
+pub const SRISC_FAILED: c_int = -1;
+
+/// An emulator along with what the C side can ask about its last run
+pub struct SriscEmulator {
+    emul: Emulator,
+    exit: Option<ExitStatus>,
+    error: Option<CString>,
+}
+
+/// Assembles the NUL-terminated `source`.
+///
+/// On success stores the instructions, to be freed with [`srisc_binary_free`], in `*binary`
+/// and their number in `*len`, then returns 0. Otherwise returns the line of the error and
+/// writes its message to `error`, truncated to `error_len` bytes along with the NUL, if it
+/// is not null.
+///
+/// # Safety
+///
+/// `source` must be a NUL-terminated string, `binary` and `len` must be valid for writes
+/// and `error` must be null or valid for writes of `error_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_assemble(
+    source: *const c_char,
+    binary: *mut *mut u32,
+    len: *mut usize,
+    error: *mut c_char,
+    error_len: usize,
+) -> c_int {
+    let source = CStr::from_ptr(source).to_string_lossy();
+    match assemble(&source) {
+        Ok(program) => {
+            let text = program.text.into_boxed_slice();
+            *len = text.len();
+            *binary = Box::into_raw(text) as *mut u32;
+            0
+        }
+        Err(err) => {
+            if !error.is_null() && error_len > 0 {
+                let message = err.to_string();
+                let n = message.len().min(error_len - 1);
+                ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, error, n);
+                *error.add(n) = 0;
+            }
+            err.line().max(1) as c_int
+        }
+    }
+}
+
+/// Frees the instructions returned by [`srisc_assemble`].
+///
+/// # Safety
+///
+/// `binary` and `len` must come from the same call of [`srisc_assemble`], or `binary` must
+/// be null.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_binary_free(binary: *mut u32, len: usize) {
+    if !binary.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(binary, len)));
+    }
+}
+
+/// Creates an emulator running the `len` instructions at `binary`.
+///
+/// # Safety
+///
+/// `binary` must be valid for reads of `len` words, or `len` must be 0.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_new(binary: *const u32, len: usize) -> *mut SriscEmulator {
+    let binary = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(binary, len)
+    };
+    Box::into_raw(Box::new(SriscEmulator {
+        emul: Emulator::new(binary),
+        exit: None,
+        error: None,
+    }))
+}
+
+/// Frees an emulator.
+///
+/// # Safety
+///
+/// `emul` must come from [`srisc_emulator_new`] and not be freed already, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_free(emul: *mut SriscEmulator) {
+    if !emul.is_null() {
+        drop(Box::from_raw(emul));
+    }
+}
+
+impl SriscEmulator {
+    fn status(&mut self, result: Result<RunStatus, EmulatorErr>) -> c_int {
+        match result {
+            Ok(RunStatus::Running) => SRISC_RUNNING,
+            Ok(RunStatus::Break(_)) => SRISC_BREAK,
+            Ok(RunStatus::Stopped(exit)) => {
+                self.exit = Some(exit);
+                SRISC_STOPPED
+            }
+            Err(err) => {
+                self.error = CString::new(err.to_string()).ok();
+                SRISC_FAILED
+            }
+        }
+    }
+}
+
+/// Executes a single instruction.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_step(emul: *mut SriscEmulator) -> c_int {
+    let emul = &mut *emul;
+    let result = emul.emul.step();
+    emul.status(result)
+}
+
+/// Executes at most `max_steps` instructions, stopping at breakpoints.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_run(emul: *mut SriscEmulator, max_steps: u64) -> c_int {
+    let emul = &mut *emul;
+    let result = emul.emul.run_for(max_steps);
+    emul.status(result)
+}
+
+/// Pauses before executing the instruction at `pc`.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_add_breakpoint(emul: *mut SriscEmulator, pc: i32) {
+    (*emul).emul.add_breakpoint(pc, None);
+}
+
+/// Returns the value of register `reg`, 0 if there is no such register.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_reg(emul: *const SriscEmulator, reg: c_int) -> i32 {
+    match usize::try_from(reg) {
+        Ok(reg) if reg < 16 => (*emul).emul.get_reg_val(reg),
+        _ => 0,
+    }
+}
+
+/// Returns the index of the next instruction.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_pc(emul: *const SriscEmulator) -> i32 {
+    (*emul).emul.pc()
+}
+
+/// Copies the `len` bytes of memory at `addr` to `buf`, returns 0 on success and -1 if
+/// they are outside the memory.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`] and `buf` must be valid for
+/// writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_read_mem(
+    emul: *mut SriscEmulator,
+    addr: usize,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    let Some(end) = addr.checked_add(len) else {
+        return -1;
+    };
+    match (*emul).emul.read_mem(addr..end) {
+        Ok(bytes) => {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buf, len);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Stores the code passed to the exit system call in `*code` and returns 1, returns 0 if
+/// the program has not used it.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`] and `code` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_exit_code(
+    emul: *const SriscEmulator,
+    code: *mut i32,
+) -> c_int {
+    match (*emul).exit {
+        Some(ExitStatus::Exited(exit_code)) => {
+            *code = exit_code;
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Returns the message of the last failure as a NUL-terminated string, owned by the
+/// emulator, or null if nothing failed.
+///
+/// # Safety
+///
+/// `emul` must be a live emulator from [`srisc_emulator_new`].
+#[no_mangle]
+pub unsafe extern "C" fn srisc_emulator_error(emul: *const SriscEmulator) -> *const c_char {
+    (*emul).error.as_ref().map_or(ptr::null(), |e| e.as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let mut binary = ptr::null_mut();
+            let mut len = 0;
+            let mut error = [0 as c_char; 64];
+            let source = c"mov r1, 1\nfoo r1\n";
+            let line = srisc_assemble(
+                source.as_ptr(),
+                &mut binary,
+                &mut len,
+                error.as_mut_ptr(),
+                error.len(),
+            );
+            assert_eq!(line, 2);
+            assert!(CStr::from_ptr(error.as_ptr())
+                .to_str()
+                .unwrap()
+                .starts_with("On line 2"));
+
+            let source = c"mov r1, 0x41\nst r1, 8[r0]\nmov r1, 5\nmov r0, 3\nsys\n";
+            let status = srisc_assemble(source.as_ptr(), &mut binary, &mut len, ptr::null_mut(), 0);
+            assert_eq!((status, len), (0, 5));
+            let emul = srisc_emulator_new(binary, len);
+            srisc_binary_free(binary, len);
+
+            assert_eq!(srisc_emulator_step(emul), SRISC_RUNNING);
+            assert_eq!(srisc_emulator_reg(emul, 1), 0x41);
+            srisc_emulator_add_breakpoint(emul, 3);
+            assert_eq!(srisc_emulator_run(emul, 100), SRISC_BREAK);
+            assert_eq!(srisc_emulator_pc(emul), 3);
+            let mut code = 0;
+            assert_eq!(srisc_emulator_exit_code(emul, &mut code), 0);
+            assert_eq!(srisc_emulator_run(emul, 100), SRISC_STOPPED);
+            assert_eq!(srisc_emulator_exit_code(emul, &mut code), 1);
+            assert_eq!(code, 5);
+
+            let mut buf = [0u8; 4];
+            assert_eq!(srisc_emulator_read_mem(emul, 8, buf.as_mut_ptr(), 4), 0);
+            assert_eq!(buf, [0x41, 0, 0, 0]);
+            assert_eq!(
+                srisc_emulator_read_mem(emul, usize::MAX, buf.as_mut_ptr(), 4),
+                -1
+            );
+            assert!(srisc_emulator_error(emul).is_null());
+            srisc_emulator_free(emul);
+
+            let source = c"div r1, r1, 0\n";
+            srisc_assemble(source.as_ptr(), &mut binary, &mut len, ptr::null_mut(), 0);
+            let emul = srisc_emulator_new(binary, len);
+            srisc_binary_free(binary, len);
+            assert_eq!(srisc_emulator_run(emul, 10), SRISC_FAILED);
+            assert!(!srisc_emulator_error(emul).is_null());
+            srisc_emulator_free(emul);
+        }
+    }
+}
@@ -0,0 +1,607 @@
+//! Stub speaking the GDB remote serial protocol, so that GDB and front-ends built on it
+//! can debug a program running in the emulator.
+//!
+//! Registers are `r0`-`r15` followed by `pc`, each 32 bits wide, as described by the
+//! `target.xml` served with `qXfer:features:read`. `pc` and breakpoints are byte
+//! addresses like those of the memory: the instruction at `pc` is at `text_base + 4 * pc`
+//! with [`EmulatorConfig::text_base`]. Otherwise the instructions are not in the memory
+//! and GDB sees them right after its end, where they can be read but not written.
+//! Supported are reading and writing registers and memory, stepping, continuing,
+//! software breakpoints(`Z0`/`Z1`) and watchpoints(`Z2`-`Z4`).
+//!
+//! [`EmulatorConfig::text_base`]: crate::emulator::EmulatorConfig::text_base
+
+use crate::{
+    debug::{Access, StopReason},
+    emulator::{Emulator, EmulatorErr, ExitStatus, RunStatus},
+    expr::Env,
+    memory::Memory,
+};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+/// Instructions executed between checks for an interrupt request from GDB
+const CHUNK_STEPS: u64 = 10_000;
+
+// Signal numbers used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+/// Byte sent by GDB to interrupt a running program
+const INTERRUPT: u8 = 0x03;
+
+/// Registers of the `g` packet, [`code_addr`] of the instruction at `pc` last.
+/// `r15` is not a code pointer as `call` stores the index of the next instruction.
+const REGISTERS: [&str; 17] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "r13", "sp",
+    "r15", "pc",
+];
+
+/// Stream to GDB
+pub trait Connection: Read + Write {
+    /// Returns true if GDB has sent an interrupt request, must not block
+    fn interrupt_pending(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupt_pending(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.peek(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == INTERRUPT => {
+                self.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+pub struct GdbStub<C> {
+    conn: C,
+    /// Reply to the `?` packet
+    last_stop: String,
+    /// Last packet sent, resent if GDB asks for it
+    last_reply: Vec<u8>,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            last_reply: Vec::new(),
+        }
+    }
+
+    /// Serves requests until GDB detaches, kills the program or disconnects
+    pub fn run<M: Memory>(&mut self, emul: &mut Emulator<M>) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(emul, &packet)?,
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the reply to `packet`, empty if it is not supported
+    fn handle<M: Memory>(&mut self, emul: &mut Emulator<M>, packet: &str) -> io::Result<String> {
+        let (cmd, args) = packet.split_at(1);
+        Ok(match cmd {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let regs = (0..16).map(|r| emul.reg(r) as u32);
+                let regs = regs.chain([code_addr(emul, emul.pc())]);
+                regs.map(|v| hex(&v.to_le_bytes())).collect()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r @ 0..=15) => hex(&emul.reg(r).to_le_bytes()),
+                Ok(16) => hex(&code_addr(emul, emul.pc()).to_le_bytes()),
+                _ => String::from("E01"),
+            },
+            "G" => {
+                let Some(bytes) = unhex(args).filter(|b| b.len() == 4 * 17) else {
+                    return Ok(String::from("E01"));
+                };
+                let values: Vec<i32> = bytes
+                    .chunks_exact(4)
+                    .map(|w| i32::from_le_bytes(w.try_into().unwrap()))
+                    .collect();
+                let Some(pc) = code_pc(emul, values[16] as u32 as usize) else {
+                    return Ok(String::from("E01"));
+                };
+                for (reg, &value) in values[..16].iter().enumerate() {
+                    emul.set_reg_val(reg, value);
+                }
+                emul.set_pc(pc);
+                String::from("OK")
+            }
+            "P" => {
+                let written = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let bytes: [u8; 4] = unhex(value)?.try_into().ok()?;
+                    let value = i32::from_le_bytes(bytes);
+                    match reg {
+                        0..=15 => emul.set_reg_val(reg, value),
+                        16 => emul.set_pc(code_pc(emul, value as u32 as usize)?),
+                        _ => return None,
+                    }
+                    Some(())
+                });
+                String::from(if written.is_some() { "OK" } else { "E01" })
+            }
+            "m" => {
+                let Some((addr, len)) = parse_range(args) else {
+                    return Ok(String::from("E01"));
+                };
+                match read_memory(emul, addr, len) {
+                    Some(bytes) => hex(&bytes),
+                    None => String::from("E01"),
+                }
+            }
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = unhex(data).filter(|b| b.len() == len)?;
+                    emul.write_mem(addr, &bytes).ok()
+                });
+                String::from(if written.is_some() { "OK" } else { "E01" })
+            }
+            "s" | "c" if !args.is_empty() => String::from("E01"),
+            "s" => self.resume(emul, 1)?,
+            "c" => self.resume(emul, u64::MAX)?,
+            "Z" | "z" => match self.set_point(emul, cmd == "Z", args) {
+                Some(true) => String::from("OK"),
+                Some(false) => String::new(),
+                None => String::from("E01"),
+            },
+            "H" | "T" => String::from("OK"),
+            _ if packet.starts_with("qSupported") => {
+                String::from("PacketSize=1000;qXfer:features:read+")
+            }
+            _ if packet.starts_with("qXfer:features:read:") => {
+                let args = &packet["qXfer:features:read:".len()..];
+                match args.split_once(':') {
+                    Some(("target.xml", range)) => match parse_range(range) {
+                        Some((offset, len)) => xfer(&target_xml(), offset, len),
+                        None => String::from("E01"),
+                    },
+                    _ => String::from("E00"),
+                }
+            }
+            _ if packet == "qAttached" => String::from("1"),
+            _ if packet == "qfThreadInfo" => String::from("m1"),
+            _ if packet == "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        })
+    }
+
+    /// Inserts or removes a breakpoint or watchpoint, `args` is `type,addr,kind`.
+    /// Returns false if the type is not supported and `None` if the request is invalid.
+    fn set_point<M: Memory>(
+        &mut self,
+        emul: &mut Emulator<M>,
+        insert: bool,
+        args: &str,
+    ) -> Option<bool> {
+        let (kind, range) = args.split_once(',')?;
+        let (addr, len) = parse_range(range)?;
+        let access = match kind {
+            "0" | "1" => {
+                let pc = code_pc(emul, addr)?;
+                match insert {
+                    true => emul.add_breakpoint(pc, None),
+                    false => emul.remove_breakpoint(pc),
+                }
+                return Some(true);
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::Any,
+            _ => return Some(false),
+        };
+        let addrs = addr..addr.checked_add(len.max(1))?;
+        match insert {
+            true => emul.watch_memory(addrs, access),
+            false => emul.unwatch_memory(addrs),
+        }
+        Some(true)
+    }
+
+    /// Executes at most `max_steps` instructions and returns the stop reply
+    fn resume<M: Memory>(&mut self, emul: &mut Emulator<M>, max_steps: u64) -> io::Result<String> {
+        let mut left = max_steps;
+        let reply = loop {
+            let chunk = left.min(CHUNK_STEPS);
+            left -= chunk;
+            match emul.run_for(chunk) {
+                Ok(RunStatus::Running) if left == 0 => break format!("S{:02x}", SIGTRAP),
+                Ok(RunStatus::Running) => {
+                    if self.conn.interrupt_pending()? {
+                        break format!("S{:02x}", SIGINT);
+                    }
+                }
+                Ok(RunStatus::Break(StopReason::MemWatch(hit))) => {
+                    let kind = match hit.access {
+                        Access::Read => "rwatch",
+                        _ => "watch",
+                    };
+                    break format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr);
+                }
+                Ok(RunStatus::Break(_)) => break format!("S{:02x}", SIGTRAP),
+                Ok(RunStatus::Stopped(ExitStatus::Finished)) => break String::from("W00"),
+                Ok(RunStatus::Stopped(ExitStatus::Exited(code))) => {
+                    break format!("W{:02x}", code as u8)
+                }
+                Ok(RunStatus::Stopped(ExitStatus::BudgetExceeded)) => {
+                    break format!("X{:02x}", SIGXCPU)
+                }
+                Err(err) => break format!("S{:02x}", signal(&err)),
+            }
+        };
+        self.last_stop = reply.clone();
+        Ok(reply)
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` when the connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(None);
+            };
+            match byte {
+                b'$' => {}
+                // Resend the last reply if GDB did not receive it correctly
+                b'-' => {
+                    self.conn.write_all(&self.last_reply)?;
+                    continue;
+                }
+                // Acknowledgements and interrupts while stopped
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.conn.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected != Some(sum(&data)) {
+                self.conn.write_all(b"-")?;
+                continue;
+            }
+            self.conn.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last_reply = format!("${}#{:02x}", data, sum(data.as_bytes())).into_bytes();
+        self.conn.write_all(&self.last_reply)?;
+        self.conn.flush()
+    }
+}
+
+/// Returns the address where GDB sees the instruction at `pc`
+fn code_addr<M: Memory>(emul: &Emulator<M>, pc: i32) -> u32 {
+    (code_base(emul) as u32).wrapping_add((pc as u32).wrapping_mul(4))
+}
+
+/// Returns `pc` for the instruction GDB sees at `addr`, None if `addr` is not aligned
+/// or before the instructions
+fn code_pc<M: Memory>(emul: &Emulator<M>, addr: usize) -> Option<i32> {
+    let offset = addr.checked_sub(code_base(emul))?;
+    match offset % 4 {
+        0 => i32::try_from(offset / 4).ok(),
+        _ => None,
+    }
+}
+
+/// Address of the first instruction, which is right after the memory without a text base
+fn code_base<M: Memory>(emul: &Emulator<M>) -> usize {
+    let end = emul.memory().size().next_multiple_of(4);
+    emul.config().text_base.unwrap_or(end)
+}
+
+/// Reads `len` bytes at `addr`, from the instructions after the memory if there is no
+/// text base
+fn read_memory<M: Memory>(emul: &mut Emulator<M>, addr: usize, len: usize) -> Option<Vec<u8>> {
+    let base = code_base(emul);
+    if emul.config().text_base.is_some() || addr < base {
+        let mut buf = vec![0; len];
+        return emul
+            .memory_mut()
+            .read_bytes(addr, &mut buf)
+            .ok()
+            .map(|_| buf);
+    }
+    let endian = emul.config().endian;
+    let code: Vec<u8> = emul
+        .instructions()
+        .iter()
+        .flat_map(|&inst| endian.word(inst).to_le_bytes())
+        .collect();
+    let start = addr - base;
+    code.get(start..start.checked_add(len)?).map(<[u8]>::to_vec)
+}
+
+/// Describes the registers to GDB
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\"><feature name=\"org.gnu.gdb.simplerisc.core\">",
+    ));
+    for (num, name) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "sp" => "data_ptr",
+            "pc" => "code_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, num
+        ));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Reply to a `qXfer` read of `len` bytes at `offset` in `doc`, `l` marks the last part
+fn xfer(doc: &str, offset: usize, len: usize) -> String {
+    let part = doc.get(offset.min(doc.len())..).unwrap_or_default();
+    match part.len() > len {
+        true => format!("m{}", &part[..len]),
+        false => format!("l{}", part),
+    }
+}
+
+/// Signal reported to GDB when the program fails with `err`
+fn signal(err: &EmulatorErr) -> u8 {
+    match err.kind() {
+        EmulatorErr::InvalidMemAddr
+        | EmulatorErr::UnalignedMemAddr
+        | EmulatorErr::ProtectionFault
+        | EmulatorErr::PageFault => SIGSEGV,
+        EmulatorErr::DivideByZero => SIGFPE,
+        _ => SIGILL,
+    }
+}
+
+/// Modulo 256 sum of the bytes
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses `addr,len` in hex
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex, sum, target_xml, Connection, GdbStub};
+    use crate::{emulator::Emulator, parser::parse_and_assemble};
+    use std::io::{self, Cursor, Read, Write};
+
+    /// Plays back packets sent by GDB and records the replies
+    struct Mock {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Mock {
+        fn interrupt_pending(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    /// Sends `packets` to a stub and returns the replies without the framing
+    fn session(code: &str, packets: &[&str]) -> Vec<String> {
+        let mut input = Vec::new();
+        for p in packets {
+            input.extend(format!("${}#{:02x}+", p, sum(p.as_bytes())).bytes());
+        }
+        let conn = Mock {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let bin = parse_and_assemble(code).unwrap();
+        let mut emul = Emulator::new(&bin);
+        let mut stub = GdbStub::new(conn);
+        stub.run(&mut emul).unwrap();
+
+        let output = String::from_utf8(stub.conn.output).unwrap();
+        let replies = output.split('$').skip(1);
+        replies
+            .map(|r| r.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let code = "mov r1, 0x1234\nst r1, 8[r0]\nadd r2, r1, 1\nmov r0, 3\nsys\n";
+        let replies = session(
+            code,
+            &[
+                "qSupported:xmlRegisters=i386",
+                "?",
+                "s",
+                "p1",
+                "Z2,8,4",
+                "c",
+                "z2,8,4",
+                "m8,4",
+                "M8,2:aabb",
+                "m8,4",
+                "Z0,400c,4",
+                "c",
+                "g",
+                "c",
+            ],
+        );
+        let mut regs = String::from("00000000341200003512");
+        regs.push_str(&"0".repeat(8 * 13 + 4));
+        regs.push_str("0c400000");
+        let expected = [
+            "PacketSize=1000;qXfer:features:read+",
+            "S05",
+            "S05",
+            "34120000",
+            "OK",
+            "T05watch:8;",
+            "OK",
+            "34120000",
+            "OK",
+            "aabb0000",
+            "OK",
+            "S05",
+            &regs,
+            "W34",
+        ];
+        assert_eq!(replies, expected);
+    }
+
+    #[test]
+    fn test_write_registers() {
+        // Zeroes the registers and sets pc to 1, the second instruction after the memory
+        let mut regs = String::from("G");
+        regs.push_str(&"0".repeat(8 * 16));
+        regs.push_str("04400000");
+        let replies = session(
+            "add r2, r1, 1\nadd r2, r2, 1\n",
+            &[
+                "P1=05000000",
+                "s",
+                "p2",
+                &regs,
+                "s",
+                "p2",
+                "P11=00000000",
+                "G00",
+            ],
+        );
+        let expected = [
+            "OK", "S05", "06000000", "OK", "W00", "01000000", "E01", "E01",
+        ];
+        assert_eq!(replies, expected);
+    }
+
+    #[test]
+    fn test_code_addresses() {
+        let code = "mov r1, 1\nadd r1, r1, 1\n";
+        let bin = parse_and_assemble(code).unwrap();
+        let replies = session(
+            code,
+            &[
+                "qXfer:features:read:target.xml:0,d",
+                "qXfer:features:read:target.xml:100,1000",
+                "qXfer:features:read:other.xml:0,100",
+                "m4000,8",
+                "m4004,8",
+                "M4000,4:00000000",
+                "Z0,4002,4",
+                "Z0,4004,4",
+                "c",
+                "p10",
+                "P10=00400000",
+                "p1",
+            ],
+        );
+        let mut code_bytes = hex(&bin[0].to_le_bytes());
+        code_bytes.push_str(&hex(&bin[1].to_le_bytes()));
+        let expected = [
+            "m<?xml version",
+            &format!("l{}", &target_xml()[0x100..]),
+            "E00",
+            &code_bytes,
+            "E01",
+            "E01",
+            "E01",
+            "OK",
+            "S05",
+            "04400000",
+            "OK",
+            "01000000",
+        ];
+        assert_eq!(replies, expected);
+        assert!(target_xml()
+            .contains("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"16\"/>"));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let conn = Mock {
+            input: Cursor::new(b"$g#00$?#3f".to_vec()),
+            output: Vec::new(),
+        };
+        let bin = parse_and_assemble("nop\n").unwrap();
+        let mut emul = Emulator::new(&bin);
+        let mut stub = GdbStub::new(conn);
+        stub.run(&mut emul).unwrap();
+        assert_eq!(stub.conn.output, b"-+$S05#b8");
+    }
+}
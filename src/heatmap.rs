@@ -0,0 +1,159 @@
+//! Counts of the loads and stores made to each address range, see [`Emulator::set_heatmap`].
+//!
+//! The memory is split into buckets of a fixed number of bytes, an access is counted in
+//! the bucket of its first byte. The counts can be shown as a text histogram, to see which
+//! regions are hot and how far apart the accesses are, or exported as CSV or JSON.
+//!
+//! [`Emulator::set_heatmap`]: crate::emulator::Emulator::set_heatmap
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::ops::Range;
+
+/// Accesses to a bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessCounts {
+    pub loads: u64,
+    pub stores: u64,
+}
+
+impl AccessCounts {
+    pub fn total(&self) -> u64 {
+        self.loads + self.stores
+    }
+}
+
+/// Loads and stores counted per bucket of addresses
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    bucket_bytes: usize,
+    /// Counts of the accessed buckets by index
+    buckets: BTreeMap<usize, AccessCounts>,
+}
+
+impl Heatmap {
+    /// Creates a heatmap with buckets of `bucket_bytes` bytes, which must not be 0
+    pub fn new(bucket_bytes: usize) -> Self {
+        assert!(bucket_bytes > 0, "Buckets must not be empty");
+        Self {
+            bucket_bytes,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    pub fn bucket_bytes(&self) -> usize {
+        self.bucket_bytes
+    }
+
+    pub(crate) fn record(&mut self, addr: usize, write: bool) {
+        let counts = self.buckets.entry(addr / self.bucket_bytes).or_default();
+        match write {
+            true => counts.stores += 1,
+            false => counts.loads += 1,
+        }
+    }
+
+    /// Returns the address range and the counts of every accessed bucket, by address
+    pub fn buckets(&self) -> Vec<(Range<usize>, AccessCounts)> {
+        self.buckets
+            .iter()
+            .map(|(&index, &counts)| {
+                let start = index * self.bucket_bytes;
+                (start..start + self.bucket_bytes, counts)
+            })
+            .collect()
+    }
+
+    /// Returns a line per accessed bucket with its counts and a bar of up to `width`
+    /// characters proportional to its accesses
+    pub fn histogram(&self, width: usize) -> String {
+        let max = self.buckets.values().map(|c| c.total()).max().unwrap_or(0);
+        let mut text = format!("{:>23} {:>10} {:>10}\n", "Range", "Loads", "Stores");
+        for (range, counts) in self.buckets() {
+            // Every accessed bucket gets at least one character
+            let bar = (counts.total() as u128 * width as u128).div_ceil(max as u128) as usize;
+            text.push_str(&format!(
+                "{:>#10x}..{:<#10x} {:>10} {:>10} {}\n",
+                range.start,
+                range.end,
+                counts.loads,
+                counts.stores,
+                "#".repeat(bar)
+            ));
+        }
+        text
+    }
+
+    /// Returns the counts as CSV with the header `start,end,loads,stores`
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("start,end,loads,stores\n");
+        for (range, counts) in self.buckets() {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                range.start, range.end, counts.loads, counts.stores
+            ));
+        }
+        csv
+    }
+
+    /// Returns the counts as a JSON array of objects with the fields of the CSV
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .buckets()
+            .iter()
+            .map(|(range, counts)| {
+                format!(
+                    "{{\"start\":{},\"end\":{},\"loads\":{},\"stores\":{}}}",
+                    range.start, range.end, counts.loads, counts.stores
+                )
+            })
+            .collect();
+        format!("[{}]\n", entries.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessCounts, Heatmap};
+
+    #[test]
+    fn test_heatmap() {
+        let mut heatmap = Heatmap::new(16);
+        for addr in [0, 4, 8, 0x40] {
+            heatmap.record(addr, false);
+        }
+        heatmap.record(0x4c, true);
+        heatmap.record(12, true);
+        assert_eq!(
+            heatmap.buckets(),
+            [
+                (
+                    0..16,
+                    AccessCounts {
+                        loads: 3,
+                        stores: 1
+                    }
+                ),
+                (
+                    0x40..0x50,
+                    AccessCounts {
+                        loads: 1,
+                        stores: 1
+                    }
+                ),
+            ]
+        );
+        let histogram = heatmap.histogram(8);
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert!(lines[1].ends_with("3          1 ########"));
+        assert!(lines[2].ends_with("1          1 ####"));
+        assert_eq!(
+            heatmap.to_csv(),
+            "start,end,loads,stores\n0,16,3,1\n64,80,1,1\n"
+        );
+        assert_eq!(
+            heatmap.to_json(),
+            "[{\"start\":0,\"end\":16,\"loads\":3,\"stores\":1},\
+             {\"start\":64,\"end\":80,\"loads\":1,\"stores\":1}]\n"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Undo records which let the emulator step backwards, see [`Emulator::step_back`].
+//!
+//! [`Emulator::step_back`]: crate::emulator::Emulator::step_back
+
+use crate::{csr::CsrFile, debug::CallFrame, emulator::EmulatorErr, memory::Memory};
+use alloc::{vec, vec::Vec};
+use core::num::Wrapping;
+
+/// State before a step, along with the old contents of the memory it overwrote
+pub(crate) struct Undo {
+    pub regs: [Wrapping<i32>; 16],
+    pub regs_hi: [i32; 16],
+    pub prog_cnt: i32,
+    pub flags: (bool, bool),
+    pub csrs: CsrFile,
+    pub exit_code: Option<i32>,
+    pub steps: u64,
+    pub cycles: u64,
+    pub energy: u64,
+    /// Only saved by steps which changed the call stack
+    pub call_stack: Option<Vec<CallFrame>>,
+    /// Address and old bytes of each write, in the order they were made
+    pub writes: Vec<(usize, Vec<u8>)>,
+}
+
+/// A write to the memory made by a step
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MemWrite {
+    pub addr: usize,
+    /// `None` for memory-mapped devices, which are not read as that may have side effects
+    pub old: Option<Vec<u8>>,
+    pub new: Vec<u8>,
+}
+
+/// Memory which logs every write made through it, if it has a log
+pub(crate) struct LoggedMemory<'m> {
+    pub inner: &'m mut dyn Memory,
+    pub log: Option<&'m mut Vec<MemWrite>>,
+}
+
+impl Memory for LoggedMemory<'_> {
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        self.inner.read_bytes(addr, buf)
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        let Some(log) = &mut self.log else {
+            return self.inner.write_bytes(addr, bytes);
+        };
+        let old = match self.inner.is_mmio(addr, bytes.len()) {
+            true => None,
+            false => {
+                let mut old = vec![0; bytes.len()];
+                self.inner.read_bytes(addr, &mut old)?;
+                Some(old)
+            }
+        };
+        self.inner.write_bytes(addr, bytes)?;
+        log.push(MemWrite {
+            addr,
+            old,
+            new: bytes.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn contents(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.inner.contents()
+    }
+
+    fn is_mmio(&self, addr: usize, len: usize) -> bool {
+        self.inner.is_mmio(addr, len)
+    }
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.inner.advance(ticks);
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        self.inner.idle_ticks()
+    }
+
+    fn pending_irqs(&self) -> u32 {
+        self.inner.pending_irqs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoggedMemory, MemWrite};
+    use crate::{
+        bus::Bus,
+        devices::{Timer, TIMER_BASE},
+        memory::{FlatMemory, Memory},
+    };
+
+    #[test]
+    fn test_logged_memory() {
+        let mut memory = FlatMemory::new(16);
+        memory.write_word(4, 0x11223344).unwrap();
+        let mut log = Vec::new();
+        let mut logged = LoggedMemory {
+            inner: &mut memory,
+            log: Some(&mut log),
+        };
+        logged.write_half(4, 0xaabb).unwrap();
+        logged.write_byte(8, 1).unwrap();
+        assert!(logged.write_word(16, 0).is_err());
+        assert_eq!(logged.read_word(4).unwrap(), 0x1122aabb);
+        let write = |addr, old: Option<Vec<u8>>, new: Vec<u8>| MemWrite { addr, old, new };
+        assert_eq!(
+            log,
+            [
+                write(4, Some(vec![0x44, 0x33]), vec![0xbb, 0xaa]),
+                write(8, Some(vec![0]), vec![1])
+            ]
+        );
+
+        // Devices are not read
+        let mut bus = Bus::new(FlatMemory::new(16));
+        bus.map(TIMER_BASE, Box::new(Timer::new()));
+        let mut log = Vec::new();
+        let mut logged = LoggedMemory {
+            inner: &mut bus,
+            log: Some(&mut log),
+        };
+        logged.write_word(TIMER_BASE, 5).unwrap();
+        assert_eq!(log, [write(TIMER_BASE, None, vec![5, 0, 0, 0])]);
+    }
+}
@@ -34,9 +34,29 @@ opcode word_offset(2's complement)
 bbbb   xxxxxxxxxxxxxxxxxxxxxxxxxxx
 opcode dont_care
 ```
+
+Extended:
+The last opcode is shared by the instructions added after the opcodes ran out, which
+are told apart by a function code in the bits the register format does not use.
+They only take registers as operands.
+```text
+5       1   4       4        4        8           6
+11111   0   bbbb    bbbb     bbbb     xxxxxxxx    bbbbbb
+opcode I=0 dst_reg src1_reg  src2_reg dont_care   funct
+```
+Their index in [`INSTRUCTIONS`] is [`EXT_OPCODE`] plus the function code, see
+[`opcode_of`] and [`encode_opcode`].
+
+Extended branches on the flags N, Z, C and V keep their offset between the opcode and
+the function code:
+```text
+5       21                      6
+11111   bbbbbbbbbbbbbbbbbbbbb   bbbbbb
+opcode  word_offset             funct
+```
 */
 
-/**
+/*!
 Extra:
 The system-call instruction.
 sys is a 0-address instruction.
@@ -50,6 +70,29 @@ Information about system calls is documented in the simpleRISC.md file
  */
 
 pub const RET_REG: usize = 15;
+/// Stack pointer by convention, `sp` in the assembly
+pub const SP_REG: usize = 14;
+
+/// Opcode shared by the extended instructions
+pub const EXT_OPCODE: u8 = 31;
+
+/// System call numbers, passed in r0
+pub mod syscalls {
+    pub const GETCHAR: i32 = 0;
+    pub const PUTCHAR: i32 = 1;
+    pub const PRINT_REG: i32 = 2;
+    pub const EXIT: i32 = 3;
+    pub const PRINT_INT: i32 = 4;
+    pub const PRINT_HEX: i32 = 5;
+    pub const READ_INT: i32 = 6;
+    pub const WRITE: i32 = 7;
+    pub const READ: i32 = 8;
+    pub const RANDOM: i32 = 9;
+    pub const TIME_MS: i32 = 10;
+    pub const CYCLES: i32 = 11;
+    pub const TRY_GETCHAR: i32 = 12;
+    pub const INPUT_READY: i32 = 13;
+}
 
 pub mod bits {
     // Offsets of fields
@@ -66,10 +109,16 @@ pub mod bits {
     pub const MOD_BITS: u8 = 2;
     pub const IMM_BITS: u8 = 16;
     pub const OFFSET_BITS: u8 = 27;
+    pub const FUNCT_BITS: u8 = 6;
+    // Offset of the extended branches, right above the function code
+    pub const EXT_OFFSET_OFF: u8 = FUNCT_BITS;
+    pub const EXT_OFFSET_BITS: u8 = 21;
     // Immediate modifier bits(2-bits)
     pub const MOD_DEF: u8 = 0b00;
     pub const MOD_U: u8 = 0b01;
     pub const MOD_H: u8 = 0b10;
+    // Shifts the immediate into bits 32-47, only in 64-bit mode
+    pub const MOD_W: u8 = 0b11;
 }
 
 #[repr(u8)]
@@ -96,6 +145,51 @@ pub enum Opcode {
     B,
     CALL,
     RET,
+    LDB,
+    STB,
+    LDH,
+    STH,
+    EI,
+    DI,
+    RETI,
+    CSRR,
+    CSRW,
+    SYS,
+    // Extended instructions
+    SWAP,
+    CAS,
+    XOR,
+    MULH,
+    MULHU,
+    DIVU,
+    MODU,
+    CMPU,
+    MLA,
+    CLZ,
+    CTZ,
+    POPCNT,
+    SXTB,
+    SXTH,
+    UXTB,
+    UXTH,
+    MIN,
+    MAX,
+    MINU,
+    MAXU,
+    ADDS,
+    SUBS,
+    BNE,
+    BMI,
+    BPL,
+    BVS,
+    BVC,
+    BHS,
+    BLO,
+    BHI,
+    BLS,
+    BGE,
+    BLT,
+    BLE,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -121,7 +215,7 @@ macro_rules! instup {
 
 use Opcode::*;
 // Must be in the same order as in opcodes
-pub const INSTRUCTIONS: [Instruction; 21] = [
+pub const INSTRUCTIONS: [Instruction; 65] = [
     // These Instructions(upto mov) support 'u' & 'h' modifiers
     instup!("add", ADD, 1, 2),
     instup!("sub", SUB, 1, 2),
@@ -146,13 +240,192 @@ pub const INSTRUCTIONS: [Instruction; 21] = [
     instup!("b", B, 0, 1),
     instup!("call", CALL, 0, 1),
     instup!("ret", RET, 0, 0),
+    // Sub-word loads support only the 'u' modifier for zero extension
+    instup!("ldb", LDB, 1, 2),
+    instup!("stb", STB, 1, 2),
+    instup!("ldh", LDH, 1, 2),
+    instup!("sth", STH, 1, 2),
+    // Interrupt control
+    instup!("ei", EI, 0, 0),
+    instup!("di", DI, 0, 0),
+    instup!("reti", RETI, 0, 0),
+    // Source is the CSR number, for csrw the destination register acts as a source
+    instup!("csrr", CSRR, 1, 1),
+    instup!("csrw", CSRW, 1, 1),
+    instup!("sys", SYS, 0, 0),
+    // Atomic memory accesses, the address is in src1 and cas takes the new value in src2
+    instup!("swap", SWAP, 1, 1),
+    instup!("cas", CAS, 1, 2),
+    // Register only ALU operations
+    instup!("xor", XOR, 1, 2),
+    // Upper 32 bits of the 64-bit product of signed and unsigned operands
+    instup!("mulh", MULH, 1, 2),
+    instup!("mulhu", MULHU, 1, 2),
+    // Treat the operands as unsigned numbers
+    instup!("divu", DIVU, 1, 2),
+    instup!("modu", MODU, 1, 2),
+    instup!("cmpu", CMPU, 0, 2),
+    // Multiply-accumulate, the destination register is also a source
+    instup!("mla", MLA, 1, 2),
+    // Bit counts of the source, 32 for clz and ctz of 0
+    instup!("clz", CLZ, 1, 1),
+    instup!("ctz", CTZ, 1, 1),
+    instup!("popcnt", POPCNT, 1, 1),
+    // Sign or zero extend the lower byte or halfword of the source
+    instup!("sxtb", SXTB, 1, 1),
+    instup!("sxth", SXTH, 1, 1),
+    instup!("uxtb", UXTB, 1, 1),
+    instup!("uxth", UXTH, 1, 1),
+    // Smaller or larger of the sources as signed and unsigned numbers
+    instup!("min", MIN, 1, 2),
+    instup!("max", MAX, 1, 2),
+    instup!("minu", MINU, 1, 2),
+    instup!("maxu", MAXU, 1, 2),
+    // Also set the flags N, Z, C and V in the FLAGS CSR
+    instup!("adds", ADDS, 1, 2),
+    instup!("subs", SUBS, 1, 2),
+    // Branches on the flags N, Z, C and V, named like the conditions of ARM
+    instup!("bne", BNE, 0, 1),
+    instup!("bmi", BMI, 0, 1),
+    instup!("bpl", BPL, 0, 1),
+    instup!("bvs", BVS, 0, 1),
+    instup!("bvc", BVC, 0, 1),
+    instup!("bhs", BHS, 0, 1),
+    instup!("blo", BLO, 0, 1),
+    instup!("bhi", BHI, 0, 1),
+    instup!("bls", BLS, 0, 1),
+    instup!("bge", BGE, 0, 1),
+    instup!("blt", BLT, 0, 1),
+    instup!("ble", BLE, 0, 1),
 ];
 
+/// Returns the index in [`INSTRUCTIONS`] of the instruction encoded in `inst`,
+/// which is out of bounds for invalid words
+pub fn opcode_of(inst: u32) -> u8 {
+    let opcode = (inst >> bits::OPCODE_OFF) as u8;
+    if opcode == EXT_OPCODE {
+        EXT_OPCODE + (inst & ((1 << bits::FUNCT_BITS) - 1)) as u8
+    } else {
+        opcode
+    }
+}
+
+/// Returns the instruction at `opcode` in [`INSTRUCTIONS`], `None` for invalid opcodes
+/// like the ones returned by [`opcode_of`] for words which are not instructions
+pub fn instruction(opcode: u8) -> Option<&'static Instruction> {
+    INSTRUCTIONS.get(opcode as usize)
+}
+
+/// Returns the bits encoding the instruction at `opcode` in [`INSTRUCTIONS`],
+/// the inverse of [`opcode_of`]
+pub fn encode_opcode(opcode: u8) -> u32 {
+    if opcode < EXT_OPCODE {
+        (opcode as u32) << bits::OPCODE_OFF
+    } else {
+        (EXT_OPCODE as u32) << bits::OPCODE_OFF | (opcode - EXT_OPCODE) as u32
+    }
+}
+
+/// Returns the sign extended word offset of `inst`, a branch to a label
+pub fn branch_offset(inst: u32) -> i32 {
+    let (off, width) = match inst >> bits::OPCODE_OFF == EXT_OPCODE as u32 {
+        true => (bits::EXT_OFFSET_OFF, bits::EXT_OFFSET_BITS),
+        false => (0, bits::OFFSET_BITS),
+    };
+    (((inst >> off) << (32 - width)) as i32) >> (32 - width)
+}
+
+/// Returns the bits encoding the branch at `opcode` in [`INSTRUCTIONS`] to a label
+/// `offset` words away, `None` if the offset does not fit
+pub fn encode_branch(opcode: u8, offset: i32) -> Option<u32> {
+    let (off, width) = match opcode < EXT_OPCODE {
+        true => (0, bits::OFFSET_BITS),
+        false => (bits::EXT_OFFSET_OFF, bits::EXT_OFFSET_BITS),
+    };
+    let limit = 1 << (width - 1);
+    (-limit..limit)
+        .contains(&offset)
+        .then(|| encode_opcode(opcode) | ((offset as u32) & (!0u32 >> (32 - width))) << off)
+}
+
 pub fn supports_mod(opcode: u8) -> bool {
     opcode <= MOV as u8
 }
 
+/// Loads of less than a word which use the 'u' modifier to zero extend
+/// the loaded value instead of the immediate
+pub fn is_subword_load(opcode: u8) -> bool {
+    opcode == LDB as u8 || opcode == LDH as u8
+}
+
+/// Load and store instructions which use the `imm[reg]` operand format
+pub fn is_mem_access(opcode: u8) -> bool {
+    matches!(
+        instruction(opcode).map(|ins| ins.opcode),
+        Some(LD | ST | LDB | STB | LDH | STH)
+    )
+}
+
+/// Atomic instructions which use the `[reg]` operand format
+pub fn is_atomic(opcode: u8) -> bool {
+    matches!(instruction(opcode).map(|ins| ins.opcode), Some(SWAP | CAS))
+}
+
+/// Instructions which read or write the data memory
+pub fn accesses_memory(opcode: u8) -> bool {
+    is_mem_access(opcode) || is_atomic(opcode)
+}
+
+/// Instructions which may change `pc` to something other than the next instruction
+pub fn is_branch(opcode: u8) -> bool {
+    matches!(
+        instruction(opcode).map(|ins| ins.opcode),
+        Some(BEQ | BGT | B | CALL | RET | RETI)
+    ) || is_nzcv_branch(opcode)
+}
+
+/// Conditional branches on the flags N, Z, C and V of the `FLAGS` CSR
+pub fn is_nzcv_branch(opcode: u8) -> bool {
+    (BNE as u8..=BLE as u8).contains(&opcode)
+}
+
+/// Instructions which can only be executed in supervisor mode
+pub fn is_privileged(opcode: u8) -> bool {
+    matches!(
+        instruction(opcode).map(|ins| ins.opcode),
+        Some(EI | DI | RETI | CSRR | CSRW)
+    )
+}
+
 pub fn supports_imm(opcode: u8) -> bool {
-    let ins = INSTRUCTIONS[opcode as usize];
-    ins.ndst + ins.nsrc >= 2
+    instruction(opcode).is_some_and(|ins| opcode < EXT_OPCODE && ins.ndst + ins.nsrc >= 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_opcodes() {
+        let invalid = EXT_OPCODE + 63;
+        assert_eq!(opcode_of(encode_opcode(EXT_OPCODE) | 63), invalid);
+        assert_eq!(instruction(invalid), None);
+        assert_eq!(instruction(ADD as u8).map(|ins| ins.name), Some("add"));
+        for opcode in [invalid, u8::MAX] {
+            assert!(!supports_imm(opcode));
+            assert!(!is_mem_access(opcode) && !is_atomic(opcode) && !accesses_memory(opcode));
+            assert!(!is_branch(opcode) && !is_privileged(opcode));
+        }
+    }
+
+    #[test]
+    fn test_branch_offsets() {
+        for (opcode, offset) in [(B, -1), (BEQ, (1 << 26) - 1), (BLO, -(1 << 20)), (BNE, 5)] {
+            let inst = encode_branch(opcode as u8, offset).unwrap();
+            assert_eq!(opcode_of(inst), opcode as u8);
+            assert_eq!(branch_offset(inst), offset);
+        }
+        assert_eq!(encode_branch(BNE as u8, 1 << 20), None);
+        assert_eq!(encode_branch(B as u8, -(1 << 26) - 1), None);
+    }
 }
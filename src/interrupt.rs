@@ -0,0 +1,52 @@
+//! Interrupt controller.
+//!
+//! Devices request interrupts on numbered lines, see [`Bus::map_irq`](crate::bus::Bus::map_irq).
+//! Before executing an instruction the emulator checks for pending lines, if interrupts are
+//! enabled the lowest numbered pending line is delivered:
+//! the handler is entered as described in [`CsrFile::enter`](crate::csr::CsrFile::enter)
+//! and execution continues at the `pc` stored in the vector table entry of the line.
+//! `reti` restores the saved state and returns to the interrupted instruction.
+//!
+//! The vector table is an array of words at [`VECTOR_TABLE`] in memory,
+//! entry 0 is reserved for traps and entry `n + 1` holds the handler for line `n`.
+//! The data section starts after it.
+
+use crate::program::DATA_BASE;
+
+/// Memory address of the vector table
+pub const VECTOR_TABLE: usize = 0;
+/// Address of the vector table entry for traps
+pub const TRAP_VECTOR: usize = VECTOR_TABLE;
+/// Number of interrupt lines
+pub const IRQ_LINES: u8 = 16;
+/// Size of the vector table in bytes, it ends before [`DATA_BASE`]
+pub const VECTOR_TABLE_SIZE: usize = 4 * (1 + IRQ_LINES as usize);
+
+const _: () = assert!(VECTOR_TABLE + VECTOR_TABLE_SIZE <= DATA_BASE);
+
+/// Returns the address of the vector table entry for interrupt `line`
+pub fn irq_vector(line: u8) -> usize {
+    VECTOR_TABLE + 4 * (1 + line as usize)
+}
+
+/// Returns the line which should be delivered given the bitmask of `pending` lines
+pub fn select(enabled: bool, pending: u32) -> Option<u8> {
+    if enabled && pending != 0 {
+        Some(pending.trailing_zeros() as u8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{irq_vector, select};
+
+    #[test]
+    fn test_select() {
+        assert_eq!(select(false, 0b110), None);
+        assert_eq!(select(true, 0), None);
+        assert_eq!(select(true, 0b110), Some(1));
+        assert_eq!(irq_vector(1), 8);
+    }
+}
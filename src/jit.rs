@@ -0,0 +1,516 @@
+//! Native code for the compiled blocks of the emulator, with the `jit` feature.
+//!
+//! Blocks are translated to x86-64 machine code when they are compiled, see
+//! [`Jit::compile`]. The code works on the register file and on [`Flags`] in place.
+//! Loads and stores call back into the emulator, and the code returns to the
+//! interpreter when they cannot be done directly in the memory, like for MMIO or
+//! with the MMU enabled. Blocks with an operation missing here are left to the
+//! interpreter, as are all blocks on other targets than x86-64 Linux.
+//!
+//! A block is a function `fn(regs, flags, emulator) -> u64` with the System V calling
+//! convention. It returns the next `pc`, or [`Jit::DEOPT`] plus the index of the load
+//! or store which could not be done, which is left for the interpreter.
+
+use crate::{
+    csr,
+    emulator::{BlockOp, Src},
+    info::Opcode,
+};
+use alloc::vec::Vec;
+
+/// Native code of a block, see the [module](self) documentation
+pub(crate) type Code = unsafe extern "sysv64" fn(*mut i32, *mut Flags, *mut u8) -> u64;
+
+/// The flags E and GT and the bits of `FLAGS`, one byte each as written by `setcc`
+#[repr(C)]
+pub(crate) struct Flags {
+    e: bool,
+    g: bool,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+}
+
+impl Flags {
+    pub(crate) fn new(e: bool, g: bool, nzcv: u32) -> Self {
+        Self {
+            e,
+            g,
+            n: nzcv & csr::FLAG_N != 0,
+            z: nzcv & csr::FLAG_Z != 0,
+            c: nzcv & csr::FLAG_C != 0,
+            v: nzcv & csr::FLAG_V != 0,
+        }
+    }
+
+    /// Returns the flags E and GT and the `FLAGS` CSR
+    pub(crate) fn unpack(&self) -> (bool, bool, u32) {
+        let nzcv = [
+            (self.n, csr::FLAG_N),
+            (self.z, csr::FLAG_Z),
+            (self.c, csr::FLAG_C),
+            (self.v, csr::FLAG_V),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        (self.e, self.g, nzcv)
+    }
+}
+
+/// Addresses of the functions called for loads and stores, which do them like
+/// `Emulator::load_direct` and `Emulator::store_direct`:
+/// `load(emulator, addr, size) -> value | 1 << 32 or 0 if not done` and
+/// `store(emulator, addr, size, value) -> 1 or 0 if not done`
+#[derive(Clone, Copy)]
+pub(crate) struct Helpers {
+    pub(crate) load: usize,
+    pub(crate) store: usize,
+}
+
+/// Generates and owns the native code of blocks
+#[derive(Default)]
+pub(crate) struct Jit {
+    /// No code is generated without them
+    helpers: Option<Helpers>,
+    chunks: Vec<exec::Chunk>,
+}
+
+impl Jit {
+    /// Added to the index of the instruction returned to the interpreter
+    pub(crate) const DEOPT: u64 = 1 << 32;
+
+    pub(crate) fn new(helpers: Helpers) -> Self {
+        Self {
+            helpers: Some(helpers),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Returns the native code of the block made of `ops`, which continues at `next_pc`
+    /// when its branch is not taken. None if the block has to be interpreted.
+    pub(crate) fn compile(&mut self, ops: &[BlockOp], next_pc: i32) -> Option<Code> {
+        let code = translate(ops, next_pc, self.helpers?)?;
+        if !self.chunks.last().is_some_and(|chunk| chunk.fits(&code)) {
+            self.chunks.push(exec::Chunk::new()?);
+        }
+        self.chunks.last_mut()?.add(&code)
+    }
+}
+
+// Registers, by their number in the ModRM byte
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+// Condition codes of jcc, setcc and cmovcc
+const CC_O: u8 = 0x0;
+const CC_NO: u8 = 0x1;
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_A: u8 = 0x7;
+const CC_S: u8 = 0x8;
+const CC_NS: u8 = 0x9;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+const CC_LE: u8 = 0xe;
+const CC_G: u8 = 0xf;
+
+/// Offsets of the fields of [`Flags`] with the condition setting them after `cmp`.
+/// C is set when there is no borrow, unlike the carry flag of x86.
+const SET_FLAGS: [(u8, u8); 6] = [
+    (0, CC_E),
+    (1, CC_G),
+    (2, CC_S),
+    (3, CC_E),
+    (4, CC_AE),
+    (5, CC_O),
+];
+
+/// Returns the condition code under which `cond`, a branch opcode, is taken after `cmp`
+fn condition(cond: Opcode) -> Option<u8> {
+    use Opcode::*;
+
+    Some(match cond {
+        BEQ => CC_E,
+        BGT => CC_G,
+        BNE => CC_NE,
+        BMI => CC_S,
+        BPL => CC_NS,
+        BVS => CC_O,
+        BVC => CC_NO,
+        BHS => CC_AE,
+        BLO => CC_B,
+        BHI => CC_A,
+        BLS => CC_BE,
+        BGE => CC_GE,
+        BLT => CC_L,
+        BLE => CC_LE,
+        _ => return None,
+    })
+}
+
+/// Machine code being generated. The registers of the program are at `[rbx]`, the
+/// flags at `[r12]` and the emulator is in `r13`. The others are scratch registers.
+struct Asm {
+    code: Vec<u8>,
+}
+
+impl Asm {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: i32) {
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Offset of the register `reg` of the program from `rbx`
+    fn disp(reg: u8) -> u8 {
+        4 * (reg % 16)
+    }
+
+    /// `mov x, [rbx + disp]`
+    fn load(&mut self, x: u8, reg: u8) {
+        self.emit(&[0x8b, 0x43 | x << 3, Self::disp(reg)]);
+    }
+
+    /// `mov [rbx + disp], x`
+    fn store(&mut self, x: u8, reg: u8) {
+        self.emit(&[0x89, 0x43 | x << 3, Self::disp(reg)]);
+    }
+
+    /// `mov x, imm32`
+    fn mov_imm(&mut self, x: u8, imm: i32) {
+        self.emit(&[0xb8 + x]);
+        self.imm32(imm);
+    }
+
+    /// Applies the ALU operation with the opcode `op` for a memory operand and
+    /// the extension `ext` of opcode 0x81 for an immediate to `eax` and `src`
+    fn arith(&mut self, op: u8, ext: u8, src: Src) {
+        match src {
+            Src::Reg(reg) => self.emit(&[op, 0x43, Self::disp(reg)]),
+            Src::Imm(imm) => {
+                self.emit(&[0x81, 0xc0 | ext << 3]);
+                self.imm32(imm);
+            }
+        }
+    }
+
+    /// Shifts `eax` by `src` with the extension `ext` of the shift opcodes.
+    /// Like simpleRISC, x86 only uses the lower 5 bits of the amount.
+    fn shift(&mut self, ext: u8, src: Src) {
+        match src {
+            Src::Reg(reg) => {
+                self.load(ECX, reg);
+                self.emit(&[0xd3, 0xc0 | ext << 3]);
+            }
+            Src::Imm(imm) => self.emit(&[0xc1, 0xc0 | ext << 3, imm as u8 & 0b11111]),
+        }
+    }
+
+    /// Compares the registers `src1` and `src2` and sets all of the flags
+    fn cmp(&mut self, src1: u8, src2: Src) {
+        self.load(EAX, src1);
+        self.arith(0x3b, 7, src2);
+        for (offset, cc) in SET_FLAGS {
+            // setcc [r12 + offset]
+            self.emit(&[0x41, 0x0f, 0x90 + cc, 0x44, 0x24, offset]);
+        }
+    }
+
+    /// Sets `eax` to `target` under the condition `cc`, or to `next_pc`
+    fn select(&mut self, cc: u8, target: i32, next_pc: i32) {
+        self.mov_imm(EAX, next_pc);
+        self.mov_imm(EDX, target);
+        // cmovcc eax, edx
+        self.emit(&[0x0f, 0x40 + cc, 0xc2]);
+    }
+
+    /// `mov rax, helper; call rax` with the emulator as the first argument
+    fn call(&mut self, helper: usize) {
+        // mov rdi, r13
+        self.emit(&[0x4c, 0x89, 0xef, 0x48, 0xb8]);
+        self.emit(&(helper as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    /// Returns [`Jit::DEOPT`] plus `at`, 16 bytes
+    fn deopt(&mut self, at: u16) {
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&(Jit::DEOPT | at as u64).to_le_bytes());
+        self.ret();
+    }
+
+    /// Restores the callee-saved registers and returns `rax`
+    fn ret(&mut self) {
+        // pop r13; pop r12; pop rbx; ret
+        self.emit(&[0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+    }
+}
+
+/// Translates the operations of a block, None if one of them is not supported
+fn translate(ops: &[BlockOp], next_pc: i32, helpers: Helpers) -> Option<Vec<u8>> {
+    let mut asm = Asm { code: Vec::new() };
+    // push rbx; push r12; push r13, which also aligns the stack for calls
+    asm.emit(&[0x53, 0x41, 0x54, 0x41, 0x55]);
+    // mov rbx, rdi; mov r12, rsi; mov r13, rdx
+    asm.emit(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4, 0x49, 0x89, 0xd5]);
+    let mut branched = false;
+    for op in ops {
+        match *op {
+            BlockOp::Add { dst, src1, src2 } => {
+                asm.load(EAX, src1);
+                asm.arith(0x03, 0, src2);
+                asm.store(EAX, dst);
+            }
+            BlockOp::Sub { dst, src1, src2 } => {
+                asm.load(EAX, src1);
+                asm.arith(0x2b, 5, src2);
+                asm.store(EAX, dst);
+            }
+            BlockOp::And { dst, src1, src2 } => {
+                asm.load(EAX, src1);
+                asm.arith(0x23, 4, src2);
+                asm.store(EAX, dst);
+            }
+            BlockOp::Or { dst, src1, src2 } => {
+                asm.load(EAX, src1);
+                asm.arith(0x0b, 1, src2);
+                asm.store(EAX, dst);
+            }
+            BlockOp::Xor { dst, src1, src2 } => {
+                asm.load(EAX, src1);
+                asm.arith(0x33, 6, src2);
+                asm.store(EAX, dst);
+            }
+            BlockOp::Mov { dst, src2 } => {
+                match src2 {
+                    Src::Reg(reg) => asm.load(EAX, reg),
+                    Src::Imm(imm) => asm.mov_imm(EAX, imm),
+                }
+                asm.store(EAX, dst);
+            }
+            BlockOp::Alu {
+                opcode,
+                dst,
+                src1,
+                src2,
+            } => {
+                asm.load(EAX, src1);
+                match (opcode, src2) {
+                    // imul eax, [rbx + disp]
+                    (Opcode::MUL, Src::Reg(reg)) => asm.emit(&[0x0f, 0xaf, 0x43, Asm::disp(reg)]),
+                    // imul eax, eax, imm32
+                    (Opcode::MUL, Src::Imm(imm)) => {
+                        asm.emit(&[0x69, 0xc0]);
+                        asm.imm32(imm);
+                    }
+                    (Opcode::LSL, _) => asm.shift(4, src2),
+                    (Opcode::LSR, _) => asm.shift(5, src2),
+                    (Opcode::ASR, _) => asm.shift(7, src2),
+                    _ => return None,
+                }
+                asm.store(EAX, dst);
+            }
+            BlockOp::Load {
+                size,
+                zero_ext,
+                dst,
+                base,
+                offset,
+                at,
+            } => {
+                asm.load(EAX, base);
+                asm.arith(0x03, 0, offset);
+                // mov esi, eax; mov edx, size
+                asm.emit(&[0x89, 0xc6]);
+                asm.mov_imm(EDX, size as i32);
+                asm.call(helpers.load);
+                // bt rax, 32; jc over the deopt
+                asm.emit(&[0x48, 0x0f, 0xba, 0xe0, 0x20, 0x72, 0x10]);
+                asm.deopt(at);
+                match (size, zero_ext) {
+                    // movsx eax, al
+                    (1, false) => asm.emit(&[0x0f, 0xbe, 0xc0]),
+                    // movsx eax, ax
+                    (2, false) => asm.emit(&[0x0f, 0xbf, 0xc0]),
+                    _ => {}
+                }
+                asm.store(EAX, dst);
+            }
+            BlockOp::Store {
+                size,
+                src,
+                base,
+                offset,
+                at,
+            } => {
+                asm.load(EAX, base);
+                asm.arith(0x03, 0, offset);
+                // mov esi, eax
+                asm.emit(&[0x89, 0xc6]);
+                asm.load(ECX, src);
+                asm.mov_imm(EDX, size as i32);
+                asm.call(helpers.store);
+                // test eax, eax; jnz over the deopt
+                asm.emit(&[0x85, 0xc0, 0x75, 0x10]);
+                asm.deopt(at);
+            }
+            BlockOp::Cmp { src1, src2 } => asm.cmp(src1, src2),
+            BlockOp::Branch {
+                cond: Opcode::B,
+                target,
+            } => {
+                asm.mov_imm(EAX, target);
+                branched = true;
+            }
+            BlockOp::Branch { cond, target } => {
+                let offset = match cond {
+                    Opcode::BEQ => 0,
+                    Opcode::BGT => 1,
+                    // Left to the interpreter as the flags would have to be combined
+                    _ => return None,
+                };
+                // cmp byte [r12 + offset], 0
+                asm.emit(&[0x41, 0x80, 0x7c, 0x24, offset, 0]);
+                asm.select(CC_NE, target, next_pc);
+                branched = true;
+            }
+            BlockOp::CmpBranch {
+                src1,
+                src2,
+                cond,
+                target,
+            } => {
+                asm.cmp(src1, src2);
+                match cond {
+                    Opcode::B => asm.mov_imm(EAX, target),
+                    _ => asm.select(condition(cond)?, target, next_pc),
+                }
+                branched = true;
+            }
+        }
+    }
+    if !branched {
+        asm.mov_imm(EAX, next_pc);
+    }
+    asm.ret();
+    Some(asm.code)
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod exec {
+    use super::Code;
+    use core::{ffi::c_void, ptr};
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+    const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    /// Executable memory holding the code of many blocks, writable only while
+    /// code is added
+    pub(crate) struct Chunk {
+        ptr: *mut u8,
+        used: usize,
+    }
+
+    // The memory is only reached through the chunk, or run
+    unsafe impl Send for Chunk {}
+    unsafe impl Sync for Chunk {}
+
+    impl Chunk {
+        const SIZE: usize = 64 * 1024;
+
+        pub(crate) fn new() -> Option<Self> {
+            // SAFETY: maps new memory, without touching any other
+            let ptr = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    Self::SIZE,
+                    PROT_READ | PROT_EXEC,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            (ptr != MAP_FAILED).then_some(Self {
+                ptr: ptr.cast(),
+                used: 0,
+            })
+        }
+
+        pub(crate) fn fits(&self, code: &[u8]) -> bool {
+            self.used + code.len() <= Self::SIZE
+        }
+
+        /// Copies `code` after the code added before, it has to [fit](Self::fits)
+        pub(crate) fn add(&mut self, code: &[u8]) -> Option<Code> {
+            let base = self.ptr.cast::<c_void>();
+            // SAFETY: the chunk is mapped, and no code runs from it while it is writable.
+            // The code is a function of the type Code, see translate.
+            unsafe {
+                if mprotect(base, Self::SIZE, PROT_READ | PROT_WRITE) != 0 {
+                    return None;
+                }
+                let start = self.ptr.add(self.used);
+                ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+                if mprotect(base, Self::SIZE, PROT_READ | PROT_EXEC) != 0 {
+                    return None;
+                }
+                self.used += code.len();
+                Some(core::mem::transmute::<*mut u8, Code>(start))
+            }
+        }
+    }
+
+    impl Drop for Chunk {
+        fn drop(&mut self) {
+            // SAFETY: mapped by new, the blocks running from it are dropped with it
+            unsafe { munmap(self.ptr.cast(), Self::SIZE) };
+        }
+    }
+}
+
+/// No native code on other targets
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod exec {
+    use super::Code;
+
+    pub(crate) struct Chunk;
+
+    impl Chunk {
+        pub(crate) fn new() -> Option<Self> {
+            None
+        }
+
+        pub(crate) fn fits(&self, _code: &[u8]) -> bool {
+            false
+        }
+
+        pub(crate) fn add(&mut self, _code: &[u8]) -> Option<Code> {
+            None
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Minimal JSON reader for the documents written by the crate, like [`State::to_json`].
+//! Numbers are limited to integers as those documents have no others.
+//!
+//! [`State::to_json`]: crate::state::State::to_json
+
+use alloc::{format, string::String, vec::Vec};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Str(String),
+    Array(Vec<Value>),
+    /// Members in the order they were written
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Returns the member `key` of an object
+    pub(crate) fn field(&self, key: &str) -> Result<&Value, String> {
+        match self {
+            Self::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+        .ok_or_else(|| format!("Missing field '{}'", key))
+    }
+
+    /// Returns the member `key` of an object, which has to be an integer fitting in `T`
+    pub(crate) fn int<T: TryFrom<i128>>(&self, key: &str) -> Result<T, String> {
+        self.field(key)?
+            .as_int()
+            .ok_or_else(|| format!("Invalid field '{}'", key))
+    }
+
+    pub(crate) fn as_int<T: TryFrom<i128>>(&self) -> Option<T> {
+        match self {
+            Self::Int(n) => T::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a whole document, which holds a single value
+pub(crate) fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_space();
+    match parser.pos == text.len() {
+        true => Ok(value),
+        false => Err(parser.error()),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    /// Byte offset of the next character
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> String {
+        format!("Invalid JSON at byte {}", self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips `byte` and the space before it, returns false if it is not next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        let found = self.peek() == Some(byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        match self.eat(byte) {
+            true => Ok(()),
+            false => Err(self.error()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'n') => self.keyword("null", Value::Null),
+            Some(b't') => self.keyword("true", Value::Bool(true)),
+            Some(b'f') => self.keyword("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::Str),
+            Some(b'-' | b'0'..=b'9') => self.int(),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_space();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        members.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Object(members))
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        match self.text[self.pos..].starts_with(word) {
+            true => {
+                self.pos += word.len();
+                Ok(value)
+            }
+            false => Err(self.error()),
+        }
+    }
+
+    fn int(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        self.pos += (self.peek() == Some(b'-')) as usize;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        self.text[start..self.pos]
+            .parse()
+            .map(Value::Int)
+            .map_err(|_| self.error())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error());
+        }
+        let mut out = String::new();
+        let mut chars = self.text[self.pos + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 2;
+                    return Ok(out);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error())?
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        _ => return Err(self.error()),
+                    };
+                    out.push(escaped);
+                }
+                c => out.push(c),
+            }
+        }
+        Err(self.error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Value};
+
+    #[test]
+    fn test_parse() {
+        let value = parse(" {\"a\": [1, -20, null], \"b\" :true,\"c\":\"x\\\"\\u0041\"} ").unwrap();
+        let items = value.field("a").unwrap().as_array().unwrap();
+        assert_eq!(items[1].as_int::<i32>(), Some(-20));
+        assert_eq!(items[2], Value::Null);
+        assert_eq!(value.field("b").unwrap().as_bool(), Some(true));
+        assert_eq!(value.field("c").unwrap().as_str(), Some("x\"A"));
+        assert_eq!(value.int::<u8>("a"), Err("Invalid field 'a'".to_string()));
+        assert_eq!(value.int::<u8>("d"), Err("Missing field 'd'".to_string()));
+        assert_eq!(parse("[]"), Ok(Value::Array(vec![])));
+        assert_eq!(parse("{}"), Ok(Value::Object(vec![])));
+
+        assert_eq!(parse("[1,]"), Err("Invalid JSON at byte 3".to_string()));
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("\"abc").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("-").is_err());
+        assert!(parse("nul").is_err());
+    }
+}
@@ -1,3 +1,67 @@
+//! Assembler, emulator and debugger for the simpleRISC ISA.
+//!
+//! Without the default `std` feature the crate is `no_std` and only needs `alloc`. The
+//! emulator core is left, running on a [`memory::FlatMemory`] or [`memory::PagedMemory`]
+//! with the system calls of a custom [`syscall::SyscallHandler`]. The assembler, the
+//! default system calls, devices, traces, replay, the debugger and pipeline diagrams
+//! need `std` for their I/O.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod bus;
+pub mod cache;
+pub mod cost;
+pub mod csr;
+pub mod debug;
+#[cfg(feature = "std")]
+pub mod debugger;
+#[cfg(feature = "std")]
+pub mod devices;
+pub mod disasm;
 pub mod emulator;
+#[cfg(feature = "std")]
+pub mod error;
+pub mod expr;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod gdb;
+pub mod heatmap;
+mod history;
 pub mod info;
+pub mod interrupt;
+#[cfg(feature = "jit")]
+mod jit;
+mod json;
+#[cfg(feature = "std")]
+pub mod lockstep;
+pub mod memcheck;
+pub mod memory;
+pub mod mmu;
+#[cfg(feature = "std")]
+pub mod multicore;
+#[cfg(feature = "std")]
 pub mod parser;
+pub mod pipeline;
+pub mod profile;
+pub mod program;
+pub mod regstats;
+#[cfg(feature = "std")]
+pub mod replay;
+pub mod rng;
+pub mod state;
+pub mod syscall;
+mod textfile;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod writelog;
+
+#[cfg(feature = "std")]
+pub use error::Error;
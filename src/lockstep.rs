@@ -0,0 +1,296 @@
+//! Runs two emulators in lockstep and compares them after each instruction, or after each
+//! compiled block of the left one, to check a faster way of executing programs against
+//! the simple interpreter.
+//!
+//! Both emulators should be created from the same program and configuration. After every
+//! step their outcome, registers, flags and number of executed instructions must match,
+//! and once they stop so must their memory. The first difference is reported as a
+//! [`Divergence`].
+
+use crate::{
+    emulator::{Emulator, EmulatorErr, RunStatus},
+    memory::Memory,
+    state::MachineState,
+};
+use std::fmt;
+
+/// First difference found between the two emulators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Instructions executed by both before the instruction(or block) which diverged
+    pub step: u64,
+    /// Index of the instruction which diverged, or the first of its block
+    pub pc: i32,
+    /// What the left emulator has where they differ
+    pub left: String,
+    /// What the right emulator has where they differ
+    pub right: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Emulators diverged at step {} executing {}: {} vs {}",
+            self.step, self.pc, self.left, self.right
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum LockstepErr {
+    /// Both emulators failed in the same way
+    Fault(EmulatorErr),
+    Diverged(Divergence),
+}
+
+impl fmt::Display for LockstepErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fault(err) => write!(f, "{}", err),
+            Self::Diverged(divergence) => write!(f, "{}", divergence),
+        }
+    }
+}
+
+impl std::error::Error for LockstepErr {}
+
+/// Executes at most `max_steps` instructions on both emulators like [`Emulator::run_for`]
+pub fn run_lockstep<A: Memory, B: Memory>(
+    left: &mut Emulator<A>,
+    right: &mut Emulator<B>,
+    max_steps: u64,
+) -> Result<RunStatus, LockstepErr> {
+    lockstep(left, right, max_steps, Emulator::step)
+}
+
+/// Like [`run_lockstep`], but runs the left emulator a compiled block at a time with
+/// [`Emulator::run_block`] and steps the right one through the same instructions,
+/// so that they are compared at the block boundaries.
+pub fn run_lockstep_blocks<A: Memory, B: Memory>(
+    left: &mut Emulator<A>,
+    right: &mut Emulator<B>,
+    max_steps: u64,
+) -> Result<RunStatus, LockstepErr> {
+    lockstep(left, right, max_steps, Emulator::run_block)
+}
+
+/// Advances the left emulator with `advance`, then steps the right one until it executed as
+/// many instructions, or once more if the left one failed
+fn lockstep<A: Memory, B: Memory>(
+    left: &mut Emulator<A>,
+    right: &mut Emulator<B>,
+    max_steps: u64,
+    advance: fn(&mut Emulator<A>) -> Result<RunStatus, EmulatorErr>,
+) -> Result<RunStatus, LockstepErr> {
+    let mut executed = 0;
+    while executed < max_steps {
+        let (step, pc) = (left.steps(), left.pc());
+        let diverged = |left: String, right: String| {
+            LockstepErr::Diverged(Divergence {
+                step,
+                pc,
+                left,
+                right,
+            })
+        };
+        let a = advance(left);
+        let mut b = right.step();
+        while b.as_ref().is_ok_and(|status| *status == RunStatus::Running)
+            && (right.steps() < left.steps() || a.is_err() && right.steps() == left.steps())
+        {
+            b = right.step();
+        }
+        let status = match (a, b) {
+            (Ok(a), Ok(b)) if a == b => a,
+            (Err(a), Err(b)) if a.to_string() == b.to_string() => {
+                return Err(LockstepErr::Fault(a))
+            }
+            (a, b) => return Err(diverged(outcome(a), outcome(b))),
+        };
+        let (a, b) = (left.machine_state(), right.machine_state());
+        if a != b {
+            let (a, b) = differences(&a, &b);
+            return Err(diverged(a, b));
+        }
+        if left.steps() != right.steps() {
+            return Err(diverged(
+                format!("{} steps", left.steps()),
+                format!("{} steps", right.steps()),
+            ));
+        }
+        if status != RunStatus::Running {
+            let (a, b) = (left.memory_mut().contents(), right.memory_mut().contents());
+            if let Some(addr) = first_difference(&a, &b) {
+                let byte = |contents| format!("[0x{:x}] = {}", addr, byte_at(contents, addr));
+                return Err(diverged(byte(&a), byte(&b)));
+            }
+            return Ok(status);
+        }
+        executed += (left.steps() - step).max(1);
+    }
+    Ok(RunStatus::Running)
+}
+
+fn outcome(result: Result<RunStatus, EmulatorErr>) -> String {
+    match result {
+        Ok(status) => format!("{:?}", status),
+        Err(err) => err.to_string(),
+    }
+}
+
+/// Describes the registers and flags which differ, for each state
+fn differences(a: &MachineState, b: &MachineState) -> (String, String) {
+    let mut fields = Vec::new();
+    for reg in (0..16).filter(|&r| a.regs[r] != b.regs[r]) {
+        fields.push((format!("r{}", reg), a.regs[reg], b.regs[reg]));
+    }
+    if a.pc != b.pc {
+        fields.push((String::from("pc"), a.pc, b.pc));
+    }
+    if a.flag_e != b.flag_e {
+        fields.push((String::from("E"), a.flag_e as i32, b.flag_e as i32));
+    }
+    if a.flag_g != b.flag_g {
+        fields.push((String::from("GT"), a.flag_g as i32, b.flag_g as i32));
+    }
+    if a.nzcv != b.nzcv {
+        fields.push((String::from("FLAGS"), a.nzcv as i32, b.nzcv as i32));
+    }
+    let describe = |value: fn(&(String, i32, i32)) -> i32| {
+        let list: Vec<String> = fields
+            .iter()
+            .map(|f| format!("{} = {}", f.0, value(f)))
+            .collect();
+        list.join(", ")
+    };
+    (describe(|f| f.1), describe(|f| f.2))
+}
+
+fn byte_at(contents: &[(usize, Vec<u8>)], addr: usize) -> u8 {
+    contents
+        .iter()
+        .find(|(start, bytes)| (*start..start + bytes.len()).contains(&addr))
+        .map_or(0, |(start, bytes)| bytes[addr - start])
+}
+
+/// Returns the lowest address whose byte differs between the memory contents
+fn first_difference(a: &[(usize, Vec<u8>)], b: &[(usize, Vec<u8>)]) -> Option<usize> {
+    let addrs = a
+        .iter()
+        .chain(b)
+        .flat_map(|(start, bytes)| *start..start + bytes.len());
+    addrs
+        .filter(|&addr| byte_at(a, addr) != byte_at(b, addr))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_lockstep, run_lockstep_blocks, Divergence, LockstepErr};
+    use crate::{
+        emulator::{Emulator, EmulatorConfig, EmulatorErr, ExitStatus, RunStatus},
+        memory::FlatMemory,
+        parser::parse_and_assemble,
+    };
+
+    #[test]
+    fn test_lockstep() {
+        let code = "
+            mov r1, 5
+        loop:
+            st r1, 0x100[r0]
+            sub r1, r1, 1
+            cmp r1, 0
+            bgt loop
+            mov r2, 7
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        let mut paged = Emulator::new(&bin);
+        let mut flat = Emulator::with_memory(&bin, FlatMemory::new(4096));
+        let status = run_lockstep(&mut paged, &mut flat, 1000).unwrap();
+        assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+
+        let mut left = Emulator::new(&bin);
+        let mut right = Emulator::new(&bin);
+        run_lockstep(&mut left, &mut right, 3).unwrap();
+        right.set_reg_val(1, 0);
+        let Err(LockstepErr::Diverged(divergence)) = run_lockstep(&mut left, &mut right, 10) else {
+            panic!("Emulators did not diverge");
+        };
+        assert_eq!(
+            divergence,
+            Divergence {
+                step: 3,
+                pc: 3,
+                left: String::from("r1 = 4, E = 0, GT = 1, FLAGS = 2"),
+                right: String::from("r1 = 0, E = 1, GT = 0, FLAGS = 6"),
+            }
+        );
+
+        // Memory is compared once both stop
+        let mut left = Emulator::new(&bin);
+        let mut right = Emulator::new(&bin);
+        right.write_word(0x104, 1).unwrap();
+        let err = run_lockstep(&mut left, &mut right, 1000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Emulators diverged at step 21 executing 5: [0x104] = 0 vs [0x104] = 1"
+        );
+
+        let bin = parse_and_assemble("div r1, r1, 0\n").unwrap();
+        let err = run_lockstep(&mut Emulator::new(&bin), &mut Emulator::new(&bin), 10);
+        assert!(matches!(
+            err.unwrap_err(),
+            LockstepErr::Fault(err) if matches!(err.kind(), EmulatorErr::DivideByZero)
+        ));
+    }
+
+    #[test]
+    fn test_lockstep_blocks() {
+        let code = "
+            mov r1, 5
+        loop:
+            st r1, 0x100[r0]
+            sub r1, r1, 1
+            cmp r1, 0
+            bgt loop
+            mov r2, 7
+            div r3, r2, r0
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        let config = |predecode| EmulatorConfig {
+            predecode,
+            ..Default::default()
+        };
+        let mut fast = Emulator::with_config(&bin, config(true));
+        let mut slow = Emulator::with_config(&bin, config(false));
+        // The fault in the middle of the last block is reached by both
+        let err = run_lockstep_blocks(&mut fast, &mut slow, 1000).unwrap_err();
+        assert!(matches!(
+            err,
+            LockstepErr::Fault(err) if matches!(err.kind(), EmulatorErr::DivideByZero)
+        ));
+        assert_eq!(fast.steps(), 22);
+
+        // Compared after the whole loop body rather than each instruction
+        let mut fast = Emulator::with_config(&bin, config(true));
+        let mut slow = Emulator::with_config(&bin, config(false));
+        run_lockstep_blocks(&mut fast, &mut slow, 1).unwrap();
+        assert_eq!(fast.steps(), 5);
+        slow.set_reg_val(1, 0);
+        let Err(LockstepErr::Diverged(divergence)) = run_lockstep_blocks(&mut fast, &mut slow, 10)
+        else {
+            panic!("Emulators did not diverge");
+        };
+        assert_eq!(
+            divergence,
+            Divergence {
+                step: 5,
+                pc: 1,
+                left: String::from("r1 = 3, pc = 1, GT = 1, FLAGS = 2"),
+                right: String::from("r1 = -1, pc = 5, GT = 0, FLAGS = 10"),
+            }
+        );
+    }
+}
@@ -1,51 +1,1059 @@
-use simple_risc::emulator::Emulator;
-use simple_risc::parser::parse_and_assemble;
-use std::{env::args, io::Write, process::exit};
+use simple_risc::bus::{Bus, Device};
+use simple_risc::cache::{Cache, CacheConfig};
+use simple_risc::debug::CallFrame;
+use simple_risc::debugger::Debugger;
+use simple_risc::devices::{
+    Framebuffer, Keyboard, Timer, Uart, FRAMEBUFFER_BASE, KEYBOARD_BASE, KEYBOARD_IRQ, TIMER_BASE,
+    TIMER_IRQ, UART_BASE,
+};
+use simple_risc::emulator::{
+    Emulator, EmulatorConfig, EmulatorErr, ExitStatus, PcBounds, RunStatus, Xlen,
+};
+use simple_risc::gdb::GdbStub;
+use simple_risc::heatmap::Heatmap;
+use simple_risc::memcheck::MemCheck;
+use simple_risc::memory::{hexdump, Memory, PagedMemory, Protection};
+use simple_risc::multicore::{Multicore, Schedule};
+use simple_risc::parser::assemble_for;
+use simple_risc::pipeline::{HazardKind, Pipeline};
+use simple_risc::profile::Profiler;
+use simple_risc::program::{read_image, write_image, Program};
+use simple_risc::regstats::RegStats;
+use simple_risc::replay::{Recorder, Recording};
+use simple_risc::state::{Checkpoint, CheckpointConfig};
+use simple_risc::trace::{TraceFilter, TraceFormat, TraceKind, Tracer};
+#[cfg(feature = "tui")]
+use simple_risc::tui::Tui;
+use simple_risc::writelog::WriteLog;
+use std::{env::args, io, io::Write, net::TcpListener, ops::Range, process::exit, time::Instant};
 
-fn main() {
-    if !matches!(args().count(), 2 | 3) {
-        eprintln!(
-            "Usage: {} <filepath>",
-            args().next().unwrap_or_else(|| String::from("simpleRISC"))
+/// Steps which can be undone in the debugger unless given with `--history`
+const DEFAULT_HISTORY: usize = 10000;
+/// Basic blocks listed by `--profile`
+const HOT_BLOCKS: usize = 10;
+/// Ticks between the keys read by the keyboard with `--deterministic`
+const PACED_KEY_TICKS: u64 = 1000;
+/// Longest bar of the histogram printed by `--heatmap`
+const HEATMAP_WIDTH: usize = 40;
+/// Address of the text loaded by `--load-mem` unless given with `--text-base`,
+/// leaving room for the vector table and some data below it
+const DEFAULT_TEXT_BASE: usize = 0x1000;
+
+/// Command line options
+struct Options {
+    /// Run the program under the interactive debugger
+    debug: bool,
+    /// Continue the execution saved in the checkpoint file `inpath`
+    resume: bool,
+    /// Use the full screen frontend for the debugger
+    tui: bool,
+    /// Wait for GDB to connect on this port instead of reading debugger commands
+    gdb_port: Option<u16>,
+    /// Execute the debugger commands in this file before reading them from the standard input
+    script: Option<String>,
+    inpath: String,
+    /// `inpath` is a binary image like the ones written to `outpath` instead of source
+    load_mem: bool,
+    outpath: Option<String>,
+    /// Map a framebuffer and save its final frame to this file
+    fbpath: Option<String>,
+    /// Map a keyboard reading the standard input instead of the UART
+    keyboard: bool,
+    /// Record the inputs of the run to this file
+    record: Option<String>,
+    /// Replay the inputs recorded in this file
+    replay: Option<String>,
+    /// Write the trace of the execution to this file
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    /// Kinds of instructions traced, all if empty
+    trace_only: Vec<TraceKind>,
+    /// Start and end of the traced range, labels or instruction indices
+    trace_range: Option<(String, String)>,
+    /// Model a five-stage pipeline and write its stage occupancy diagram to this file
+    pipeline: Option<String>,
+    /// Model an instruction cache with this configuration
+    icache: Option<CacheConfig>,
+    /// Model a data cache with this configuration
+    dcache: Option<CacheConfig>,
+    /// Report the hottest basic blocks
+    profile: bool,
+    /// Report the reads, writes and live ranges of the registers
+    reg_stats: bool,
+    /// Report how fast the program was emulated
+    bench: bool,
+    /// Report the loads reading uninitialized memory
+    memcheck: bool,
+    /// Report the loads and stores per range of this many bytes
+    heatmap: Option<usize>,
+    /// Export the counts of the heatmap to this file, as JSON if it ends with `.json`
+    /// or else as CSV
+    heatmap_out: Option<String>,
+    /// Regions of memory which cannot be written or accessed at all
+    regions: Vec<(Range<usize>, Protection)>,
+    /// Ranges of memory shown after the run
+    dumps: Vec<Range<usize>>,
+    /// Report the hazards of the pipeline
+    hazards: bool,
+    /// The diagram and the hazards are for the pipeline with forwarding
+    forwarding: bool,
+    /// Save the state to this file if the program is still running after `suspend_after` steps
+    checkpoint: Option<String>,
+    suspend_after: Option<u64>,
+    /// Run the program on this many harts sharing the memory
+    harts: usize,
+    /// Order in which the harts run
+    schedule: Schedule,
+    config: EmulatorConfig,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: {} [debug [--tui | --gdb <port> | -x <file>] [--history <n>] [--auto-checkpoint <interval>[:<kept>]] | resume] [--checkpoint <file> --suspend-after <steps>] [--max-steps <n>] [--mem-size <bytes>] [--endian little|big] [--xlen 32|64] [--text-base <addr>] [--load-mem] [--framebuffer <ppmfile>] [--keyboard] [--trap-faults] [--trap-overflow] [--strict] [--pc-bounds halt|warn|error] [--seed <n>] [--fake-clock] [--deterministic] [--cycle-costs <costs>] [--energy-costs <costs>] [--pipeline <file>] [--hazards] [--no-forwarding] [--icache <config>] [--dcache <config>] [--profile] [--reg-stats] [--bench] [--memcheck] [--heatmap <bytes> [--heatmap-out <file>]] [--detect-loops] [--harts <n> [--schedule <schedule>]] [--read-only <start>..<end>] [--no-access <start>..<end>] [--dump <start>..<end>] [--record <file> | --replay <file>] [--trace <file> [--trace-format text|json|vcd] [--trace-only branches,memory,syscalls] [--trace-range <start>..<end>]] <filepath> [outfile]",
+        args().next().unwrap_or_else(|| String::from("simpleRISC"))
+    );
+    exit(1);
+}
+
+/// Parses a size in bytes, which may have a 'K'(KiB) or 'M'(MiB) suffix
+fn parse_size(size: &str) -> Option<usize> {
+    let (num, scale) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 1024),
+        b'M' | b'm' => (&size[..size.len() - 1], 1024 * 1024),
+        _ => (size, 1),
+    };
+    num.parse::<usize>().ok()?.checked_mul(scale)
+}
+
+/// Parses a range of addresses like `0x100..0x200`, in decimal or hexadecimal
+fn parse_addr(addr: &str) -> Option<usize> {
+    match addr.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => addr.parse().ok(),
+    }
+}
+
+fn parse_addr_range(range: &str) -> Option<Range<usize>> {
+    let (start, end) = range.split_once("..")?;
+    Some(parse_addr(start)?..parse_addr(end)?)
+}
+
+fn parse_args() -> Options {
+    let mut positional = Vec::new();
+    let mut config = EmulatorConfig::default();
+    let mut fbpath = None;
+    let mut keyboard = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut trace = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut trace_only = Vec::new();
+    let mut trace_range = None;
+    let mut pipeline = None;
+    let mut hazards = false;
+    let mut profile = false;
+    let mut reg_stats = false;
+    let mut bench = false;
+    let mut memcheck = false;
+    let mut heatmap = None;
+    let mut heatmap_out = None;
+    let mut regions = Vec::new();
+    let mut dumps = Vec::new();
+    let mut icache = None;
+    let mut dcache = None;
+    let mut forwarding = true;
+    let mut checkpoint = None;
+    let mut suspend_after = None;
+    let mut tui = false;
+    let mut gdb_port = None;
+    let mut script = None;
+    let mut harts = 1;
+    let mut schedule = Schedule::default();
+    let mut load_mem = false;
+    let mut iter = args().skip(1).peekable();
+    let mode = iter.next_if(|arg| arg == "debug" || arg == "resume");
+    let debug = mode.as_deref() == Some("debug");
+    let resume = mode.as_deref() == Some("resume");
+    if debug {
+        config.history_depth = DEFAULT_HISTORY;
+    }
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--mem-size" | "--endian" | "--xlen" | "--text-base" | "--trap-faults"
+            | "--trap-overflow" | "--strict" | "--pc-bounds"
+                if resume =>
+            {
+                eprintln!(
+                    "[ERROR] '{}' is taken from the checkpoint when resuming",
+                    arg
+                );
+                exit(1);
+            }
+            "--mem-size" => {
+                let size = iter.next().unwrap_or_else(|| usage());
+                config.mem_bytes = parse_size(&size).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid memory size '{}'", size);
+                    exit(1);
+                });
+            }
+            "--pc-bounds" => {
+                let policy = iter.next().unwrap_or_else(|| usage());
+                config.pc_bounds = policy.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--endian" => {
+                let endian = iter.next().unwrap_or_else(|| usage());
+                config.endian = endian.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--xlen" => {
+                let xlen = iter.next().unwrap_or_else(|| usage());
+                config.xlen = xlen.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--text-base" => {
+                let addr = iter.next().unwrap_or_else(|| usage());
+                config.text_base = Some(parse_addr(&addr).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid address '{}'", addr);
+                    exit(1);
+                }));
+            }
+            "--load-mem" => load_mem = true,
+            "--framebuffer" => fbpath = Some(iter.next().unwrap_or_else(|| usage())),
+            "--keyboard" => keyboard = true,
+            "--record" if replay.is_none() => record = Some(iter.next().unwrap_or_else(|| usage())),
+            "--replay" if record.is_none() => replay = Some(iter.next().unwrap_or_else(|| usage())),
+            "--tui" if debug => {
+                if !cfg!(feature = "tui") {
+                    eprintln!("[ERROR] Built without the 'tui' feature");
+                    exit(1);
+                }
+                tui = true;
+            }
+            "-x" if debug => script = Some(iter.next().unwrap_or_else(|| usage())),
+            "--gdb" if debug => {
+                let port = iter.next().unwrap_or_else(|| usage());
+                gdb_port = Some(port.parse().unwrap_or_else(|_| {
+                    eprintln!("[ERROR] Invalid port '{}'", port);
+                    exit(1);
+                }));
+            }
+            "--history" if debug => {
+                let depth = iter.next().unwrap_or_else(|| usage());
+                config.history_depth = depth.parse().unwrap_or_else(|_| {
+                    eprintln!("[ERROR] Invalid history depth '{}'", depth);
+                    exit(1);
+                });
+            }
+            "--auto-checkpoint" if debug => {
+                let arg = iter.next().unwrap_or_else(|| usage());
+                let (interval, kept) = match arg.split_once(':') {
+                    Some((interval, kept)) => (interval, Some(kept)),
+                    None => (arg.as_str(), None),
+                };
+                let invalid = || -> ! {
+                    eprintln!("[ERROR] Invalid checkpoint interval '{}'", arg);
+                    exit(1);
+                };
+                config.checkpoint_interval = interval.parse().unwrap_or_else(|_| invalid());
+                if let Some(kept) = kept {
+                    config.checkpoints_kept = kept.parse().unwrap_or_else(|_| invalid());
+                }
+            }
+            "--checkpoint" if !debug => checkpoint = Some(iter.next().unwrap_or_else(|| usage())),
+            "--suspend-after" if !debug => {
+                let steps = iter.next().unwrap_or_else(|| usage());
+                suspend_after = Some(steps.parse().unwrap_or_else(|_| {
+                    eprintln!("[ERROR] Invalid number of steps '{}'", steps);
+                    exit(1);
+                }));
+            }
+            "--max-steps" => {
+                let steps = iter.next().unwrap_or_else(|| usage());
+                config.max_steps = Some(steps.parse().unwrap_or_else(|_| {
+                    eprintln!("[ERROR] Invalid number of steps '{}'", steps);
+                    exit(1);
+                }));
+            }
+            "--trace" => trace = Some(iter.next().unwrap_or_else(|| usage())),
+            "--trace-format" => {
+                trace_format = match iter.next().as_deref() {
+                    Some("text") => TraceFormat::Text,
+                    Some("json") => TraceFormat::Json,
+                    Some("vcd") => TraceFormat::Vcd,
+                    _ => usage(),
+                }
+            }
+            "--trace-only" => {
+                let kinds = iter.next().unwrap_or_else(|| usage());
+                for kind in kinds.split(',') {
+                    trace_only.push(match kind {
+                        "branches" => TraceKind::Branch,
+                        "memory" => TraceKind::Memory,
+                        "syscalls" => TraceKind::Syscall,
+                        _ => usage(),
+                    });
+                }
+            }
+            "--trace-range" => {
+                let range = iter.next().unwrap_or_else(|| usage());
+                let (start, end) = range.split_once("..").unwrap_or_else(|| usage());
+                trace_range = Some((start.to_string(), end.to_string()));
+            }
+            "--pipeline" => pipeline = Some(iter.next().unwrap_or_else(|| usage())),
+            "--hazards" => hazards = true,
+            "--profile" => profile = true,
+            "--reg-stats" => reg_stats = true,
+            "--bench" if !debug => bench = true,
+            "--memcheck" => memcheck = true,
+            "--heatmap" => {
+                let bytes = iter.next().unwrap_or_else(|| usage());
+                heatmap = Some(bytes.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid range size '{}'", bytes);
+                    exit(1);
+                }));
+            }
+            "--heatmap-out" => heatmap_out = Some(iter.next().unwrap_or_else(|| usage())),
+            "--detect-loops" => config.detect_loops = true,
+            "--harts" if !debug && !resume => {
+                let n = iter.next().unwrap_or_else(|| usage());
+                harts = n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid number of harts '{}'", n);
+                    exit(1);
+                });
+            }
+            "--schedule" => {
+                let s = iter.next().unwrap_or_else(|| usage());
+                schedule = s.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--dump" => {
+                let range = iter.next().unwrap_or_else(|| usage());
+                dumps.push(parse_addr_range(&range).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid address range '{}'", range);
+                    exit(1);
+                }));
+            }
+            "--read-only" | "--no-access" => {
+                let range = iter.next().unwrap_or_else(|| usage());
+                let range = parse_addr_range(&range).unwrap_or_else(|| {
+                    eprintln!("[ERROR] Invalid address range '{}'", range);
+                    exit(1);
+                });
+                let protection = match arg.as_str() {
+                    "--read-only" => Protection::ReadOnly,
+                    _ => Protection::NoAccess,
+                };
+                regions.push((range, protection));
+            }
+            "--icache" | "--dcache" => {
+                let config = iter.next().unwrap_or_else(|| usage());
+                let config = config.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+                match arg.as_str() {
+                    "--icache" => icache = Some(config),
+                    _ => dcache = Some(config),
+                }
+            }
+            "--no-forwarding" => forwarding = false,
+            "--trap-faults" => config.trap_faults = true,
+            "--trap-overflow" => config.trap_overflow = true,
+            "--strict" => config.strict = true,
+            "--fake-clock" => config.fake_clock = true,
+            "--deterministic" => config.deterministic = true,
+            "--cycle-costs" => {
+                let costs = iter.next().unwrap_or_else(|| usage());
+                config.cycle_costs = costs.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--energy-costs" => {
+                let costs = iter.next().unwrap_or_else(|| usage());
+                config.energy_costs = costs.parse().unwrap_or_else(|err| {
+                    eprintln!("[ERROR] {}", err);
+                    exit(1);
+                });
+            }
+            "--seed" => {
+                let seed = iter.next().unwrap_or_else(|| usage());
+                config.seed = Some(seed.parse().unwrap_or_else(|_| {
+                    eprintln!("[ERROR] Invalid seed '{}'", seed);
+                    exit(1);
+                }));
+            }
+            a if a.starts_with("--") => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    // A checkpoint has no source to assemble into an outfile
+    let max_positional = if resume { 1 } else { 2 };
+    if !(1..=max_positional).contains(&positional.len())
+        || checkpoint.is_some() != suspend_after.is_some()
+        || (load_mem && resume)
+        || (script.is_some() && (tui || gdb_port.is_some()))
+    {
+        usage();
+    }
+    if load_mem && config.text_base.is_none() {
+        config.text_base = Some(DEFAULT_TEXT_BASE);
+    }
+
+    let mut positional = positional.into_iter();
+    Options {
+        debug,
+        resume,
+        tui,
+        gdb_port,
+        script,
+        inpath: positional.next().unwrap(),
+        load_mem,
+        outpath: positional.next(),
+        fbpath,
+        keyboard,
+        record,
+        replay,
+        trace,
+        trace_format,
+        trace_only,
+        trace_range,
+        pipeline,
+        icache,
+        dcache,
+        hazards,
+        profile,
+        reg_stats,
+        bench,
+        memcheck,
+        heatmap,
+        heatmap_out,
+        regions,
+        dumps,
+        forwarding,
+        checkpoint,
+        suspend_after,
+        harts,
+        schedule,
+        config,
+    }
+}
+
+/// Prints the hazards of the pipeline by source line, followed by the totals of each kind
+fn print_hazards(pipeline: &Pipeline, forwarding: bool, program: &Program, code: &str) {
+    let source: Vec<&str> = code.lines().collect();
+    let with = if forwarding { "with" } else { "without" };
+    println!("Pipeline hazards {} forwarding:", with);
+    let mut totals = [HazardKind::Raw, HazardKind::LoadUse, HazardKind::Control].map(|k| (k, 0, 0));
+    for hazard in pipeline.hazards(forwarding) {
+        let line = usize::try_from(hazard.pc)
+            .ok()
+            .and_then(|pc| program.lines.get(pc));
+        match line {
+            Some(&line) => print!(
+                "{:>5}: {:<30}",
+                line,
+                source.get(line - 1).map_or("", |s| s.trim())
+            ),
+            None => print!("   pc {:<30}", hazard.pc),
+        }
+        println!(
+            " {} x{}, {} stall cycles",
+            hazard.kind, hazard.count, hazard.stalls
         );
-        exit(1);
+        let total = totals.iter_mut().find(|(k, ..)| *k == hazard.kind).unwrap();
+        total.1 += hazard.count;
+        total.2 += hazard.stalls;
+    }
+    for (kind, count, stalls) in totals {
+        println!("Total {}: {} hazards, {} stall cycles", kind, count, stalls);
     }
+}
 
-    let code: String;
-    if let Some(p) = args().nth(1) {
-        let path = std::path::Path::new(&p);
-        code = std::fs::read_to_string(path).unwrap_or_else(|err| {
-            eprintln!("Cannot read file: {}", err);
-            exit(1);
-        });
-    } else {
-        exit(1);
+/// Prints the usage of every register, marking the ones which are never used or never read
+fn print_reg_stats(reg_stats: &RegStats) {
+    println!("Register usage:");
+    println!(
+        "  reg {:>10} {:>10} {:>11} {:>11} {:>9} {:>9}",
+        "reads", "writes", "dead writes", "live ranges", "avg live", "max live"
+    );
+    for (reg, usage) in reg_stats.usage().iter().enumerate() {
+        let note = match usage {
+            u if u.unused() => " (unused)",
+            u if u.reads == 0 => " (never read)",
+            _ => "",
+        };
+        let average = usage.live_steps as f64 / usage.live_ranges.max(1) as f64;
+        println!(
+            "  {:>3} {:>10} {:>10} {:>11} {:>11} {:>9.1} {:>9}{}",
+            format!("r{}", reg),
+            usage.reads,
+            usage.writes,
+            usage.dead_writes,
+            usage.live_ranges,
+            average,
+            usage.longest_live_range,
+            note
+        );
     }
+}
 
-    let instructions = parse_and_assemble(&code).unwrap_or_else(|err| {
-        eprintln!("[ERROR] {}", err);
+/// Prints the basic blocks which executed the most instructions along with their source lines
+fn print_profile(profiler: &Profiler, program: &Program) {
+    let total = profiler.total().max(1);
+    println!("Hottest basic blocks:");
+    for block in profiler.hot_blocks(&program.text).iter().take(HOT_BLOCKS) {
+        let lines = (
+            program.lines.get(block.pcs.start),
+            program.lines.get(block.pcs.end - 1),
+        );
+        let place = match lines {
+            (Some(first), Some(last)) if first == last => format!("line {}", first),
+            (Some(first), Some(last)) => format!("lines {}-{}", first, last),
+            _ => format!("pc {}-{}", block.pcs.start, block.pcs.end - 1),
+        };
+        let label = program
+            .label_at(block.pcs.start)
+            .map_or_else(String::new, |l| format!(" ({})", l));
+        println!(
+            "  {}{}: {} instructions({:.2}%), entered {} times",
+            place,
+            label,
+            block.instructions,
+            100.0 * block.instructions as f64 / total as f64,
+            block.entries
+        );
+    }
+
+    let functions = profiler.functions();
+    if functions.is_empty() {
+        return;
+    }
+    println!("Functions:");
+    for function in functions {
+        let name = match program.label_at(function.entry) {
+            Some(label) => label.to_string(),
+            None => format!("pc {}", function.entry),
+        };
+        println!(
+            "  {}: {} calls, {} instructions({:.2}%), {} in itself",
+            name,
+            function.calls,
+            function.inclusive,
+            100.0 * function.inclusive as f64 / total as f64,
+            function.exclusive
+        );
+    }
+}
+
+/// Prints the loads which read uninitialized memory
+fn print_memcheck(memcheck: &MemCheck, program: &Program) {
+    let reports = memcheck.reports();
+    if reports.is_empty() {
+        println!("No reads of uninitialized memory");
+        return;
+    }
+    println!("Reads of uninitialized memory:");
+    for read in reports {
+        let line = usize::try_from(read.pc)
+            .ok()
+            .and_then(|pc| program.lines.get(pc));
+        let place = match line {
+            Some(line) => format!("line {}", line),
+            None => format!("pc {}", read.pc),
+        };
+        println!(
+            "  {}: {}-byte load of the uninitialized byte at {:#x}",
+            place, read.size, read.addr
+        );
+    }
+}
+
+/// Prints the calls which led to an error, naming the functions by their labels
+fn print_backtrace(backtrace: &[CallFrame], program: &Program) {
+    for (i, frame) in backtrace.iter().enumerate() {
+        let name = usize::try_from(frame.target)
+            .ok()
+            .and_then(|pc| program.label_at(pc))
+            .map_or_else(|| format!("pc {}", frame.target), String::from);
+        let line = usize::try_from(frame.call_pc)
+            .ok()
+            .and_then(|pc| program.lines.get(pc));
+        match line {
+            Some(line) => eprintln!("  #{} in {}, called from line {}", i, name, line),
+            None => eprintln!("  #{} in {}, called from pc {}", i, name, frame.call_pc),
+        }
+    }
+}
+
+/// Runs the program on `harts` harts sharing the memory, then prints the registers of each
+fn run_harts(
+    program: &Program,
+    code: &str,
+    harts: usize,
+    schedule: Schedule,
+    config: EmulatorConfig,
+    dumps: &[Range<usize>],
+) {
+    let mut multicore = Multicore::with_config(&program.text, harts, config, schedule);
+    multicore.load(program).unwrap_or_else(|err| {
+        eprintln!("[ERROR] {}. The data section does not fit in memory", err);
         exit(1);
     });
+    let statuses = multicore.exec().unwrap_or_else(|fault| {
+        eprintln!("[ERROR] {}", fault);
+        let line = fault
+            .err
+            .pc()
+            .and_then(|pc| usize::try_from(pc).ok())
+            .and_then(|pc| program.lines.get(pc));
+        if let Some(&line) = line {
+            let source = code.lines().nth(line.wrapping_sub(1)).unwrap_or("");
+            eprintln!("  on line {}: {}", line, source.trim());
+        }
+        exit(1);
+    });
+    for (hart, emul) in multicore.harts().iter().enumerate() {
+        println!("Hart {}:", hart);
+        print!("{}", emul.machine_state());
+        if let Some(code) = emul.exit_code() {
+            println!("Exited with code {}", code);
+        }
+    }
+    println!("Executed {} instructions", multicore.steps());
+    for addrs in dumps {
+        println!("Memory {:#x}..{:#x}:", addrs.start, addrs.end);
+        match multicore.hart_mut(0).read_mem(addrs.clone()) {
+            Ok(bytes) => print!("{}", hexdump(addrs.start, &bytes)),
+            Err(err) => eprintln!("[ERROR] {}", err),
+        }
+    }
+    if statuses.contains(&ExitStatus::BudgetExceeded) {
+        eprintln!("[ERROR] A hart executed the number of instructions set by --max-steps");
+        exit(2);
+    }
+    // A failing hart is not hidden by the others succeeding
+    let failed = statuses.iter().find_map(|status| match status {
+        ExitStatus::Exited(code) if *code != 0 => Some(*code),
+        _ => None,
+    });
+    if let Some(code) = failed {
+        exit(code);
+    }
+}
+
+/// Runs the program under GDB or the debugger taking commands from the standard input
+fn debug<M: Memory>(
+    emul: &mut Emulator<M>,
+    program: &Program,
+    code: &str,
+    tui: bool,
+    gdb_port: Option<u16>,
+    script: Option<&str>,
+) -> io::Result<()> {
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for GDB to connect on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept()?;
+        return GdbStub::new(stream).run(emul);
+    }
+    let input = io::BufReader::new(io::stdin());
+    #[cfg(feature = "tui")]
+    if tui {
+        return Tui::new(program, code).run(emul, input, io::stdout());
+    }
+    #[cfg(not(feature = "tui"))]
+    let _ = tui;
+    let mut debugger = Debugger::new(program, code, input, io::stdout());
+    if let Some(script) = script {
+        if debugger.run_script(emul, script)? {
+            return Ok(());
+        }
+    }
+    debugger.run(emul)
+}
+
+fn main() {
+    let mut opts = parse_args();
+
+    let checkpoint = opts.resume.then(|| {
+        std::fs::File::open(&opts.inpath)
+            .and_then(|file| Checkpoint::read_from(io::BufReader::new(file)))
+            .unwrap_or_else(|err| {
+                eprintln!("[ERROR] {}. Cannot read checkpoint '{}'", err, opts.inpath);
+                exit(1);
+            })
+    });
+    let (code, program) = match &checkpoint {
+        Some(checkpoint) => {
+            opts.config.mem_bytes = checkpoint.mem_bytes;
+            checkpoint.config.apply(&mut opts.config);
+            let text = checkpoint.text.clone();
+            (
+                String::new(),
+                Program {
+                    text,
+                    ..Default::default()
+                },
+            )
+        }
+        None if opts.load_mem => {
+            let image = std::fs::read(&opts.inpath).unwrap_or_else(|err| {
+                eprintln!("Cannot read file: {}", err);
+                exit(1);
+            });
+            let (text, xlen) = read_image(&image, opts.config.endian).unwrap_or_else(|err| {
+                eprintln!("[ERROR] {}", err);
+                exit(1);
+            });
+            if xlen == Xlen::X64 {
+                opts.config.xlen = xlen;
+            }
+            (
+                String::new(),
+                Program {
+                    text,
+                    ..Default::default()
+                },
+            )
+        }
+        None => {
+            let path = std::path::Path::new(&opts.inpath);
+            let code = std::fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Cannot read file: {}", err);
+                exit(1);
+            });
+            let (endian, xlen) = (opts.config.endian, opts.config.xlen);
+            let program = assemble_for(&code, endian, xlen).unwrap_or_else(|err| {
+                eprintln!("[ERROR] {}", err);
+                exit(1);
+            });
+            (code, program)
+        }
+    };
+    let instructions = &program.text;
+    // Only known now, as images and checkpoints can select the 64-bit mode
+    if opts.config.xlen == Xlen::X64 && opts.config.trap_overflow {
+        eprintln!("[ERROR] --trap-overflow is not supported in 64-bit mode");
+        exit(1);
+    }
 
     // Write assembled binary to file if outfile name given
-    if let Some(outpath) = args().nth(2) {
+    if let Some(outpath) = opts.outpath {
         let mut outfile = std::fs::File::create(&outpath).unwrap_or_else(|err| {
             eprintln!("[ERROR] {}. Cannot open outfile '{}'", err, outpath);
             exit(1);
         });
 
-        for ins in &instructions {
-            outfile.write(&ins.to_le_bytes()).unwrap_or_else(|err| {
-                eprintln!("[ERROR] {}. Cannot write to outfile {}", err, outpath);
+        let image = write_image(instructions, opts.config.endian, opts.config.xlen);
+        outfile.write_all(&image).unwrap_or_else(|err| {
+            eprintln!("[ERROR] {}. Cannot write to outfile {}", err, outpath);
+            exit(1);
+        });
+    }
+
+    if opts.harts > 1 {
+        let (harts, schedule) = (opts.harts, opts.schedule);
+        run_harts(&program, &code, harts, schedule, opts.config, &opts.dumps);
+        return;
+    }
+
+    let recorder = opts.record.as_ref().map(|_| Recorder::new());
+    let recording = opts.replay.as_ref().map(|path| {
+        std::fs::File::open(path)
+            .and_then(|file| Recording::read_from(io::BufReader::new(file)))
+            .unwrap_or_else(|err| {
+                eprintln!("[ERROR] {}. Cannot read recording '{}'", err, path);
+                exit(1);
+            })
+    });
+    // Devices reading the standard input are recorded or replayed
+    let input_device = |base, device: Box<dyn Device>| -> Box<dyn Device> {
+        match (&recorder, &recording) {
+            (Some(recorder), _) => Box::new(recorder.device(base, device)),
+            (_, Some(recording)) => Box::new(recording.device(base, device)),
+            _ => device,
+        }
+    };
+
+    let mut bus = Bus::new(PagedMemory::new(opts.config.mem_bytes));
+    if opts.keyboard {
+        bus.map(UART_BASE, Box::new(Uart::new(io::empty(), io::stdout())));
+        let keyboard: Box<dyn Device> = match recording {
+            Some(_) => Box::new(Keyboard::new()),
+            None if opts.config.deterministic => {
+                Box::new(Keyboard::paced(Box::new(io::stdin()), PACED_KEY_TICKS))
+            }
+            None => Box::new(Keyboard::from_reader(io::stdin())),
+        };
+        bus.map_irq(
+            KEYBOARD_BASE,
+            input_device(KEYBOARD_BASE, keyboard),
+            KEYBOARD_IRQ,
+        );
+    } else {
+        let uart = Box::new(Uart::new(io::stdin(), io::stdout()));
+        bus.map(UART_BASE, input_device(UART_BASE, uart));
+    }
+    bus.map_irq(TIMER_BASE, Box::new(Timer::new()), TIMER_IRQ);
+    let screen = opts.fbpath.as_ref().map(|_| {
+        let fb = Framebuffer::new();
+        let screen = fb.screen();
+        bus.map(FRAMEBUFFER_BASE, Box::new(fb));
+        screen
+    });
+    let mem_bytes = opts.config.mem_bytes;
+    let mut emul = Emulator::with_memory_and_config(instructions, bus, opts.config);
+    emul.load(&program).unwrap_or_else(|err| {
+        eprintln!("[ERROR] {}. The data section does not fit in memory", err);
+        exit(1);
+    });
+    if let Some(path) = &opts.trace {
+        let file = std::fs::File::create(path).unwrap_or_else(|err| {
+            eprintln!("[ERROR] {}. Cannot open trace file '{}'", err, path);
+            exit(1);
+        });
+        let out = Box::new(io::BufWriter::new(file));
+        let mut tracer = Tracer::with_format(out, opts.trace_format);
+        // Either end may be a label or an instruction index
+        let resolve = |bound: &str| {
+            let index = program.labels.get(bound).copied();
+            index.or_else(|| bound.parse().ok()).unwrap_or_else(|| {
+                eprintln!("[ERROR] Unknown label or instruction '{}'", bound);
+                exit(1);
+            }) as i32
+        };
+        tracer.set_filter(TraceFilter {
+            kinds: opts.trace_only.clone(),
+            pcs: opts
+                .trace_range
+                .as_ref()
+                .map(|(start, end)| resolve(start)..resolve(end)),
+        });
+        emul.set_tracer(tracer);
+    }
+    if opts.pipeline.is_some() || opts.hazards {
+        let mut pipeline = Pipeline::new();
+        if let Some(path) = &opts.pipeline {
+            let file = std::fs::File::create(path).unwrap_or_else(|err| {
+                eprintln!(
+                    "[ERROR] {}. Cannot open pipeline diagram file '{}'",
+                    err, path
+                );
                 exit(1);
             });
+            pipeline.set_diagram(Box::new(io::BufWriter::new(file)), opts.forwarding);
+        }
+        emul.set_pipeline(pipeline);
+    }
+    if opts.profile {
+        emul.set_profiler(Profiler::new());
+    }
+    if opts.reg_stats {
+        emul.set_reg_stats(RegStats::new());
+    }
+    if let Some(bytes) = opts.heatmap {
+        emul.set_heatmap(Heatmap::new(bytes));
+    }
+    if let Some(config) = &opts.icache {
+        emul.set_icache(Cache::new(config.clone()));
+    }
+    if let Some(config) = &opts.dcache {
+        emul.set_dcache(Cache::new(config.clone()));
+    }
+    if let Some(checkpoint) = &checkpoint {
+        emul.restore(&checkpoint.state).unwrap_or_else(|err| {
+            eprintln!("[ERROR] {}. Cannot restore the checkpoint", err);
+            exit(1);
+        });
+    }
+    if opts.debug {
+        emul.set_write_log(WriteLog::new());
+    }
+    for (addrs, protection) in &opts.regions {
+        emul.protect(addrs.clone(), *protection);
+    }
+    if opts.memcheck {
+        let mut memcheck = MemCheck::new();
+        // Memory restored from a checkpoint was written before it was saved
+        for (addr, bytes) in checkpoint.iter().flat_map(|c| &c.state.memory) {
+            memcheck.initialize(*addr..addr + bytes.len());
+        }
+        emul.set_memcheck(memcheck);
+    }
+    if let Some(recorder) = &recorder {
+        emul.record(recorder.clone());
+    }
+    if let Some(recording) = &recording {
+        emul.replay(recording);
+    }
+    let mut suspended = false;
+    let mut exceeded = false;
+    let started = Instant::now();
+    let result: Result<(), Box<dyn std::error::Error>> = if opts.debug {
+        let script = opts.script.as_ref().map(|path| {
+            std::fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("[ERROR] {}. Cannot read debugger script '{}'", err, path);
+                exit(1);
+            })
+        });
+        let (tui, gdb_port) = (opts.tui, opts.gdb_port);
+        debug(&mut emul, &program, &code, tui, gdb_port, script.as_deref()).map_err(Into::into)
+    } else if let Some(steps) = opts.suspend_after {
+        emul.run_for(steps)
+            .map(|status| {
+                suspended = status == RunStatus::Running;
+                exceeded = status == RunStatus::Stopped(ExitStatus::BudgetExceeded);
+            })
+            .map_err(Into::into)
+    } else {
+        emul.exec()
+            .map(|status| exceeded = status == ExitStatus::BudgetExceeded)
+            .map_err(Into::into)
+    };
+    let elapsed = started.elapsed();
+
+    // Save the last frame even if the program failed, it helps in finding out why
+    if let (Some(fbpath), Some(screen)) = (&opts.fbpath, screen) {
+        let saved = std::fs::File::create(fbpath)
+            .and_then(|mut file| screen.write_ppm(&mut io::BufWriter::new(&mut file)));
+        if let Err(err) = saved {
+            eprintln!("[ERROR] {}. Cannot write framebuffer to '{}'", err, fbpath);
+        }
+    }
+
+    // Save the recording even if the program failed, so that the failure can be replayed
+    if let (Some(path), Some(recorder)) = (&opts.record, &recorder) {
+        let saved = std::fs::File::create(path).and_then(|mut file| {
+            recorder
+                .recording()
+                .write_to(&mut io::BufWriter::new(&mut file))
+        });
+        if let Err(err) = saved {
+            eprintln!("[ERROR] {}. Cannot write recording to '{}'", err, path);
+        }
+    }
+
+    if let (true, Some(path)) = (suspended, &opts.checkpoint) {
+        let checkpoint = Checkpoint {
+            text: instructions.clone(),
+            mem_bytes,
+            config: CheckpointConfig::of(emul.config()),
+            state: emul.snapshot(),
+        };
+        let saved = std::fs::File::create(path)
+            .and_then(|mut file| checkpoint.write_to(&mut io::BufWriter::new(&mut file)));
+        match saved {
+            Ok(_) => eprintln!("Suspended, continue using 'resume {}'", path),
+            Err(err) => eprintln!("[ERROR] {}. Cannot write checkpoint to '{}'", err, path),
         }
     }
 
-    let mut emul = Emulator::new(&instructions);
-    emul.exec().unwrap_or_else(|err| {
+    result.unwrap_or_else(|err| {
         eprintln!("[ERROR] {}", err);
+        if let Some(err) = err.downcast_ref::<EmulatorErr>() {
+            let line = err
+                .pc()
+                .and_then(|pc| usize::try_from(pc).ok())
+                .and_then(|pc| program.lines.get(pc));
+            if let Some(&line) = line {
+                let source = code.lines().nth(line.wrapping_sub(1)).unwrap_or("");
+                eprintln!("  on line {}: {}", line, source.trim());
+            }
+            print_backtrace(err.backtrace(), &program);
+        }
         exit(1);
     });
-    emul.debug();
+    if !opts.debug {
+        emul.debug();
+        if let Some(code) = emul.exit_code() {
+            println!("Exited with code {}", code);
+        } else if emul.config().pc_bounds == PcBounds::Warn && !exceeded && !suspended {
+            eprintln!(
+                "[WARN] Program ended by jumping to {} without the exit system call",
+                emul.pc()
+            );
+        }
+        println!(
+            "Executed {} instructions in {} cycles",
+            emul.steps(),
+            emul.cycles()
+        );
+        println!("Estimated energy: {} units", emul.energy());
+        if opts.bench {
+            let secs = elapsed.as_secs_f64();
+            println!(
+                "Emulated {} instructions and {} cycles in {:.3} s, {:.2} million instructions per second",
+                emul.steps(),
+                emul.cycles(),
+                secs,
+                emul.steps() as f64 / secs / 1e6
+            );
+        }
+        if let Some(pipeline) = emul.pipeline() {
+            println!(
+                "Five-stage pipeline: {} cycles with forwarding, {} without",
+                pipeline.cycles(true),
+                pipeline.cycles(false)
+            );
+            if opts.hazards {
+                print_hazards(pipeline, opts.forwarding, &program, &code);
+            }
+        }
+        if let Some(profiler) = emul.profiler() {
+            print_profile(profiler, &program);
+        }
+        if let Some(reg_stats) = emul.reg_stats() {
+            print_reg_stats(reg_stats);
+        }
+        if let Some(memcheck) = emul.memcheck() {
+            print_memcheck(memcheck, &program);
+        }
+        if let Some(icache) = emul.icache() {
+            println!("I-cache: {}", icache.stats());
+        }
+        if let Some(dcache) = emul.dcache() {
+            println!("D-cache: {}", dcache.stats());
+        }
+        if let Some(heatmap) = emul.heatmap() {
+            println!("Memory accesses per {} bytes:", heatmap.bucket_bytes());
+            print!("{}", heatmap.histogram(HEATMAP_WIDTH));
+            if let Some(path) = &opts.heatmap_out {
+                let counts = match path.ends_with(".json") {
+                    true => heatmap.to_json(),
+                    false => heatmap.to_csv(),
+                };
+                if let Err(err) = std::fs::write(path, counts) {
+                    eprintln!("[ERROR] {}. Cannot write the heatmap to '{}'", err, path);
+                }
+            }
+        }
+        for addrs in &opts.dumps {
+            println!("Memory {:#x}..{:#x}:", addrs.start, addrs.end);
+            match emul.read_mem(addrs.clone()) {
+                Ok(bytes) => print!("{}", hexdump(addrs.start, &bytes)),
+                Err(err) => eprintln!("[ERROR] {}", err),
+            }
+        }
+    }
+    if exceeded {
+        eprintln!(
+            "[ERROR] Stopped after executing {} instructions, the limit set by --max-steps",
+            emul.steps()
+        );
+        exit(2);
+    }
+    if let Some(code) = emul.exit_code() {
+        exit(code);
+    }
 }
@@ -0,0 +1,110 @@
+//! Detection of reads from uninitialized memory, see [`Emulator::set_memcheck`].
+//!
+//! Every byte written by a store or a system call is marked as initialized, and a load
+//! which reads a byte never written is reported with its `pc` and address. Each
+//! instruction is reported once for each address. Memory-mapped devices are not checked,
+//! neither are reads made by system calls.
+//!
+//! [`Emulator::set_memcheck`]: crate::emulator::Emulator::set_memcheck
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::ops::Range;
+
+/// Bytes tracked by each entry of the shadow memory
+const PAGE_BYTES: usize = 4096;
+
+/// A load which read uninitialized memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UninitRead {
+    pub pc: i32,
+    /// Address of the first uninitialized byte
+    pub addr: usize,
+    /// Bytes read by the load
+    pub size: usize,
+}
+
+/// Tracks the initialized bytes of the memory
+#[derive(Debug, Clone, Default)]
+pub struct MemCheck {
+    /// One bit for each byte of a page, pages never written are absent
+    pages: BTreeMap<usize, [u64; PAGE_BYTES / 64]>,
+    reports: Vec<UninitRead>,
+    reported: BTreeSet<(i32, usize)>,
+}
+
+impl MemCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks `addrs` as initialized, like for memory restored from a checkpoint
+    pub fn initialize(&mut self, addrs: Range<usize>) {
+        for addr in addrs {
+            let bits = self
+                .pages
+                .entry(addr / PAGE_BYTES)
+                .or_insert([0; PAGE_BYTES / 64]);
+            let offset = addr % PAGE_BYTES;
+            bits[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    pub fn is_initialized(&self, addr: usize) -> bool {
+        let offset = addr % PAGE_BYTES;
+        self.pages
+            .get(&(addr / PAGE_BYTES))
+            .is_some_and(|bits| bits[offset / 64] & (1 << (offset % 64)) != 0)
+    }
+
+    /// Reports a read of `size` bytes at `addr` by the instruction at `pc` if any of
+    /// them is uninitialized
+    pub(crate) fn check_read(&mut self, pc: i32, addr: usize, size: usize) {
+        let Some(addr) = (addr..addr + size).find(|&a| !self.is_initialized(a)) else {
+            return;
+        };
+        if self.reported.insert((pc, addr)) {
+            self.reports.push(UninitRead { pc, addr, size });
+        }
+    }
+
+    /// Reads of uninitialized memory in the order they were made
+    pub fn reports(&self) -> &[UninitRead] {
+        &self.reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemCheck, UninitRead};
+
+    #[test]
+    fn test_check_read() {
+        let mut check = MemCheck::new();
+        check.initialize(4094..4098);
+        assert!(check.is_initialized(4095) && check.is_initialized(4096));
+        assert!(!check.is_initialized(4098));
+
+        check.check_read(1, 4094, 4);
+        check.check_read(2, 4096, 4);
+        check.check_read(2, 4096, 4);
+        check.check_read(3, 0, 1);
+        assert_eq!(
+            check.reports(),
+            [
+                UninitRead {
+                    pc: 2,
+                    addr: 4098,
+                    size: 4
+                },
+                UninitRead {
+                    pc: 3,
+                    addr: 0,
+                    size: 1
+                }
+            ]
+        );
+    }
+}
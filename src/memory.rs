@@ -0,0 +1,564 @@
+//! Memory used by the emulator.
+//!
+//! The emulator is generic over the [`Memory`] trait, so that the storage can be
+//! substituted by flat arrays, sparse pages or wrappers which trace accesses.
+//! The accessors of [`Memory`] are little-endian, the emulator converts the values it
+//! loads and stores to the byte order in [`EmulatorConfig::endian`].
+//!
+//! [`EmulatorConfig::endian`]: crate::emulator::EmulatorConfig::endian
+
+use crate::{debug::Access, emulator::EmulatorErr};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec};
+use core::{fmt, ops::Range, str::FromStr};
+
+/// Size of a page in bytes
+pub const PAGE_SIZE: usize = 4096;
+
+/// Byte addressable memory with addresses in `[0, size())`.
+///
+/// Accesses which are not completely within the memory must fail with
+/// [`EmulatorErr::InvalidMemAddr`] without changing anything.
+/// Alignment is checked by the emulator before calling these methods.
+/// Reads take `&mut self` so that implementations can have side effects.
+pub trait Memory {
+    /// Size of the memory in bytes
+    fn size(&self) -> usize;
+
+    /// Fills `buf` with the bytes starting at `addr`
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr>;
+
+    /// Writes `bytes` starting at `addr`
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr>;
+
+    fn read_byte(&mut self, addr: usize) -> Result<u8, EmulatorErr> {
+        let mut buf = [0; 1];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_half(&mut self, addr: usize) -> Result<u16, EmulatorErr> {
+        let mut buf = [0; 2];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn read_word(&mut self, addr: usize) -> Result<u32, EmulatorErr> {
+        let mut buf = [0; 4];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn write_byte(&mut self, addr: usize, value: u8) -> Result<(), EmulatorErr> {
+        self.write_bytes(addr, &[value])
+    }
+
+    fn write_half(&mut self, addr: usize, value: u16) -> Result<(), EmulatorErr> {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+
+    fn write_word(&mut self, addr: usize, value: u32) -> Result<(), EmulatorErr> {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+
+    /// Returns the parts of the memory which may hold non-zero bytes as their address
+    /// and contents, in increasing order of address. Memory-mapped devices are not
+    /// included. The default implementation reads the whole memory a page at a time.
+    fn contents(&mut self) -> Vec<(usize, Vec<u8>)> {
+        let mut regions = Vec::new();
+        for addr in (0..self.size()).step_by(PAGE_SIZE) {
+            let mut page = vec![0; PAGE_SIZE.min(self.size() - addr)];
+            if self.read_bytes(addr, &mut page).is_ok() && page.iter().any(|&b| b != 0) {
+                regions.push((addr, page));
+            }
+        }
+        regions
+    }
+
+    /// Sets every byte returned by [`Memory::contents`] to zero
+    fn clear(&mut self) {
+        for (addr, bytes) in self.contents() {
+            // The region was just read, so it can be written
+            let _ = self.write_bytes(addr, &vec![0; bytes.len()]);
+        }
+    }
+
+    /// Returns the `len` bytes at `addr` if they can be accessed in place, without the
+    /// other methods. The emulator uses it for the loads and stores of compiled blocks,
+    /// the default returns `None` so that they all go through the other methods.
+    fn bytes_mut(&mut self, _addr: usize, _len: usize) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Returns true if `[addr, addr + len)` touches a memory-mapped device,
+    /// reading which may have side effects
+    fn is_mmio(&self, _addr: usize, _len: usize) -> bool {
+        false
+    }
+
+    /// Called by the emulator after each executed instruction, or batched with
+    /// [`Memory::advance`], lets memory-mapped devices advance their time
+    fn tick(&mut self) {}
+
+    /// Makes `ticks` calls to [`Memory::tick`] at once
+    fn advance(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Number of upcoming ticks after which [`Memory::pending_irqs`] still returns the same
+    /// lines, the emulator runs that many instructions before checking for interrupts again.
+    /// Memories whose `tick` can request interrupts have to override it.
+    fn idle_ticks(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Bitmask of the interrupt lines requested by memory-mapped devices
+    fn pending_irqs(&self) -> u32 {
+        0
+    }
+}
+
+/// Order of the bytes of multi-byte values in memory and in the assembled binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    /// Least significant byte first
+    #[default]
+    Little,
+    /// Most significant byte first
+    Big,
+}
+
+impl Endian {
+    /// Converts a word between little-endian and this order, in either direction
+    pub fn word(self, value: u32) -> u32 {
+        match self {
+            Self::Little => value,
+            Self::Big => value.swap_bytes(),
+        }
+    }
+
+    /// Converts a halfword between little-endian and this order, in either direction
+    pub fn half(self, value: u16) -> u16 {
+        match self {
+            Self::Little => value,
+            Self::Big => value.swap_bytes(),
+        }
+    }
+
+    /// Bytes of `value` in this order
+    pub fn word_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Parses `little` or `big`
+impl FromStr for Endian {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "little" => Ok(Self::Little),
+            "big" => Ok(Self::Big),
+            _ => Err(format!("Invalid byte order '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Endian {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Little => "little",
+            Self::Big => "big",
+        })
+    }
+}
+
+/// Returns an error if `[addr, addr + len)` is not within `[0, size)`
+fn check_range(size: usize, addr: usize, len: usize) -> Result<(), EmulatorErr> {
+    match addr.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(EmulatorErr::InvalidMemAddr),
+    }
+}
+
+/// Memory allocated upfront as one contiguous array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bytes: vec![0; size],
+        }
+    }
+}
+
+impl Memory for FlatMemory {
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        check_range(self.size(), addr, buf.len())?;
+        buf.copy_from_slice(&self.bytes[addr..addr + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        check_range(self.size(), addr, bytes.len())?;
+        self.bytes[addr..addr + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn bytes_mut(&mut self, addr: usize, len: usize) -> Option<&mut [u8]> {
+        self.bytes.get_mut(addr..addr.checked_add(len)?)
+    }
+
+    fn clear(&mut self) {
+        self.bytes.fill(0);
+    }
+}
+
+/// Sparse memory made of pages which are allocated on their first write.
+/// Reading from a page which was never written returns zeros.
+#[derive(Debug, Clone, Default)]
+pub struct PagedMemory {
+    size: usize,
+    /// Maps page number(`addr / PAGE_SIZE`) to its contents
+    pages: BTreeMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl PagedMemory {
+    /// Creates a memory with addresses in `[0, size)`, nothing is allocated upfront
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Number of pages which have been allocated
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Reads `N` bytes at `addr` with a single lookup if they are within one page
+    #[inline]
+    fn read_array<const N: usize>(&mut self, addr: usize) -> Result<[u8; N], EmulatorErr> {
+        let (page, off) = split_addr(addr);
+        let mut buf = [0; N];
+        match self.pages.get(&page) {
+            Some(data) if off + N <= PAGE_SIZE && addr + N <= self.size => {
+                buf.copy_from_slice(&data[off..off + N])
+            }
+            _ => self.read_bytes(addr, &mut buf)?,
+        }
+        Ok(buf)
+    }
+
+    /// Writes `bytes` at `addr` with a single lookup if they are within an allocated page
+    #[inline]
+    fn write_array<const N: usize>(
+        &mut self,
+        addr: usize,
+        bytes: [u8; N],
+    ) -> Result<(), EmulatorErr> {
+        let (page, off) = split_addr(addr);
+        match self.pages.get_mut(&page) {
+            Some(data) if off + N <= PAGE_SIZE && addr + N <= self.size => {
+                data[off..off + N].copy_from_slice(&bytes);
+                Ok(())
+            }
+            _ => self.write_bytes(addr, &bytes),
+        }
+    }
+}
+
+impl Memory for PagedMemory {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        check_range(self.size, addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let (page, off) = split_addr(addr + done);
+            let n = (PAGE_SIZE - off).min(buf.len() - done);
+            match self.pages.get(&page) {
+                Some(data) => buf[done..done + n].copy_from_slice(&data[off..off + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        check_range(self.size, addr, bytes.len())?;
+        let mut done = 0;
+        while done < bytes.len() {
+            let (page, off) = split_addr(addr + done);
+            let n = (PAGE_SIZE - off).min(bytes.len() - done);
+            let data = self
+                .pages
+                .entry(page)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            data[off..off + n].copy_from_slice(&bytes[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    // Loads and stores access a single page, unless they are misaligned
+
+    fn read_byte(&mut self, addr: usize) -> Result<u8, EmulatorErr> {
+        self.read_array(addr).map(|[byte]| byte)
+    }
+
+    fn read_half(&mut self, addr: usize) -> Result<u16, EmulatorErr> {
+        self.read_array(addr).map(u16::from_le_bytes)
+    }
+
+    fn read_word(&mut self, addr: usize) -> Result<u32, EmulatorErr> {
+        self.read_array(addr).map(u32::from_le_bytes)
+    }
+
+    fn write_byte(&mut self, addr: usize, value: u8) -> Result<(), EmulatorErr> {
+        self.write_array(addr, [value])
+    }
+
+    fn write_half(&mut self, addr: usize, value: u16) -> Result<(), EmulatorErr> {
+        self.write_array(addr, value.to_le_bytes())
+    }
+
+    fn write_word(&mut self, addr: usize, value: u32) -> Result<(), EmulatorErr> {
+        self.write_array(addr, value.to_le_bytes())
+    }
+
+    /// Only allocated pages are accessed in place, so that reading does not allocate
+    fn bytes_mut(&mut self, addr: usize, len: usize) -> Option<&mut [u8]> {
+        let (page, off) = split_addr(addr);
+        match self.pages.get_mut(&page) {
+            Some(data) if off + len <= PAGE_SIZE && addr + len <= self.size => {
+                Some(&mut data[off..off + len])
+            }
+            _ => None,
+        }
+    }
+
+    fn contents(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.pages
+            .iter()
+            .map(|(&page, data)| (page * PAGE_SIZE, data.to_vec()))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+/// Accesses allowed to a protected region, see [`Emulator::protect`]
+///
+/// [`Emulator::protect`]: crate::emulator::Emulator::protect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    ReadOnly,
+    NoAccess,
+}
+
+/// Memory range which loads and stores can access only as its protection allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub addrs: Range<usize>,
+    pub protection: Protection,
+}
+
+impl Region {
+    /// Returns true if an `access` to `[addr, addr + size)` violates the protection
+    pub(crate) fn forbids(&self, addr: usize, size: usize, access: Access) -> bool {
+        let overlaps = addr < self.addrs.end && self.addrs.start < addr + size;
+        overlaps && (self.protection == Protection::NoAccess || access != Access::Read)
+    }
+}
+
+/// Formats `bytes` starting at address `start` like `hexdump -C`, 16 bytes per line
+/// as hex followed by the printable ones as ASCII. For example:
+/// ```text
+/// 00000100  48 65 6c 6c 6f 0a 00 00  2a 00 00 00              |Hello...*...|
+/// ```
+pub fn hexdump(start: usize, bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:08x}  {:<49} |{}|\n",
+            start + 16 * i,
+            hex,
+            ascii
+        ));
+    }
+    dump
+}
+
+/// Returns the addresses, multiple of `align`, at which `pattern` occurs in `memory` in
+/// increasing order. Only the regions returned by [`Memory::contents`] are searched, so a
+/// match must lie within memory which may hold non-zero bytes.
+pub fn find<M: Memory + ?Sized>(memory: &mut M, pattern: &[u8], align: usize) -> Vec<usize> {
+    let mut matches = Vec::new();
+    if pattern.is_empty() {
+        return matches;
+    }
+    // Adjacent regions are joined so that matches can span them
+    let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+    for (addr, bytes) in memory.contents() {
+        match runs.last_mut() {
+            Some((start, run)) if *start + run.len() == addr => run.extend(bytes),
+            _ => runs.push((addr, bytes)),
+        }
+    }
+    for (start, run) in runs {
+        let found = run.windows(pattern.len()).enumerate();
+        matches.extend(
+            found
+                .filter(|&(i, window)| (start + i).is_multiple_of(align) && window == pattern)
+                .map(|(i, _)| start + i),
+        );
+    }
+    matches
+}
+
+/// Splits an address into its page number and offset within the page
+fn split_addr(addr: usize) -> (usize, usize) {
+    (addr / PAGE_SIZE, addr % PAGE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find, hexdump, Endian, FlatMemory, Memory, PagedMemory, PAGE_SIZE};
+    use crate::emulator::EmulatorErr;
+
+    #[test]
+    fn test_paged() {
+        // Large sizes are cheap as nothing is allocated upfront
+        let mut mem = PagedMemory::new(1 << 30);
+        let mut buf = [0xaau8; 8];
+        mem.read_bytes(1 << 29, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+        assert_eq!(mem.allocated_pages(), 0);
+
+        // Write spanning two pages
+        let at = PAGE_SIZE * 3 - 2;
+        mem.write_bytes(at, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(mem.allocated_pages(), 2);
+        let mut buf = [0u8; 6];
+        mem.read_bytes(at - 1, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 4, 0]);
+
+        // In place only within an allocated page
+        assert_eq!(mem.read_word(at - 2).unwrap(), 0x02010000);
+        assert_eq!(mem.bytes_mut(at, 2), Some(&mut [1, 2][..]));
+        assert_eq!(mem.bytes_mut(at, 4), None);
+        assert_eq!(mem.bytes_mut(0, 4), None);
+        mem.write_half(at + 4, 0xabcd).unwrap();
+        assert_eq!(mem.read_half(at + 4).unwrap(), 0xabcd);
+
+        let contents = mem.contents();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[1].0, PAGE_SIZE * 3);
+        assert_eq!(contents[1].1[..2], [3, 4]);
+        mem.clear();
+        assert_eq!(mem.allocated_pages(), 0);
+    }
+
+    #[test]
+    fn test_endian() {
+        let mut mem = FlatMemory::new(8);
+        mem.write_word(0, Endian::Big.word(0x12345678)).unwrap();
+        assert_eq!(mem.read_byte(0).unwrap(), 0x12);
+        assert_eq!(Endian::Big.half(mem.read_half(2).unwrap()), 0x5678);
+        assert_eq!(Endian::Big.word_bytes(0x12345678), [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(Endian::Little.word(0x12345678), 0x12345678);
+        assert_eq!("big".parse(), Ok(Endian::Big));
+        assert!("middle".parse::<Endian>().is_err());
+    }
+
+    #[test]
+    fn test_flat() {
+        let mut mem = FlatMemory::new(16);
+        mem.write_word(4, 0x12345678).unwrap();
+        assert_eq!(mem.read_byte(4).unwrap(), 0x78);
+        assert_eq!(mem.read_half(6).unwrap(), 0x1234);
+        mem.write_half(12, 0xabcd).unwrap();
+        assert_eq!(mem.read_word(12).unwrap(), 0xabcd);
+        assert_eq!(mem.bytes_mut(12, 2), Some(&mut [0xcd, 0xab][..]));
+        assert_eq!(mem.bytes_mut(14, 4), None);
+
+        assert!(matches!(
+            mem.read_word(14),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+        assert!(matches!(
+            mem.write_byte(16, 0),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+        assert!(matches!(
+            mem.read_byte(usize::MAX),
+            Err(EmulatorErr::InvalidMemAddr)
+        ));
+
+        // Only the non-zero parts are returned
+        let mut mem = FlatMemory::new(PAGE_SIZE * 2 + 8);
+        mem.write_byte(PAGE_SIZE * 2 + 1, 7).unwrap();
+        let contents = mem.contents();
+        assert_eq!(contents, [(PAGE_SIZE * 2, vec![0, 7, 0, 0, 0, 0, 0, 0])]);
+        mem.clear();
+        assert!(mem.contents().is_empty());
+    }
+
+    #[test]
+    fn test_find() {
+        let mut memory = PagedMemory::new(1 << 16);
+        memory.write_bytes(PAGE_SIZE - 2, b"abcd").unwrap();
+        memory.write_bytes(0x3000, b"abcdab").unwrap();
+        assert_eq!(find(&mut memory, b"abcd", 1), [PAGE_SIZE - 2, 0x3000]);
+        assert_eq!(find(&mut memory, b"ab", 2), [PAGE_SIZE - 2, 0x3000, 0x3004]);
+        assert_eq!(find(&mut memory, b"cd", 4), [PAGE_SIZE]);
+        assert!(find(&mut memory, b"abce", 1).is_empty());
+        assert!(find(&mut memory, b"", 1).is_empty());
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = (0x3c..0x50).collect();
+        assert_eq!(
+            hexdump(0x100, &bytes),
+            "00000100  3c 3d 3e 3f 40 41 42 43  44 45 46 47 48 49 4a 4b  |<=>?@ABCDEFGHIJK|\n\
+             00000110  4c 4d 4e 4f                                       |LMNO|\n"
+        );
+        assert_eq!(
+            hexdump(0, b"a\n\0 "),
+            format!("00000000  61 0a 00 20{}|a.. |\n", " ".repeat(39))
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Translation of virtual addresses with two-level page tables.
+//!
+//! Translation is enabled by setting bit 0 of the `PTBR` CSR, whose bits 12-31 contain
+//! the physical address of the page directory. An address is then split into:
+//! | Bits  | Contents                        |
+//! | ----- | ------------------------------- |
+//! | 22-31 | Index of the directory entry    |
+//! | 12-21 | Index of the page table entry   |
+//! | 0-11  | Offset in the 4 KiB page        |
+//!
+//! Both the directory and the page tables are pages of 1024 word sized entries, bits 12-31
+//! of an entry contain the physical address of the page table or of the page it maps.
+//! A directory entry only needs [`PTE_VALID`], the permissions are taken from the page
+//! table entry. The tables are read from physical memory without going through the caches,
+//! their entries are in the byte order of the emulator.
+
+use crate::{
+    emulator::EmulatorErr,
+    memory::{Endian, Memory},
+};
+
+pub const PAGE_BYTES: usize = 1 << 12;
+
+/// Bit 0 of `PTBR`, translation is enabled
+pub const ENABLE: u32 = 1 << 0;
+
+// Bits of the entries
+/// The entry maps a page table or a page, accesses through other entries fault
+pub const PTE_VALID: u32 = 1 << 0;
+/// The page can be written
+pub const PTE_WRITE: u32 = 1 << 1;
+/// The page can be accessed in user mode, otherwise only in supervisor mode
+pub const PTE_USER: u32 = 1 << 2;
+
+/// Physical address in bits 12-31 of a `PTBR` value or an entry
+const FRAME_MASK: u32 = !(PAGE_BYTES as u32 - 1);
+
+/// Returns the physical address for an access to `addr` with the page directory in `ptbr`,
+/// or [`EmulatorErr::PageFault`] if the page is not mapped or the access is not allowed.
+/// `user` is set for accesses made in user mode.
+pub fn translate<M: Memory>(
+    memory: &mut M,
+    endian: Endian,
+    ptbr: u32,
+    addr: usize,
+    write: bool,
+    user: bool,
+) -> Result<usize, EmulatorErr> {
+    let dir_entry = (ptbr & FRAME_MASK) as usize + 4 * ((addr >> 22) & 0x3ff);
+    let dir = endian.word(memory.read_word(dir_entry)?);
+    if dir & PTE_VALID == 0 {
+        return Err(EmulatorErr::PageFault);
+    }
+    let table_entry = (dir & FRAME_MASK) as usize + 4 * ((addr >> 12) & 0x3ff);
+    let pte = endian.word(memory.read_word(table_entry)?);
+    let allowed =
+        pte & PTE_VALID != 0 && (!write || pte & PTE_WRITE != 0) && (!user || pte & PTE_USER != 0);
+    match allowed {
+        true => Ok((pte & FRAME_MASK) as usize | (addr & (PAGE_BYTES - 1))),
+        false => Err(EmulatorErr::PageFault),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::EmulatorErr, memory::PagedMemory};
+
+    #[test]
+    fn test_translate() {
+        let mut memory = PagedMemory::new(1 << 16);
+        // Directory at 0x1000 whose entry 1 points to a table at 0x2000
+        memory.write_word(0x1000 + 4, 0x2000 | PTE_VALID).unwrap();
+        memory
+            .write_word(0x2000 + 4 * 3, 0x5000 | PTE_VALID | PTE_USER)
+            .unwrap();
+        memory
+            .write_word(0x2000 + 4 * 4, 0x6000 | PTE_VALID | PTE_WRITE)
+            .unwrap();
+        let ptbr = 0x1000 | ENABLE;
+        let page = |table: usize| (1 << 22) | (table << 12);
+
+        let mut translate =
+            |addr, write, user| translate(&mut memory, Endian::Little, ptbr, addr, write, user);
+        assert_eq!(translate(page(3) + 0x24, false, true).unwrap(), 0x5024);
+        assert_eq!(translate(page(4) + 0xffc, true, false).unwrap(), 0x6ffc);
+        let faults = [
+            (page(3), true, false),
+            (page(4), false, true),
+            (page(5), false, false),
+            (0x24, false, false),
+        ];
+        for (addr, write, user) in faults {
+            assert!(matches!(
+                translate(addr, write, user),
+                Err(EmulatorErr::PageFault)
+            ));
+        }
+    }
+}
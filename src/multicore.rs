@@ -0,0 +1,332 @@
+//! Several harts(hardware threads) running the same program with a shared memory.
+//!
+//! Each hart is an [`Emulator`] with its own registers, flags and CSRs, and reads its
+//! index from the `HARTID` CSR. A [`Schedule`] picks which hart runs next and for how
+//! many instructions, so an interleaving, along with the races it causes, can be
+//! reproduced. Instructions are never split between harts, which makes `swap` and `cas`
+//! atomic while a load followed by a store is not.
+//!
+//! A hart stops when its `pc` leaves the program, it uses the exit system call or runs out
+//! of its instruction budget, the others keep running. Breakpoints are not supported.
+//! Memory-mapped devices advance by one tick for each instruction of any hart.
+
+use crate::{
+    emulator::{Emulator, EmulatorConfig, EmulatorErr, ExitStatus, RunStatus},
+    memory::{Memory, PagedMemory},
+    program::Program,
+    rng::Rng,
+};
+use std::{cell::RefCell, fmt, rc::Rc, str::FromStr};
+
+/// Memory shared by the harts
+#[derive(Debug)]
+pub struct SharedMemory<M>(Rc<RefCell<M>>);
+
+impl<M> Clone for SharedMemory<M> {
+    fn clone(&self) -> Self {
+        Self(Rc::clone(&self.0))
+    }
+}
+
+impl<M: Memory> Memory for SharedMemory<M> {
+    fn size(&self) -> usize {
+        self.0.borrow().size()
+    }
+
+    fn read_bytes(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        self.0.borrow_mut().read_bytes(addr, buf)
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        self.0.borrow_mut().write_bytes(addr, bytes)
+    }
+
+    fn contents(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.0.borrow_mut().contents()
+    }
+
+    fn clear(&mut self) {
+        self.0.borrow_mut().clear()
+    }
+
+    fn is_mmio(&self, addr: usize, len: usize) -> bool {
+        self.0.borrow().is_mmio(addr, len)
+    }
+
+    fn tick(&mut self) {
+        self.0.borrow_mut().tick()
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.0.borrow_mut().advance(ticks)
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        self.0.borrow().idle_ticks()
+    }
+
+    fn pending_irqs(&self) -> u32 {
+        self.0.borrow().pending_irqs()
+    }
+}
+
+/// Order in which the harts run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// The harts run `quantum` instructions each in turn
+    RoundRobin { quantum: u64 },
+    /// A random hart runs between 1 and `max_quantum` instructions, the same seed always
+    /// gives the same interleaving
+    Random { seed: u64, max_quantum: u64 },
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::RoundRobin { quantum: 1 }
+    }
+}
+
+/// Parses `round-robin[:<quantum>]` or `random:<seed>[:<max_quantum>]`, both quanta
+/// default to 1
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid schedule '{}'", s);
+        let fields: Vec<&str> = s.split(':').collect();
+        let number = |i: usize, default: Option<u64>| match fields.get(i) {
+            Some(num) => num.parse().map_err(|_| err()),
+            None => default.ok_or_else(err),
+        };
+        let (schedule, max_fields) = match fields[0] {
+            "round-robin" => {
+                let quantum = number(1, Some(1))?;
+                (Self::RoundRobin { quantum }, 2)
+            }
+            "random" => {
+                let (seed, max_quantum) = (number(1, None)?, number(2, Some(1))?);
+                (Self::Random { seed, max_quantum }, 3)
+            }
+            _ => return Err(err()),
+        };
+        match fields.len() <= max_fields {
+            true => Ok(schedule),
+            false => Err(err()),
+        }
+    }
+}
+
+/// Error of one of the harts
+#[derive(Debug)]
+pub struct HartFault {
+    pub hart: usize,
+    pub err: EmulatorErr,
+}
+
+impl fmt::Display for HartFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hart {}: {}", self.hart, self.err)
+    }
+}
+
+impl std::error::Error for HartFault {}
+
+/// Harts running a program with a shared memory
+pub struct Multicore<M: Memory = PagedMemory> {
+    harts: Vec<Emulator<SharedMemory<M>>>,
+    /// How each hart stopped, `None` while it is running
+    stopped: Vec<Option<ExitStatus>>,
+    schedule: Schedule,
+    rng: Rng,
+    /// Hart which runs next with [`Schedule::RoundRobin`]
+    next: usize,
+}
+
+impl Multicore {
+    pub fn new(instructions: &[u32], harts: usize, schedule: Schedule) -> Self {
+        Self::with_config(instructions, harts, EmulatorConfig::default(), schedule)
+    }
+
+    pub fn with_config(
+        instructions: &[u32],
+        harts: usize,
+        config: EmulatorConfig,
+        schedule: Schedule,
+    ) -> Self {
+        let memory = PagedMemory::new(config.mem_bytes);
+        Self::with_memory_and_config(instructions, harts, memory, config, schedule)
+    }
+}
+
+impl<M: Memory> Multicore<M> {
+    /// Creates `harts` harts sharing `memory`, each configured with `config` except for
+    /// `mem_bytes` which is ignored
+    pub fn with_memory_and_config(
+        instructions: &[u32],
+        harts: usize,
+        memory: M,
+        config: EmulatorConfig,
+        schedule: Schedule,
+    ) -> Self {
+        assert!(harts > 0, "There must be at least one hart");
+        let memory = SharedMemory(Rc::new(RefCell::new(memory)));
+        let harts: Vec<_> = (0..harts)
+            .map(|hart| {
+                let mut emul =
+                    Emulator::with_memory_and_config(instructions, memory.clone(), config.clone());
+                emul.set_hart_id(hart as u32);
+                emul
+            })
+            .collect();
+        let seed = match schedule {
+            Schedule::Random { seed, .. } => seed,
+            Schedule::RoundRobin { .. } => 0,
+        };
+        Self {
+            stopped: vec![None; harts.len()],
+            harts,
+            schedule,
+            rng: Rng::new(seed),
+            next: 0,
+        }
+    }
+
+    pub fn harts(&self) -> &[Emulator<SharedMemory<M>>] {
+        &self.harts
+    }
+
+    /// The emulator of `hart`, which can also access the shared memory
+    pub fn hart_mut(&mut self, hart: usize) -> &mut Emulator<SharedMemory<M>> {
+        &mut self.harts[hart]
+    }
+
+    /// How each hart stopped, `None` for the ones still running
+    pub fn exit_statuses(&self) -> &[Option<ExitStatus>] {
+        &self.stopped
+    }
+
+    /// Replaces the program of every hart like [`Emulator::load`], its data is copied to
+    /// the shared memory
+    pub fn load(&mut self, program: &Program) -> Result<(), EmulatorErr> {
+        for hart in &mut self.harts {
+            hart.load(program)?;
+        }
+        self.stopped.fill(None);
+        Ok(())
+    }
+
+    /// Instructions executed by all the harts
+    pub fn steps(&self) -> u64 {
+        self.harts.iter().map(|hart| hart.steps()).sum()
+    }
+
+    /// Runs the harts until all of them stop and returns how each one stopped
+    pub fn exec(&mut self) -> Result<Vec<ExitStatus>, HartFault> {
+        while let Some((hart, quantum)) = self.pick() {
+            match self.harts[hart].run_for(quantum) {
+                Ok(RunStatus::Stopped(status)) => self.stopped[hart] = Some(status),
+                Ok(RunStatus::Running | RunStatus::Break(_)) => {}
+                Err(err) => return Err(HartFault { hart, err }),
+            }
+        }
+        Ok(self.stopped.iter().flatten().copied().collect())
+    }
+
+    /// Returns the hart to run next and how many instructions it runs,
+    /// `None` once all of them stopped
+    fn pick(&mut self) -> Option<(usize, u64)> {
+        let running: Vec<usize> = (0..self.harts.len())
+            .filter(|&hart| self.stopped[hart].is_none())
+            .collect();
+        if running.is_empty() {
+            return None;
+        }
+        match self.schedule {
+            Schedule::RoundRobin { quantum } => {
+                let hart = running
+                    .iter()
+                    .copied()
+                    .find(|&hart| hart >= self.next)
+                    .unwrap_or(running[0]);
+                self.next = hart + 1;
+                Some((hart, quantum.max(1)))
+            }
+            Schedule::Random { max_quantum, .. } => {
+                let hart = running[(self.rng.next_u64() % running.len() as u64) as usize];
+                let quantum = 1 + self.rng.next_u64() % max_quantum.max(1);
+                Some((hart, quantum))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Multicore, Schedule};
+    use crate::{emulator::ExitStatus, parser::parse_and_assemble};
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(
+            "round-robin".parse(),
+            Ok(Schedule::RoundRobin { quantum: 1 })
+        );
+        assert_eq!(
+            "round-robin:10".parse(),
+            Ok(Schedule::RoundRobin { quantum: 10 })
+        );
+        assert_eq!(
+            "random:7".parse(),
+            Ok(Schedule::Random {
+                seed: 7,
+                max_quantum: 1
+            })
+        );
+        assert_eq!(
+            "random:7:4".parse(),
+            Ok(Schedule::Random {
+                seed: 7,
+                max_quantum: 4
+            })
+        );
+        assert!("random".parse::<Schedule>().is_err());
+        assert!("round-robin:x".parse::<Schedule>().is_err());
+        assert!("random:1:2:3".parse::<Schedule>().is_err());
+        assert!("round-robin:1:2".parse::<Schedule>().is_err());
+        assert!("fifo".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_hart_ids() {
+        // Each hart stores its index at 4 * index, the last one exits with it
+        let code = "
+            csrr r1, 5
+            lsl r2, r1, 2
+            st r1, 0[r2]
+            cmp r1, 2
+            bgt end
+            beq exit
+            b end
+        exit:
+            mov r0, 3
+            sys
+        end:
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        let mut multicore = Multicore::new(&bin, 3, Schedule::default());
+        let statuses = multicore.exec().unwrap();
+        assert_eq!(multicore.steps(), 7 + 7 + 8);
+        assert_eq!(
+            statuses,
+            [
+                ExitStatus::Finished,
+                ExitStatus::Finished,
+                ExitStatus::Exited(2)
+            ]
+        );
+        for hart in 0..3 {
+            let value = multicore.hart_mut(0).read_word(4 * hart as usize).unwrap();
+            assert_eq!(value, hart);
+        }
+    }
+}
@@ -1,5 +1,14 @@
-use crate::info::{self, bits, Instruction, Opcode};
-use std::{collections::HashMap, fmt, num::IntErrorKind};
+use crate::{
+    emulator::Xlen,
+    info::{self, bits, Instruction, Opcode},
+    memory::Endian,
+    program::{Program, DATA_BASE},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::IntErrorKind,
+};
 
 const REGISTERS: [(&str, u8); 17] = [
     ("r0", 0),
@@ -21,50 +30,115 @@ const REGISTERS: [(&str, u8); 17] = [
     ("r15", 15),
 ];
 
+/// An error encountered while parsing or assembling the source code
 #[derive(Debug, PartialEq, Eq)]
 pub struct ParseErr {
     kind: ErrKind,
     line: usize,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum ErrKind {
-    IllegalModifier,
-    ImmOverflow,
-    InvalidImm,
+impl ParseErr {
+    /// The category of the error along with the offending lexeme, if any
+    pub fn kind(&self) -> &ErrKind {
+        &self.kind
+    }
+
+    /// Line number(starting from 1) at which the error was detected
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+/// Kinds of errors reported by the assembler.
+/// `String` fields hold the offending lexeme as it appears in the source,
+/// it is empty if the error was caused by the end of input.
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ErrKind {
+    IllegalModifier(String),
+    /// Immediate operand of an instruction which only takes registers
+    IllegalImm(String),
+    ImmOverflow(String),
+    InvalidImm(String),
     OpenComment,
-    RegExp,
-    ImmBrackExp,
-    OperandExp,
-    IdentExp,
-    IllegalToken,
-    CharExp(char),
-    DuplicateLabel(String),
+    RegExp(String),
+    ImmBrackExp(String),
+    OperandExp(String),
+    IdentExp(String),
+    IllegalToken(String),
+    /// Expected character and the lexeme found instead
+    CharExp(char, String),
+    /// Label along with the line of its first definition
+    DuplicateLabel(String, usize),
     UndefinedLabel(String),
+    /// Symbol defined using `.equ` or used as both a label and a constant
+    DuplicateSymbol(String),
+    UndefinedSymbol(String),
+    /// Symbol whose value depends on itself
+    RecursiveSymbol(String),
+    OpenString,
+    InvalidEscape(String),
+    StringExp(String),
+    /// Instruction in the data section or data in the text section
+    WrongSection(String),
+    /// Branch target is a label in the data section
+    DataLabel(String),
+    /// Address of a literal pool entry does not fit in an immediate
+    PoolOverflow,
+    /// Label too far from the branch for its offset field
+    BranchOverflow(String),
+    /// Instruction with the 'w' modifier in a program which is not for the 64-bit mode
+    WideModifier(String),
 }
 
 impl std::error::Error for ParseErr {}
 
 impl fmt::Display for ParseErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.kind {
-            ErrKind::UndefinedLabel(_) => write!(f, ""),
-            _ => write!(f, "On line {}: ", self.line),
-        }?;
+        write!(f, "On line {}: ", self.line)?;
 
         match &self.kind {
-            ErrKind::IllegalModifier => write!(f, "Modifier not allowed"),
-            ErrKind::ImmOverflow => write!(f, "Immediate out of range(overflow)"),
-            ErrKind::InvalidImm => write!(f, "Invalid immediate"),
+            ErrKind::IllegalModifier(s) => write!(f, "Modifier not allowed in '{s}'"),
+            ErrKind::IllegalImm(s) => write!(f, "Immediate not allowed in '{s}'"),
+            ErrKind::ImmOverflow(s) => write!(f, "Immediate out of range(overflow) '{s}'"),
+            ErrKind::InvalidImm(s) => write!(f, "Invalid immediate '{s}'"),
             ErrKind::OpenComment => write!(f, "Comment not closed"),
-            ErrKind::IllegalToken => write!(f, "Token not expected by any rule"),
-            ErrKind::RegExp => write!(f, "Register Expected"),
-            ErrKind::ImmBrackExp => write!(f, "Immediate or '[' Expected"),
-            ErrKind::OperandExp => write!(f, "Immediate or register expected"),
-            ErrKind::IdentExp => write!(f, "Label expected"),
-            ErrKind::CharExp(c) => write!(f, "Character '{c}' expected"),
-            ErrKind::DuplicateLabel(s) => write!(f, "Duplicate label '{s}'"),
+            ErrKind::IllegalToken(s) => write!(f, "Token not expected by any rule{}", Found(s)),
+            ErrKind::RegExp(s) => write!(f, "Register Expected{}", Found(s)),
+            ErrKind::ImmBrackExp(s) => write!(f, "Immediate or '[' Expected{}", Found(s)),
+            ErrKind::OperandExp(s) => write!(f, "Immediate or register expected{}", Found(s)),
+            ErrKind::IdentExp(s) => write!(f, "Label expected{}", Found(s)),
+            ErrKind::CharExp(c, s) => {
+                write!(f, "Character '{}' expected{}", c.escape_debug(), Found(s))
+            }
+            ErrKind::DuplicateLabel(s, first) => {
+                write!(f, "Duplicate label '{s}', first defined on line {first}")
+            }
             ErrKind::UndefinedLabel(s) => write!(f, "Label not found '{s}'"),
+            ErrKind::DuplicateSymbol(s) => write!(f, "Symbol '{s}' cannot be redefined"),
+            ErrKind::UndefinedSymbol(s) => write!(f, "Symbol not found '{s}'"),
+            ErrKind::RecursiveSymbol(s) => write!(f, "Symbol '{s}' is defined in terms of itself"),
+            ErrKind::OpenString => write!(f, "String not closed"),
+            ErrKind::InvalidEscape(s) => write!(f, "Invalid escape sequence '{s}'"),
+            ErrKind::StringExp(s) => write!(f, "String expected{}", Found(s)),
+            ErrKind::WrongSection(s) => write!(f, "'{s}' not allowed in the current section"),
+            ErrKind::DataLabel(s) => write!(f, "Label '{s}' does not refer to an instruction"),
+            ErrKind::PoolOverflow => write!(f, "Literal pool is beyond the addressable range"),
+            ErrKind::BranchOverflow(s) => write!(f, "Label '{s}' is too far from the branch"),
+            ErrKind::WideModifier(s) => write!(f, "'{s}' is only allowed in 64-bit mode"),
+        }
+    }
+}
+
+/// Formats the lexeme found in place of the expected one
+struct Found<'a>(&'a str);
+
+impl fmt::Display for Found<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, ", found end of input")
+        } else {
+            write!(f, ", found '{}'", self.0.escape_debug())
         }
     }
 }
@@ -75,42 +149,53 @@ enum Token {
     Ident(String),
     Inst(Instruction),
     Reg(u8),
-    Imm(u16),
+    /// Integer literal, 32-bit wide. Range for operands is checked by the parser.
+    Imm(i32),
+    /// String literal with escape sequences already processed
+    Str(Vec<u8>),
     Char(char),
 }
 
-impl Token {
-    fn try_reg(self) -> Result<u8, ErrKind> {
-        if let Self::Reg(reg) = self {
-            Ok(reg)
-        } else {
-            Err(ErrKind::RegExp)
-        }
-    }
-
-    fn try_ident(self) -> Result<String, ErrKind> {
-        if let Self::Ident(ident) = self {
-            Ok(ident)
-        } else {
-            Err(ErrKind::IdentExp)
-        }
-    }
-
-    fn try_the_char(self, mc: char) -> Result<char, ErrKind> {
-        if let Self::Char(c) = self {
-            if c == mc {
-                return Ok(mc);
-            }
-        }
-        Err(ErrKind::CharExp(mc))
-    }
-}
-
 #[derive(Debug, PartialEq)]
 enum Operand {
     Label(String),
     Imm(u16),
     Reg(u8),
+    /// Immediate referring to symbols defined later, along with its source text
+    Expr(Expr, String),
+    /// Address of the literal pool entry with the given index
+    Literal(usize),
+}
+
+/// An expression with the values of already defined symbols folded into `constant`
+#[derive(Debug, PartialEq, Default)]
+struct Expr {
+    constant: i32,
+    /// Symbols whose value is not known yet, paired with true if negated
+    pending: Vec<(bool, String)>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// Location of a label, `at` is a statement index for text
+/// and a byte offset for data
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    section: Section,
+    at: usize,
+    /// Source line of the definition
+    line: usize,
+}
+
+/// A constant defined using the `.equ` or `.set` directive
+struct Symbol {
+    value: Expr,
+    redefinable: bool,
+    line: usize,
 }
 
 struct Statement {
@@ -118,6 +203,7 @@ struct Statement {
     dst: u8,
     src1: u8,
     src2: Operand,
+    line: usize,
 }
 
 struct Scanner<'a> {
@@ -180,9 +266,17 @@ impl<'a> Scanner<'a> {
         let mut iter = self.left.chars();
         let ret = iter.next();
         self.left = iter.as_str();
+        if let Some(ch) = ret {
+            self.update_cursor(ch);
+        }
         ret
     }
 
+    /// Returns the input consumed since `start` was the remaining input
+    fn consumed_since(&self, start: &'a str) -> &'a str {
+        &start[..start.len() - self.left.len()]
+    }
+
     fn update_cursor(&mut self, ch: char) {
         self.cursor += 1;
         self.col += 1;
@@ -195,58 +289,184 @@ impl<'a> Scanner<'a> {
 
 struct Parser<'a> {
     scn: Scanner<'a>,
-    labels: HashMap<String, usize>,
+    labels: HashMap<String, Label>,
+    symbols: HashMap<String, Symbol>,
     stmt_cnt: usize,
+    section: Section,
+    data: Vec<u8>,
+    /// Values for the literal pool along with the line they are used on
+    literals: Vec<(Expr, usize)>,
+    /// Byte order of the words in the data section
+    endian: Endian,
+    /// Mode the program is for, the 'w' modifier is only allowed in 64-bit mode
+    xlen: Xlen,
+    /// Remaining input at the start of the last token returned by `next_tok`
+    tok_start: &'a str,
+    /// Source text of the last token returned by `next_tok`
+    lexeme: &'a str,
+    /// Line on which the last token(or comment) starts
+    tok_line: usize,
 }
 
 impl<'a> Parser<'a> {
-    fn new(code: &'a str) -> Self {
+    fn new(code: &'a str, endian: Endian, xlen: Xlen) -> Self {
         Self {
             scn: Scanner::new(code),
             labels: HashMap::new(),
+            symbols: HashMap::new(),
             stmt_cnt: 0,
+            section: Section::Text,
+            data: Vec::new(),
+            literals: Vec::new(),
+            endian,
+            xlen,
+            tok_start: code,
+            lexeme: "",
+            tok_line: 1,
         }
     }
 
     fn line_num(&self) -> usize {
-        self.scn.line
+        self.tok_line
     }
 
-    fn parse(&mut self) -> Result<Vec<u32>, ErrKind> {
+    fn parse(&mut self) -> Result<Program, ErrKind> {
         let mut stmts: Vec<Statement> = Vec::new();
 
         loop {
             match self.next_tok()? {
+                Token::Ident(ident) if is_directive(&ident) => self.directive(&ident)?,
                 Token::Ident(ident) => {
-                    self.next_tok()?.try_the_char(':')?;
-                    if self.labels.contains_key(&ident) {
-                        return Err(ErrKind::DuplicateLabel(ident));
+                    let line = self.tok_line;
+                    self.expect_char(':')?;
+                    if let Some(first) = self.labels.get(&ident) {
+                        return Err(ErrKind::DuplicateLabel(ident, first.line));
+                    }
+                    if self.symbols.contains_key(&ident) {
+                        return Err(ErrKind::DuplicateSymbol(ident));
                     }
-                    self.labels.insert(ident, self.stmt_cnt);
+                    let at = match self.section {
+                        Section::Text => self.stmt_cnt,
+                        Section::Data => DATA_BASE + self.data.len(),
+                    };
+                    let section = self.section;
+                    self.labels.insert(ident, Label { section, at, line });
                 }
-                Token::Inst(inst) => stmts.push(self.make_statement(inst)?),
+                Token::Inst(_) if self.section != Section::Text => {
+                    return Err(ErrKind::WrongSection(self.lexeme()))
+                }
+                Token::Inst(inst) => self.make_statement(inst, &mut stmts)?,
                 Token::Char('\n') => { /* Ignore extra newlines */ }
                 Token::Eof => break,
-                _ => return Err(ErrKind::IllegalToken),
+                _ => return Err(ErrKind::IllegalToken(self.lexeme())),
+            };
+        }
+        self.check_symbols()?;
+        let pool_at = self.emit_literal_pool()?;
+        let lines = stmts.iter().map(|stmt| stmt.line).collect();
+        let text = self.assemble(stmts, pool_at)?;
+        let labels = self
+            .labels
+            .iter()
+            .filter(|(_, label)| label.section == Section::Text)
+            .map(|(name, label)| (name.clone(), label.at))
+            .collect::<BTreeMap<_, _>>();
+        Ok(Program {
+            text,
+            data: std::mem::take(&mut self.data),
+            entry: labels.get("_start").copied().unwrap_or(0),
+            lines,
+            labels,
+        })
+    }
+
+    /// Verifies that every symbol can be evaluated once all of them are defined
+    fn check_symbols(&mut self) -> Result<(), ErrKind> {
+        let mut names: Vec<(usize, &String)> =
+            self.symbols.iter().map(|(k, v)| (v.line, k)).collect();
+        names.sort();
+
+        for (line, name) in names {
+            if let Err(e) = self.evaluate(&self.symbols[name].value, &mut vec![name]) {
+                self.tok_line = line;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the value of `expr`, `visiting` holds the symbols being evaluated
+    fn evaluate<'s>(
+        &'s self,
+        expr: &'s Expr,
+        visiting: &mut Vec<&'s String>,
+    ) -> Result<i32, ErrKind> {
+        let mut value = expr.constant;
+
+        for (is_neg, name) in &expr.pending {
+            let term = if let Some(sym) = self.symbols.get(name) {
+                if visiting.contains(&name) {
+                    return Err(ErrKind::RecursiveSymbol(name.clone()));
+                }
+                visiting.push(name);
+                let term = self.evaluate(&sym.value, visiting)?;
+                visiting.pop();
+                term
+            } else if let Some(label) = self.labels.get(name) {
+                label.at as i32
+            } else {
+                return Err(ErrKind::UndefinedSymbol(name.clone()));
+            };
+            value = if *is_neg {
+                value.wrapping_sub(term)
+            } else {
+                value.wrapping_add(term)
             };
         }
-        self.assemble(stmts)
+        Ok(value)
     }
 
-    fn assemble(&self, stmts: Vec<Statement>) -> Result<Vec<u32>, ErrKind> {
+    /// Places the literal pool after the data aligned to 4 bytes and returns its address.
+    /// Nothing is added to the data if the pool is empty.
+    fn emit_literal_pool(&mut self) -> Result<usize, ErrKind> {
+        let offset = self.data.len().next_multiple_of(4);
+        let pool_at = DATA_BASE + offset;
+        if self.literals.is_empty() {
+            return Ok(pool_at);
+        }
+        self.data.resize(offset, 0);
+
+        for (expr, line) in &self.literals {
+            self.tok_line = *line;
+            let value = self.evaluate(expr, &mut Vec::new())?;
+            self.data.extend(self.endian.word_bytes(value as u32));
+        }
+        Ok(pool_at)
+    }
+
+    fn assemble(&mut self, stmts: Vec<Statement>, pool_at: usize) -> Result<Vec<u32>, ErrKind> {
         let mut ret: Vec<u32> = Vec::new();
 
         for Statement {
             inst,
             dst,
             src1,
-            src2,
+            mut src2,
+            line,
         } in stmts
         {
+            // Errors are reported on the line of the statement
+            self.tok_line = line;
+            if let Operand::Expr(expr, text) = src2 {
+                src2 = imm16(self.evaluate(&expr, &mut Vec::new())?, &text)?;
+            } else if let Operand::Literal(idx) = src2 {
+                let addr = pool_at + idx * 4;
+                src2 = Operand::Imm(u16::try_from(addr).map_err(|_| ErrKind::PoolOverflow)?);
+            }
             let tmp = match (inst.ndst, inst.nsrc) {
                 (1, 2) | (1, 1) | (0, 2) => encode_rrx(inst.opcode, dst, src1, inst.modbits, src2),
-                (0, 1) => encode_label(inst.opcode, self.get_label_index(src2)?, ret.len()),
-                (0, 0) => (inst.opcode as u32) << bits::OPCODE_OFF,
+                (0, 1) => self.encode_label(inst.opcode, src2, ret.len())?,
+                (0, 0) => info::encode_opcode(inst.opcode as u8),
                 (_, _) => panic!("Unsupported addressing mode for '{}'", inst.name),
             };
             ret.push(tmp);
@@ -254,15 +474,27 @@ impl<'a> Parser<'a> {
         Ok(ret)
     }
 
-    fn get_label_index(&self, label_op: Operand) -> Result<usize, ErrKind> {
+    /// Encodes the branch at `cur_at` to the label `label_op`
+    fn encode_label(
+        &self,
+        opcode: Opcode,
+        label_op: Operand,
+        cur_at: usize,
+    ) -> Result<u32, ErrKind> {
         if let Operand::Label(ident) = label_op {
-            if let Some(&label_at) = self.labels.get(&ident) {
-                Ok(label_at)
-            } else {
-                Err(ErrKind::UndefinedLabel(ident))
-            }
+            let label_at = match self.labels.get(&ident) {
+                Some(&Label {
+                    section: Section::Text,
+                    at,
+                    ..
+                }) => at,
+                Some(_) => return Err(ErrKind::DataLabel(ident)),
+                None => return Err(ErrKind::UndefinedLabel(ident)),
+            };
+            let offset = label_at as i32 - cur_at as i32;
+            info::encode_branch(opcode as u8, offset).ok_or(ErrKind::BranchOverflow(ident))
         } else {
-            panic!("Non-label operand passed to get_label_pos");
+            panic!("Non-label operand passed to encode_label");
         }
     }
 
@@ -272,91 +504,322 @@ impl<'a> Parser<'a> {
                 self.scn.next();
                 continue;
             }
+            self.tok_line = self.scn.line;
             if c == '@' || c == '/' {
                 eat_comment(&mut self.scn)?;
                 continue;
             }
 
-            return match c {
+            let start = self.scn.left;
+            self.tok_start = start;
+            let tok = match c {
                 '+' | '-' | '0'..='9' => immediate(&mut self.scn),
+                '"' => string(&mut self.scn),
+                'r' if self.scn.peekn(1) == Some('"') => string(&mut self.scn),
                 c if is_ident_char(c) => identifier(&mut self.scn),
                 c => {
                     self.scn.next();
                     Ok(Token::Char(c))
                 }
             };
+            self.lexeme = self.scn.consumed_since(start);
+            return tok;
         }
+        self.tok_start = self.scn.left;
+        self.lexeme = "";
+        self.tok_line = self.scn.line;
         Ok(Token::Eof)
     }
 
-    fn make_statement(&mut self, inst: Instruction) -> Result<Statement, ErrKind> {
+    fn directive(&mut self, name: &str) -> Result<(), ErrKind> {
+        match name {
+            ".equ" | ".set" => self.define_symbol(name == ".set")?,
+            ".text" => self.section = Section::Text,
+            ".data" => self.section = Section::Data,
+            // := (".ascii" | ".asciz") string
+            ".ascii" | ".asciz" => {
+                if self.section != Section::Data {
+                    return Err(ErrKind::WrongSection(String::from(name)));
+                }
+                match self.next_tok()? {
+                    Token::Str(bytes) => self.data.extend(bytes),
+                    _ => return Err(ErrKind::StringExp(self.lexeme())),
+                }
+                if name == ".asciz" {
+                    self.data.push(0);
+                }
+            }
+            _ => panic!("Unknown directive '{name}'"),
+        }
+        self.expect_char('\n')?;
+        Ok(())
+    }
+
+    /// := ".equ" ident ',' expr
+    ///  | ".set" ident ',' expr
+    fn define_symbol(&mut self, redefinable: bool) -> Result<(), ErrKind> {
+        let ident = self.expect_ident()?;
+        self.expect_char(',')?;
+        let line = self.tok_line;
+        let first = self.next_tok()?;
+        let value = self.expression(first)?;
+
+        if self.labels.contains_key(&ident) {
+            return Err(ErrKind::DuplicateSymbol(ident));
+        }
+        if let Some(sym) = self.symbols.get(&ident) {
+            if !(sym.redefinable && redefinable) {
+                return Err(ErrKind::DuplicateSymbol(ident));
+            }
+        }
+        self.symbols.insert(
+            ident,
+            Symbol {
+                value,
+                redefinable,
+                line,
+            },
+        );
+        Ok(())
+    }
+
+    /// Parses an expression whose first token `first` has already been read.
+    /// Arithmetic is 32-bit wrap-around.
+    ///
+    /// := term (('+' | '-') term)*
+    /// term := imm | ident
+    fn expression(&mut self, first: Token) -> Result<Expr, ErrKind> {
+        let mut expr = Expr::default();
+        self.add_term(&mut expr, false, first)?;
+
+        loop {
+            self.scn.take_while(|c| c == ' ' || c == '\t');
+            let is_neg = match self.scn.peek() {
+                Some('+') => false,
+                Some('-') => true,
+                _ => break,
+            };
+            self.scn.next();
+            let tok = self.next_tok()?;
+            self.add_term(&mut expr, is_neg, tok)?;
+        }
+        Ok(expr)
+    }
+
+    /// Adds the term to `expr`, if its value is not known yet then it is left pending
+    fn add_term(&self, expr: &mut Expr, is_neg: bool, tok: Token) -> Result<(), ErrKind> {
+        let value = match tok {
+            Token::Imm(imm) => imm,
+            Token::Ident(ident) => match self.known_value(&ident) {
+                Some(value) => value,
+                None => {
+                    expr.pending.push((is_neg, ident));
+                    return Ok(());
+                }
+            },
+            _ => return Err(ErrKind::OperandExp(self.lexeme())),
+        };
+        expr.constant = if is_neg {
+            expr.constant.wrapping_sub(value)
+        } else {
+            expr.constant.wrapping_add(value)
+        };
+        Ok(())
+    }
+
+    /// Value of a symbol or label if it has been defined and can be evaluated now.
+    /// Labels evaluate to the index of the statement they are attached to.
+    fn known_value(&self, ident: &str) -> Option<i32> {
+        if let Some(sym) = self.symbols.get(ident) {
+            sym.value.pending.is_empty().then_some(sym.value.constant)
+        } else {
+            self.labels.get(ident).map(|label| label.at as i32)
+        }
+    }
+
+    /// Parses an immediate operand starting with `first`.
+    /// Checks that it fits in 16-bits if its value is already known.
+    fn immediate_operand(&mut self, first: Token) -> Result<Operand, ErrKind> {
+        let start = self.tok_start;
+        let expr = self.expression(first)?;
+        let text = self.scn.consumed_since(start).trim_end();
+
+        if expr.pending.is_empty() {
+            imm16(expr.constant, text)
+        } else {
+            Ok(Operand::Expr(expr, String::from(text)))
+        }
+    }
+
+    /// Returns the source text of the last token as an owned string
+    fn lexeme(&self) -> String {
+        String::from(self.lexeme)
+    }
+
+    fn expect_reg(&mut self) -> Result<u8, ErrKind> {
+        match self.next_tok()? {
+            Token::Reg(reg) => Ok(reg),
+            _ => Err(ErrKind::RegExp(self.lexeme())),
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, ErrKind> {
+        match self.next_tok()? {
+            Token::Ident(ident) => Ok(ident),
+            _ => Err(ErrKind::IdentExp(self.lexeme())),
+        }
+    }
+
+    fn expect_char(&mut self, mc: char) -> Result<char, ErrKind> {
+        match self.next_tok()? {
+            Token::Char(c) if c == mc => Ok(mc),
+            _ => Err(ErrKind::CharExp(mc, self.lexeme())),
+        }
+    }
+
+    fn make_statement(
+        &mut self,
+        mut inst: Instruction,
+        stmts: &mut Vec<Statement>,
+    ) -> Result<(), ErrKind> {
+        let mnemonic = self.lexeme;
+        let line = self.tok_line;
+        if inst.modbits == bits::MOD_W && self.xlen != Xlen::X64 {
+            return Err(ErrKind::WideModifier(String::from(mnemonic)));
+        }
         let (mut dst, mut src1, mut src2) = (0u8, 0u8, Operand::Reg(0));
-        let is_ldst = matches!(inst.opcode, Opcode::LD | Opcode::ST);
+        let is_ldst = info::is_mem_access(inst.opcode as u8);
+        let is_atomic = info::is_atomic(inst.opcode as u8);
         // Label only instructions take only one source and no destination
         let is_op2_label = inst.ndst == 0 && inst.nsrc == 1;
 
         // := reg
         //  | reg ',' # If at least one source operand
         if inst.ndst == 1 {
-            dst = self.next_tok()?.try_reg()?;
+            dst = self.expect_reg()?;
             if inst.nsrc > 0 {
-                self.next_tok()?.try_the_char(',')?;
+                self.expect_char(',')?;
             }
         }
         // := imm? '[' reg ']'
+        //  | '=' expr  # Only for ld, loads the value from the literal pool
         if is_ldst {
             match self.next_tok()? {
-                Token::Imm(imm) => {
-                    src2 = Operand::Imm(imm);
-                    self.next_tok()?.try_the_char('[')?;
+                Token::Char('=') if inst.opcode == Opcode::LD => {
+                    let first = self.next_tok()?;
+                    let expr = self.expression(first)?;
+                    self.expect_char('\n')?;
+                    self.literal_load(dst, expr, line, stmts);
+                    return Ok(());
+                }
+                tok @ (Token::Imm(_) | Token::Ident(_)) => {
+                    src2 = self.immediate_operand(tok)?;
+                    self.expect_char('[')?;
                 }
                 // If immediate is omitted, then it is 0
                 Token::Char('[') => src2 = Operand::Imm(0),
-                _ => return Err(ErrKind::ImmBrackExp),
+                _ => return Err(ErrKind::ImmBrackExp(self.lexeme())),
             }
-            src1 = self.next_tok()?.try_reg()?;
-            self.next_tok()?.try_the_char(']')?;
+            src1 = self.expect_reg()?;
+            self.expect_char(']')?;
+        }
+        // := (reg ',')? '[' reg ']'  # The register is the second source
+        else if is_atomic {
+            if inst.nsrc == 2 {
+                src2 = Operand::Reg(self.expect_reg()?);
+                self.expect_char(',')?;
+            }
+            self.expect_char('[')?;
+            src1 = self.expect_reg()?;
+            self.expect_char(']')?;
         }
         // := ident
         else if is_op2_label {
-            src2 = Operand::Label(self.next_tok()?.try_ident()?);
+            src2 = Operand::Label(self.expect_ident()?);
         }
         // := reg
         //  | imm
         else if inst.nsrc == 1 {
             src2 = match self.next_tok()? {
                 Token::Reg(reg) => Operand::Reg(reg),
-                Token::Imm(imm) => Operand::Imm(imm),
-                _ => return Err(ErrKind::OperandExp),
+                tok @ (Token::Imm(_) | Token::Ident(_)) => self.immediate_operand(tok)?,
+                _ => return Err(ErrKind::OperandExp(self.lexeme())),
             };
         }
         // := reg ',' reg
         //  | reg ',' imm
         else if inst.nsrc == 2 {
-            src1 = self.next_tok()?.try_reg()?;
-            self.next_tok()?.try_the_char(',')?;
+            src1 = self.expect_reg()?;
+            self.expect_char(',')?;
             src2 = match self.next_tok()? {
                 Token::Reg(reg) => Operand::Reg(reg),
-                Token::Imm(imm) => Operand::Imm(imm),
-                _ => return Err(ErrKind::OperandExp),
+                tok @ (Token::Imm(_) | Token::Ident(_)) => self.immediate_operand(tok)?,
+                _ => return Err(ErrKind::OperandExp(self.lexeme())),
             };
         }
         // If operand is not immediate and modifier is present, then error
-        if let Operand::Imm(_) = src2 {
-            // Operand is immediate, fine
+        if let Operand::Imm(_) | Operand::Expr(..) = src2 {
+            if !info::supports_imm(inst.opcode as u8) {
+                // `divu r1, r2, 3` is `div` with the 'u' modifier, not the extended `divu`
+                inst = modifier_form(mnemonic)
+                    .filter(|ins| (ins.ndst, ins.nsrc) == (inst.ndst, inst.nsrc))
+                    .ok_or_else(|| ErrKind::IllegalImm(String::from(mnemonic)))?;
+            }
         } else if inst.modbits != bits::MOD_DEF {
-            return Err(ErrKind::IllegalModifier);
+            return Err(ErrKind::IllegalModifier(String::from(mnemonic)));
         }
         // Each statement is terminated by a newline
-        self.next_tok()?.try_the_char('\n')?;
+        self.expect_char('\n')?;
         self.stmt_cnt += 1;
 
-        Ok(Statement {
+        stmts.push(Statement {
             inst,
             dst,
             src1,
             src2,
-        })
+            line,
+        });
+        Ok(())
+    }
+
+    /// Expands `ld reg, =expr` into `movu reg, entry_addr` and `ld reg, 0[reg]`,
+    /// identical constants share a pool entry
+    fn literal_load(&mut self, dst: u8, expr: Expr, line: usize, stmts: &mut Vec<Statement>) {
+        let existing = self
+            .literals
+            .iter()
+            .position(|(lit, _)| expr.pending.is_empty() && *lit == expr);
+        let idx = existing.unwrap_or_else(|| {
+            self.literals.push((expr, line));
+            self.literals.len() - 1
+        });
+
+        let mut movu = info::INSTRUCTIONS[Opcode::MOV as usize];
+        movu.modbits = bits::MOD_U;
+        stmts.push(Statement {
+            inst: movu,
+            dst,
+            src1: 0,
+            src2: Operand::Literal(idx),
+            line,
+        });
+        stmts.push(Statement {
+            inst: info::INSTRUCTIONS[Opcode::LD as usize],
+            dst,
+            src1: dst,
+            src2: Operand::Imm(0),
+            line,
+        });
+        self.stmt_cnt += 2;
+    }
+}
+
+/// Converts `value` to an immediate operand if it lies in `[-32768, 65535]`
+fn imm16(value: i32, text: &str) -> Result<Operand, ErrKind> {
+    if (i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+        Ok(Operand::Imm(value as u16))
+    } else {
+        Err(ErrKind::ImmOverflow(String::from(text)))
     }
 }
 
@@ -364,29 +827,25 @@ impl<'a> Parser<'a> {
 fn encode_rrx(opcode: Opcode, dst: u8, src1: u8, modbits: u8, src2: Operand) -> u32 {
     match src2 {
         Operand::Reg(regs2) => {
-            (opcode as u32) << bits::OPCODE_OFF
+            info::encode_opcode(opcode as u8)
                 | (dst as u32) << bits::DST_OFF
                 | (src1 as u32) << bits::SRC1_OFF
                 | (regs2 as u32) << bits::SRC2_OFF
         }
         Operand::Imm(imm) => {
-            (opcode as u32) << bits::OPCODE_OFF
+            info::encode_opcode(opcode as u8)
                 | 1 << bits::IMMBIT_OFF
                 | (dst as u32) << bits::DST_OFF
                 | (src1 as u32) << bits::SRC1_OFF
                 | (modbits as u32) << bits::MOD_OFF
                 | (imm as u32)
         }
-        Operand::Label(_) => panic!("This function cannot encode Operand::Label types"),
+        Operand::Label(_) | Operand::Expr(..) | Operand::Literal(_) => {
+            panic!("This function can only encode registers and immediates")
+        }
     }
 }
 
-/// Encodes the format `inst label`
-fn encode_label(opcode: Opcode, label_at: usize, cur_at: usize) -> u32 {
-    let offset = (label_at as i32 - cur_at as i32) as u32;
-    (opcode as u32) << bits::OPCODE_OFF | (offset & (!0u32 >> bits::OPCODE_BITS))
-}
-
 /// := '@' [^'\n']* '\n'
 ///  | "/*" [^"*/"]* "*/"
 fn eat_comment(scn: &mut Scanner) -> Result<(), ErrKind> {
@@ -395,21 +854,23 @@ fn eat_comment(scn: &mut Scanner) -> Result<(), ErrKind> {
         Ok(())
     } else if scn.eat_prefix("/*") {
         while !scn.eat_prefix("*/") {
-            if scn.next() == None {
+            if scn.next().is_none() {
                 return Err(ErrKind::OpenComment);
             }
         }
         Ok(())
     } else {
         // We know that the first char is '/', so '*' is missing
-        Err(ErrKind::CharExp('*'))
+        let found = scn.peekn(1).map(String::from).unwrap_or_default();
+        Err(ErrKind::CharExp('*', found))
     }
 }
 
 fn immediate(scn: &mut Scanner) -> Result<Token, ErrKind> {
     let mut base = 10;
     let mut is_neg = false;
-    let num: u16;
+    let num: u32;
+    let start = scn.left;
 
     if let Some(c) = scn.peek() {
         if c == '+' || c == '-' {
@@ -431,23 +892,83 @@ fn immediate(scn: &mut Scanner) -> Result<Token, ErrKind> {
     }
 
     let num_str = scn.take_while(|c| c.is_ascii_alphanumeric());
-    match u16::from_str_radix(num_str, base) {
+    match u32::from_str_radix(num_str, base) {
         Ok(ntmp) => {
             num = ntmp;
         }
         Err(e) => match e.kind() {
-            IntErrorKind::PosOverflow => return Err(ErrKind::ImmOverflow),
-            _ => return Err(ErrKind::InvalidImm),
+            IntErrorKind::PosOverflow => {
+                return Err(ErrKind::ImmOverflow(scn.consumed_since(start).into()))
+            }
+            _ => return Err(ErrKind::InvalidImm(scn.consumed_since(start).into())),
         },
     }
     if is_neg {
         // Check for overflow and then convert to 2's Complement representation
-        if num > std::i16::MIN.unsigned_abs() {
-            return Err(ErrKind::ImmOverflow);
+        if num > i32::MIN.unsigned_abs() {
+            return Err(ErrKind::ImmOverflow(scn.consumed_since(start).into()));
         }
-        return Ok(Token::Imm(!num + 1));
+        return Ok(Token::Imm((!num).wrapping_add(1) as i32));
     }
-    Ok(Token::Imm(num))
+    Ok(Token::Imm(num as i32))
+}
+
+#[inline]
+fn is_directive(ident: &str) -> bool {
+    matches!(
+        ident,
+        ".equ" | ".set" | ".text" | ".data" | ".ascii" | ".asciz"
+    )
+}
+
+/// := '"' (char | escape)* '"'
+///  | 'r' '"' [^'"']* '"'  # Raw string, escapes are not processed
+fn string(scn: &mut Scanner) -> Result<Token, ErrKind> {
+    let is_raw = scn.eat_prefix("r");
+    let mut bytes = Vec::new();
+    scn.next();
+
+    loop {
+        let start = scn.left;
+        match scn.next() {
+            None | Some('\n') => return Err(ErrKind::OpenString),
+            Some('"') => break,
+            Some('\\') if !is_raw => bytes.push(escape(scn, start)?),
+            Some(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(Token::Str(bytes))
+}
+
+/// := '\\' ('n' | 't' | '0' | '\\' | '"')
+///  | '\\' 'x' hex hex
+/// `start` is the input starting at the backslash.
+fn escape(scn: &mut Scanner, start: &str) -> Result<u8, ErrKind> {
+    let byte = match scn.next() {
+        Some('n') => b'\n',
+        Some('t') => b'\t',
+        Some('0') => 0,
+        Some('\\') => b'\\',
+        Some('"') => b'"',
+        Some('x') => {
+            let mut byte = 0u8;
+            for _ in 0..2 {
+                let digit = scn.peek().and_then(|c| c.to_digit(16));
+                // Include the offending character unless it ends the string
+                if !matches!(scn.peek(), None | Some('\n' | '"')) {
+                    scn.next();
+                }
+                match digit {
+                    Some(d) => byte = byte << 4 | d as u8,
+                    None => return Err(ErrKind::InvalidEscape(scn.consumed_since(start).into())),
+                }
+            }
+            byte
+        }
+        None | Some('\n') => return Err(ErrKind::OpenString),
+        Some(_) => return Err(ErrKind::InvalidEscape(scn.consumed_since(start).into())),
+    };
+    Ok(byte)
 }
 
 #[inline]
@@ -461,7 +982,7 @@ fn identifier(citer: &mut Scanner) -> Result<Token, ErrKind> {
     if let Some(&reg) = REGISTERS.iter().find(|&&reg| reg.0 == ident) {
         return Ok(Token::Reg(reg.1));
     }
-    match instruction(&ident) {
+    match instruction(ident) {
         Ok(Some(tok)) => return Ok(tok),
         Ok(None) => {}
         Err(e) => return Err(e),
@@ -469,48 +990,64 @@ fn identifier(citer: &mut Scanner) -> Result<Token, ErrKind> {
     Ok(Token::Ident(String::from(ident)))
 }
 
-fn instruction(mut instr: &str) -> Result<Option<Token>, ErrKind> {
-    let modbits: u8;
+fn instruction(lexeme: &str) -> Result<Option<Token>, ErrKind> {
+    let find = |name: &str| info::INSTRUCTIONS.iter().find(|ins| ins.name == name);
 
-    if instr.ends_with('u') {
-        instr = instr.strip_suffix('u').unwrap();
-        modbits = bits::MOD_U;
-    } else if instr.ends_with('h') {
-        instr = instr.strip_suffix('h').unwrap();
-        modbits = bits::MOD_H;
+    // An exact match takes precedence, so that instructions like `ldh`
+    // are not mistaken for `ld` with the 'h' modifier
+    let (found, modbits) = if let Some(ins) = find(lexeme) {
+        (ins, bits::MOD_DEF)
+    } else if let Some(ins) = lexeme.strip_suffix('u').and_then(find) {
+        (ins, bits::MOD_U)
+    } else if let Some(ins) = lexeme.strip_suffix('h').and_then(find) {
+        (ins, bits::MOD_H)
+    } else if let Some(ins) = lexeme.strip_suffix('w').and_then(find) {
+        (ins, bits::MOD_W)
     } else {
-        modbits = bits::MOD_DEF;
-    }
-
-    for Instruction {
-        name,
-        opcode,
-        ndst,
-        nsrc,
-        modbits: _,
-    } in info::INSTRUCTIONS
-    {
-        if instr != name {
-            continue;
-        }
-        if modbits != bits::MOD_DEF && !info::supports_mod(opcode as u8) {
-            return Err(ErrKind::IllegalModifier);
-        }
+        return Ok(None);
+    };
 
-        return Ok(Some(Token::Inst(Instruction {
-            name,
-            opcode,
-            ndst,
-            nsrc,
-            modbits,
-        })));
+    let opcode = found.opcode as u8;
+    let allowed =
+        info::supports_mod(opcode) || (modbits == bits::MOD_U && info::is_subword_load(opcode));
+    if modbits != bits::MOD_DEF && !allowed {
+        return Err(ErrKind::IllegalModifier(String::from(lexeme)));
     }
 
-    Ok(None)
+    Ok(Some(Token::Inst(Instruction { modbits, ..*found })))
 }
 
+/// Returns the instruction named `lexeme` with a modifier, even if an instruction is
+/// named `lexeme` itself, like the extended `divu` which takes only registers
+fn modifier_form(lexeme: &str) -> Option<Instruction> {
+    let find = |name: &str| info::INSTRUCTIONS.iter().find(|ins| ins.name == name);
+    [('u', bits::MOD_U), ('h', bits::MOD_H)]
+        .into_iter()
+        .find_map(|(suffix, modbits)| {
+            let ins = find(lexeme.strip_suffix(suffix)?)?;
+            info::supports_mod(ins.opcode as u8).then_some(Instruction { modbits, ..*ins })
+        })
+}
+
+/// Assembles the source code and returns only the instructions, see [`assemble`]
 pub fn parse_and_assemble(input: &str) -> Result<Vec<u32>, ParseErr> {
-    let mut asm = Parser::new(input);
+    assemble(input).map(|prog| prog.text)
+}
+
+/// Assembles the source code into a program including its data section
+pub fn assemble(input: &str) -> Result<Program, ParseErr> {
+    assemble_with_endian(input, Endian::Little)
+}
+
+/// Like [`assemble`], with the words of the data section in the byte order `endian`
+pub fn assemble_with_endian(input: &str, endian: Endian) -> Result<Program, ParseErr> {
+    assemble_for(input, endian, Xlen::X32)
+}
+
+/// Like [`assemble_with_endian`], for a program run in the mode `xlen`. Instructions with
+/// the 'w' modifier are rejected unless it is [`Xlen::X64`].
+pub fn assemble_for(input: &str, endian: Endian, xlen: Xlen) -> Result<Program, ParseErr> {
+    let mut asm = Parser::new(input, endian, xlen);
     match asm.parse() {
         Ok(ret) => Ok(ret),
         Err(kind) => Err(ParseErr {
@@ -521,6 +1058,7 @@ pub fn parse_and_assemble(input: &str) -> Result<Vec<u32>, ParseErr> {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // Grouped by instruction fields
 mod tests {
     use super::*;
 
@@ -535,19 +1073,41 @@ mod tests {
 
     #[test]
     fn imm_test() {
-        let test_pairs: [(&str, Result<Token, ErrKind>); 6] = [
+        let test_pairs: [(&str, Result<Token, ErrKind>); 8] = [
             ("42", Ok(Token::Imm(42))),
             ("0", Ok(Token::Imm(0))),
             ("-42", Ok(Token::Imm(!42 + 1))),
             ("-0x101", Ok(Token::Imm(!0x101 + 1))),
-            ("0x1FFFF", Err(ErrKind::ImmOverflow)),
-            ("0x1oops", Err(ErrKind::InvalidImm)),
+            ("0xFFFFFFFF", Ok(Token::Imm(-1))),
+            ("-0x80000000", Ok(Token::Imm(i32::MIN))),
+            (
+                "0x1FFFFFFFF",
+                Err(ErrKind::ImmOverflow("0x1FFFFFFFF".into())),
+            ),
+            ("0x1oops", Err(ErrKind::InvalidImm("0x1oops".into()))),
         ];
         for (test, res) in test_pairs {
             assert_eq!(immediate(&mut Scanner::new(test)), res);
         }
     }
 
+    #[test]
+    fn string_test() {
+        let test_pairs: [(&str, Result<Token, ErrKind>); 8] = [
+            (r#""hi\t\"x\"\n""#, Ok(Token::Str(b"hi\t\"x\"\n".to_vec()))),
+            (r#""\0\\\x41\xfF""#, Ok(Token::Str(b"\0\\A\xff".to_vec()))),
+            (r#"r"C:\dir\n""#, Ok(Token::Str(br"C:\dir\n".to_vec()))),
+            ("\"\u{e9}\"", Ok(Token::Str("\u{e9}".as_bytes().to_vec()))),
+            (r#""ab\q""#, Err(ErrKind::InvalidEscape(r"\q".into()))),
+            (r#""\x4g0""#, Err(ErrKind::InvalidEscape(r"\x4g".into()))),
+            (r#""\x""#, Err(ErrKind::InvalidEscape(r"\x".into()))),
+            ("\"abc\n\"", Err(ErrKind::OpenString)),
+        ];
+        for (test, res) in test_pairs {
+            assert_eq!(string(&mut Scanner::new(test)), res);
+        }
+    }
+
     #[test]
     fn instruction_test() {
         assert_eq!(
@@ -571,14 +1131,17 @@ mod tests {
             })))
         );
         // Invalid ones
-        assert_eq!(instruction("nopu"), Err(ErrKind::IllegalModifier));
+        assert_eq!(
+            instruction("nopu"),
+            Err(ErrKind::IllegalModifier("nopu".into()))
+        );
         assert_eq!(instruction("nosuchins"), Ok(None));
     }
 
     #[test]
     fn test_fine() {
         // Test only for first instruction
-        let test_pairs: [(&str, u32); 6] = [
+        let test_pairs: [(&str, u32); 25] = [
             ("mov r0, -0x1\n", 0b01001_1_0000_0000_00_1111111111111111),
             ("add r0, r1, r2\n", 0b00000_0_0000_0001_0010 << 14),
             (
@@ -591,36 +1154,243 @@ mod tests {
             ),
             ("ld r0, -1[r0]\n", 0b01110_1_0000_0000_00_1111111111111111),
             ("ld r0, [r1]\n", 0b01110_1_0000_0001_00_0000000000000000),
+            ("ldbu r2, -2[r1]\n", 0b10101_1_0010_0001_01_1111111111111110),
+            ("stb r2, 3[sp]\n", 0b10110_1_0010_1110_00_0000000000000011),
+            ("ldh r2, [r1]\n", 0b10111_1_0010_0001_00_0000000000000000),
+            ("ldhu r2, 2[r1]\n", 0b10111_1_0010_0001_01_0000000000000010),
+            ("sth r2, -2[r1]\n", 0b11000_1_0010_0001_00_1111111111111110),
+            ("swap r1, [r2]\n", 0b11111_0_0001_0010_0000_00000000_000000),
+            (
+                "cas r1, r3, [r2]\n",
+                0b11111_0_0001_0010_0011_00000000_000001,
+            ),
+            ("xor r1, r2, r3\n", 0b11111_0_0001_0010_0011_00000000_000010),
+            (
+                "mulh r1, r2, r3\n",
+                0b11111_0_0001_0010_0011_00000000_000011,
+            ),
+            ("cmpu r1, r2\n", 0b11111_0_0000_0001_0010_00000000_000111),
+            // With an immediate they are `div`, `cmp` and `mul` with a modifier
+            (
+                "divu r1, r2, 40000\n",
+                0b00011_1_0001_0010_01_1001110001000000,
+            ),
+            ("cmpu r2, 0xFFFF\n", 0b00101_1_0000_0010_01_1111111111111111),
+            ("mulh r3, r2, 1\n", 0b00010_1_0011_0010_10_0000000000000001),
+            ("mla r1, r2, r3\n", 0b11111_0_0001_0010_0011_00000000_001000),
+            ("clz r1, r2\n", 0b11111_0_0001_0000_0010_00000000_001001),
+            ("uxtb r1, r2\n", 0b11111_0_0001_0000_0010_00000000_001110),
+            ("min r1, r2, r3\n", 0b11111_0_0001_0010_0011_00000000_010000),
+            (
+                "adds r1, r2, r3\n",
+                0b11111_0_0001_0010_0011_00000000_010100,
+            ),
+            (
+                "blo has_label\n ret\n has_label: ret\n",
+                0b11111_000000000000000000010_011100,
+            ),
         ];
         for (input, res) in test_pairs {
-            assert_eq!(Parser::new(input).parse().unwrap()[0], res);
+            assert_eq!(
+                Parser::new(input, Endian::Little, Xlen::X32)
+                    .parse()
+                    .unwrap()
+                    .text[0],
+                res
+            );
         }
     }
 
     #[test]
     fn test_bad() {
-        let test_pairs: [(&str, ErrKind); 12] = [
-            ("add r0, r1", ErrKind::CharExp(',')),
+        let test_pairs: [(&str, ErrKind); 31] = [
+            ("add r0, r1", ErrKind::CharExp(',', "".into())),
             ("add r0, /* uncomp*", ErrKind::OpenComment),
-            ("/ *Illegal comment */", ErrKind::CharExp('*')),
-            ("add r0, r1, r4", ErrKind::CharExp('\n')),
-            ("add r0, r1, \n", ErrKind::OperandExp),
-            ("addh r0, r1, r2 \n", ErrKind::IllegalModifier),
-            ("noph\n", ErrKind::IllegalModifier),
-            ("b r0\n", ErrKind::IdentExp),
-            ("cmp 24, 88\n", ErrKind::RegExp),
-            ("r13 add r11\n", ErrKind::IllegalToken),
+            ("/ *Illegal comment */", ErrKind::CharExp('*', " ".into())),
+            ("add r0, r1, r4", ErrKind::CharExp('\n', "".into())),
+            ("add r0, r1, \n", ErrKind::OperandExp("\n".into())),
+            (
+                "addh r0, r1, r2 \n",
+                ErrKind::IllegalModifier("addh".into()),
+            ),
+            ("noph\n", ErrKind::IllegalModifier("noph".into())),
+            ("stbu r0, [r1]\n", ErrKind::IllegalModifier("stbu".into())),
+            ("ldbh r0, [r1]\n", ErrKind::IllegalModifier("ldbh".into())),
+            ("sthu r0, [r1]\n", ErrKind::IllegalModifier("sthu".into())),
+            ("b r0\n", ErrKind::IdentExp("r0".into())),
+            ("cas r1, 5, [r2]\n", ErrKind::RegExp("5".into())),
+            ("swap r1, r2\n", ErrKind::CharExp('[', "r2".into())),
+            ("xor r1, r2, 1\n", ErrKind::IllegalImm("xor".into())),
+            ("ctz r1, 8\n", ErrKind::IllegalImm("ctz".into())),
+            ("minu r1, r2, 1\n", ErrKind::IllegalImm("minu".into())),
+            ("cmp 24, 88\n", ErrKind::RegExp("24".into())),
+            ("r13 add r11\n", ErrKind::IllegalToken("r13".into())),
+            ("mov r0, 0x1FFFF\n", ErrKind::ImmOverflow("0x1FFFF".into())),
+            (
+                ".set N, 0x8000\nmov r0, N + N\n",
+                ErrKind::ImmOverflow("N + N".into()),
+            ),
+            (
+                ".equ N, 1\n.set N, 2\n",
+                ErrKind::DuplicateSymbol("N".into()),
+            ),
+            ("N:\n.set N, 2\n", ErrKind::DuplicateSymbol("N".into())),
+            ("add r0, r0, N\n", ErrKind::UndefinedSymbol("N".into())),
+            (
+                ".equ A, B + 1\n.equ B, A\n",
+                ErrKind::RecursiveSymbol("A".into()),
+            ),
+            (
+                "mov r0, BIG\n.equ BIG, 0x10000\n",
+                ErrKind::ImmOverflow("BIG".into()),
+            ),
+            (".ascii \"x\"\n", ErrKind::WrongSection(".ascii".into())),
+            (".data\nnop\n", ErrKind::WrongSection("nop".into())),
+            (".data\n.asciz 42\n", ErrKind::StringExp("42".into())),
+            (
+                ".data\nmsg:\n.text\nb msg\n",
+                ErrKind::DataLabel("msg".into()),
+            ),
             (
                 "b undefme\n",
                 ErrKind::UndefinedLabel(String::from("undefme")),
             ),
             (
                 "abc:\n\n abc: ret\n",
-                ErrKind::DuplicateLabel(String::from("abc")),
+                ErrKind::DuplicateLabel(String::from("abc"), 1),
             ),
         ];
         for (input, err) in test_pairs {
-            assert_eq!(parse_and_assemble(input).unwrap_err().kind, err);
+            assert_eq!(parse_and_assemble(input).unwrap_err().kind(), &err);
         }
     }
+
+    #[test]
+    fn test_symbols() {
+        let code = ".equ BASE, 0x100
+            .set i, 1
+            mov r0, i
+            .set i, i+1
+            add r1, r1, BASE - i - -2
+            ld r2, BASE[r0]
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        assert_eq!(bin[0], 0b01001_1_0000_0000_00_0000000000000001);
+        assert_eq!(bin[1], 0b00000_1_0001_0001_00_0000000100000000);
+        assert_eq!(bin[2], 0b01110_1_0010_0000_00_0000000100000000);
+    }
+
+    #[test]
+    fn test_forward_symbols() {
+        let code = "
+            mov r0, SIZE
+            ld r1, table[r0]
+            add r2, r2, end - start
+            .set i, 5
+        start:
+            .equ SIZE, COUNT + i
+            .equ COUNT, 3
+        table: nop
+        end:
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        assert_eq!(bin[0], 0b01001_1_0000_0000_00_0000000000001000);
+        assert_eq!(bin[1], 0b01110_1_0001_0000_00_0000000000000011);
+        assert_eq!(bin[2], 0b00000_1_0010_0010_00_0000000000000001);
+
+        let err = parse_and_assemble("nop\n .equ A, B\n").unwrap_err();
+        assert_eq!(err.kind(), &ErrKind::UndefinedSymbol("B".into()));
+        assert_eq!(err.line(), 2);
+        let err = parse_and_assemble("nop\nmov r0, X\nnop\n").unwrap_err();
+        assert_eq!(err.line(), 2);
+    }
+
+    #[test]
+    fn test_data() {
+        let code = r#"
+            mov r1, msg
+            mov r2, end - msg
+        .data
+            .ascii "ab"
+        msg: .asciz "\x41\tb\\"
+        end:
+        .text
+            nop
+        "#;
+        let prog = assemble(code).unwrap();
+        assert_eq!(prog.data, b"abA\tb\\\0");
+        assert_eq!(prog.text.len(), 3);
+        // The data starts at 0x100
+        assert_eq!(prog.text[0], 0b01001_1_0001_0000_00_0000000100000010);
+        assert_eq!(prog.text[1], 0b01001_1_0010_0000_00_0000000000000101);
+    }
+
+    #[test]
+    fn test_literal_pool() {
+        let code = r#"
+            ld r1, =0x12345678
+            ld r2, =msg + 1
+        next:
+            ld r3, =0x12345678
+            ld r4, =next
+        .data
+        msg: .asciz "ab"
+        "#;
+        let prog = assemble(code).unwrap();
+        assert_eq!(prog.text.len(), 8);
+        assert_eq!(prog.text[0], 0b01001_1_0001_0000_01_0000000100000100);
+        assert_eq!(prog.text[1], 0b01110_1_0001_0001_00_0000000000000000);
+        assert_eq!(prog.text[2], 0b01001_1_0010_0000_01_0000000100001000);
+        // Shares the entry with the first constant
+        assert_eq!(prog.text[4], 0b01001_1_0011_0000_01_0000000100000100);
+        assert_eq!(prog.text[6], 0b01001_1_0100_0000_01_0000000100001100);
+        assert_eq!(
+            prog.data,
+            [b'a', b'b', 0, 0, 0x78, 0x56, 0x34, 0x12, 1, 1, 0, 0, 4, 0, 0, 0]
+        );
+
+        let prog = assemble_with_endian(code, Endian::Big).unwrap();
+        assert_eq!(prog.data[4..8], [0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let code = "
+            mov r1, 1
+        loop:
+            ld r2, =0x12345678
+        .data
+        msg: .asciz \"a\"
+        .text
+        end: nop
+        ";
+        let prog = assemble(code).unwrap();
+        assert_eq!(prog.lines, [2, 4, 4, 8]);
+        let labels: Vec<_> = prog.labels.iter().map(|(k, &v)| (k.as_str(), v)).collect();
+        assert_eq!(labels, [("end", 3), ("loop", 1)]);
+        assert_eq!(prog.label_at(1), Some("loop"));
+        assert_eq!(prog.label_at(0), None);
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let err = parse_and_assemble("nop\nloop: nop\n\n.data\nloop: .ascii \"\"\n").unwrap_err();
+        assert_eq!(err.kind(), &ErrKind::DuplicateLabel("loop".into(), 2));
+        assert_eq!(err.line(), 5);
+        assert_eq!(
+            err.to_string(),
+            "On line 5: Duplicate label 'loop', first defined on line 2"
+        );
+    }
+
+    #[test]
+    fn test_err_line() {
+        let err = parse_and_assemble("nop\n\n  mov r0, r1, r2\n").unwrap_err();
+        assert_eq!(err.line(), 3);
+        assert_eq!(err.kind(), &ErrKind::CharExp('\n', ",".into()));
+        assert_eq!(
+            err.to_string(),
+            "On line 3: Character '\\n' expected, found ','"
+        );
+    }
 }
@@ -0,0 +1,575 @@
+//! Timing model of the classic five-stage pipeline, IF ID EX MEM WB, see [`Emulator::set_pipeline`].
+//!
+//! The executed instructions are fed to the model in order, so it does not change what the
+//! program computes, only how many cycles it is estimated to take. Two pipelines are modeled
+//! at once, one with forwarding and one without, using the textbook assumptions:
+//! - Registers are read in ID and written in WB, a value written in WB can be read by ID
+//!   in the same cycle. The flags written by `cmp` are treated like a register.
+//! - With forwarding a result reaches EX right after it is computed, so only using the
+//!   result of a load in the next instruction stalls for a cycle. The value stored by a
+//!   store is needed in MEM, where it can be forwarded from a load as well.
+//! - Branches are resolved in EX, so after any change of the flow like a taken branch,
+//!   call, return, interrupt or trap the two instructions fetched meanwhile are discarded.
+//!
+//! Hazards are counted along with the cycles they stall, by the `pc` of the instruction
+//! which has to wait for a register, or for a control hazard of the instruction which
+//! changed the flow, see [`Pipeline::hazards`]. A data hazard is a read of a register
+//! whose new value has not been written back yet, even if forwarding avoids the stall.
+//!
+//! With the `std` feature the stage occupancy of each cycle can be written as a diagram using
+//! `Pipeline::set_diagram`, with a line per cycle giving the `pc` of the instruction
+//! in each stage or `-` for a bubble:
+//! ```text
+//!  cycle    IF    ID    EX   MEM    WB
+//!      1     0     -     -     -     -
+//!      2     1     0     -     -     -
+//! ```
+//!
+//! [`Emulator::set_pipeline`]: crate::emulator::Emulator::set_pipeline
+
+use crate::{
+    csr,
+    emulator::EmulatorErr,
+    info::{self, bits::*, Opcode, RET_REG},
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+pub const STAGES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+/// Bit of the flags in the register masks
+pub(crate) const FLAGS: u32 = 1 << 16;
+
+/// Registers used by an instruction, as masks with a bit per register
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Operands {
+    /// Read in ID, needed in EX
+    pub reads: u32,
+    /// Read in ID, but needed only in MEM
+    pub mem_reads: u32,
+    pub writes: u32,
+    pub load: bool,
+}
+
+fn reg_bit(inst: u32, offset: u8) -> u32 {
+    1 << ((inst >> offset) & 0xf)
+}
+
+pub(crate) fn operands(inst: u32) -> Operands {
+    use Opcode::*;
+
+    let opcode = info::opcode_of(inst);
+    let Some(ins) = info::instruction(opcode) else {
+        return Operands::default();
+    };
+    let is_imm = info::supports_imm(opcode) && (inst >> IMMBIT_OFF) & 1 == 1;
+    let dst = reg_bit(inst, DST_OFF);
+    let src1 = reg_bit(inst, SRC1_OFF);
+    let src2 = if is_imm { 0 } else { reg_bit(inst, SRC2_OFF) };
+    let mut ops = Operands::default();
+    match ins.opcode {
+        ADD | SUB | MUL | MULH | MULHU | DIV | MOD | DIVU | MODU | AND | OR | XOR | LSL | LSR
+        | ASR | MIN | MAX | MINU | MAXU => {
+            ops.reads = src1 | src2;
+            ops.writes = dst;
+        }
+        MLA => {
+            ops.reads = dst | src1 | src2;
+            ops.writes = dst;
+        }
+        ADDS | SUBS => {
+            ops.reads = src1 | src2;
+            ops.writes = dst | FLAGS;
+        }
+        CMP | CMPU => {
+            ops.reads = src1 | src2;
+            ops.writes = FLAGS;
+        }
+        NOT | MOV | CLZ | CTZ | POPCNT | SXTB | SXTH | UXTB | UXTH => {
+            ops.reads = src2;
+            ops.writes = dst;
+        }
+        LD | LDB | LDH => {
+            ops.reads = src1;
+            ops.writes = dst;
+            ops.load = true;
+        }
+        ST | STB | STH => {
+            ops.reads = src1;
+            ops.mem_reads = dst;
+        }
+        // The value written to memory is needed in MEM, like for a store
+        SWAP => {
+            ops.reads = src1;
+            ops.mem_reads = dst;
+            ops.writes = dst;
+            ops.load = true;
+        }
+        CAS => {
+            ops.reads = src1 | dst;
+            ops.mem_reads = src2;
+            ops.writes = dst | FLAGS;
+            ops.load = true;
+        }
+        BEQ | BGT | BNE | BMI | BPL | BVS | BVC | BHS | BLO | BHI | BLS | BGE | BLT | BLE => {
+            ops.reads = FLAGS
+        }
+        CALL => ops.writes = 1 << RET_REG,
+        RET => ops.reads = 1 << RET_REG,
+        RETI => ops.writes = FLAGS,
+        // The `FLAGS` CSR is tracked along with the flags E and GT
+        CSRR if is_imm && inst & 0xffff == csr::FLAGS => {
+            ops.reads = FLAGS;
+            ops.writes = dst;
+        }
+        CSRW if is_imm && inst & 0xffff == csr::FLAGS => {
+            ops.reads = dst;
+            ops.writes = FLAGS;
+        }
+        CSRR => ops.writes = dst,
+        CSRW => ops.reads = dst,
+        // Arguments are passed in r[0-4] and results returned in r0 and r1
+        SYS => {
+            ops.reads = 0b11111;
+            ops.writes = 0b11;
+        }
+        NOP | B | EI | DI => {}
+    }
+    ops
+}
+
+/// Cycles at which an instruction enters the stages, it is in MEM and WB right after EX
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timing {
+    fetch: u64,
+    decode: u64,
+    execute: u64,
+}
+
+impl Timing {
+    fn write_back(&self) -> u64 {
+        self.execute + 2
+    }
+
+    /// Index of the stage the instruction is in during `cycle`
+    #[cfg(feature = "std")]
+    fn stage(&self, cycle: u64) -> Option<usize> {
+        match cycle {
+            c if c < self.fetch => None,
+            c if c < self.decode => Some(0),
+            c if c < self.execute => Some(1),
+            c => usize::try_from(c - self.execute + 2)
+                .ok()
+                .filter(|&s| s < STAGES.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HazardKind {
+    /// Read of a register written by one of the previous instructions
+    Raw,
+    /// Read of a register loaded by the previous instruction
+    LoadUse,
+    /// Instructions fetched after a change of the flow are discarded
+    Control,
+}
+
+impl fmt::Display for HazardKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Raw => write!(f, "RAW"),
+            Self::LoadUse => write!(f, "load-use"),
+            Self::Control => write!(f, "control"),
+        }
+    }
+}
+
+/// Hazards of a kind caused by an instruction, see [`Pipeline::hazards`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hazard {
+    pub pc: i32,
+    pub kind: HazardKind,
+    /// Number of times it occurred
+    pub count: u64,
+    /// Cycles lost to it in total
+    pub stalls: u64,
+}
+
+/// Last instruction which wrote a register
+#[derive(Debug, Clone, Copy)]
+struct Producer {
+    /// Position in the sequence of issued instructions
+    seq: u64,
+    load: bool,
+    /// Earliest cycle in which an instruction can be in EX and use the value
+    ex_ready: u64,
+    /// Same as `ex_ready` if the value is needed only in MEM
+    mem_ready: u64,
+    /// Cycle in which the value is written back
+    write_back: u64,
+}
+
+/// One of the modeled pipelines
+#[derive(Debug, Default)]
+struct Model {
+    forwarding: bool,
+    last: Option<Timing>,
+    /// Number of issued instructions
+    issued: u64,
+    producers: [Option<Producer>; 17],
+    hazards: BTreeMap<(i32, HazardKind), (u64, u64)>,
+}
+
+impl Model {
+    /// Issues the instruction at `pc`, `redirect` is the `pc` of the previous
+    /// instruction if it changed the flow
+    fn issue(&mut self, pc: i32, ops: &Operands, redirect: Option<i32>) -> Timing {
+        let timing = match self.last {
+            None => Timing {
+                fetch: 1,
+                decode: 2,
+                execute: 3,
+            },
+            Some(last) => {
+                // A stage is free once the previous instruction moved out of it
+                let mut fetch = (last.fetch + 1).max(last.decode);
+                if let Some(branch_pc) = redirect {
+                    let resolved = last.execute + 1;
+                    self.record(
+                        branch_pc,
+                        HazardKind::Control,
+                        resolved.saturating_sub(fetch),
+                    );
+                    fetch = fetch.max(resolved);
+                }
+                let decode = (fetch + 1).max(last.execute);
+                Timing {
+                    fetch,
+                    decode,
+                    execute: (decode + 1).max(last.execute + 1),
+                }
+            }
+        };
+
+        // Reads of values not yet written back, with the cycle each one allows EX in
+        let reads = (0..17).filter(|r| ops.reads >> r & 1 == 1);
+        let mem_reads = (0..17).filter(|r| ops.mem_reads >> r & 1 == 1);
+        let pending = reads
+            .map(|r| (r, false))
+            .chain(mem_reads.map(|r| (r, true)))
+            .filter_map(|(r, in_mem)| self.producers[r].map(|p| (p, in_mem)))
+            // Written back before the last cycle in which it could be read in ID
+            .filter(|(p, _)| p.write_back >= timing.execute)
+            .map(|(p, in_mem)| (p, if in_mem { p.mem_ready } else { p.ex_ready }));
+        let binding = pending.max_by_key(|&(p, ready)| (ready, p.seq));
+        let mut execute = timing.execute;
+        if let Some((producer, ready)) = binding {
+            execute = execute.max(ready);
+            let kind = match producer.load && producer.seq + 1 == self.issued {
+                true => HazardKind::LoadUse,
+                false => HazardKind::Raw,
+            };
+            self.record(pc, kind, execute - timing.execute);
+        }
+        let timing = Timing { execute, ..timing };
+
+        let (ex_ready, mem_ready) = match (self.forwarding, ops.load) {
+            (false, _) => (execute + 3, execute + 3),
+            (true, false) => (execute + 1, execute),
+            (true, true) => (execute + 2, execute + 1),
+        };
+        let producer = Producer {
+            seq: self.issued,
+            load: ops.load,
+            ex_ready,
+            mem_ready,
+            write_back: timing.write_back(),
+        };
+        for r in (0..17).filter(|r| ops.writes >> r & 1 == 1) {
+            self.producers[r] = Some(producer);
+        }
+        self.issued += 1;
+        self.last = Some(timing);
+        timing
+    }
+
+    fn record(&mut self, pc: i32, kind: HazardKind, stalls: u64) {
+        let (count, total) = self.hazards.entry((pc, kind)).or_default();
+        *count += 1;
+        *total += stalls;
+    }
+
+    fn cycles(&self) -> u64 {
+        self.last.map_or(0, |t| t.write_back())
+    }
+}
+
+/// Writes the stage occupancy of each cycle once no later instruction can change it
+#[cfg(feature = "std")]
+struct Diagram {
+    out: Box<dyn Write>,
+    forwarding: bool,
+    /// Instructions which may still be in the pipeline in `next_cycle`
+    in_flight: VecDeque<(i32, Timing)>,
+    next_cycle: u64,
+}
+
+#[cfg(feature = "std")]
+impl Diagram {
+    /// Writes the cycles before `end`
+    fn write_until(&mut self, end: u64) -> io::Result<()> {
+        if self.next_cycle == 1 && end > 1 {
+            write!(self.out, " cycle")?;
+            for stage in STAGES {
+                write!(self.out, " {:>5}", stage)?;
+            }
+            writeln!(self.out)?;
+        }
+        for cycle in self.next_cycle..end {
+            let mut stages = [None; STAGES.len()];
+            for (pc, timing) in &self.in_flight {
+                if let Some(stage) = timing.stage(cycle) {
+                    stages[stage] = Some(*pc);
+                }
+            }
+            write!(self.out, "{:>6}", cycle)?;
+            for pc in stages {
+                match pc {
+                    Some(pc) => write!(self.out, " {:>5}", pc)?,
+                    None => write!(self.out, " {:>5}", "-")?,
+                }
+            }
+            writeln!(self.out)?;
+        }
+        self.next_cycle = self.next_cycle.max(end);
+        let next_cycle = self.next_cycle;
+        self.in_flight.retain(|(_, t)| t.write_back() >= next_cycle);
+        Ok(())
+    }
+}
+
+/// Estimates the cycles taken by a five-stage pipeline, see the module documentation
+pub struct Pipeline {
+    forwarding: Model,
+    stalling: Model,
+    #[cfg(feature = "std")]
+    diagram: Option<Diagram>,
+    /// `pc` of the last issued instruction
+    last_pc: Option<i32>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            forwarding: Model {
+                forwarding: true,
+                ..Default::default()
+            },
+            stalling: Model::default(),
+            #[cfg(feature = "std")]
+            diagram: None,
+            last_pc: None,
+        }
+    }
+
+    /// Writes the stage occupancy of each cycle to `out`, for the pipeline with or
+    /// without forwarding
+    #[cfg(feature = "std")]
+    pub fn set_diagram(&mut self, out: Box<dyn Write>, forwarding: bool) {
+        self.diagram = Some(Diagram {
+            out,
+            forwarding,
+            in_flight: VecDeque::new(),
+            next_cycle: 1,
+        });
+    }
+
+    /// Cycles taken by the instructions issued so far, until the last one leaves WB
+    pub fn cycles(&self, forwarding: bool) -> u64 {
+        match forwarding {
+            true => self.forwarding.cycles(),
+            false => self.stalling.cycles(),
+        }
+    }
+
+    /// Hazards which occurred so far in the pipeline with or without forwarding,
+    /// sorted by `pc`
+    pub fn hazards(&self, forwarding: bool) -> Vec<Hazard> {
+        let model = if forwarding {
+            &self.forwarding
+        } else {
+            &self.stalling
+        };
+        model
+            .hazards
+            .iter()
+            .map(|(&(pc, kind), &(count, stalls))| Hazard {
+                pc,
+                kind,
+                count,
+                stalls,
+            })
+            .collect()
+    }
+
+    /// Feeds the next executed instruction to the model
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    pub(crate) fn issue(&mut self, pc: i32, inst: u32) -> Result<(), EmulatorErr> {
+        let ops = operands(inst);
+        let redirect = self.last_pc.filter(|last| last + 1 != pc);
+        self.last_pc = Some(pc);
+        let with = self.forwarding.issue(pc, &ops, redirect);
+        let without = self.stalling.issue(pc, &ops, redirect);
+        #[cfg(feature = "std")]
+        if let Some(diagram) = &mut self.diagram {
+            let timing = if diagram.forwarding { with } else { without };
+            // Earlier cycles cannot be changed by this or later instructions
+            diagram.write_until(timing.fetch)?;
+            diagram.in_flight.push_back((pc, timing));
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining cycles of the diagram, once no more instructions are issued
+    pub(crate) fn finish(&mut self) -> Result<(), EmulatorErr> {
+        #[cfg(feature = "std")]
+        if let Some(diagram) = &mut self.diagram {
+            let end = match diagram.forwarding {
+                true => self.forwarding.cycles(),
+                false => self.stalling.cycles(),
+            };
+            diagram.write_until(end + 1)?;
+            diagram.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{operands, Hazard, HazardKind, Operands, Pipeline, FLAGS};
+    use crate::parser::parse_and_assemble;
+    use std::{cell::RefCell, io, rc::Rc};
+
+    fn run(code: &str) -> Pipeline {
+        let bin = parse_and_assemble(code).unwrap();
+        let mut pipeline = Pipeline::new();
+        for (pc, &inst) in bin.iter().enumerate() {
+            pipeline.issue(pc as i32, inst).unwrap();
+        }
+        pipeline
+    }
+
+    #[test]
+    fn test_operands() {
+        let bin =
+            parse_and_assemble("add r1, r2, r3\nmov r4, 5\nst r5, 4[r6]\ncmp r1, r2\n").unwrap();
+        let ops: Vec<Operands> = bin.iter().map(|&inst| operands(inst)).collect();
+        assert_eq!((ops[0].reads, ops[0].writes), (0b1100, 0b10));
+        assert_eq!((ops[1].reads, ops[1].writes), (0, 0b10000));
+        assert_eq!((ops[2].reads, ops[2].mem_reads), (1 << 6, 1 << 5));
+        assert_eq!((ops[3].reads, ops[3].writes), (0b110, FLAGS));
+        assert_eq!(operands(u32::MAX), Operands::default());
+    }
+
+    #[test]
+    fn test_hazards() {
+        // Independent instructions complete one per cycle after filling the pipeline
+        let pipeline = run("mov r1, 1\nmov r2, 2\nmov r3, 3\n");
+        assert_eq!(pipeline.cycles(true), 7);
+        assert_eq!(pipeline.cycles(false), 7);
+        // Using a result right away waits for its WB without forwarding
+        let pipeline = run("mov r1, 1\nadd r2, r1, r1\n");
+        assert_eq!(pipeline.cycles(true), 6);
+        assert_eq!(pipeline.cycles(false), 8);
+        // Load-use stalls a cycle even with forwarding, but storing the value does not
+        let pipeline = run("ld r1, 0[r0]\nadd r2, r1, r1\n");
+        assert_eq!(pipeline.cycles(true), 7);
+        let pipeline = run("ld r1, 0[r0]\nst r1, 4[r0]\n");
+        assert_eq!(pipeline.cycles(true), 6);
+        // Taken branches cost two cycles
+        let mut pipeline = Pipeline::new();
+        let bin = parse_and_assemble("b next\nnop\nnext:\nnop\n").unwrap();
+        pipeline.issue(0, bin[0]).unwrap();
+        pipeline.issue(2, bin[2]).unwrap();
+        assert_eq!(pipeline.cycles(true), 8);
+    }
+
+    #[test]
+    fn test_hazard_counts() {
+        let code = "ld r1, 0[r0]\nadd r2, r1, r1\nnop\nnop\nsub r3, r2, r1\n";
+        let mut pipeline = run(code);
+        let bin = parse_and_assemble("start:\nb start\n").unwrap();
+        pipeline.issue(5, bin[0]).unwrap();
+        pipeline.issue(0, bin[0]).unwrap();
+        let hazard = |pc, kind, count, stalls| Hazard {
+            pc,
+            kind,
+            count,
+            stalls,
+        };
+        assert_eq!(
+            pipeline.hazards(true),
+            [
+                hazard(1, HazardKind::LoadUse, 1, 1),
+                hazard(5, HazardKind::Control, 1, 2)
+            ]
+        );
+        // Forwarding removes the stall but not the hazard, sub reads r2 after its WB
+        assert_eq!(
+            pipeline.hazards(false),
+            [
+                hazard(1, HazardKind::LoadUse, 1, 2),
+                hazard(5, HazardKind::Control, 1, 2)
+            ]
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_diagram() {
+        let bin = parse_and_assemble("ld r1, 0[r0]\nadd r2, r1, r1\n").unwrap();
+        let out = SharedBuf::default();
+        let mut pipeline = Pipeline::new();
+        pipeline.set_diagram(Box::new(out.clone()), true);
+        for (pc, &inst) in bin.iter().enumerate() {
+            pipeline.issue(pc as i32, inst).unwrap();
+        }
+        pipeline.finish().unwrap();
+        let expected = " cycle    IF    ID    EX   MEM    WB
+     1     0     -     -     -     -
+     2     1     0     -     -     -
+     3     -     1     0     -     -
+     4     -     1     -     0     -
+     5     -     -     1     -     0
+     6     -     -     -     1     -
+     7     -     -     -     -     1
+";
+        assert_eq!(String::from_utf8(out.0.take()).unwrap(), expected);
+    }
+}
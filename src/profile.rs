@@ -0,0 +1,281 @@
+//! Profiling of the executed instructions, see [`Emulator::set_profiler`].
+//!
+//! The profiler counts the executions of each instruction, which are summed up over the
+//! basic blocks of the program to find its hottest regions. A basic block starts at the
+//! first instruction, at the target of a branch or call, and after a branch, call or
+//! return, so it is entered only at its start unless an interrupt or trap returns into it.
+//!
+//! Calls are tracked as well, giving each function, identified by its entry point, the
+//! number of calls and the instructions executed in it(exclusive) and in it along with
+//! the functions it called(inclusive). Like for the call stack of the emulator, a `ret`
+//! to the instruction after a `call` returns from that call and the ones made after it.
+//! Instructions of interrupt handlers count for the function they interrupted.
+//!
+//! [`Emulator::set_profiler`]: crate::emulator::Emulator::set_profiler
+
+use crate::info::{self, Opcode};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::ops::Range;
+
+/// Splits `text` into basic blocks, each one a range of `pc`
+pub fn basic_blocks(text: &[u32]) -> Vec<Range<usize>> {
+    let mut leaders = vec![false; text.len() + 1];
+    leaders[0] = true;
+    for (pc, &inst) in text.iter().enumerate() {
+        let opcode = info::opcode_of(inst);
+        let Some(ins) = info::instruction(opcode).filter(|_| info::is_branch(opcode)) else {
+            continue;
+        };
+        leaders[pc + 1] = true;
+        if ins.nsrc == 1 {
+            let target = pc as i64 + info::branch_offset(inst) as i64;
+            if let Some(leader) = usize::try_from(target)
+                .ok()
+                .and_then(|t| leaders.get_mut(t))
+            {
+                *leader = true;
+            }
+        }
+    }
+    let starts: Vec<usize> = (0..text.len()).filter(|&pc| leaders[pc]).collect();
+    let ends = starts.iter().skip(1).copied().chain([text.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| start..end)
+        .collect()
+}
+
+/// Executions of a basic block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockProfile {
+    pub pcs: Range<usize>,
+    /// Executions of its first instruction
+    pub entries: u64,
+    /// Executed instructions in the block
+    pub instructions: u64,
+}
+
+/// Calls of a function and the instructions executed by them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// `pc` of the first instruction
+    pub entry: usize,
+    pub calls: u64,
+    /// Instructions executed in the function and the functions it called, recursive
+    /// calls are counted once
+    pub inclusive: u64,
+    /// Instructions executed in the function itself
+    pub exclusive: u64,
+}
+
+/// A call which has not returned yet
+#[derive(Debug, Clone)]
+struct Frame {
+    entry: usize,
+    return_pc: usize,
+    /// Instructions executed before the call
+    start: u64,
+}
+
+/// Counts the executions of each instruction and tracks the calls
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    counts: Vec<u64>,
+    /// Executed instructions in total
+    total: u64,
+    functions: BTreeMap<usize, FunctionProfile>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, pc: usize) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+        self.total += 1;
+        if let Some(frame) = self.stack.last() {
+            self.function(frame.entry).exclusive += 1;
+        }
+    }
+
+    /// Tracks calls and returns, `inst` at `pc` was recorded and continued at `next_pc`
+    /// without trapping
+    pub(crate) fn transfer(&mut self, pc: usize, inst: u32, next_pc: i32) {
+        let opcode = info::opcode_of(inst);
+        let Ok(next_pc) = usize::try_from(next_pc) else {
+            return;
+        };
+        if opcode == Opcode::CALL as u8 {
+            self.function(next_pc).calls += 1;
+            self.stack.push(Frame {
+                entry: next_pc,
+                return_pc: pc + 1,
+                start: self.total,
+            });
+        } else if opcode == Opcode::RET as u8 {
+            if let Some(pos) = self.stack.iter().rposition(|f| f.return_pc == next_pc) {
+                for frame in self.stack.split_off(pos).into_iter().rev() {
+                    self.leave(frame);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, entry: usize) -> &mut FunctionProfile {
+        self.functions.entry(entry).or_insert(FunctionProfile {
+            entry,
+            ..Default::default()
+        })
+    }
+
+    /// Adds the instructions executed since `frame` was called, unless it is a recursive call
+    fn leave(&mut self, frame: Frame) {
+        if self.stack.iter().all(|f| f.entry != frame.entry) {
+            self.function(frame.entry).inclusive += self.total - frame.start;
+        }
+    }
+
+    /// Executions of the instruction at `pc`
+    pub fn count(&self, pc: usize) -> u64 {
+        self.counts.get(pc).copied().unwrap_or(0)
+    }
+
+    /// Executed instructions in total
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Functions which were called, the ones executing the most instructions first.
+    /// Calls which have not returned yet are included.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut profiler = self.clone();
+        while let Some(frame) = profiler.stack.pop() {
+            profiler.leave(frame);
+        }
+        let mut functions: Vec<FunctionProfile> = profiler.functions.into_values().collect();
+        functions.sort_by(|a, b| (b.inclusive, a.entry).cmp(&(a.inclusive, b.entry)));
+        functions
+    }
+
+    /// Basic blocks of `text` which were executed, the ones executing the most
+    /// instructions first
+    pub fn hot_blocks(&self, text: &[u32]) -> Vec<BlockProfile> {
+        let mut blocks: Vec<BlockProfile> = basic_blocks(text)
+            .into_iter()
+            .map(|pcs| BlockProfile {
+                entries: self.count(pcs.start),
+                instructions: pcs.clone().map(|pc| self.count(pc)).sum(),
+                pcs,
+            })
+            .filter(|block| block.instructions > 0)
+            .collect();
+        blocks.sort_by(|a, b| (b.instructions, a.pcs.start).cmp(&(a.instructions, b.pcs.start)));
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{basic_blocks, BlockProfile, FunctionProfile, Profiler};
+    use crate::parser::parse_and_assemble;
+
+    #[test]
+    fn test_basic_blocks() {
+        let code =
+            "mov r1, 3\nloop:\nsub r1, r1, 1\ncmp r1, 0\nbgt loop\ncall f\nb end\nf:\nret\nend:\n";
+        let bin = parse_and_assemble(code).unwrap();
+        assert_eq!(basic_blocks(&bin), [0..1, 1..4, 4..5, 5..6, 6..7]);
+        assert_eq!(basic_blocks(&[]), []);
+
+        let mut profiler = Profiler::new();
+        let pcs = [0, 1, 2, 3, 1, 2, 3, 4, 6, 5, 7];
+        for (&pc, &next) in pcs.iter().zip(&pcs[1..]) {
+            profiler.record(pc);
+            profiler.transfer(pc, bin[pc], next as i32);
+        }
+        let blocks = profiler.hot_blocks(&bin);
+        assert_eq!(
+            blocks[0],
+            BlockProfile {
+                pcs: 1..4,
+                entries: 2,
+                instructions: 6
+            }
+        );
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(blocks[1].pcs, 0..1);
+        assert_eq!(profiler.total(), 10);
+        assert_eq!(
+            profiler.functions(),
+            [FunctionProfile {
+                entry: 6,
+                calls: 1,
+                inclusive: 1,
+                exclusive: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        // main calls f twice, f calls g, g calls itself once
+        let code = "
+            mov sp, 64
+            call f
+            call f
+            b end
+        f:
+            sub sp, sp, 4
+            st r15, 0[sp]
+            mov r1, 1
+            call g
+            ld r15, 0[sp]
+            add sp, sp, 4
+            ret
+        g:
+            cmp r1, 0
+            sub r1, r1, 1
+            beq gret
+            sub sp, sp, 4
+            st r15, 0[sp]
+            call g
+            ld r15, 0[sp]
+            add sp, sp, 4
+        gret:
+            ret
+        end:
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        let mut emul = crate::emulator::Emulator::new(&bin);
+        emul.set_profiler(Profiler::new());
+        emul.exec().unwrap();
+        let profiler = emul.profiler().unwrap();
+        let functions = profiler.functions();
+        let (f, g) = (4, 11);
+        // Each call of g from f runs 6 + 3 instructions and 4 in the recursive call
+        assert_eq!(
+            functions,
+            [
+                FunctionProfile {
+                    entry: f,
+                    calls: 2,
+                    inclusive: 2 * (7 + 13),
+                    exclusive: 2 * 7
+                },
+                FunctionProfile {
+                    entry: g,
+                    calls: 4,
+                    inclusive: 2 * 13,
+                    exclusive: 2 * 13
+                },
+            ]
+        );
+        assert_eq!(profiler.total(), 4 + 2 * (7 + 13));
+    }
+}
@@ -0,0 +1,104 @@
+//! Output of the assembler which can be loaded into the emulator.
+
+use crate::{emulator::Xlen, memory::Endian};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// Bytes starting a binary image with a header, an invalid instruction so that an
+/// image of plain instructions cannot be mistaken for one
+pub const IMAGE_MAGIC: [u8; 4] = *b"sRI\xff";
+/// Flag of the image header set if the program is for the 64-bit mode, see [`Xlen::X64`]
+pub const IMAGE_XLEN64: u32 = 1;
+/// Memory address the data section is copied to, above the vector table
+/// (see [`crate::interrupt`]) so that installing a handler does not overwrite it
+pub const DATA_BASE: usize = 0x100;
+
+/// An assembled program
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    /// Encoded instructions, `pc` is an index into it
+    pub text: Vec<u32>,
+    /// Initial contents of the data memory starting from address [`DATA_BASE`]
+    pub data: Vec<u8>,
+    /// Index of the first instruction to execute, the `_start` label if there is one
+    /// and otherwise 0
+    pub entry: usize,
+    /// Source line(starting from 1) of each instruction
+    pub lines: Vec<usize>,
+    /// Labels in the text section along with the index of the instruction they refer to
+    pub labels: BTreeMap<String, usize>,
+}
+
+impl Program {
+    /// Returns the text label referring to the instruction at `pc`, if any
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|&(_, &at)| at == pc)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Returns the binary image of the instructions `text` with their words in the byte order
+/// `endian`. Images of 64-bit programs start with a header of [`IMAGE_MAGIC`] and a word
+/// of flags, images of 32-bit programs are only the instructions.
+pub fn write_image(text: &[u32], endian: Endian, xlen: Xlen) -> Vec<u8> {
+    let mut image = Vec::with_capacity(4 * text.len() + 8);
+    if xlen == Xlen::X64 {
+        image.extend_from_slice(&IMAGE_MAGIC);
+        image.extend_from_slice(&endian.word_bytes(IMAGE_XLEN64));
+    }
+    for &inst in text {
+        image.extend_from_slice(&endian.word_bytes(inst));
+    }
+    image
+}
+
+/// Reads an image written by [`write_image`], returning the instructions and the width
+/// of the registers the program is for
+pub fn read_image(image: &[u8], endian: Endian) -> Result<(Vec<u32>, Xlen), String> {
+    let word = |bytes: &[u8]| endian.word(u32::from_le_bytes(bytes.try_into().unwrap()));
+    let (xlen, text) = match image.strip_prefix(&IMAGE_MAGIC) {
+        Some(rest) if rest.len() < 4 => return Err(String::from("Truncated image header")),
+        Some(rest) => match word(&rest[..4]) {
+            IMAGE_XLEN64 => (Xlen::X64, &rest[4..]),
+            0 => (Xlen::X32, &rest[4..]),
+            _ => return Err(String::from("Unknown flags in the image header")),
+        },
+        None => (Xlen::X32, image),
+    };
+    if !text.len().is_multiple_of(4) {
+        return Err(String::from(
+            "The image is not a whole number of instructions",
+        ));
+    }
+    Ok((text.chunks_exact(4).map(word).collect(), xlen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_image, write_image, IMAGE_MAGIC};
+    use crate::{emulator::Xlen, memory::Endian};
+
+    #[test]
+    fn test_image() {
+        let text = [0x1234_5678, 0xffff_0000];
+        for endian in [Endian::Little, Endian::Big] {
+            let image = write_image(&text, endian, Xlen::X32);
+            assert_eq!(image.len(), 8);
+            assert_eq!(read_image(&image, endian), Ok((text.to_vec(), Xlen::X32)));
+            let image = write_image(&text, endian, Xlen::X64);
+            assert!(image.starts_with(&IMAGE_MAGIC));
+            assert_eq!(read_image(&image, endian), Ok((text.to_vec(), Xlen::X64)));
+        }
+        assert_eq!(
+            write_image(&text, Endian::Big, Xlen::X32)[..4],
+            [0x12, 0x34, 0x56, 0x78]
+        );
+
+        let mut image = write_image(&text, Endian::Little, Xlen::X64);
+        image[4] = 2;
+        assert!(read_image(&image, Endian::Little).is_err());
+        assert!(read_image(&IMAGE_MAGIC, Endian::Little).is_err());
+        assert!(read_image(&[1, 2, 3], Endian::Little).is_err());
+    }
+}
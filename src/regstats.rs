@@ -0,0 +1,136 @@
+//! Usage statistics of the registers, see [`Emulator::set_reg_stats`].
+//!
+//! Every executed instruction counts as a read of its source registers and a write of its
+//! destination register, even if the value does not change. `sys` reads r0-r4 and writes
+//! r0 and r1, `call` writes and `ret` reads r15.
+//!
+//! A live range of a register goes from a write to the last read of the value it wrote,
+//! its length is the number of instructions executed in between. A value overwritten
+//! before being read is a dead write. The initial values are treated as written before
+//! the first instruction, they are never dead, and neither is the last value written
+//! to a register.
+//!
+//! [`Emulator::set_reg_stats`]: crate::emulator::Emulator::set_reg_stats
+
+use crate::pipeline::operands;
+
+/// Usage of a register
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegUsage {
+    pub reads: u64,
+    pub writes: u64,
+    /// Writes whose value was overwritten without being read
+    pub dead_writes: u64,
+    /// Number of live ranges
+    pub live_ranges: u64,
+    /// Instructions spanned by all the live ranges
+    pub live_steps: u64,
+    /// Instructions spanned by the longest live range
+    pub longest_live_range: u64,
+}
+
+impl RegUsage {
+    /// Returns true if the register was neither read nor written
+    pub fn unused(&self) -> bool {
+        self.reads == 0 && self.writes == 0
+    }
+
+    fn end_range(&mut self, def: &Def) {
+        match def.last_read {
+            Some(read) => {
+                let len = read - def.step;
+                self.live_ranges += 1;
+                self.live_steps += len;
+                self.longest_live_range = self.longest_live_range.max(len);
+            }
+            None if def.written => self.dead_writes += 1,
+            None => {}
+        }
+    }
+}
+
+/// Value held by a register
+#[derive(Debug, Clone, Copy, Default)]
+struct Def {
+    /// Step which wrote the value
+    step: u64,
+    /// Step of its last read so far
+    last_read: Option<u64>,
+    /// Unset for the initial value
+    written: bool,
+}
+
+/// Counts the reads and writes of every register along with their live ranges
+#[derive(Debug, Clone, Default)]
+pub struct RegStats {
+    usage: [RegUsage; 16],
+    defs: [Def; 16],
+}
+
+impl RegStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the instruction `inst` executed as instruction number `step`
+    pub(crate) fn record(&mut self, step: u64, inst: u32) {
+        let ops = operands(inst);
+        let (reads, writes) = (ops.reads | ops.mem_reads, ops.writes);
+        for reg in (0..16).filter(|r| reads & (1 << r) != 0) {
+            self.usage[reg].reads += 1;
+            self.defs[reg].last_read = Some(step);
+        }
+        for reg in (0..16).filter(|r| writes & (1 << r) != 0) {
+            let usage = &mut self.usage[reg];
+            usage.writes += 1;
+            usage.end_range(&self.defs[reg]);
+            self.defs[reg] = Def {
+                step,
+                last_read: None,
+                written: true,
+            };
+        }
+    }
+
+    /// Returns the usage of each register, counting the live ranges still open
+    pub fn usage(&self) -> [RegUsage; 16] {
+        let mut usage = self.usage;
+        for (reg, def) in self.defs.iter().enumerate() {
+            if def.last_read.is_some() {
+                usage[reg].end_range(def);
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegStats;
+    use crate::parser::parse_and_assemble;
+
+    #[test]
+    fn test_reg_stats() {
+        let code = "
+            mov r1, 5
+            mov r2, 7
+            add r3, r1, 1
+            mov r2, 8
+            add r3, r3, r1
+        ";
+        let bin = parse_and_assemble(code).unwrap();
+        let mut stats = RegStats::new();
+        for (step, &inst) in bin.iter().enumerate() {
+            stats.record(step as u64, inst);
+        }
+        let usage = stats.usage();
+        assert_eq!((usage[1].reads, usage[1].writes), (2, 1));
+        assert_eq!((usage[1].live_ranges, usage[1].live_steps), (1, 4));
+        // The first value of r2 is never read, the last one is not dead
+        assert_eq!((usage[2].writes, usage[2].dead_writes), (2, 1));
+        assert_eq!(usage[2].live_ranges, 0);
+        assert_eq!((usage[3].reads, usage[3].writes), (1, 2));
+        assert_eq!(usage[3].longest_live_range, 2);
+        assert!(usage[4].unused());
+    }
+}
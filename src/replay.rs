@@ -0,0 +1,361 @@
+//! Recording the nondeterministic inputs of a run so that it can be replayed exactly.
+//!
+//! The inputs are the bytes read by the input system calls, the seed of the random
+//! system call, the values returned by the time system call, and the reads and
+//! interrupt requests of devices like the keyboard whose behaviour depends on the host.
+//!
+//! A run is recorded by passing a [`Recorder`] to [`Emulator::record`] and wrapping the
+//! devices using [`Recorder::device`]. It is replayed by passing the [`Recording`] to
+//! [`Emulator::replay`] and wrapping the same devices using [`Recording::device`].
+//! Output is produced again during a replay, while the inputs come from the recording.
+//! A replay fails with [`EmulatorErr::ReplayDiverged`] if the program asks for inputs
+//! which were not recorded, for example because it was changed.
+//!
+//! [`Emulator::record`]: crate::emulator::Emulator::record
+//! [`Emulator::replay`]: crate::emulator::Emulator::replay
+
+use crate::{
+    bus::Device,
+    emulator::EmulatorErr,
+    textfile::{from_hex, invalid, parse, to_hex},
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    io::{self, BufRead, Read, Write},
+    rc::Rc,
+};
+
+/// Inputs of a device, as seen by the program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceLog {
+    /// Tick, offset and bytes of each read
+    pub reads: Vec<(u64, usize, Vec<u8>)>,
+    /// Tick at which the interrupt request changed, and the new state
+    pub irqs: Vec<(u64, bool)>,
+}
+
+/// Nondeterministic inputs of a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    /// Seed of the random system call
+    pub seed: u64,
+    /// Bytes read from the input stream by the system calls
+    pub input: Vec<u8>,
+    /// Values returned by the time system call
+    pub clock: Vec<i32>,
+    /// Logs of the recorded devices by their base address
+    pub devices: BTreeMap<usize, DeviceLog>,
+}
+
+const HEADER: &str = "simple-risc recording 1";
+
+impl Recording {
+    /// Writes the recording in a line based text format
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "seed {}", self.seed)?;
+        writeln!(out, "input {}", to_hex(&self.input))?;
+        let clock: Vec<String> = self.clock.iter().map(i32::to_string).collect();
+        writeln!(out, "clock {}", clock.join(" "))?;
+        for (base, log) in &self.devices {
+            writeln!(out, "device {:#x}", base)?;
+            for (tick, offset, bytes) in &log.reads {
+                writeln!(out, "read {} {} {}", tick, offset, to_hex(bytes))?;
+            }
+            for (tick, irq) in &log.irqs {
+                writeln!(out, "irq {} {}", tick, *irq as u8)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a recording written by [`Recording::write_to`]
+    pub fn read_from(input: impl BufRead) -> io::Result<Self> {
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid("Not a recording"));
+        }
+        let mut recording = Self::default();
+        let mut device = None;
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let mut next = || fields.next().ok_or_else(|| invalid("Missing field"));
+            match next()? {
+                "seed" => recording.seed = parse(next()?)?,
+                "input" => recording.input = from_hex(next().unwrap_or(""))?,
+                "clock" => {
+                    recording.clock = line
+                        .split_whitespace()
+                        .skip(1)
+                        .map(parse)
+                        .collect::<Result<_, _>>()?
+                }
+                "device" => {
+                    let base = next()?;
+                    let base = base.strip_prefix("0x").ok_or_else(|| invalid(base))?;
+                    let base = usize::from_str_radix(base, 16).map_err(|_| invalid(base))?;
+                    device = Some(recording.devices.entry(base).or_default());
+                }
+                "read" => {
+                    let log = device
+                        .as_mut()
+                        .ok_or_else(|| invalid("Read outside a device"))?;
+                    let read = (parse(next()?)?, parse(next()?)?, from_hex(next()?)?);
+                    log.reads.push(read);
+                }
+                "irq" => {
+                    let log = device
+                        .as_mut()
+                        .ok_or_else(|| invalid("Irq outside a device"))?;
+                    let tick = parse(next()?)?;
+                    log.irqs.push((tick, parse::<u8>(next()?)? != 0));
+                }
+                field => return Err(invalid(field)),
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Wraps the `device` mapped at `base` so that its reads and interrupt requests
+    /// are taken from the recording, writes still reach the device
+    pub fn device<D: Device>(&self, base: usize, device: D) -> ReplayedDevice<D> {
+        let log = self.devices.get(&base).cloned().unwrap_or_default();
+        ReplayedDevice {
+            device,
+            reads: log.reads.into(),
+            irqs: log.irqs,
+            ticks: 0,
+        }
+    }
+}
+
+/// Shared handle through which the inputs of a run are recorded
+#[derive(Debug, Clone, Default)]
+pub struct Recorder(Rc<RefCell<Recording>>);
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inputs recorded so far
+    pub fn recording(&self) -> Recording {
+        self.0.borrow().clone()
+    }
+
+    /// Wraps the `device` mapped at `base` to record its reads and interrupt requests
+    pub fn device<D: Device>(&self, base: usize, device: D) -> RecordedDevice<D> {
+        let irq = device.irq();
+        let mut recording = self.0.borrow_mut();
+        let log = recording.devices.entry(base).or_default();
+        if irq {
+            log.irqs.push((0, true));
+        }
+        RecordedDevice {
+            device,
+            recorder: self.clone(),
+            base,
+            ticks: 0,
+            irq,
+        }
+    }
+
+    pub(crate) fn set_seed(&self, seed: u64) {
+        self.0.borrow_mut().seed = seed;
+    }
+
+    pub(crate) fn push_clock(&self, ms: i32) {
+        self.0.borrow_mut().clock.push(ms);
+    }
+
+    fn log(&self, base: usize, f: impl FnOnce(&mut DeviceLog)) {
+        f(self.0.borrow_mut().devices.entry(base).or_default());
+    }
+}
+
+/// Input stream which records the bytes read from it
+pub(crate) struct RecordedInput {
+    pub input: Box<dyn Read>,
+    pub recorder: Recorder,
+}
+
+impl Read for RecordedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.recorder
+            .0
+            .borrow_mut()
+            .input
+            .extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Device whose reads and interrupt requests are recorded, see [`Recorder::device`]
+pub struct RecordedDevice<D> {
+    device: D,
+    recorder: Recorder,
+    base: usize,
+    ticks: u64,
+    /// Last recorded interrupt request
+    irq: bool,
+}
+
+impl<D: Device> Device for RecordedDevice<D> {
+    fn size(&self) -> usize {
+        self.device.size()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        self.device.read(offset, buf)?;
+        let read = (self.ticks, offset, buf.to_vec());
+        self.recorder.log(self.base, |log| log.reads.push(read));
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        self.device.write(offset, bytes)
+    }
+
+    fn tick(&mut self) {
+        self.advance(1);
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.device.advance(ticks);
+        self.ticks += ticks;
+        // Interrupts are checked before the next instruction, so this is what the program sees
+        let irq = self.device.irq();
+        if irq != self.irq {
+            self.irq = irq;
+            let ticks = self.ticks;
+            self.recorder
+                .log(self.base, |log| log.irqs.push((ticks, irq)));
+        }
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        self.device.idle_ticks()
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+}
+
+/// Device whose reads and interrupt requests come from a recording, see [`Recording::device`]
+pub struct ReplayedDevice<D> {
+    device: D,
+    reads: VecDeque<(u64, usize, Vec<u8>)>,
+    irqs: Vec<(u64, bool)>,
+    ticks: u64,
+}
+
+impl<D: Device> Device for ReplayedDevice<D> {
+    fn size(&self) -> usize {
+        self.device.size()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EmulatorErr> {
+        match self.reads.pop_front() {
+            Some((ticks, off, bytes))
+                if ticks == self.ticks && off == offset && bytes.len() == buf.len() =>
+            {
+                buf.copy_from_slice(&bytes);
+                Ok(())
+            }
+            _ => Err(EmulatorErr::ReplayDiverged),
+        }
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), EmulatorErr> {
+        self.device.write(offset, bytes)
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        self.ticks += ticks;
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        let next = self.irqs.partition_point(|&(ticks, _)| ticks <= self.ticks);
+        self.irqs
+            .get(next)
+            .map_or(u64::MAX, |&(ticks, _)| ticks - self.ticks - 1)
+    }
+
+    fn irq(&self) -> bool {
+        // State of the last change at or before the current tick
+        let changed = self.irqs.partition_point(|&(ticks, _)| ticks <= self.ticks);
+        changed > 0 && self.irqs[changed - 1].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Recording};
+    use crate::{
+        bus::Device,
+        devices::{keyboard, Keyboard},
+    };
+
+    #[test]
+    fn test_file_format() {
+        let recorder = Recorder::new();
+        recorder.set_seed(42);
+        recorder.push_clock(5);
+        recorder.push_clock(-1);
+        recorder.log(0x100, |log| {
+            log.reads.push((3, 4, vec![1, 0xff]));
+            log.irqs.push((7, true));
+        });
+        let recording = recorder.recording();
+        let mut text = Vec::new();
+        recording.write_to(&mut text).unwrap();
+        assert_eq!(Recording::read_from(&text[..]).unwrap(), recording);
+
+        assert!(Recording::read_from(&b"seed 1\n"[..]).is_err());
+        let bad = format!("{}\nread 1 2 ab\n", super::HEADER);
+        assert!(Recording::read_from(bad.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_device_replay() {
+        let read_word = |dev: &mut dyn Device, offset| {
+            let mut buf = [0u8; 4];
+            dev.read(offset, &mut buf).map(|_| u32::from_le_bytes(buf))
+        };
+        let recorder = Recorder::new();
+        let kbd = Keyboard::new();
+        let sender = kbd.sender();
+        let mut dev = recorder.device(0, kbd);
+        dev.write(keyboard::CTRL, &keyboard::IRQ_ENABLE.to_le_bytes())
+            .unwrap();
+        dev.tick();
+        sender.send(b'a').unwrap();
+        dev.tick();
+        assert!(dev.irq());
+        assert_eq!(read_word(&mut dev, keyboard::DATA).unwrap(), b'a' as u32);
+        dev.tick();
+        assert!(!dev.irq());
+
+        let recording = recorder.recording();
+        let mut dev = recording.device(0, Keyboard::new());
+        dev.write(keyboard::CTRL, &keyboard::IRQ_ENABLE.to_le_bytes())
+            .unwrap();
+        assert!(!dev.irq());
+        dev.tick();
+        dev.tick();
+        assert!(dev.irq());
+        assert_eq!(read_word(&mut dev, keyboard::DATA).unwrap(), b'a' as u32);
+        dev.tick();
+        assert!(!dev.irq());
+        // Nothing more was read in the recorded run
+        assert!(read_word(&mut dev, keyboard::DATA).is_err());
+    }
+}
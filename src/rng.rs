@@ -0,0 +1,62 @@
+//! Pseudo-random number generator used by the random system call.
+//!
+//! It is the SplitMix64 generator, which is small and good enough for games
+//! and randomized tests. The same seed always produces the same sequence.
+
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Creates a generator seeded with the current time
+    #[cfg(feature = "std")]
+    pub fn from_time() -> Self {
+        Self::new(time_seed())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_i32(&mut self) -> i32 {
+        (self.next_u64() >> 32) as i32
+    }
+}
+
+/// Seed derived from the current time
+#[cfg(feature = "std")]
+pub fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+
+    #[test]
+    fn test_rng() {
+        // Reference values of SplitMix64 for seed 0
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rng.next_u64(), 0x6e78_9e6a_a1b9_65f4);
+
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let first: Vec<i32> = (0..8).map(|_| a.next_i32()).collect();
+        assert!(first.iter().all(|&x| x == b.next_i32()));
+    }
+}
@@ -0,0 +1,571 @@
+//! Snapshots of the machine state, see [`Emulator::snapshot`], and checkpoints which
+//! save them to files so that the execution can be continued later. The registers and
+//! flags alone are returned by [`Emulator::machine_state`] for showing them.
+//!
+//! [`Emulator::snapshot`]: crate::emulator::Emulator::snapshot
+//! [`Emulator::machine_state`]: crate::emulator::Emulator::machine_state
+
+#[cfg(feature = "std")]
+use crate::textfile::{from_hex, invalid, parse};
+use crate::{
+    csr::{self, CsrFile},
+    debug::CallFrame,
+    emulator::{EmulatorConfig, PcBounds, Xlen},
+    json,
+    memory::Endian,
+    textfile::{decode_hex, to_hex},
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+
+/// Everything which decides how the execution continues, except for the program
+/// and the state of memory-mapped devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub regs: [i32; 16],
+    /// Upper halves of the registers in 64-bit mode, see [`Xlen::X64`]
+    ///
+    /// [`Xlen::X64`]: crate::emulator::Xlen::X64
+    pub regs_hi: Option<[i32; 16]>,
+    pub pc: i32,
+    pub flag_e: bool,
+    pub flag_g: bool,
+    pub csrs: CsrFile,
+    /// Set by the exit system call
+    pub exit_code: Option<i32>,
+    pub steps: u64,
+    pub cycles: u64,
+    pub energy: u64,
+    pub call_stack: Vec<CallFrame>,
+    /// Parts of the memory which may hold non-zero bytes, see [`Memory::contents`]
+    ///
+    /// [`Memory::contents`]: crate::memory::Memory::contents
+    pub memory: Vec<(usize, Vec<u8>)>,
+}
+
+impl State {
+    /// Returns the state as a JSON object, for tools outside Rust. The memory is a list
+    /// of objects with the `addr` and the `bytes` as a hex string.
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+        let regs = |regs: &[i32; 16]| list(regs.iter().map(i32::to_string).collect());
+        let csrs = &self.csrs;
+        let call_stack = self
+            .call_stack
+            .iter()
+            .map(|f| format!("{{\"call_pc\":{},\"target\":{}}}", f.call_pc, f.target));
+        let memory = self
+            .memory
+            .iter()
+            .map(|(addr, bytes)| format!("{{\"addr\":{},\"bytes\":\"{}\"}}", addr, to_hex(bytes)));
+        format!(
+            "{{\"regs\":{},\"regs_hi\":{},\"pc\":{},\"flag_e\":{},\"flag_g\":{},\"csrs\":{{\"status\":{},\"epc\":{},\"cause\":{},\"scratch\":{},\"badaddr\":{},\"ptbr\":{},\"flags\":{}}},\"exit_code\":{},\"steps\":{},\"cycles\":{},\"energy\":{},\"call_stack\":{},\"memory\":{}}}",
+            regs(&self.regs),
+            self.regs_hi.as_ref().map_or_else(|| String::from("null"), regs),
+            self.pc,
+            self.flag_e,
+            self.flag_g,
+            csrs.status,
+            csrs.epc,
+            csrs.cause,
+            csrs.scratch,
+            csrs.badaddr,
+            csrs.ptbr,
+            csrs.flags,
+            self.exit_code.map_or_else(|| String::from("null"), |c| c.to_string()),
+            self.steps,
+            self.cycles,
+            self.energy,
+            list(call_stack.collect()),
+            list(memory.collect()),
+        )
+    }
+
+    /// Reads a state written by [`State::to_json`]
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let root = json::parse(text)?;
+        let invalid = |key: &str| format!("Invalid field '{}'", key);
+        let list = |key: &str| root.field(key)?.as_array().ok_or_else(|| invalid(key));
+        let regs = |key: &str| {
+            let values = list(key)?;
+            let mut regs = [0; 16];
+            if values.len() != regs.len() {
+                return Err(invalid(key));
+            }
+            for (reg, value) in regs.iter_mut().zip(values) {
+                *reg = value.as_int().ok_or_else(|| invalid(key))?;
+            }
+            Ok(regs)
+        };
+        // Also missing from states written before the 64-bit mode existed
+        let regs_hi = match root.field("regs_hi") {
+            Err(_) | Ok(json::Value::Null) => None,
+            Ok(_) => Some(regs("regs_hi")?),
+        };
+        let flag = |key: &str| root.field(key)?.as_bool().ok_or_else(|| invalid(key));
+        let fields = root.field("csrs")?;
+        // PTBR and FLAGS are missing from states written before they existed
+        let optional = |key: &str| match fields.field(key) {
+            Ok(_) => fields.int(key),
+            Err(_) => Ok(0),
+        };
+        let csrs = CsrFile {
+            status: fields.int("status")?,
+            epc: fields.int("epc")?,
+            cause: fields.int("cause")?,
+            scratch: fields.int("scratch")?,
+            badaddr: fields.int("badaddr")?,
+            ptbr: optional("ptbr")?,
+            flags: optional("flags")?,
+            ..CsrFile::default()
+        };
+        let exit_code = match root.field("exit_code")? {
+            json::Value::Null => None,
+            code => Some(code.as_int().ok_or_else(|| invalid("exit_code"))?),
+        };
+        let call_stack = list("call_stack")?
+            .iter()
+            .map(|frame| {
+                Ok(CallFrame {
+                    call_pc: frame.int("call_pc")?,
+                    target: frame.int("target")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let memory = list("memory")?
+            .iter()
+            .map(|part| {
+                let bytes = part.field("bytes")?.as_str().and_then(decode_hex);
+                Ok((part.int("addr")?, bytes.ok_or_else(|| invalid("bytes"))?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            regs: regs("regs")?,
+            regs_hi,
+            pc: root.int("pc")?,
+            flag_e: flag("flag_e")?,
+            flag_g: flag("flag_g")?,
+            csrs,
+            exit_code,
+            steps: root.int("steps")?,
+            cycles: root.int("cycles")?,
+            energy: root.int("energy")?,
+            call_stack,
+            memory,
+        })
+    }
+}
+
+/// A [`State`] along with the program, the memory size and the options needed to
+/// continue the execution later, possibly in another process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Encoded instructions of the program
+    pub text: Vec<u32>,
+    /// Size of the memory in bytes
+    pub mem_bytes: usize,
+    pub config: CheckpointConfig,
+    pub state: State,
+}
+
+/// Options of [`EmulatorConfig`] which change how the program executes, so the execution
+/// has to continue with the same ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    pub endian: Endian,
+    pub xlen: Xlen,
+    pub text_base: Option<usize>,
+    pub trap_faults: bool,
+    pub trap_overflow: bool,
+    pub strict: bool,
+    pub pc_bounds: PcBounds,
+}
+
+impl CheckpointConfig {
+    /// Returns the options set in `config`
+    pub fn of(config: &EmulatorConfig) -> Self {
+        Self {
+            endian: config.endian,
+            xlen: config.xlen,
+            text_base: config.text_base,
+            trap_faults: config.trap_faults,
+            trap_overflow: config.trap_overflow,
+            strict: config.strict,
+            pc_bounds: config.pc_bounds,
+        }
+    }
+
+    /// Sets the options in `config`, leaving the others alone
+    pub fn apply(&self, config: &mut EmulatorConfig) {
+        config.endian = self.endian;
+        config.xlen = self.xlen;
+        config.text_base = self.text_base;
+        config.trap_faults = self.trap_faults;
+        config.trap_overflow = self.trap_overflow;
+        config.strict = self.strict;
+        config.pc_bounds = self.pc_bounds;
+    }
+}
+
+#[cfg(feature = "std")]
+const HEADER: &str = "simple-risc checkpoint 1";
+
+impl Checkpoint {
+    /// Writes the checkpoint in a line based text format
+    #[cfg(feature = "std")]
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let state = &self.state;
+        let join = |values: &mut dyn Iterator<Item = String>| values.collect::<Vec<_>>().join(" ");
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "mem-size {}", self.mem_bytes)?;
+        let config = &self.config;
+        writeln!(out, "endian {}", config.endian)?;
+        writeln!(out, "xlen {}", config.xlen)?;
+        if let Some(base) = config.text_base {
+            writeln!(out, "text-base {:#x}", base)?;
+        }
+        for (set, option) in [
+            (config.trap_faults, "trap-faults"),
+            (config.trap_overflow, "trap-overflow"),
+            (config.strict, "strict"),
+        ] {
+            if set {
+                writeln!(out, "{}", option)?;
+            }
+        }
+        writeln!(out, "pc-bounds {}", config.pc_bounds)?;
+        for inst in &self.text {
+            writeln!(out, "inst {:08x}", inst)?;
+        }
+        writeln!(
+            out,
+            "regs {}",
+            join(&mut state.regs.iter().map(i32::to_string))
+        )?;
+        if let Some(regs_hi) = &state.regs_hi {
+            writeln!(
+                out,
+                "regs-hi {}",
+                join(&mut regs_hi.iter().map(i32::to_string))
+            )?;
+        }
+        writeln!(out, "pc {}", state.pc)?;
+        writeln!(out, "flags {} {}", state.flag_e as u8, state.flag_g as u8)?;
+        let csrs = &state.csrs;
+        let values = [
+            csrs.status,
+            csrs.epc,
+            csrs.cause,
+            csrs.scratch,
+            csrs.badaddr,
+            csrs.ptbr,
+            csrs.flags,
+        ];
+        writeln!(out, "csrs {}", join(&mut values.iter().map(u32::to_string)))?;
+        if let Some(code) = state.exit_code {
+            writeln!(out, "exit {}", code)?;
+        }
+        writeln!(out, "steps {}", state.steps)?;
+        writeln!(out, "cycles {}", state.cycles)?;
+        writeln!(out, "energy {}", state.energy)?;
+        for frame in &state.call_stack {
+            writeln!(out, "call {} {}", frame.call_pc, frame.target)?;
+        }
+        for (addr, bytes) in &state.memory {
+            writeln!(out, "mem {:#x} {}", addr, to_hex(bytes))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a checkpoint written by [`Checkpoint::write_to`]
+    #[cfg(feature = "std")]
+    pub fn read_from(input: impl BufRead) -> io::Result<Self> {
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid("Not a checkpoint"));
+        }
+        let mut checkpoint = Self {
+            text: Vec::new(),
+            mem_bytes: 0,
+            config: CheckpointConfig::default(),
+            state: State {
+                regs: [0; 16],
+                regs_hi: None,
+                pc: 0,
+                flag_e: false,
+                flag_g: false,
+                csrs: CsrFile::default(),
+                exit_code: None,
+                steps: 0,
+                cycles: 0,
+                energy: 0,
+                call_stack: Vec::new(),
+                memory: Vec::new(),
+            },
+        };
+        let (config, state) = (&mut checkpoint.config, &mut checkpoint.state);
+        for line in lines {
+            let line = line?;
+            let (key, rest) = line.split_once(' ').unwrap_or((&line, ""));
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let field = |i: usize| fields.get(i).copied().ok_or_else(|| invalid(&line));
+            match key {
+                "mem-size" => checkpoint.mem_bytes = parse(field(0)?)?,
+                "endian" => config.endian = parse(field(0)?)?,
+                "xlen" => config.xlen = parse(field(0)?)?,
+                "text-base" => {
+                    let base = field(0)?.strip_prefix("0x").ok_or_else(|| invalid(&line))?;
+                    let base = usize::from_str_radix(base, 16).map_err(|_| invalid(&line))?;
+                    config.text_base = Some(base);
+                }
+                "trap-faults" => config.trap_faults = true,
+                "trap-overflow" => config.trap_overflow = true,
+                "strict" => config.strict = true,
+                "pc-bounds" => config.pc_bounds = parse(field(0)?)?,
+                "inst" => {
+                    let inst = u32::from_str_radix(field(0)?, 16).map_err(|_| invalid(&line))?;
+                    checkpoint.text.push(inst);
+                }
+                "regs" => {
+                    for (r, reg) in state.regs.iter_mut().enumerate() {
+                        *reg = parse(field(r)?)?;
+                    }
+                }
+                "regs-hi" => {
+                    let mut regs_hi = [0; 16];
+                    for (r, reg) in regs_hi.iter_mut().enumerate() {
+                        *reg = parse(field(r)?)?;
+                    }
+                    state.regs_hi = Some(regs_hi);
+                }
+                "pc" => state.pc = parse(field(0)?)?,
+                "flags" => {
+                    state.flag_e = parse::<u8>(field(0)?)? != 0;
+                    state.flag_g = parse::<u8>(field(1)?)? != 0;
+                }
+                "csrs" => {
+                    let csrs = &mut state.csrs;
+                    let regs = [
+                        &mut csrs.status,
+                        &mut csrs.epc,
+                        &mut csrs.cause,
+                        &mut csrs.scratch,
+                        &mut csrs.badaddr,
+                        &mut csrs.ptbr,
+                        &mut csrs.flags,
+                    ];
+                    // PTBR and FLAGS are missing from checkpoints written before they existed
+                    field(4)?;
+                    for (csr, value) in regs.into_iter().zip(&fields) {
+                        *csr = parse(value)?;
+                    }
+                }
+                "exit" => state.exit_code = Some(parse(field(0)?)?),
+                "steps" => state.steps = parse(field(0)?)?,
+                "cycles" => state.cycles = parse(field(0)?)?,
+                "energy" => state.energy = parse(field(0)?)?,
+                "call" => state.call_stack.push(CallFrame {
+                    call_pc: parse(field(0)?)?,
+                    target: parse(field(1)?)?,
+                }),
+                "mem" => {
+                    let addr = field(0)?.strip_prefix("0x").ok_or_else(|| invalid(&line))?;
+                    let addr = usize::from_str_radix(addr, 16).map_err(|_| invalid(&line))?;
+                    state.memory.push((addr, from_hex(field(1)?)?));
+                }
+                _ => return Err(invalid(&line)),
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+/// Registers and flags visible to the program, displayed one register per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineState {
+    pub regs: [i32; 16],
+    /// Upper halves of the registers in 64-bit mode, which are then displayed whole
+    pub regs_hi: Option<[i32; 16]>,
+    pub pc: i32,
+    pub flag_e: bool,
+    pub flag_g: bool,
+    /// Flags N, Z, C and V in the `FLAGS` CSR
+    pub nzcv: u32,
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (reg, &value) in self.regs.iter().enumerate() {
+            match self.regs_hi {
+                Some(hi) => {
+                    let value = (hi[reg] as i64) << 32 | value as u32 as i64;
+                    writeln!(f, "r{:<2} = {:<20} (0x{:016x})", reg, value, value)?
+                }
+                None => writeln!(f, "r{:<2} = {:<11} (0x{:08x})", reg, value, value)?,
+            }
+        }
+        writeln!(f, "pc  = {}", self.pc)?;
+        write!(f, "E = {}, GT = {}", self.flag_e as u8, self.flag_g as u8)?;
+        for (name, flag) in [
+            ("N", csr::FLAG_N),
+            ("Z", csr::FLAG_Z),
+            ("C", csr::FLAG_C),
+            ("V", csr::FLAG_V),
+        ] {
+            write!(f, ", {} = {}", name, (self.nzcv & flag != 0) as u8)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, CheckpointConfig, MachineState, State};
+    use crate::{
+        csr::CsrFile,
+        debug::CallFrame,
+        emulator::{EmulatorConfig, PcBounds, Xlen},
+        memory::Endian,
+    };
+
+    #[test]
+    fn test_checkpoint_file() {
+        let mut regs = [0; 16];
+        regs[1] = -5;
+        regs[14] = 4096;
+        let checkpoint = Checkpoint {
+            text: vec![0x1234_5678, 0xffff_ffff],
+            mem_bytes: 8192,
+            config: CheckpointConfig {
+                endian: Endian::Big,
+                text_base: Some(0x1000),
+                trap_faults: true,
+                pc_bounds: PcBounds::Error,
+                ..Default::default()
+            },
+            state: State {
+                regs,
+                regs_hi: None,
+                pc: 1,
+                flag_e: false,
+                flag_g: true,
+                csrs: CsrFile {
+                    epc: 7,
+                    flags: 5,
+                    ..Default::default()
+                },
+                exit_code: Some(-1),
+                steps: 1 << 40,
+                cycles: 3 << 40,
+                energy: 12,
+                call_stack: vec![CallFrame {
+                    call_pc: 0,
+                    target: 1,
+                }],
+                memory: vec![(0, vec![1, 2]), (4096, vec![0xab])],
+            },
+        };
+        let mut text = Vec::new();
+        checkpoint.write_to(&mut text).unwrap();
+        assert_eq!(Checkpoint::read_from(&text[..]).unwrap(), checkpoint);
+        let lines = String::from_utf8(text.clone()).unwrap();
+        assert!(
+            lines.contains("endian big\nxlen 32\ntext-base 0x1000\ntrap-faults\npc-bounds error\n")
+        );
+
+        let mut config = EmulatorConfig {
+            strict: true,
+            seed: Some(3),
+            ..Default::default()
+        };
+        checkpoint.config.apply(&mut config);
+        assert_eq!(CheckpointConfig::of(&config), checkpoint.config);
+        assert_eq!((config.strict, config.seed), (false, Some(3)));
+
+        text.extend_from_slice(b"regs 1 2\n");
+        assert!(Checkpoint::read_from(&text[..]).is_err());
+        assert!(Checkpoint::read_from(&b"pc 1\n"[..]).is_err());
+
+        let json = checkpoint.state.to_json();
+        assert!(json.starts_with("{\"regs\":[0,-5,0,"));
+        assert!(json.contains("\"flag_g\":true,\"csrs\":{\"status\":2,\"epc\":7,"));
+        assert!(json.contains(
+            "\"exit_code\":-1,\"steps\":1099511627776,\"cycles\":3298534883328,\"energy\":12,"
+        ));
+        assert!(json.ends_with(
+            "\"call_stack\":[{\"call_pc\":0,\"target\":1}],\"memory\":[{\"addr\":0,\"bytes\":\"0102\"},{\"addr\":4096,\"bytes\":\"ab\"}]}"
+        ));
+        assert_eq!(State::from_json(&json).unwrap(), checkpoint.state);
+        let older = json.replace(",\"ptbr\":0,\"flags\":5", "");
+        let state = State::from_json(&older).unwrap();
+        assert_eq!(state.csrs.flags, 0);
+        assert_eq!(state.memory, checkpoint.state.memory);
+
+        let bad = json.replace("\"bytes\":\"ab\"", "\"bytes\":\"a\"");
+        assert_eq!(
+            State::from_json(&bad),
+            Err("Invalid field 'bytes'".to_string())
+        );
+        let bad = json.replace("\"pc\":1,", "");
+        assert_eq!(
+            State::from_json(&bad),
+            Err("Missing field 'pc'".to_string())
+        );
+        assert!(State::from_json(&json[1..]).is_err());
+
+        let mut wide = checkpoint.clone();
+        wide.state.regs_hi = Some([-1; 16]);
+        wide.config.xlen = Xlen::X64;
+        let mut text = Vec::new();
+        wide.write_to(&mut text).unwrap();
+        assert_eq!(Checkpoint::read_from(&text[..]).unwrap(), wide);
+        let json = wide.state.to_json();
+        assert!(
+            json.starts_with("{\"regs\":[0,-5,0,0,0,0,0,0,0,0,0,0,0,0,4096,0],\"regs_hi\":[-1,")
+        );
+        assert_eq!(State::from_json(&json).unwrap(), wide.state);
+    }
+
+    #[test]
+    fn test_machine_state() {
+        let mut regs = [0; 16];
+        regs[1] = -1;
+        let mut state = MachineState {
+            regs,
+            regs_hi: None,
+            pc: 3,
+            flag_e: true,
+            flag_g: false,
+            nzcv: 0b0110,
+        };
+        let text = state.to_string();
+        let mut lines = text.lines();
+        assert_eq!(lines.nth(1), Some("r1  = -1          (0xffffffff)"));
+        assert_eq!(lines.nth(14), Some("pc  = 3"));
+        assert_eq!(
+            lines.next(),
+            Some("E = 1, GT = 0, N = 0, Z = 1, C = 1, V = 0")
+        );
+        assert_eq!(lines.next(), None);
+
+        let mut regs_hi = [0; 16];
+        regs_hi[2] = 1;
+        state.regs_hi = Some(regs_hi);
+        let text = state.to_string();
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.nth(1),
+            Some("r1  = 4294967295           (0x00000000ffffffff)")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("r2  = 4294967296           (0x0000000100000000)")
+        );
+    }
+}
@@ -0,0 +1,557 @@
+//! System calls made by the `sys` instruction.
+//!
+//! The emulator first offers a system call to the handler installed using
+//! [`Emulator::set_syscall_handler`](crate::emulator::Emulator::set_syscall_handler),
+//! if it does not handle the call then [`DefaultSyscalls`] does.
+//! So embedders can add new system calls or override the default ones.
+//! The default ones use the standard input and output, so without the `std` feature
+//! only the installed handler implements system calls.
+//!
+//! The input system calls never block the emulator when their stream does not: a stream
+//! whose reads fail with [`io::ErrorKind::WouldBlock`], like [`HostInput`], makes them wait
+//! by executing the `sys` instruction again, so [`Emulator::run_for`] returns to the caller
+//! in between. `try_getchar` and `input_ready` return at once instead.
+//!
+//! [`Emulator::run_for`]: crate::emulator::Emulator::run_for
+
+use crate::{emulator::EmulatorErr, memory::Memory};
+#[cfg(feature = "std")]
+use crate::{
+    emulator::{EmulatorConfig, FAKE_CLOCK_STEPS_PER_MS},
+    info::syscalls,
+    replay::{RecordedInput, Recorder, Recording},
+    rng::{self, Rng},
+};
+use alloc::{vec, vec::Vec};
+use core::num::Wrapping;
+#[cfg(feature = "std")]
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::Instant,
+};
+
+/// Implements system calls, see the module documentation
+pub trait SyscallHandler {
+    /// Handles the system call whose number is [`SyscallContext::number`] and returns true,
+    /// or returns false without doing anything if it is not handled by this handler.
+    fn syscall(&mut self, ctx: &mut SyscallContext) -> Result<bool, EmulatorErr>;
+}
+
+/// Machine state available to a system call
+pub struct SyscallContext<'e> {
+    pub(crate) regs: &'e mut [Wrapping<i32>; 16],
+    pub(crate) memory: &'e mut dyn Memory,
+    pub(crate) steps: u64,
+    pub(crate) cycles: u64,
+    pub(crate) exit_code: &'e mut Option<i32>,
+    pub(crate) retry: bool,
+}
+
+impl SyscallContext<'_> {
+    /// System call number, passed in r0
+    pub fn number(&self) -> i32 {
+        self.regs[0].0
+    }
+
+    /// Returns the value of the register `reg`, arguments are passed in r[1-4]
+    pub fn reg(&self, reg: usize) -> i32 {
+        self.regs[reg].0
+    }
+
+    pub fn set_reg(&mut self, reg: usize, value: i32) {
+        self.regs[reg] = Wrapping(value);
+    }
+
+    /// Sets the return value, which is passed in r0
+    pub fn set_return(&mut self, value: i32) {
+        self.set_reg(0, value);
+    }
+
+    pub fn memory(&mut self) -> &mut dyn Memory {
+        self.memory
+    }
+
+    /// Number of instructions executed before the `sys` instruction
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Cycles taken by the instructions executed before the `sys` instruction
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Stops the program after the `sys` instruction with the exit `code`
+    pub fn exit(&mut self, code: i32) {
+        *self.exit_code = Some(code);
+    }
+
+    /// Executes the `sys` instruction again instead of the next one, without changing the
+    /// registers, for a system call waiting for something which is not available yet
+    pub fn retry(&mut self) {
+        self.retry = true;
+    }
+
+    /// Checks that the buffer of `len` bytes at `addr` lies within the memory
+    /// and returns its length
+    pub fn buffer_len(&self, addr: i32, len: i32) -> Result<usize, EmulatorErr> {
+        let (Ok(addr), Ok(len)) = (usize::try_from(addr), usize::try_from(len)) else {
+            return Err(EmulatorErr::InvalidMemAddr);
+        };
+        match addr.checked_add(len) {
+            Some(end) if end <= self.memory.size() => Ok(len),
+            _ => Err(EmulatorErr::InvalidMemAddr),
+        }
+    }
+
+    /// Reads the buffer of `len` bytes at `addr`
+    pub fn read_buffer(&mut self, addr: i32, len: i32) -> Result<Vec<u8>, EmulatorErr> {
+        let mut buf = vec![0u8; self.buffer_len(addr, len)?];
+        self.memory.read_bytes(addr as usize, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Seed of the random system call in deterministic mode unless another one is given,
+/// see [`EmulatorConfig::deterministic`](crate::emulator::EmulatorConfig::deterministic)
+pub const DETERMINISTIC_SEED: u64 = 0;
+
+#[cfg(feature = "std")]
+/// System calls documented in simpleRISC.md.
+/// By default they use the standard input and output of the process.
+pub struct DefaultSyscalls {
+    seed: u64,
+    rng: Rng,
+    fake_clock: bool,
+    started: Instant,
+    /// Records the values returned by the time system call
+    recorder: Option<Recorder>,
+    /// Values returned by the time system call when replaying a run
+    replayed_clock: Option<VecDeque<i32>>,
+    input: Input,
+    output: Box<dyn Write>,
+}
+
+#[cfg(feature = "std")]
+impl DefaultSyscalls {
+    pub fn new(config: &EmulatorConfig) -> Self {
+        let seed = match config.deterministic {
+            true => config.seed.unwrap_or(DETERMINISTIC_SEED),
+            false => config.seed.unwrap_or_else(rng::time_seed),
+        };
+        Self {
+            seed,
+            rng: Rng::new(seed),
+            fake_clock: config.fake_clock || config.deterministic,
+            started: Instant::now(),
+            recorder: None,
+            replayed_clock: None,
+            input: Input::new(Box::new(io::stdin()), config.deterministic),
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// Sets the stream read by the input system calls
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.input = Input::new(input, self.input.blocking);
+    }
+
+    /// Sets the stream written by the output system calls
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Records the inputs read from now on using `recorder`, see [`crate::replay`]
+    pub fn record(&mut self, recorder: Recorder) {
+        recorder.set_seed(self.seed);
+        let input = std::mem::replace(&mut self.input.stream, Box::new(io::empty()));
+        self.input.stream = Box::new(RecordedInput {
+            input,
+            recorder: recorder.clone(),
+        });
+        self.recorder = Some(recorder);
+    }
+
+    /// Takes the inputs from `recording` instead of the input stream, the clock
+    /// and the seed, see [`crate::replay`]
+    pub fn replay(&mut self, recording: &Recording) {
+        self.seed = recording.seed;
+        self.rng = Rng::new(recording.seed);
+        let input = io::Cursor::new(recording.input.clone());
+        self.input = Input::new(Box::new(input), self.input.blocking);
+        self.replayed_clock = Some(recording.clock.iter().copied().collect());
+    }
+
+    /// Restarts the clock and the random numbers from the seed, like for a new emulator
+    pub(crate) fn reset(&mut self) {
+        self.rng = Rng::new(self.seed);
+        self.started = Instant::now();
+    }
+
+    /// Returns the value of the time system call
+    fn time_ms(&mut self, steps: u64) -> Result<i32, EmulatorErr> {
+        if let Some(clock) = &mut self.replayed_clock {
+            return clock.pop_front().ok_or(EmulatorErr::ReplayDiverged);
+        }
+        let ms = if self.fake_clock {
+            (steps / FAKE_CLOCK_STEPS_PER_MS) as i32
+        } else {
+            self.started.elapsed().as_millis() as i32
+        };
+        if let Some(recorder) = &self.recorder {
+            recorder.push_clock(ms);
+        }
+        Ok(ms)
+    }
+
+    /// Writes `bytes` to the output and flushes it
+    fn write_out(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)?;
+        self.output.flush()
+    }
+}
+
+#[cfg(feature = "std")]
+impl SyscallHandler for DefaultSyscalls {
+    fn syscall(&mut self, ctx: &mut SyscallContext) -> Result<bool, EmulatorErr> {
+        let (arg1, arg2) = (ctx.reg(1), ctx.reg(2));
+        self.input.begin();
+        let ret = match ctx.number() {
+            syscalls::GETCHAR | syscalls::TRY_GETCHAR => {
+                let mut byte = [0u8; 1];
+                match self.input.read(&mut byte) {
+                    Ok(1) => byte[0] as i32,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        if ctx.number() == syscalls::GETCHAR {
+                            ctx.retry();
+                            return Ok(true);
+                        }
+                        -2
+                    }
+                    _ => -1,
+                }
+            }
+            syscalls::PUTCHAR => match self.write_out(&[arg1 as u8]) {
+                Ok(_) => arg1 as u8 as i32,
+                Err(_) => -1,
+            },
+            syscalls::PRINT_REG => {
+                let text = format!("{}\n", ctx.reg((arg1 & 0xf) as usize));
+                match self.write_out(text.as_bytes()) {
+                    Ok(_) => 0,
+                    Err(_) => -1,
+                }
+            }
+            syscalls::EXIT => {
+                ctx.exit(arg1);
+                0
+            }
+            syscalls::PRINT_INT | syscalls::PRINT_HEX => {
+                let text = if ctx.number() == syscalls::PRINT_INT {
+                    format!("{}", arg1)
+                } else {
+                    format!("{:#x}", arg1)
+                };
+                match self.write_out(text.as_bytes()) {
+                    Ok(_) => 0,
+                    Err(_) => -1,
+                }
+            }
+            syscalls::READ_INT => {
+                let value = match read_int(&mut self.input) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        if self.input.wait(&err) {
+                            ctx.retry();
+                            return Ok(true);
+                        }
+                        None
+                    }
+                };
+                // Success is reported in r1 as any value can be read
+                ctx.set_reg(1, if value.is_some() { 0 } else { -1 });
+                value.unwrap_or(0)
+            }
+            syscalls::WRITE => {
+                let buf = ctx.read_buffer(arg1, arg2)?;
+                match self.write_out(&buf) {
+                    Ok(_) => buf.len() as i32,
+                    Err(_) => -1,
+                }
+            }
+            syscalls::READ => {
+                let mut buf = vec![0u8; ctx.buffer_len(arg1, arg2)?];
+                match read_line(&mut self.input, &mut buf) {
+                    Ok(n) => {
+                        ctx.memory().write_bytes(arg1 as usize, &buf[..n])?;
+                        n as i32
+                    }
+                    Err(err) if self.input.wait(&err) => {
+                        ctx.retry();
+                        return Ok(true);
+                    }
+                    Err(_) => -1,
+                }
+            }
+            syscalls::RANDOM => self.rng.next_i32(),
+            syscalls::TIME_MS => self.time_ms(ctx.steps())?,
+            syscalls::CYCLES => {
+                ctx.set_reg(1, (ctx.cycles() >> 32) as i32);
+                ctx.cycles() as i32
+            }
+            syscalls::INPUT_READY => self.input.ready(),
+            _ => return Ok(false),
+        };
+        ctx.set_return(ret);
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Input stream of the system calls, which can put back the bytes read by a system call
+/// that has to wait so that it reads them again when it is retried
+struct Input {
+    stream: Box<dyn Read>,
+    /// Bytes to read before the ones of the stream
+    unread: VecDeque<u8>,
+    /// Bytes read since [`Input::begin`]
+    taken: Vec<u8>,
+    /// Wait for the bytes of a non-blocking stream, so that what is read does not depend
+    /// on when they arrive
+    blocking: bool,
+}
+
+#[cfg(feature = "std")]
+impl Input {
+    fn new(stream: Box<dyn Read>, blocking: bool) -> Self {
+        Self {
+            stream,
+            unread: VecDeque::new(),
+            taken: Vec::new(),
+            blocking,
+        }
+    }
+
+    /// Reads from the stream, waiting for it if the input is blocking
+    fn read_stream(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(err) if self.blocking && err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::yield_now()
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Starts a system call, the bytes it reads are put back if it has to wait
+    fn begin(&mut self) {
+        self.taken.clear();
+    }
+
+    /// Returns true if reading failed with `err` only because no byte is available yet,
+    /// then the bytes read since [`Input::begin`] are put back
+    fn wait(&mut self, err: &io::Error) -> bool {
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return false;
+        }
+        for &byte in self.taken.iter().rev() {
+            self.unread.push_front(byte);
+        }
+        self.taken.clear();
+        true
+    }
+
+    /// Returns 1 if a byte can be read without waiting, 0 if it would wait and
+    /// -1 at the end of input or on failure
+    fn ready(&mut self) -> i32 {
+        if !self.unread.is_empty() {
+            return 1;
+        }
+        let mut byte = [0u8; 1];
+        match self.read_stream(&mut byte) {
+            Ok(1) => {
+                self.unread.push_back(byte[0]);
+                1
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+            _ => -1,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.unread.pop_front() {
+            Some(byte) if !buf.is_empty() => {
+                buf[0] = byte;
+                1
+            }
+            Some(byte) => {
+                self.unread.push_front(byte);
+                0
+            }
+            None => self.read_stream(buf)?,
+        };
+        self.taken.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Input stream fed by the host through a channel, for embeddings like GUIs and servers
+/// which must not be blocked while the program waits for input.
+///
+/// Bytes are sent using the [`Sender`] returned by [`HostInput::new`], which can be moved
+/// to another thread or task. Reading never waits, it fails with
+/// [`io::ErrorKind::WouldBlock`] while no byte is available, see the module documentation.
+/// The input ends once every sender is dropped.
+pub struct HostInput {
+    receiver: Receiver<u8>,
+}
+
+#[cfg(feature = "std")]
+impl HostInput {
+    pub fn new() -> (Self, Sender<u8>) {
+        let (sender, receiver) = mpsc::channel();
+        (Self { receiver }, sender)
+    }
+
+    /// Creates an input whose bytes are read from `input` by a separate thread
+    pub fn from_reader(mut input: impl Read + Send + 'static) -> Self {
+        let (host_input, sender) = Self::new();
+        thread::spawn(move || {
+            let mut byte = [0u8; 1];
+            while let Ok(1) = input.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        host_input
+    }
+}
+
+#[cfg(feature = "std")]
+impl Read for HostInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.receiver.try_recv() {
+                Ok(byte) => buf[n] = byte,
+                Err(_) if n > 0 => break,
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Reads a decimal integer with an optional sign, skipping the whitespace before it.
+/// The byte after the digits is consumed. Returns `None` if no valid integer is found
+/// or if it does not fit in 32-bits, and fails only if reading fails.
+fn read_int(input: &mut impl Read) -> io::Result<Option<i32>> {
+    let mut next = || {
+        let mut byte = [0u8; 1];
+        match input.read(&mut byte)? {
+            1 => Ok(Some(byte[0])),
+            _ => Ok::<_, io::Error>(None),
+        }
+    };
+    let mut ch = next()?;
+    while ch.is_some_and(|c| c.is_ascii_whitespace()) {
+        ch = next()?;
+    }
+    let mut text = String::new();
+    if let Some(sign @ (b'-' | b'+')) = ch {
+        text.push(sign as char);
+        ch = next()?;
+    }
+    while let Some(digit) = ch.filter(u8::is_ascii_digit) {
+        text.push(digit as char);
+        ch = next()?;
+    }
+    Ok(text.parse().ok())
+}
+
+#[cfg(feature = "std")]
+/// Reads bytes into `buf` until it is full or a newline is read, the newline is kept.
+/// Returns the number of bytes read, which is 0 only at the end of input.
+fn read_line(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        if input.read(&mut buf[n..n + 1])? == 0 {
+            break;
+        }
+        n += 1;
+        if buf[n - 1] == b'\n' {
+            break;
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_int, read_line, HostInput, Input};
+    use std::io::{ErrorKind, Read};
+
+    #[test]
+    fn test_read_int() {
+        let mut input = "  42\n-17 +5 2147483648 x 12".as_bytes();
+        let mut read = || read_int(&mut input).unwrap();
+        assert_eq!(read(), Some(42));
+        assert_eq!(read(), Some(-17));
+        assert_eq!(read(), Some(5));
+        assert_eq!(read(), None);
+        assert_eq!(read(), None);
+        assert_eq!(read(), Some(12));
+        assert_eq!(read(), None);
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut input = "ab\ncdefg".as_bytes();
+        let mut buf = [0u8; 4];
+        assert_eq!(read_line(&mut input, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ab\n");
+        assert_eq!(read_line(&mut input, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"cdef");
+        assert_eq!(read_line(&mut input, &mut buf).unwrap(), 1);
+        assert_eq!(read_line(&mut input, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_host_input() {
+        let (host_input, sender) = HostInput::new();
+        let mut input = Input::new(Box::new(host_input), false);
+        let mut buf = [0u8; 4];
+        assert_eq!(input.ready(), 0);
+        let err = read_int(&mut input).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        // The digits read before waiting are read again
+        input.begin();
+        sender.send(b'1').unwrap();
+        let err = read_int(&mut input).unwrap_err();
+        assert!(input.wait(&err));
+        sender.send(b'2').unwrap();
+        sender.send(b' ').unwrap();
+        assert_eq!(read_int(&mut input).unwrap(), Some(12));
+
+        sender.send(b'x').unwrap();
+        assert_eq!(input.ready(), 1);
+        drop(sender);
+        assert_eq!(input.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'x');
+        assert_eq!(input.ready(), -1);
+        assert_eq!(input.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,34 @@
+//! Helpers for the line based text files used for recordings and checkpoints.
+
+use alloc::{format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "std")]
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file: {}", msg))
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn parse<T: std::str::FromStr>(field: &str) -> io::Result<T> {
+    field.parse().map_err(|_| invalid(field))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    decode_hex(text).ok_or_else(|| invalid(text))
+}
+
+pub(crate) fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
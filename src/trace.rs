@@ -0,0 +1,411 @@
+//! Execution traces which log every executed instruction, see [`Emulator::set_tracer`].
+//!
+//! In the text format each line has the form
+//! `pc: disassembly ; register changes ; memory writes`, with `-` for an empty part.
+//! Entering an interrupt or a trap is logged as a line starting with `->`. For example:
+//! ```text
+//!     4: add r1, r1, 4 ; r1=20 ; -
+//!     5: st r1, 0[r14] ; - ; [0x3ffc]=0x00000014
+//!     6: div r2, r1, r0 ; - ; -
+//! -> trap: Divide by 0 error
+//! ```
+//!
+//! In the JSON format each line is an object, for instructions:
+//! ```text
+//! {"pc":5,"opcode":"st","operands":["r1","0[r14]"],"regs":{},
+//!  "mem":[{"addr":16380,"bytes":"14000000"}],
+//!  "flags":{"e":false,"g":true,"n":false,"z":false,"c":true,"v":false}}
+//! ```
+//! `regs` has the changed registers and `flags` the flags after the instruction, along
+//! with the flags N, Z, C and V of the `FLAGS` CSR.
+//! Events are objects like `{"event":"interrupt","line":1}` and
+//! `{"event":"trap","error":"Divide by 0 error"}`.
+//!
+//! The VCD(Value Change Dump) format can be viewed in waveform viewers like GTKWave, to
+//! compare a run with a hardware implementation of the ISA. Its time advances by one per
+//! executed instruction, at time `t` the signals `pc` and `inst` hold the index and the
+//! encoding of instruction `t`, while `r0`-`r15`, `flag_e`, `flag_g` and the 4-bit `nzcv`
+//! hold the values after it. Events are not logged.
+//!
+//! A [`TraceFilter`] limits the trace to some kinds of instructions or a range of `pc`,
+//! events are always logged. The VCD format ignores it to keep the signals complete.
+//!
+//! [`Emulator::set_tracer`]: crate::emulator::Emulator::set_tracer
+
+use crate::{
+    csr,
+    disasm::disassemble,
+    emulator::EmulatorErr,
+    history::MemWrite,
+    info::{self, Opcode},
+    memory::Endian,
+};
+use std::{
+    io::{self, Write},
+    num::Wrapping,
+    ops::Range,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Value Change Dump of the registers, flags and `pc`
+    Vcd,
+}
+
+/// Kinds of instructions which can be selected by a [`TraceFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    /// Branches, calls and returns
+    Branch,
+    /// Loads and stores
+    Memory,
+    Syscall,
+}
+
+/// Selects the instructions which are logged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Kinds of instructions to log, all of them if empty
+    pub kinds: Vec<TraceKind>,
+    /// Log only instructions whose `pc` lies in the range
+    pub pcs: Option<Range<i32>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: i32, inst: u32) -> bool {
+        if self.pcs.as_ref().is_some_and(|pcs| !pcs.contains(&pc)) {
+            return false;
+        }
+        if self.kinds.is_empty() {
+            return true;
+        }
+        let opcode = info::opcode_of(inst);
+        let Some(ins) = info::instruction(opcode) else {
+            return false;
+        };
+        let kind = match ins.opcode {
+            _ if info::is_branch(opcode) => TraceKind::Branch,
+            _ if info::accesses_memory(opcode) => TraceKind::Memory,
+            Opcode::SYS => TraceKind::Syscall,
+            _ => return false,
+        };
+        self.kinds.contains(&kind)
+    }
+}
+
+/// An executed instruction and its results
+pub(crate) struct TracedStep<'s> {
+    pub pc: i32,
+    pub inst: u32,
+    /// Registers changed by the instruction and their new values
+    pub regs: &'s [(usize, i32)],
+    /// All the registers after the instruction
+    pub all_regs: &'s [Wrapping<i32>; 16],
+    pub writes: &'s [MemWrite],
+    /// Byte order in which the values of `writes` are shown
+    pub endian: Endian,
+    /// `E` and `GT` flags after the instruction
+    pub flags: (bool, bool),
+    /// The `FLAGS` CSR after the instruction
+    pub nzcv: u32,
+}
+
+/// Something which happened outside an instruction
+pub(crate) enum TraceEvent<'e> {
+    Interrupt(u8),
+    Trap(&'e EmulatorErr),
+}
+
+/// Signals of the VCD format last written
+struct VcdState {
+    time: u64,
+    pc: i32,
+    inst: u32,
+    regs: [i32; 16],
+    flags: (bool, bool),
+    nzcv: u32,
+}
+
+/// Identifiers of the VCD signals, `pc`, `inst`, `r0`-`r15`, `flag_e`, `flag_g` and `nzcv`
+const VCD_PC: char = '!';
+const VCD_INST: char = '"';
+const VCD_REG0: u8 = b'#';
+const VCD_FLAG_E: char = '3';
+const VCD_FLAG_G: char = '4';
+const VCD_NZCV: char = '5';
+
+/// Writes the trace of the execution to a stream
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    /// `None` until the VCD header is written
+    vcd: Option<VcdState>,
+}
+
+impl Tracer {
+    /// Creates a tracer writing the text format
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self::with_format(out, TraceFormat::Text)
+    }
+
+    pub fn with_format(out: Box<dyn Write>, format: TraceFormat) -> Self {
+        Self {
+            out,
+            format,
+            filter: TraceFilter::default(),
+            vcd: None,
+        }
+    }
+
+    /// Logs only the instructions selected by `filter`
+    pub fn set_filter(&mut self, filter: TraceFilter) {
+        self.filter = filter;
+    }
+
+    pub(crate) fn instruction(&mut self, step: &TracedStep) -> io::Result<()> {
+        if self.format == TraceFormat::Vcd {
+            return self.vcd_instruction(step);
+        }
+        if !self.filter.matches(step.pc, step.inst) {
+            return Ok(());
+        }
+        let asm = disassemble(step.inst, step.pc as usize);
+        if self.format == TraceFormat::Json {
+            return self.json_instruction(step, &asm);
+        }
+        let regs: Vec<String> = step
+            .regs
+            .iter()
+            .map(|(r, v)| format!("r{}={}", r, v))
+            .collect();
+        let writes: Vec<String> = step
+            .writes
+            .iter()
+            .map(|write| format_write(write, step.endian))
+            .collect();
+        let or_dash = |parts: Vec<String>| match parts.is_empty() {
+            true => String::from("-"),
+            false => parts.join(" "),
+        };
+        writeln!(
+            self.out,
+            "{:>5}: {} ; {} ; {}",
+            step.pc,
+            asm,
+            or_dash(regs),
+            or_dash(writes)
+        )
+    }
+
+    fn json_instruction(&mut self, step: &TracedStep, asm: &str) -> io::Result<()> {
+        let (opcode, operands) = asm.split_once(' ').unwrap_or((asm, ""));
+        let operands: Vec<String> = operands
+            .split(", ")
+            .filter(|op| !op.is_empty())
+            .map(json_string)
+            .collect();
+        let regs: Vec<String> = step
+            .regs
+            .iter()
+            .map(|(r, v)| format!("\"r{}\":{}", r, v))
+            .collect();
+        let writes: Vec<String> = step
+            .writes
+            .iter()
+            .map(|w| format!("{{\"addr\":{},\"bytes\":\"{}\"}}", w.addr, to_hex(&w.new)))
+            .collect();
+        let nzcv: Vec<String> = [
+            ("n", csr::FLAG_N),
+            ("z", csr::FLAG_Z),
+            ("c", csr::FLAG_C),
+            ("v", csr::FLAG_V),
+        ]
+        .iter()
+        .map(|(name, flag)| format!(",\"{}\":{}", name, step.nzcv & flag != 0))
+        .collect();
+        writeln!(
+            self.out,
+            "{{\"pc\":{},\"opcode\":{},\"operands\":[{}],\"regs\":{{{}}},\"mem\":[{}],\"flags\":{{\"e\":{},\"g\":{}{}}}}}",
+            step.pc,
+            json_string(opcode),
+            operands.join(","),
+            regs.join(","),
+            writes.join(","),
+            step.flags.0,
+            step.flags.1,
+            nzcv.concat()
+        )
+    }
+
+    fn vcd_instruction(&mut self, step: &TracedStep) -> io::Result<()> {
+        let regs = step.all_regs.map(|r| r.0);
+        let Some(last) = &mut self.vcd else {
+            self.vcd_header()?;
+            writeln!(self.out, "#0\n$dumpvars")?;
+            writeln!(self.out, "b{:b} {}", step.pc, VCD_PC)?;
+            writeln!(self.out, "b{:b} {}", step.inst, VCD_INST)?;
+            for (r, value) in regs.iter().enumerate() {
+                writeln!(self.out, "b{:b} {}", value, (VCD_REG0 + r as u8) as char)?;
+            }
+            writeln!(self.out, "{}{}", step.flags.0 as u8, VCD_FLAG_E)?;
+            writeln!(self.out, "{}{}", step.flags.1 as u8, VCD_FLAG_G)?;
+            writeln!(self.out, "b{:b} {}\n$end", step.nzcv, VCD_NZCV)?;
+            self.vcd = Some(VcdState {
+                time: 0,
+                pc: step.pc,
+                inst: step.inst,
+                regs,
+                flags: step.flags,
+                nzcv: step.nzcv,
+            });
+            return Ok(());
+        };
+        last.time += 1;
+        writeln!(self.out, "#{}", last.time)?;
+        if last.pc != step.pc {
+            writeln!(self.out, "b{:b} {}", step.pc, VCD_PC)?;
+        }
+        if last.inst != step.inst {
+            writeln!(self.out, "b{:b} {}", step.inst, VCD_INST)?;
+        }
+        for (r, &value) in regs.iter().enumerate() {
+            if last.regs[r] != value {
+                writeln!(self.out, "b{:b} {}", value, (VCD_REG0 + r as u8) as char)?;
+            }
+        }
+        if last.flags.0 != step.flags.0 {
+            writeln!(self.out, "{}{}", step.flags.0 as u8, VCD_FLAG_E)?;
+        }
+        if last.flags.1 != step.flags.1 {
+            writeln!(self.out, "{}{}", step.flags.1 as u8, VCD_FLAG_G)?;
+        }
+        if last.nzcv != step.nzcv {
+            writeln!(self.out, "b{:b} {}", step.nzcv, VCD_NZCV)?;
+        }
+        (last.pc, last.inst, last.regs) = (step.pc, step.inst, regs);
+        (last.flags, last.nzcv) = (step.flags, step.nzcv);
+        Ok(())
+    }
+
+    /// Declares the signals, one time unit is one instruction
+    fn vcd_header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "$version simple-risc $end")?;
+        writeln!(self.out, "$timescale 1ns $end")?;
+        writeln!(self.out, "$scope module cpu $end")?;
+        writeln!(self.out, "$var wire 32 {} pc $end", VCD_PC)?;
+        writeln!(self.out, "$var wire 32 {} inst $end", VCD_INST)?;
+        for r in 0..16 {
+            let id = (VCD_REG0 + r as u8) as char;
+            writeln!(self.out, "$var wire 32 {} r{} $end", id, r)?;
+        }
+        writeln!(self.out, "$var wire 1 {} flag_e $end", VCD_FLAG_E)?;
+        writeln!(self.out, "$var wire 1 {} flag_g $end", VCD_FLAG_G)?;
+        writeln!(self.out, "$var wire 4 {} nzcv $end", VCD_NZCV)?;
+        writeln!(self.out, "$upscope $end\n$enddefinitions $end")
+    }
+
+    pub(crate) fn event(&mut self, event: TraceEvent) -> io::Result<()> {
+        match (self.format, event) {
+            (TraceFormat::Vcd, _) => Ok(()),
+            (TraceFormat::Text, TraceEvent::Interrupt(line)) => {
+                writeln!(self.out, "-> interrupt {}", line)
+            }
+            (TraceFormat::Text, TraceEvent::Trap(err)) => writeln!(self.out, "-> trap: {}", err),
+            (TraceFormat::Json, TraceEvent::Interrupt(line)) => {
+                writeln!(self.out, "{{\"event\":\"interrupt\",\"line\":{}}}", line)
+            }
+            (TraceFormat::Json, TraceEvent::Trap(err)) => writeln!(
+                self.out,
+                "{{\"event\":\"trap\",\"error\":{}}}",
+                json_string(&err.to_string())
+            ),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Values of 1, 2 or 4 bytes are shown as a number, longer writes as their bytes
+fn format_write(write: &MemWrite, endian: Endian) -> String {
+    let bytes = &write.new;
+    match bytes.len() {
+        1 | 2 | 4 => {
+            let value = to_number(bytes, endian);
+            format!(
+                "[{:#x}]={:#0w$x}",
+                write.addr,
+                value,
+                w = 2 + 2 * bytes.len()
+            )
+        }
+        _ => format!("[{:#x}]={}", write.addr, to_hex(bytes)),
+    }
+}
+
+/// Value of up to 4 bytes stored in the order `endian`
+pub(crate) fn to_number(bytes: &[u8], endian: Endian) -> u32 {
+    let shift_in = |acc: u32, &b: &u8| acc << 8 | b as u32;
+    match endian {
+        Endian::Little => bytes.iter().rev().fold(0, shift_in),
+        Endian::Big => bytes.iter().fold(0, shift_in),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Quotes `text` as a JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, TraceFilter, TraceKind};
+    use crate::parser::parse_and_assemble;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("r1"), "\"r1\"");
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+    }
+
+    #[test]
+    fn test_filter() {
+        let code = "add r1, r1, 1\nld r2, 0[r1]\nstb r2, 1[r1]\ncall f\nret\nbeq f\nsys\nf:\n";
+        let bin = parse_and_assemble(code).unwrap();
+        let selected = |filter: &TraceFilter| -> Vec<i32> {
+            (0..bin.len() as i32)
+                .filter(|&pc| filter.matches(pc, bin[pc as usize]))
+                .collect()
+        };
+        let mut filter = TraceFilter::default();
+        assert_eq!(selected(&filter), [0, 1, 2, 3, 4, 5, 6]);
+        filter.kinds = vec![TraceKind::Branch];
+        assert_eq!(selected(&filter), [3, 4, 5]);
+        filter.kinds = vec![TraceKind::Memory, TraceKind::Syscall];
+        assert_eq!(selected(&filter), [1, 2, 6]);
+        filter.pcs = Some(2..6);
+        assert_eq!(selected(&filter), [2]);
+        assert!(!filter.matches(2, u32::MAX));
+    }
+}
@@ -0,0 +1,261 @@
+//! Full screen terminal frontend for the [`Debugger`], drawn using ANSI escape sequences.
+//!
+//! The screen shows the source around the current instruction, the registers with the ones
+//! changed by the last command highlighted, a memory view and the call stack. Commands are
+//! the same as for the [`Debugger`], plus `mem <addr>` which moves the memory view and
+//! `mem sp` which makes it follow the stack pointer again.
+
+use crate::{
+    debugger::Debugger,
+    emulator::Emulator,
+    expr::{Env, Expr},
+    memory::Memory,
+    program::Program,
+};
+use std::io::{self, BufRead, Write};
+
+/// Width of the source and memory panes
+const LEFT_WIDTH: usize = 60;
+/// Lines of source shown
+const SOURCE_LINES: usize = 16;
+/// Lines of the command output shown
+const MESSAGE_LINES: usize = 8;
+const MEM_ROWS: i32 = 4;
+const SP_REG: usize = 14;
+
+const HIGHLIGHT: &str = "\x1b[7m";
+const CHANGED: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+pub struct Tui<'p> {
+    program: &'p Program,
+    source: Vec<&'p str>,
+    debugger: Debugger<'p, io::Empty, Vec<u8>>,
+    /// Register values before the last command, to highlight changes
+    prev_regs: [i32; 16],
+    /// Start of the memory view, it follows `sp` if `None`
+    mem_addr: Option<i32>,
+    /// Output of the last command
+    message: String,
+}
+
+impl<'p> Tui<'p> {
+    /// Creates a frontend for `program` assembled from `source`
+    pub fn new(program: &'p Program, source: &'p str) -> Self {
+        Self {
+            program,
+            source: source.lines().collect(),
+            debugger: Debugger::new(program, source, io::empty(), Vec::new()),
+            prev_regs: [0; 16],
+            mem_addr: None,
+            message: String::from("Type 'help' for the list of commands"),
+        }
+    }
+
+    /// Reads and executes commands from `input` until `quit` or the end of input,
+    /// redrawing the screen on `output` after each of them
+    pub fn run<M: Memory>(
+        &mut self,
+        emul: &mut Emulator<M>,
+        mut input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        self.prev_regs = std::array::from_fn(|r| emul.reg(r));
+        loop {
+            // Move to the top left and clear the screen
+            write!(output, "\x1b[H\x1b[2J{}(srdb) ", self.render(emul))?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            self.prev_regs = std::array::from_fn(|r| emul.reg(r));
+            if let Some(arg) = line.trim().strip_prefix("mem") {
+                self.move_memory_view(emul, arg.trim());
+                continue;
+            }
+            let quit = self.debugger.execute(emul, &line)?;
+            let out = std::mem::take(self.debugger.output_mut());
+            self.message = String::from_utf8_lossy(&out).into_owned();
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn move_memory_view<M: Memory>(&mut self, emul: &mut Emulator<M>, arg: &str) {
+        self.message.clear();
+        match arg.parse::<Expr>().map(|expr| expr.eval(emul)) {
+            _ if arg == "sp" => self.mem_addr = None,
+            Ok(Ok(addr)) => self.mem_addr = Some(addr),
+            _ => self.message = String::from("Invalid address"),
+        }
+    }
+
+    /// Draws all the panes
+    fn render<M: Memory>(&self, emul: &mut Emulator<M>) -> String {
+        let mut screen = String::new();
+        let source = self.source_pane(emul);
+        let regs = self.register_pane(emul);
+        join_panes(&mut screen, "Source", &source, "Registers", &regs);
+        let memory = self.memory_pane(emul);
+        let calls = self.call_pane(emul);
+        join_panes(&mut screen, "Memory", &memory, "Call stack", &calls);
+
+        screen.push_str(&"─".repeat(LEFT_WIDTH + 30));
+        screen.push('\n');
+        let lines: Vec<&str> = self.message.lines().collect();
+        for line in &lines[lines.len().saturating_sub(MESSAGE_LINES)..] {
+            screen.push_str(line);
+            screen.push('\n');
+        }
+        screen
+    }
+
+    /// Source lines around the current instruction, breakpoints are marked with '*'
+    fn source_pane<M: Memory>(&self, emul: &Emulator<M>) -> Vec<(String, &'static str)> {
+        let pc = emul.pc();
+        let Some(&current) = usize::try_from(pc)
+            .ok()
+            .and_then(|pc| self.program.lines.get(pc))
+        else {
+            return vec![(format!("pc = {} is outside the program", pc), "")];
+        };
+        let breakpoint_lines: Vec<usize> = emul
+            .breakpoints()
+            .iter()
+            .filter_map(|b| usize::try_from(b.pc).ok())
+            .filter_map(|pc| self.program.lines.get(pc).copied())
+            .collect();
+
+        let first = current.saturating_sub(SOURCE_LINES / 2).max(1);
+        let last = (first + SOURCE_LINES - 1).min(self.source.len());
+        (first..=last)
+            .map(|line| {
+                let marker = if breakpoint_lines.contains(&line) {
+                    '*'
+                } else {
+                    ' '
+                };
+                let text = format!("{}{:>4}  {}", marker, line, self.source[line - 1]);
+                (text, if line == current { HIGHLIGHT } else { "" })
+            })
+            .collect()
+    }
+
+    fn register_pane<M: Memory>(&self, emul: &Emulator<M>) -> Vec<(String, &'static str)> {
+        let mut pane: Vec<_> = (0..16)
+            .map(|r| {
+                let value = emul.reg(r);
+                let style = if value != self.prev_regs[r] {
+                    CHANGED
+                } else {
+                    ""
+                };
+                (format!("r{:<2} {:08x} {}", r, value, value), style)
+            })
+            .collect();
+        pane.push((format!("pc  {}", emul.pc()), ""));
+        pane
+    }
+
+    fn memory_pane<M: Memory>(&self, emul: &mut Emulator<M>) -> Vec<(String, &'static str)> {
+        // Align so that rows line up with the words
+        let start = self.mem_addr.unwrap_or_else(|| emul.reg(SP_REG)) & !3;
+        (0..MEM_ROWS)
+            .map(|row| {
+                let addr = start.wrapping_add(16 * row);
+                let mut text = format!("{:08x}:", addr);
+                for i in 0..4 {
+                    match Env::load(emul, addr.wrapping_add(4 * i)) {
+                        Ok(word) => text.push_str(&format!(" {:08x}", word)),
+                        Err(_) => text.push_str(" ????????"),
+                    }
+                }
+                (text, "")
+            })
+            .collect()
+    }
+
+    /// Innermost call first
+    fn call_pane<M: Memory>(&self, emul: &Emulator<M>) -> Vec<(String, &'static str)> {
+        let name = |pc: i32| {
+            let label = usize::try_from(pc)
+                .ok()
+                .and_then(|pc| self.program.label_at(pc));
+            label.map_or_else(|| pc.to_string(), String::from)
+        };
+        let frames = emul.call_stack().iter().rev().enumerate();
+        let mut pane: Vec<_> = frames
+            .map(|(i, f)| {
+                let text = format!("#{} {} from {}", i, name(f.target), f.call_pc);
+                (text, "")
+            })
+            .collect();
+        pane.push((format!("#{} <top>", pane.len()), ""));
+        pane
+    }
+}
+
+/// Draws two panes with titles side by side
+fn join_panes(
+    screen: &mut String,
+    left_title: &str,
+    left: &[(String, &str)],
+    right_title: &str,
+    right: &[(String, &str)],
+) {
+    let title = format!("── {} ", left_title);
+    screen.push_str(&fit(&format!("{:─<w$}", title, w = LEFT_WIDTH), LEFT_WIDTH));
+    screen.push_str(&format!("┬── {} ──\n", right_title));
+    for i in 0..left.len().max(right.len()) {
+        let (text, style) = left.get(i).map_or(("", ""), |(t, s)| (t.as_str(), *s));
+        screen.push_str(&format!("{}{}{}", style, fit(text, LEFT_WIDTH), RESET));
+        let (text, style) = right.get(i).map_or(("", ""), |(t, s)| (t.as_str(), *s));
+        screen.push_str(&format!("│ {}{}{}\n", style, text, RESET));
+    }
+}
+
+/// Pads or truncates `text` to exactly `width` characters
+fn fit(text: &str, width: usize) -> String {
+    let text = text.replace('\t', "    ");
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit, Tui, CHANGED, HIGHLIGHT};
+    use crate::{emulator::Emulator, parser::assemble};
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit("ab", 4), "ab  ");
+        assert_eq!(fit("abcdef", 4), "abcd");
+        assert_eq!(fit("\tx", 6), "    x ");
+    }
+
+    #[test]
+    fn test_screen() {
+        let code = "mov sp, 64\nmov r1, 5\ncall func\nb end\nfunc:\nst r1, 0[sp]\nret\nend:\n";
+        let program = assemble(code).unwrap();
+        let mut emul = Emulator::new(&program.text);
+        let commands = "b func\nc\ns\nmem 0x40\n";
+        let mut output = Vec::new();
+        let mut tui = Tui::new(&program, code);
+        tui.run(&mut emul, commands.as_bytes(), &mut output)
+            .unwrap();
+
+        // Last screen drawn, after storing r1 in func
+        let out = String::from_utf8(output).unwrap();
+        let screen = out.rsplit("\x1b[2J").next().unwrap();
+        assert!(screen.contains(&format!("{}    7  ret", HIGHLIGHT)));
+        assert!(screen.contains("*   6  st r1, 0[sp]"));
+        assert!(screen.contains("#0 func from 2"));
+        assert!(screen.contains("00000040: 00000005 00000000"));
+        // Nothing changed since the last command only moved the memory view
+        assert!(!screen.contains(CHANGED));
+    }
+}
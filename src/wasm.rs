@@ -0,0 +1,281 @@
+//! Wrappers for building a browser playground on the assembler and the emulator.
+//!
+//! They only pass numbers, strings, byte and word vectors, C-like enums and callbacks,
+//! the types wasm-bindgen can convert to and from JavaScript, so each item can be exported
+//! by annotating it with `#[wasm_bindgen]` and taking the callbacks as `js_sys::Function`.
+//! The emulator has no lifetimes or type parameters here for the same reason.
+//!
+//! The standard input and output of the program go through callbacks instead of the
+//! process streams, which do not exist in a browser.
+
+use crate::{
+    emulator::{Emulator, EmulatorErr, ExitStatus, RunStatus},
+    parser::assemble as assemble_program,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+/// Error found by the assembler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Line number starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+/// Result of [`assemble`], either a binary or the diagnostics explaining why there is none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assembled {
+    binary: Vec<u32>,
+    /// Source line of each instruction
+    lines: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembled {
+    pub fn ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn binary(&self) -> Vec<u32> {
+        self.binary.clone()
+    }
+
+    /// Source line of the instruction at `pc`, 0 if there is none
+    pub fn line_of(&self, pc: i32) -> usize {
+        usize::try_from(pc)
+            .ok()
+            .and_then(|pc| self.lines.get(pc))
+            .map_or(0, |&line| line)
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.clone()
+    }
+}
+
+pub fn assemble(source: &str) -> Assembled {
+    match assemble_program(source) {
+        Ok(program) => Assembled {
+            binary: program.text,
+            lines: program.lines,
+            diagnostics: Vec::new(),
+        },
+        Err(err) => Assembled {
+            diagnostics: vec![Diagnostic {
+                line: err.line(),
+                message: err.to_string(),
+            }],
+            ..Default::default()
+        },
+    }
+}
+
+/// State of a [`Playground`] after running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// It can execute more instructions
+    Running,
+    /// Paused at a breakpoint
+    Break,
+    /// `pc` went outside the program, it used the exit system call or ran out of its
+    /// instruction budget, see [`Playground::exit_code`]
+    Stopped,
+    /// An instruction failed, see [`Playground::error`]
+    Failed,
+}
+
+/// Writes everything to a callback
+struct CallbackWriter(Box<dyn FnMut(Vec<u8>)>);
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the bytes returned by a callback, which returns nothing at the end of input
+struct CallbackReader {
+    callback: Box<dyn FnMut() -> Vec<u8>>,
+    buffered: VecDeque<u8>,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffered.is_empty() {
+            self.buffered.extend((self.callback)());
+        }
+        self.buffered.read(buf)
+    }
+}
+
+/// An emulator running a program in the playground
+pub struct Playground {
+    emul: Emulator,
+    /// Set when the program stopped
+    exit: Option<ExitStatus>,
+    /// Set when the last run failed
+    error: Option<String>,
+}
+
+impl Playground {
+    /// Creates an emulator for `binary`, whose output is dropped and input is empty
+    /// until callbacks are set
+    pub fn new(binary: &[u32]) -> Self {
+        let mut emul = Emulator::new(binary);
+        emul.set_input(Box::new(io::empty()));
+        emul.set_output(Box::new(io::sink()));
+        Self {
+            emul,
+            exit: None,
+            error: None,
+        }
+    }
+
+    /// Passes everything the program writes to `callback`
+    pub fn set_output(&mut self, callback: Box<dyn FnMut(Vec<u8>)>) {
+        self.emul.set_output(Box::new(CallbackWriter(callback)));
+    }
+
+    /// Asks `callback` for more input whenever the program reads past what it returned
+    /// so far, it returns nothing at the end of input
+    pub fn set_input(&mut self, callback: Box<dyn FnMut() -> Vec<u8>>) {
+        self.emul.set_input(Box::new(CallbackReader {
+            callback,
+            buffered: VecDeque::new(),
+        }));
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> Status {
+        let result = self.emul.step();
+        self.status(result)
+    }
+
+    /// Executes at most `max_steps` instructions, stopping at breakpoints
+    pub fn run(&mut self, max_steps: u32) -> Status {
+        let result = self.emul.run_for(max_steps as u64);
+        self.status(result)
+    }
+
+    fn status(&mut self, result: Result<RunStatus, EmulatorErr>) -> Status {
+        match result {
+            Ok(RunStatus::Running) => Status::Running,
+            Ok(RunStatus::Break(_)) => Status::Break,
+            Ok(RunStatus::Stopped(exit)) => {
+                self.exit = Some(exit);
+                Status::Stopped
+            }
+            Err(err) => {
+                self.error = Some(err.to_string());
+                Status::Failed
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: i32) {
+        self.emul.add_breakpoint(pc, None);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: i32) {
+        self.emul.remove_breakpoint(pc);
+    }
+
+    /// Puts the machine back in its initial state, see [`Emulator::reset`]
+    pub fn reset(&mut self) {
+        self.emul.reset();
+        self.exit = None;
+        self.error = None;
+    }
+
+    pub fn reg(&self, reg: usize) -> i32 {
+        self.emul.get_reg_val(reg)
+    }
+
+    pub fn registers(&self) -> Vec<i32> {
+        self.emul.machine_state().regs.to_vec()
+    }
+
+    pub fn pc(&self) -> i32 {
+        self.emul.pc()
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.emul.steps()
+    }
+
+    /// Reads `len` bytes at `addr`, empty if they are outside the memory
+    pub fn read_memory(&mut self, addr: usize, len: usize) -> Vec<u8> {
+        addr.checked_add(len)
+            .and_then(|end| self.emul.read_mem(addr..end).ok())
+            .unwrap_or_default()
+    }
+
+    /// Code passed to the exit system call, `None` if it was not used
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.exit {
+            Some(ExitStatus::Exited(code)) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Why the last run failed
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, Playground, Status};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_playground() {
+        let assembled = assemble("mov r1, 1\nfoo r1\n");
+        assert!(!assembled.ok());
+        assert_eq!(assembled.diagnostics()[0].line, 2);
+
+        let code = "
+            mov r0, 0       @ getchar
+            sys
+            mov r1, r0
+            add r1, r1, 1
+            mov r0, 1       @ putchar
+            sys
+            mov r1, 7
+            mov r0, 3       @ exit
+            sys
+        ";
+        let assembled = assemble(code);
+        assert!(assembled.ok());
+        assert_eq!(assembled.line_of(2), 4);
+        let mut playground = Playground::new(&assembled.binary());
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let written = output.clone();
+        playground.set_output(Box::new(move |bytes| written.borrow_mut().extend(bytes)));
+        playground.set_input(Box::new(|| b"a".to_vec()));
+
+        assert_eq!(playground.step(), Status::Running);
+        playground.add_breakpoint(5);
+        assert_eq!(playground.run(100), Status::Break);
+        assert_eq!(playground.reg(1), 'b' as i32);
+        assert_eq!(playground.run(100), Status::Stopped);
+        assert_eq!(*output.borrow(), b"b");
+        assert_eq!(playground.exit_code(), Some(7));
+        assert_eq!(playground.read_memory(0, 2), [0, 0]);
+
+        playground.reset();
+        playground.remove_breakpoint(5);
+        playground.set_input(Box::new(Vec::new));
+        assert_eq!(playground.run(100), Status::Stopped);
+        assert_eq!(playground.registers()[1], 7);
+    }
+}
@@ -0,0 +1,69 @@
+//! Log of every write made to the memory, see [`Emulator::set_write_log`].
+//!
+//! Unlike a watchpoint, which has to be set before the write happens, the log answers
+//! which instruction last wrote an address after the fact. Writes made by system calls
+//! are logged with the `pc` of the `sys` instruction.
+//!
+//! [`Emulator::set_write_log`]: crate::emulator::Emulator::set_write_log
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// A write made by an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteRecord {
+    /// Number of instructions executed before the writing one
+    pub step: u64,
+    pub pc: i32,
+    pub addr: usize,
+    /// `None` for memory-mapped devices, which are not read as that may have side effects
+    pub old: Option<Vec<u8>>,
+    pub new: Vec<u8>,
+}
+
+impl WriteRecord {
+    pub fn addrs(&self) -> Range<usize> {
+        self.addr..self.addr + self.new.len()
+    }
+}
+
+/// Writes made to the memory, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct WriteLog {
+    records: Vec<WriteRecord>,
+}
+
+impl WriteLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn push(&mut self, record: WriteRecord) {
+        self.records.push(record);
+    }
+
+    /// Drops the writes made by step `step` and the later ones, when they are undone
+    pub(crate) fn truncate(&mut self, step: u64) {
+        let keep = self.records.partition_point(|r| r.step < step);
+        self.records.truncate(keep);
+    }
+
+    pub fn records(&self) -> &[WriteRecord] {
+        &self.records
+    }
+
+    /// Returns the last write to the byte at `addr`
+    pub fn last_write(&self, addr: usize) -> Option<&WriteRecord> {
+        self.records
+            .iter()
+            .rev()
+            .find(|r| r.addrs().contains(&addr))
+    }
+
+    /// Writes to any byte in `addrs`, oldest first
+    pub fn writes_to(&self, addrs: Range<usize>) -> impl Iterator<Item = &WriteRecord> {
+        self.records
+            .iter()
+            .filter(move |r| r.addr < addrs.end && addrs.start < r.addrs().end)
+    }
+}
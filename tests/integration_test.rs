@@ -1,4 +1,39 @@
-use simple_risc::{emulator::Emulator, parser::parse_and_assemble};
+use simple_risc::{
+    bus::Bus,
+    cache::{Cache, CacheConfig},
+    debug::{Access, MemHit, RegHit, StopReason},
+    devices::{Timer, Uart, TIMER_BASE, TIMER_IRQ, UART_BASE},
+    emulator::{Emulator, EmulatorConfig, EmulatorErr, ExitStatus, PcBounds, RunStatus, Xlen},
+    heatmap::Heatmap,
+    info::{self, Opcode},
+    lockstep::{run_lockstep, run_lockstep_blocks},
+    memcheck::MemCheck,
+    memory::{Endian, FlatMemory, Memory, PagedMemory, Protection},
+    multicore::{Multicore, Schedule},
+    parser::{assemble, assemble_for, parse_and_assemble, ErrKind},
+    pipeline::Pipeline,
+    program::DATA_BASE,
+    replay,
+    syscall::{HostInput, SyscallContext, SyscallHandler},
+    trace::{TraceFilter, TraceFormat, TraceKind, Tracer},
+    writelog::WriteLog,
+};
+use std::{cell::RefCell, io, rc::Rc};
+
+/// Output stream whose contents can be inspected after it is handed to a device
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 #[test]
 fn test_factorial() {
@@ -31,3 +66,2269 @@ fn test_factorial() {
     emul.exec().unwrap();
     assert_eq!(emul.get_reg_val(0), 120);
 }
+
+#[test]
+fn test_byte_access() {
+    let code = "
+        mov r1, 0x1ff
+        stb r1, 5[r0]   @ Only the lower byte 0xff is stored
+        ld r2, 4[r0]    @ Byte at 5 is the second byte of the word at 4
+        ldb r3, 5[r0]
+        ldbu r4, 5[r0]
+        mov r6, 8
+        stb r1, -1[r6]
+        ldbu r5, 7[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(2), 0xff00);
+    assert_eq!(emul.get_reg_val(3), -1);
+    assert_eq!(emul.get_reg_val(4), 0xff);
+    assert_eq!(emul.get_reg_val(5), 0xff);
+}
+
+#[test]
+fn test_big_endian() {
+    let code = "
+        movh r1, 0x1234
+        addu r1, r1, 0x5678
+        st r1, 4[r0]
+        ldbu r2, 4[r0]  @ Most significant byte first
+        ldhu r3, 6[r0]
+        mov r4, 0xab
+        stb r4, 7[r0]
+        ld r5, 4[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        endian: Endian::Big,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(2), 0x12);
+    assert_eq!(emul.get_reg_val(3), 0x5678);
+    assert_eq!(emul.get_reg_val(5), 0x123456ab);
+    assert_eq!(emul.read_mem(4..8).unwrap(), [0x12, 0x34, 0x56, 0xab]);
+    assert_eq!(emul.read_word(4).unwrap(), 0x123456ab);
+}
+
+#[test]
+fn test_self_modifying() {
+    let code = "
+        ld r1, 0x1014[r0]   @ Word of the instruction at 5
+        st r1, 0x100c[r0]   @ Replaces the instruction at 3
+        mov r2, 1
+        mov r2, 2
+        b end
+        mov r3, 42
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        text_base: Some(0x1000),
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    assert_eq!(emul.read_word(0x1004).unwrap(), bincode[1] as i32);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!((emul.get_reg_val(2), emul.get_reg_val(3)), (1, 42));
+    assert_eq!(emul.steps(), 5);
+
+    // Reset loads the original program again
+    emul.reset();
+    assert_eq!(emul.read_word(0x100c).unwrap(), bincode[3] as i32);
+
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!((emul.get_reg_val(2), emul.get_reg_val(3)), (2, 0));
+}
+
+#[test]
+fn test_halfword_access() {
+    let code = "
+        movu r1, 0x8001
+        sth r1, 6[r0]
+        ld r2, 4[r0]
+        ldh r3, 6[r0]
+        ldhu r4, 6[r0]
+        mov r5, 0x7fff
+        sth r5, 2[r0]
+        ldh r6, 2[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(2), 0x8001 << 16);
+    assert_eq!(emul.get_reg_val(3), -0x7fff);
+    assert_eq!(emul.get_reg_val(4), 0x8001);
+    assert_eq!(emul.get_reg_val(6), 0x7fff);
+
+    let bincode = parse_and_assemble("sth r0, 1[r0]\n").unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(emul.exec().is_err());
+}
+
+#[test]
+fn test_uart_echo() {
+    // Echoes the input in upper case until it ends
+    let code = "
+        movh r1, 0x7fff     @ UART base
+    loop:
+        ld r2, 4[r1]        @ Status
+        and r2, r2, 1       @ Input available
+        cmp r2, 0
+        beq end
+        ldb r3, 0[r1]
+        sub r3, r3, 32
+        stb r3, 0[r1]
+        b loop
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let out = SharedBuf::default();
+    let mut bus = Bus::new(PagedMemory::new(1024));
+    bus.map(UART_BASE, Box::new(Uart::new(&b"risc"[..], out.clone())));
+    let mut emul = Emulator::with_memory(&bincode, bus);
+    emul.exec().unwrap();
+    assert_eq!(*out.0.borrow(), b"RISC");
+}
+
+#[test]
+fn test_timer_interrupt() {
+    let code = "
+        b main
+    timer_isr:
+        add r5, r5, 1
+        movh r6, 0x7fff
+        mov r7, 1
+        st r7, 0x10c[r6]    @ Clear the expired flag
+        reti
+    main:
+        mov r1, timer_isr
+        st r1, 4[r0]        @ Vector of line 0
+        movh r1, 0x7fff
+        mov r2, 10
+        st r2, 0x100[r1]    @ Count
+        st r2, 0x104[r1]    @ Reload
+        mov r2, 3
+        st r2, 0x108[r1]    @ Enable with interrupts
+        ei
+        mov r3, 0
+    loop:
+        add r3, r3, 1
+        cmp r3, 100
+        beq done
+        b loop
+    done:
+        di
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut bus = Bus::new(PagedMemory::new(1024));
+    bus.map_irq(TIMER_BASE, Box::new(Timer::new()), TIMER_IRQ);
+    let mut emul = Emulator::with_memory(&bincode, bus);
+    emul.exec().unwrap();
+    // Handlers do not disturb the loop, including its flags
+    assert_eq!(emul.get_reg_val(3), 100);
+    assert!(emul.get_reg_val(5) > 10);
+}
+
+#[test]
+fn test_user_mode() {
+    let code = "
+        mov r1, user
+        csrw r1, 1      @ EPC
+        mov r1, 2
+        csrw r1, 0      @ STATUS, supervisor now and user after reti
+        mov r2, 5
+        reti
+    user:
+        add r2, r2, 1
+        di              @ Not allowed in user mode
+        mov r2, 0
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::PrivilegedIns
+    ));
+    assert_eq!(emul.get_reg_val(2), 6);
+}
+
+#[test]
+fn test_trap_overflow() {
+    let code = "
+        mov r1, 0x7fff
+        lsl r1, r1, 16
+        add r2, r1, r1
+        sub r3, r0, r1
+        sub r3, r3, 2
+        mul r4, r1, 2
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(2), 0x7fff0000u32.wrapping_mul(2) as i32);
+
+    let config = EmulatorConfig {
+        trap_overflow: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config.clone());
+    let err = emul.exec().unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        EmulatorErr::Overflow(0x7fff0000, 0x7fff0000)
+    ));
+    assert_eq!(err.pc(), Some(2));
+    assert!(err.to_string().contains("add r2, r1, r1"));
+
+    // Skip the add, sub of -0x7fff0000 and 2 fits, the next one overflows
+    let mut emul = Emulator::with_config(&bincode, config);
+    for _ in 0..2 {
+        emul.step().unwrap();
+    }
+    emul.set_pc(3);
+    let err = emul.exec().unwrap_err();
+    assert_eq!(err.pc(), Some(5));
+    assert!(matches!(err.kind(), EmulatorErr::Overflow(0x7fff0000, 2)));
+}
+
+#[test]
+fn test_strict() {
+    let config = EmulatorConfig {
+        strict: true,
+        ..Default::default()
+    };
+    let run = |code: &str| {
+        let bincode = parse_and_assemble(code).unwrap();
+        let mut emul = Emulator::with_config(&bincode, config.clone());
+        emul.exec().map(|_| emul.get_reg_val(1))
+    };
+    let nested = "
+        mov sp, 1024
+        call f
+        b end
+    f:
+        sub sp, sp, 4
+        st r15, 0[sp]
+        call g
+        ld r15, 0[sp]
+        add sp, sp, 4
+        ret
+    g:
+        add r1, r1, 1
+        ret
+    end:
+    ";
+    assert_eq!(run(nested).unwrap(), 1);
+
+    let err = run("mov sp, 1024\nsub sp, sp, 2\n").unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::UnalignedStack(1022)));
+    assert_eq!(err.pc(), Some(1));
+    let err = run("call f\nf:\nmov r15, 5\nret\n").unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::ClobberedRetReg));
+    assert_eq!(err.pc(), Some(1));
+}
+
+#[test]
+fn test_pc_bounds() {
+    let code = "
+        mov r1, 1
+        cmp r1, 0
+        bgt skip
+        b skip
+    skip:
+        add r1, r1, 1
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    for pc_bounds in [PcBounds::Halt, PcBounds::Warn] {
+        let config = EmulatorConfig {
+            pc_bounds,
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&bincode, config);
+        assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    }
+
+    let config = EmulatorConfig {
+        pc_bounds: PcBounds::Error,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config.clone());
+    let err = emul.exec().unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::PcOutOfBounds(5)));
+    assert_eq!(err.pc(), Some(4));
+    assert_eq!(emul.get_reg_val(1), 2);
+
+    // Using the exit system call is fine
+    let bincode = parse_and_assemble("mov r0, 3\nmov r1, 0\nsys\n").unwrap();
+    let mut emul = Emulator::with_config(&bincode, config);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Exited(0));
+    assert_eq!("warn".parse(), Ok(PcBounds::Warn));
+    assert!("stop".parse::<PcBounds>().is_err());
+}
+
+#[test]
+fn test_trap_faults() {
+    let code = "
+        b main
+    trap:
+        csrr r10, 2     @ CAUSE
+        csrr r11, 4     @ BADADDR
+        csrr r12, 1     @ EPC
+        add r12, r12, 1 @ Skip the faulting instruction
+        csrw r12, 1
+        reti
+    main:
+        mov r1, trap
+        st r1, 0[r0]    @ Trap vector
+        div r3, r1, r0
+        mov r4, r10
+        ld r5, 6[r0]
+        mov r6, 1
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        trap_faults: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(4), 3);
+    assert_eq!(emul.get_reg_val(10), 5);
+    assert_eq!(emul.get_reg_val(11), 6);
+    assert_eq!(emul.get_reg_val(6), 1);
+
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::DivideByZero
+    ));
+}
+
+#[test]
+fn test_protection() {
+    let code = "
+        b main
+    trap:
+        csrr r10, 2     @ CAUSE
+        csrr r11, 4     @ BADADDR
+        csrr r12, 1     @ EPC
+        add r12, r12, 1 @ Skip the faulting instruction
+        csrw r12, 1
+        reti
+    main:
+        mov r1, trap
+        st r1, 0[r0]    @ Trap vector
+        ld r2, 0x104[r0]
+        st r2, 0x108[r0]
+        mov r3, r10
+        mov r4, r11
+        ld r5, 0x204[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        trap_faults: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.protect(0x100..0x200, Protection::ReadOnly);
+    emul.protect(0x200..0x300, Protection::NoAccess);
+    emul.exec().unwrap();
+    assert_eq!((emul.get_reg_val(3), emul.get_reg_val(4)), (7, 0x108));
+    assert_eq!((emul.get_reg_val(10), emul.get_reg_val(11)), (7, 0x204));
+
+    let mut emul = Emulator::new(&bincode);
+    emul.protect(0..4, Protection::ReadOnly);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::ProtectionFault
+    ));
+    emul.unprotect(0..4);
+    assert!(emul.regions().is_empty());
+}
+
+#[test]
+fn test_page_faults() {
+    // Pages 0-2 are identity mapped, the handler maps virtual page 5 to physical page 3
+    let code = "
+        b main
+    trap:
+        csrr r10, 2     @ CAUSE
+        csrr r11, 4     @ BADADDR
+        lsr r12, r11, 12
+        lsl r12, r12, 2
+        mov r13, 0x3003 @ Valid and writable
+        st r13, 0x2000[r12]
+        reti            @ Retry the faulting instruction
+    main:
+        mov r1, trap
+        st r1, 0[r0]    @ Trap vector
+        mov r1, 0x2001
+        st r1, 0x1000[r0]
+        mov r1, 0x0003
+        st r1, 0x2000[r0]
+        mov r1, 0x1003
+        st r1, 0x2004[r0]
+        mov r1, 0x2003
+        st r1, 0x2008[r0]
+        mov r1, 0x1001
+        csrw r1, 6      @ PTBR
+        mov r2, 42
+        st r2, 0x5010[r0]
+        ld r3, 0x5010[r0]
+        mov r4, user
+        csrw r4, 1      @ EPC
+        mov r4, 0
+        csrw r4, 0      @ STATUS
+        reti
+    user:
+        ld r5, 0x10[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        trap_faults: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.add_breakpoint(23, None);
+    assert!(matches!(emul.run_for(100).unwrap(), RunStatus::Break(_)));
+    assert_eq!(emul.get_reg_val(3), 42);
+    assert_eq!((emul.get_reg_val(10), emul.get_reg_val(11)), (8, 0x5010));
+    assert_eq!(emul.read_word(0x3010).unwrap(), 42);
+
+    // User mode cannot access pages without the user bit
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::PageFault
+    ));
+}
+
+#[test]
+fn test_fetch_page_faults() {
+    // Instructions 0-6 are in page 0, which is mapped, and main starts page 1 which is not
+    let code = "
+        mov r1, 0x2001
+        csrw r1, 6      @ PTBR
+        b main
+    trap:
+        csrr r11, 4     @ BADADDR
+        mov r13, 0x1001 @ Valid, maps page 1 to itself
+        st r13, 0x3004[r0]
+        reti            @ Fetch the instruction again
+    main:
+        mov r2, 42
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let new = |trap_faults| {
+        let config = EmulatorConfig {
+            text_base: Some(0xfe4),
+            trap_faults,
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&bincode, config);
+        emul.write_word(0, 3).unwrap(); // Trap vector
+        emul.write_word(0x2000, 0x3001).unwrap();
+        emul.write_word(0x3000, 0x0001).unwrap();
+        emul.write_word(0x3000 + 4 * 3, 0x3003).unwrap();
+        emul
+    };
+    let mut emul = new(true);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!((emul.get_reg_val(2), emul.get_reg_val(11)), (42, 0x1000));
+
+    let err = new(false).exec().unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::PageFault));
+    assert_eq!(err.pc(), Some(7));
+}
+
+#[test]
+fn test_max_steps() {
+    let config = EmulatorConfig {
+        max_steps: Some(10),
+        ..Default::default()
+    };
+    let bincode = parse_and_assemble("loop:\nadd r1, r1, 1\nb loop\n").unwrap();
+    let mut emul = Emulator::with_config(&bincode, config.clone());
+    assert_eq!(emul.exec().unwrap(), ExitStatus::BudgetExceeded);
+    assert_eq!((emul.steps(), emul.get_reg_val(1)), (10, 5));
+    assert_eq!(
+        emul.run_for(1).unwrap(),
+        RunStatus::Stopped(ExitStatus::BudgetExceeded)
+    );
+
+    let bincode = parse_and_assemble("mov r1, 1\n").unwrap();
+    let mut emul = Emulator::with_config(&bincode, config);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+}
+
+#[test]
+fn test_detect_loops() {
+    let config = EmulatorConfig {
+        detect_loops: true,
+        ..Default::default()
+    };
+    let run = |code: &str, steps: u64| {
+        let bincode = parse_and_assemble(code).unwrap();
+        let mut emul = Emulator::with_config(&bincode, config.clone());
+        emul.run_for(steps)
+            .map_err(|err| (err.kind().to_string(), emul.steps()))
+    };
+
+    let stuck = "mov r1, 3\nloop:\nadd r2, r1, 1\nb loop\n";
+    assert_eq!(
+        run(stuck, 100).unwrap_err(),
+        (EmulatorErr::InfiniteLoop.to_string(), 5)
+    );
+    let counting = "mov r1, 50\nloop:\nsub r1, r1, 1\ncmp r1, 0\nbgt loop\n";
+    assert_eq!(
+        run(counting, 1000).unwrap(),
+        RunStatus::Stopped(ExitStatus::Finished)
+    );
+    // Memory may be changed by a device, an interrupt may arrive
+    let polling = "loop:\nld r1, 0[r0]\nb loop\n";
+    assert_eq!(run(polling, 100).unwrap(), RunStatus::Running);
+    let waiting = "mov r1, 1\ncsrw r1, 0\nloop:\nb loop\n";
+    assert_eq!(run(waiting, 100).unwrap(), RunStatus::Running);
+}
+
+#[test]
+fn test_backtrace() {
+    let code = "
+        mov sp, 64
+        call outer
+        b end
+    outer:
+        sub sp, sp, 4
+        st r15, 0[sp]
+        call inner
+        ld r15, 0[sp]
+        add sp, sp, 4
+        ret
+    inner:
+        ld r1, -4[r0]
+        ret
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    let err = emul.exec().unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::InvalidMemAddr));
+    assert_eq!(err.pc(), Some(9));
+    assert_eq!(
+        err.to_string(),
+        "Memory address out of range at 9: ld r1, -4[r0] (0x7440fffc)"
+    );
+    let frames: Vec<_> = err
+        .backtrace()
+        .iter()
+        .map(|f| (f.call_pc, f.target))
+        .collect();
+    assert_eq!(frames, [(5, 9), (1, 3)]);
+}
+
+#[test]
+fn test_exit() {
+    let code = "
+        mov r0, 3       @ exit
+        mov r1, 42
+        sys
+        mov r2, 1       @ Never executed
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert_eq!(emul.exit_code(), None);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Exited(42));
+    assert_eq!(emul.exit_code(), Some(42));
+    assert_eq!(emul.get_reg_val(2), 0);
+
+    let bincode = parse_and_assemble("mov r2, 1\n").unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!(emul.exit_code(), None);
+
+    let bincode = parse_and_assemble("mov r0, 1000\nsys\n").unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::InvalidSyscall
+    ));
+}
+
+#[test]
+fn test_owned_program() {
+    // The emulator outlives the assembled program
+    let make = |code: &str| Emulator::new(&parse_and_assemble(code).unwrap());
+    let mut emuls = vec![make("mov r1, 1\n"), make("mov r1, 2\nadd r1, r1, r1\n")];
+    for emul in &mut emuls {
+        emul.exec().unwrap();
+    }
+    assert_eq!(emuls[1].get_reg_val(1), 4);
+    assert_eq!(emuls[0].instructions().len(), 1);
+}
+
+#[test]
+fn test_state_accessors() {
+    let code = "
+        ld r2, 0[r1]
+        add r2, r2, r1
+        st r2, 4[r1]
+        bgt end
+        mov r3, 1
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_memcheck(MemCheck::new());
+    emul.set_reg_val(1, 16);
+    emul.write_word(16, 100).unwrap();
+    emul.set_flags(false, true);
+    assert_eq!(emul.flags(), (false, true));
+    emul.exec().unwrap();
+    assert_eq!(emul.read_word(20).unwrap(), 116);
+    assert_eq!(emul.get_reg_val(3), 0);
+    assert!(emul.memcheck().unwrap().reports().is_empty());
+
+    emul.set_pc(4);
+    assert_eq!(emul.pc(), 4);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 1);
+}
+
+#[test]
+fn test_predecode() {
+    let code = "
+        mov sp, 64
+        mov r1, 10
+    loop:
+        sub sp, sp, 4
+        st r1, 0[sp]
+        ldb r2, 0[sp]
+        add sp, sp, 4
+        mul r3, r1, 0x7fff
+        lsl r4, r3, r1
+        mod r5, r4, 7
+        orh r6, r6, 0x10
+        sub r1, r1, 1
+        cmp r1, 0
+        bgt loop
+        b end
+        nop
+    end:
+    ";
+    let mut bincode = parse_and_assemble(code).unwrap();
+    // Words which are not instructions are only a problem if executed
+    *bincode.last_mut().unwrap() = 0xffff_ffff;
+    let config = |predecode| EmulatorConfig {
+        predecode,
+        ..Default::default()
+    };
+    let mut fast = Emulator::with_config(&bincode, config(true));
+    let mut slow = Emulator::with_config(&bincode, config(false));
+    let status = run_lockstep(&mut fast, &mut slow, 1000).unwrap();
+    assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+    assert_eq!(fast.get_reg_val(6), 0x100000);
+}
+
+#[test]
+fn test_block_dispatch() {
+    // Interrupts and traps must be taken at the same instructions as when stepping
+    let code = "
+        b main
+    timer_isr:
+        add r5, r5, 1
+        movh r6, 0x7fff
+        mov r7, 1
+        st r7, 0x10c[r6]    @ Clear the expired flag
+        reti
+    trap:
+        add r8, r8, 1
+        csrr r12, 1         @ EPC
+        add r12, r12, 1
+        csrw r12, 1
+        reti
+    main:
+        mov r1, timer_isr
+        st r1, 4[r0]
+        mov r1, trap
+        st r1, 0[r0]
+        movh r1, 0x7fff
+        mov r2, 7
+        st r2, 0x100[r1]
+        st r2, 0x104[r1]
+        mov r2, 3
+        st r2, 0x108[r1]
+        ei
+    loop:
+        add r3, r3, 1
+        div r9, r3, r0
+        cmp r3, 50
+        beq done
+        b loop
+    done:
+        di
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let new = |predecode| {
+        let mut bus = Bus::new(PagedMemory::new(1024));
+        bus.map_irq(TIMER_BASE, Box::new(Timer::new()), TIMER_IRQ);
+        let config = EmulatorConfig {
+            trap_faults: true,
+            predecode,
+            ..Default::default()
+        };
+        Emulator::with_memory_and_config(&bincode, bus, config)
+    };
+    let run = |predecode, steps| {
+        let mut emul = new(predecode);
+        while emul.run_for(steps).unwrap() == RunStatus::Running {}
+        emul
+    };
+    // Same state at every block boundary
+    let status = run_lockstep_blocks(&mut new(true), &mut new(false), 10_000).unwrap();
+    assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+
+    let (mut fast, mut slow) = (run(true, 5), run(false, 5));
+    assert_eq!(fast.get_reg_val(8), 50);
+    assert!(fast.get_reg_val(5) > 10);
+    assert_eq!(fast.snapshot(), slow.snapshot());
+    // Devices ticked in batches still interrupt at the same instructions
+    assert_eq!(run(true, u64::MAX).snapshot(), slow.snapshot());
+}
+
+#[test]
+fn test_compiled_blocks() {
+    // Loops of compiled blocks, with cmp fused into the branches after it.
+    // The first store to a page is left to exec_inst, which allocates it.
+    let code = "
+        mov r1, 0
+        mov r2, 20
+    outer:
+        add r1, r1, 1
+        mul r8, r1, -3
+        lsl r9, r1, 33
+        st r8, 8192[r0]
+        sth r9, 8198[r0]
+        ldb r10, 8192[r0]
+        ldh r11, 8196[r0]
+        ld r12, 8192[r0]
+        xor r3, r3, r1
+        nop
+        cmp r1, 10
+        bne skip
+        or r4, r4, r1
+    skip:
+        cmp r2, r1
+        bgt outer
+        sub r5, r2, r1
+        and r6, r3, 0xff
+        cmp r1, r5
+        bhs end
+        mov r7, 1
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    for (steps, endian) in [1, 3, 7, u64::MAX]
+        .into_iter()
+        .zip([Endian::Little, Endian::Big].into_iter().cycle())
+    {
+        let config = |predecode| EmulatorConfig {
+            predecode,
+            endian,
+            ..Default::default()
+        };
+        let mut fast = Emulator::with_config(&bincode, config(true));
+        let mut slow = Emulator::with_config(&bincode, config(false));
+        while fast.run_for(steps).unwrap() == RunStatus::Running {}
+        slow.exec().unwrap();
+        assert_eq!(fast.snapshot(), slow.snapshot());
+        assert_eq!(fast.energy(), slow.energy());
+        assert_eq!(fast.get_reg_val(4), 10);
+        let low_byte = if endian == Endian::Little { -60 } else { -1 };
+        assert_eq!(fast.get_reg_val(10), low_byte);
+    }
+}
+
+#[test]
+fn test_reset() {
+    let code = "mov r1, 5\nst r1, 8[r0]\ncmp r1, 5\n";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.add_breakpoint(1, None);
+    emul.exec().unwrap();
+    emul.reset();
+    assert_eq!(emul.get_reg_val(1), 0);
+    assert_eq!(emul.read_mem(8..12).unwrap(), [0; 4]);
+    assert_eq!(emul.steps(), 0);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(1), 5);
+
+    let bincode = parse_and_assemble("ld r2, 8[r0]\nadd r2, r2, 1\n").unwrap();
+    emul.load_program(&bincode);
+    assert!(emul.breakpoints().is_empty());
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!((emul.get_reg_val(1), emul.get_reg_val(2)), (0, 1));
+    assert_eq!(emul.steps(), 2);
+}
+
+#[test]
+fn test_run_for() {
+    let code = "
+        mov r1, 10
+    loop:
+        add r2, r2, 1
+        sub r1, r1, 1
+        cmp r1, 0
+        bgt loop
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert_eq!(emul.run_for(0).unwrap(), RunStatus::Running);
+    assert_eq!(emul.run_for(5).unwrap(), RunStatus::Running);
+    assert_eq!(emul.get_reg_val(2), 1);
+    assert_eq!(emul.steps(), 5);
+    let status = emul.run_for(100).unwrap();
+    assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+    assert_eq!(emul.get_reg_val(2), 10);
+    assert_eq!(
+        emul.step().unwrap(),
+        RunStatus::Stopped(ExitStatus::Finished)
+    );
+}
+
+#[test]
+fn test_memory_watchpoint() {
+    let code = "
+        mov r1, 0x1234
+        st r1, 16[r0]
+        ld r2, 12[r0]
+        stb r1, 17[r0]
+        ld r3, 16[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.watch_memory(17..18, Access::Write);
+    emul.watch_memory(16..20, Access::Read);
+
+    let status = emul.run_for(100).unwrap();
+    let hit = MemHit {
+        pc: 1,
+        addr: 16,
+        size: 4,
+        access: Access::Write,
+        old: 0,
+        new: 0x1234,
+    };
+    assert_eq!(status, RunStatus::Break(StopReason::MemWatch(hit)));
+    // The load from 12 does not touch the watched range
+    let status = emul.run_for(100).unwrap();
+    let hit = MemHit {
+        pc: 3,
+        addr: 17,
+        size: 1,
+        access: Access::Write,
+        old: 0x12,
+        new: 0x34,
+    };
+    assert_eq!(status, RunStatus::Break(StopReason::MemWatch(hit)));
+
+    emul.unwatch_memory(16..20);
+    assert_eq!(emul.mem_watches().len(), 1);
+    let status = emul.run_for(100).unwrap();
+    assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+    assert_eq!(emul.get_reg_val(3), 0x3434);
+}
+
+#[test]
+fn test_register_watchpoint() {
+    let code = "
+        mov r1, 3
+    loop:
+        sub r1, r1, 1
+        cmp r1, 0
+        bgt loop
+        call func
+        mov r0, 4       @ print_int
+        sys
+        b end
+    func:
+        ret
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_output(Box::new(io::sink()));
+    emul.watch_reg(1, Some(0));
+    emul.watch_reg(15, None);
+    emul.watch_reg(0, None);
+
+    let hit =
+        |pc, reg, old, new| RunStatus::Break(StopReason::RegWatch(RegHit { pc, reg, old, new }));
+    assert_eq!(emul.run_for(100).unwrap(), hit(1, 1, 1, 0));
+    assert_eq!(emul.run_for(100).unwrap(), hit(4, 15, 0, 5));
+    assert_eq!(emul.run_for(100).unwrap(), hit(5, 0, 0, 4));
+    emul.unwatch_reg(0);
+    assert_eq!(emul.reg_watches().len(), 2);
+    // The system call does not change any other register
+    let status = emul.run_for(100).unwrap();
+    assert_eq!(status, RunStatus::Stopped(ExitStatus::Finished));
+}
+
+#[test]
+fn test_register_observer() {
+    let code = "
+        mov sp, 64
+        sub sp, sp, 4
+        mov r1, 2
+        st r1, 0[sp]
+        add sp, sp, 2
+        mov r0, 8       @ read
+        mov r2, 1
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(io::empty()));
+    let unaligned = Rc::new(RefCell::new(Vec::new()));
+    let seen = unaligned.clone();
+    emul.observe_reg(
+        14,
+        Box::new(move |hit| {
+            if hit.new % 4 != 0 {
+                seen.borrow_mut().push(hit.pc);
+            }
+        }),
+    );
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let seen = changes.clone();
+    emul.observe_reg(0, Box::new(move |hit| seen.borrow_mut().push(*hit)));
+    emul.exec().unwrap();
+
+    assert_eq!(*unaligned.borrow(), [4]);
+    let change = |pc, old, new| RegHit {
+        pc,
+        reg: 0,
+        old,
+        new,
+    };
+    // The read system call returns 0 as there is no input
+    assert_eq!(*changes.borrow(), [change(5, 0, 8), change(7, 8, 0)]);
+
+    emul.unobserve_reg(0);
+    emul.reset();
+    emul.exec().unwrap();
+    assert_eq!(changes.borrow().len(), 2);
+    assert_eq!(unaligned.borrow().len(), 2);
+}
+
+#[test]
+fn test_conditional_breakpoint() {
+    let code = "
+        mov r1, 0
+        mov r2, 9
+        st r2, 0x100[r0]
+    loop:
+        add r1, r1, 1
+        cmp r1, 20
+        bgt end
+        b loop
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    let cond = "r1 % 4 == 0 && [0x100] > 5".parse().unwrap();
+    emul.add_breakpoint(3, Some(cond));
+
+    let hit = RunStatus::Break(StopReason::Breakpoint(3));
+    assert_eq!(emul.run_for(1000).unwrap(), hit);
+    assert_eq!(emul.get_reg_val(1), 0);
+    assert_eq!(emul.run_for(1000).unwrap(), hit);
+    assert_eq!(emul.get_reg_val(1), 4);
+    // Stepping from a breakpoint executes its instruction
+    assert_eq!(emul.step().unwrap(), RunStatus::Running);
+    assert_eq!(emul.get_reg_val(1), 5);
+
+    emul.remove_breakpoint(3);
+    emul.add_breakpoint(4, None);
+    emul.add_breakpoint(5, Some("[-4] == 0".parse().unwrap()));
+    assert_eq!(emul.breakpoints().len(), 2);
+    // An unconditional breakpoint and one whose condition fails to evaluate
+    let status = emul.run_for(1000).unwrap();
+    assert_eq!(status, RunStatus::Break(StopReason::Breakpoint(4)));
+    let status = emul.run_for(1000).unwrap();
+    assert_eq!(status, RunStatus::Break(StopReason::Breakpoint(5)));
+    // Breakpoints are ignored by exec
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!(emul.get_reg_val(1), 21);
+}
+
+#[test]
+fn test_write_syscall() {
+    let code = "
+        mov r0, 7       @ write
+        mov r1, 4
+        movh r2, 1      @ 64 KiB
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::InvalidMemAddr
+    ));
+
+    let code = "
+        mov r1, 0x6968  @ 'hi'
+        sth r1, 4[r0]
+        mov r0, 7
+        mov r1, 4
+        mov r2, 2
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let out = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_output(Box::new(out.clone()));
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(0), 2);
+    assert_eq!(*out.0.borrow(), b"hi");
+}
+
+#[test]
+fn test_random_seed() {
+    let code = "
+        mov r0, 9
+        sys
+        mov r1, r0
+        mov r0, 9
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let run = |seed| {
+        let config = EmulatorConfig {
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_config(&bincode, config);
+        emul.exec().unwrap();
+        (emul.get_reg_val(1), emul.get_reg_val(0))
+    };
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[test]
+fn test_clock() {
+    let code = "
+        mov r5, 2000
+    loop:
+        sub r5, r5, 1
+        cmp r5, 0
+        bgt loop
+        mov r0, 10      @ time_ms
+        sys
+        mov r6, r0
+        mov r0, 11      @ cycles
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        fake_clock: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.exec().unwrap();
+    // 1 + 3 * 2000 instructions before time_ms
+    assert_eq!(emul.get_reg_val(6), 6);
+    assert_eq!(emul.get_reg_val(0), 6001 + 4);
+    assert_eq!(emul.get_reg_val(1), 0);
+}
+
+#[test]
+fn test_cycle_costs() {
+    let code = "
+        mov r1, 6
+        mul r2, r1, r1
+        st r2, 0[r0]
+        ld r3, 0[r0]
+        b next
+    next:
+        mov r0, 11      @ cycles
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        cycle_costs: "mul=3,memory=5,ld=7".parse().unwrap(),
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.exec().unwrap();
+    assert_eq!(emul.steps(), 7);
+    assert_eq!(emul.cycles(), 1 + 3 + 5 + 7 + 1 + 1 + 1);
+    // Cycles before the system call
+    assert_eq!(emul.get_reg_val(0), 18);
+
+    let config = EmulatorConfig {
+        energy_costs: "mul=4,memory=2,read=10,write=20".parse().unwrap(),
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.exec().unwrap();
+    assert_eq!(emul.energy(), 1 + 4 + (2 + 20) + (2 + 10) + 1 + 1 + 1);
+    assert_eq!(emul.cycles(), 7);
+}
+
+#[test]
+fn test_pipeline() {
+    let code = "
+        mov r1, 2
+    loop:
+        sub r1, r1, 1
+        cmp r1, 0
+        bgt loop
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_pipeline(Pipeline::new());
+    emul.exec().unwrap();
+    let pipeline = emul.pipeline().unwrap();
+    // 7 instructions, 1 taken branch costing 2 cycles
+    assert_eq!(pipeline.cycles(true), 4 + 7 + 2);
+    // Except for the sub after the branch, each instruction waits 2 cycles for the WB
+    // of the one before it
+    assert_eq!(pipeline.cycles(false), 4 + 7 + 2 + 5 * 2);
+}
+
+#[test]
+fn test_caches() {
+    // Sums an array of 16 words, twice
+    let code = "
+        mov r3, 2
+    again:
+        mov r1, 0
+    loop:
+        ld r2, 256[r1]
+        add r4, r4, r2
+        add r1, r1, 4
+        cmp r1, 64
+        beq done
+        b loop
+    done:
+        sub r3, r3, 1
+        cmp r3, 0
+        bgt again
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config: CacheConfig = "size=64,assoc=2,line=16".parse().unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_icache(Cache::new(config.clone()));
+    emul.set_dcache(Cache::new(config));
+    emul.exec().unwrap();
+    let dcache = emul.dcache().unwrap().stats();
+    assert_eq!(dcache.reads, 2 * 16);
+    // The array fits in the cache, so only the first pass misses, once per line
+    assert_eq!(dcache.misses(), 4);
+    let icache = emul.icache().unwrap().stats();
+    assert_eq!(icache.accesses(), emul.steps());
+    // The 11 instructions take 3 lines, which fit too
+    assert_eq!(icache.misses(), 3);
+}
+
+/// Adds system call 100 which doubles r1 and makes putchar record the characters
+struct Recorder(Rc<RefCell<Vec<u8>>>);
+
+impl SyscallHandler for Recorder {
+    fn syscall(&mut self, ctx: &mut SyscallContext) -> Result<bool, EmulatorErr> {
+        match ctx.number() {
+            1 => self.0.borrow_mut().push(ctx.reg(1) as u8),
+            100 => ctx.set_return(ctx.reg(1) * 2),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_syscall_handler() {
+    let code = "
+        mov r0, 1
+        mov r1, 65
+        sys
+        mov r0, 100
+        mov r1, 21
+        sys
+        mov r5, r0
+        mov r0, 3       @ Default system calls are still available
+        mov r1, 0
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let out = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_syscall_handler(Box::new(Recorder(out.0.clone())));
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Exited(0));
+    assert_eq!(emul.get_reg_val(5), 42);
+    assert_eq!(*out.0.borrow(), b"A");
+}
+
+#[test]
+fn test_injected_io() {
+    // Reads a number and a line, prints their sum and the line back
+    let code = "
+        mov r0, 6       @ read_int
+        sys
+        mov r5, r0
+        mov r0, 8       @ read
+        mov r1, 64
+        mov r2, 32
+        sys
+        mov r2, r0
+        mov r0, 7       @ write
+        sys
+        mov r0, 4       @ print_int
+        add r1, r5, 1
+        sys
+        mov r0, 2       @ print_reg
+        mov r1, 5
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let out = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(&b"41 hello\nrest"[..]));
+    emul.set_output(Box::new(out.clone()));
+    emul.exec().unwrap();
+    assert_eq!(*out.0.borrow(), b"hello\n4241\n");
+}
+
+#[test]
+fn test_memcheck() {
+    let code = "
+        mov r0, 8       @ read
+        mov r1, 32
+        mov r2, 4
+        sys
+        ldh r3, 0[r1]
+        ldb r3, 2[r1]   @ Only 2 bytes were read
+        st r3, 8[r1]
+        ld r3, 8[r1]
+        ld r3, 12[r1]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(&b"ab"[..]));
+    emul.set_memcheck(MemCheck::new());
+    emul.exec().unwrap();
+    let reports: Vec<_> = emul
+        .memcheck()
+        .unwrap()
+        .reports()
+        .iter()
+        .map(|r| (r.pc, r.addr, r.size))
+        .collect();
+    assert_eq!(reports, [(5, 34, 1), (8, 44, 4)]);
+}
+
+#[test]
+fn test_load_data() {
+    let code = r#"
+        mov r1, 9       @ Skipped, execution starts at _start
+    _start:
+        ldbu r2, msg + 1[r0]
+        ld r3, =0x12345678
+        stb r3, msg[r0]
+        mov r4, trap
+        st r4, 4[r0]    @ Vector of line 0, below the data
+    trap:
+    .data
+    msg: .asciz "hi"
+    "#;
+    let program = assemble(code).unwrap();
+    assert_eq!(program.entry, 1);
+    let mut emul = Emulator::new(&[]);
+    emul.load(&program).unwrap();
+    emul.set_memcheck(MemCheck::new());
+    assert_eq!(emul.pc(), 1);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(1), 0);
+    assert_eq!(emul.get_reg_val(2), 'i' as i32);
+    assert_eq!(emul.get_reg_val(3), 0x12345678);
+    assert!(emul.memcheck().unwrap().reports().is_empty());
+    let msg = DATA_BASE..DATA_BASE + 3;
+    assert_eq!(emul.read_mem(msg.clone()).unwrap(), [0x78, b'i', 0]);
+    assert_eq!(emul.read_word(4).unwrap(), program.labels["trap"] as i32);
+
+    // Reset copies the data again
+    emul.reset();
+    assert_eq!(emul.read_mem(msg).unwrap(), b"hi\0");
+    assert_eq!(emul.pc(), 1);
+
+    let config = EmulatorConfig {
+        mem_bytes: 2,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&[], config);
+    assert!(matches!(
+        emul.load(&program),
+        Err(EmulatorErr::InvalidMemAddr)
+    ));
+}
+
+#[test]
+fn test_write_log() {
+    let code = "
+        mov r1, 0x1234
+        sth r1, 64[r0]
+        stb r1, 65[r0]
+        mov r0, 8       @ read
+        mov r1, 66
+        mov r2, 2
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(&b"xy"[..]));
+    emul.set_write_log(WriteLog::new());
+    emul.exec().unwrap();
+    let log = emul.write_log().unwrap();
+    assert_eq!(log.records().len(), 3);
+    let last = log.last_write(65).unwrap();
+    assert_eq!((last.step, last.pc, last.addr), (2, 2, 65));
+    assert_eq!(
+        (last.old.as_deref(), &last.new[..]),
+        (Some(&[0x12][..]), &[0x34][..])
+    );
+    assert_eq!(log.last_write(64).unwrap().pc, 1);
+    // Written by the read system call
+    assert_eq!(log.last_write(67).unwrap().pc, 6);
+    assert!(log.last_write(68).is_none());
+    let pcs: Vec<_> = log.writes_to(60..66).map(|w| w.pc).collect();
+    assert_eq!(pcs, [1, 2]);
+    assert_eq!(emul.read_mem(64..68).unwrap(), [0x34, 0x34, b'x', b'y']);
+    assert!(emul.read_mem(0x3fff..0x4001).is_err());
+}
+
+#[test]
+fn test_step_back() {
+    let code = "
+        mov sp, 64
+        mov r1, 7
+        st r1, 0[sp]
+        call func
+        mov r0, 3       @ exit
+        mov r1, 1
+        sys
+    func:
+        mov r0, 8       @ read
+        mov r1, 32
+        mov r2, 2
+        sys
+        cmp r0, 2
+        ret
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        history_depth: 10,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.set_input(Box::new(&b"ab"[..]));
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Exited(1));
+    assert_eq!(emul.steps(), 13);
+
+    // Undo the exit and the return
+    assert!(emul.step_back());
+    assert!(emul.step_back());
+    assert!(emul.step_back());
+    assert!(emul.step_back());
+    assert_eq!(emul.get_reg_val(0), 2);
+    assert_eq!(emul.call_stack().len(), 1);
+    assert_eq!(emul.step().unwrap(), RunStatus::Running);
+    assert!(emul.call_stack().is_empty());
+
+    // Back to before the read
+    for _ in 0..3 {
+        assert!(emul.step_back());
+    }
+    assert_eq!(emul.get_reg_val(0), 8);
+    assert_eq!(emul.memory_mut().read_half(32).unwrap(), 0);
+    assert_eq!(emul.steps(), 7);
+
+    // Only the last 10 steps were recorded, so the call is as far back as it goes
+    for _ in 0..4 {
+        assert!(emul.step_back());
+    }
+    assert!(!emul.step_back());
+    assert_eq!(emul.steps(), 3);
+    assert_eq!(emul.get_reg_val(1), 7);
+    assert_eq!(emul.memory_mut().read_word(64).unwrap(), 7);
+    assert!(emul.call_stack().is_empty());
+}
+
+#[test]
+fn test_record_replay() {
+    let code = "
+        mov r0, 6       @ read_int
+        sys
+        mov r5, r0
+        mov r0, 9       @ random
+        sys
+        mov r6, r0
+        mov r0, 10      @ time_ms
+        sys
+        mov r7, r0
+        movh r1, 0x7fff @ UART
+        ldb r8, 0[r1]
+        mov r1, r5
+        mov r0, 4       @ print_int
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let recorder = replay::Recorder::new();
+    let mut bus = Bus::new(PagedMemory::new(4096));
+    let uart = Uart::new(&b"x"[..], io::sink());
+    bus.map(UART_BASE, Box::new(recorder.device(UART_BASE, uart)));
+    let mut emul = Emulator::with_memory(&bincode, bus);
+    emul.set_input(Box::new(&b"42\n"[..]));
+    emul.set_output(Box::new(io::sink()));
+    emul.record(recorder.clone());
+    emul.exec().unwrap();
+    let regs: Vec<i32> = (5..=8).map(|r| emul.get_reg_val(r)).collect();
+    assert_eq!((regs[0], regs[3]), (42, b'x' as i32));
+
+    // Nothing is read from the input streams, and a different seed is ignored
+    let recording = recorder.recording();
+    let replay = |code: &str| {
+        let bincode = parse_and_assemble(code).unwrap();
+        let mut bus = Bus::new(PagedMemory::new(4096));
+        let uart = Uart::new(io::empty(), io::sink());
+        bus.map(UART_BASE, Box::new(recording.device(UART_BASE, uart)));
+        let config = EmulatorConfig {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut emul = Emulator::with_memory_and_config(&bincode, bus, config);
+        let out = SharedBuf::default();
+        emul.set_input(Box::new(io::empty()));
+        emul.set_output(Box::new(out.clone()));
+        emul.replay(&recording);
+        let result = emul
+            .exec()
+            .map(|_| (5..=8).map(|r| emul.get_reg_val(r)).collect::<Vec<_>>());
+        (result, out.0.take())
+    };
+    let (replayed, out) = replay(code);
+    assert_eq!(replayed.unwrap(), regs);
+    assert_eq!(out, b"42");
+
+    // Reading the clock twice was not recorded
+    let (replayed, _) = replay("mov r0, 10\nsys\nmov r0, 10\nsys\n");
+    assert!(matches!(
+        replayed.unwrap_err().kind(),
+        EmulatorErr::ReplayDiverged
+    ));
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let code = "
+        mov r1, 0
+        mov r2, 0x1000
+    loop:
+        add r1, r1, 1
+        st r1, 0[r2]
+        add r2, r2, 4
+        cmp r1, 10
+        bgt end
+        b loop
+    end:
+        call func
+        b done
+    func:
+        ret
+    done:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert_eq!(emul.run_for(20).unwrap(), RunStatus::Running);
+    let state = emul.snapshot();
+    assert_eq!(state.memory.len(), 1);
+    let finished = emul.exec().unwrap();
+    let expected = emul.snapshot();
+
+    // Restoring rewinds everything, including memory written after the snapshot
+    emul.restore(&state).unwrap();
+    assert_eq!(emul.snapshot(), state);
+    assert_eq!(emul.exec().unwrap(), finished);
+    assert_eq!(emul.snapshot(), expected);
+
+    // Fork the execution in another emulator
+    let mut fork = Emulator::with_memory(&bincode, FlatMemory::new(8192));
+    fork.restore(&state).unwrap();
+    assert_eq!(fork.get_reg_val(1), state.regs[1]);
+    fork.exec().unwrap();
+    assert_eq!(fork.snapshot(), expected);
+
+    let mut small = Emulator::with_memory(&bincode, FlatMemory::new(64));
+    assert!(matches!(
+        small.restore(&state),
+        Err(EmulatorErr::InvalidMemAddr)
+    ));
+}
+
+#[test]
+fn test_trace() {
+    let code = "
+        mov r1, 0x4142
+        sth r1, 6[r0]
+        mov r0, 8       @ read
+        mov r1, 16
+        mov r2, 3
+        sys
+        cmp r0, 3
+        beq end
+        nop
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let trace = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(&b"xyz"[..]));
+    emul.set_tracer(Tracer::new(Box::new(trace.clone())));
+    emul.exec().unwrap();
+    let expected = "    0: mov r1, 16706 ; r1=16706 ; -
+    1: sth r1, 6[r0] ; - ; [0x6]=0x4142
+    2: mov r0, 8 ; r0=8 ; -
+    3: mov r1, 16 ; r1=16 ; -
+    4: mov r2, 3 ; r2=3 ; -
+    5: sys ; r0=3 ; [0x10]=78797a
+    6: cmp r0, 3 ; - ; -
+    7: beq 9 ; - ; -
+";
+    assert_eq!(String::from_utf8(trace.0.take()).unwrap(), expected);
+
+    // Traps are logged after the faulting instruction
+    let code = "
+        mov r1, 0
+        mov r2, 1
+        div r3, r2, r1
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        trap_faults: true,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    emul.set_tracer(Tracer::new(Box::new(trace.clone())));
+    emul.run_for(3).unwrap();
+    let out = String::from_utf8(trace.0.take()).unwrap();
+    assert!(out.ends_with("    2: div r3, r2, r1 ; - ; -\n-> trap: Divide by 0 error\n"));
+}
+
+#[test]
+fn test_json_trace() {
+    let code = "
+        mov r1, 5
+        st r1, 8[r0]
+        cmp r1, 4
+        ldbu r2, 8[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let trace = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_tracer(Tracer::with_format(
+        Box::new(trace.clone()),
+        TraceFormat::Json,
+    ));
+    emul.exec().unwrap();
+    let out = String::from_utf8(trace.0.take()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines[1],
+        r#"{"pc":1,"opcode":"st","operands":["r1","8[r0]"],"regs":{},"mem":[{"addr":8,"bytes":"05000000"}],"flags":{"e":false,"g":false,"n":false,"z":false,"c":false,"v":false}}"#
+    );
+    assert!(lines[2]
+        .ends_with(r#""flags":{"e":false,"g":true,"n":false,"z":false,"c":true,"v":false}}"#));
+    assert!(lines[3]
+        .starts_with(r#"{"pc":3,"opcode":"ldbu","operands":["r2","8[r0]"],"regs":{"r2":5},"#));
+}
+
+#[test]
+fn test_trace_vcd() {
+    let code = "
+        mov r1, -1
+        cmp r0, r1
+        add r2, r1, 3
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let trace = SharedBuf::default();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_tracer(Tracer::with_format(
+        Box::new(trace.clone()),
+        TraceFormat::Vcd,
+    ));
+    emul.exec().unwrap();
+    let out = String::from_utf8(trace.0.take()).unwrap();
+    assert!(out.contains("$var wire 32 $ r1 $end\n"));
+    assert!(out.contains("$var wire 1 3 flag_e $end\n"));
+    assert!(out.contains("$var wire 4 5 nzcv $end\n"));
+    let (dump, changes) = out.split_once("$end\n#1\n").unwrap();
+    let dump = dump.split_once("$dumpvars\n").unwrap().1;
+    assert!(dump.starts_with(&format!(
+        "b0 !\nb{:b} \"\nb0 #\nb{} $\nb0 %\n",
+        bincode[0],
+        "1".repeat(32)
+    )));
+    assert!(dump.ends_with("b0 2\n03\n04\nb0 5\n"));
+    let expected = format!(
+        "b1 !\nb{:b} \"\n14\n#2\nb10 !\nb{:b} \"\nb10 %\n",
+        bincode[1], bincode[2]
+    );
+    assert_eq!(changes, expected);
+}
+
+#[test]
+fn test_trace_filter() {
+    let code = "
+        mov r1, 3
+    loop:
+        st r1, 0[r0]
+        sub r1, r1, 1
+        cmp r1, 0
+        bgt loop
+    done:
+        call func
+        b end
+    func:
+        ld r2, 0[r0]
+        ret
+    end:
+    ";
+    let program = assemble(code).unwrap();
+    let trace = SharedBuf::default();
+    let run = |filter: TraceFilter| {
+        let mut emul = Emulator::new(&program.text);
+        let mut tracer = Tracer::new(Box::new(trace.clone()));
+        tracer.set_filter(filter);
+        emul.set_tracer(tracer);
+        emul.exec().unwrap();
+        let out = String::from_utf8(trace.0.take()).unwrap();
+        out.lines()
+            .map(|line| line.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let branches = run(TraceFilter {
+        kinds: vec![TraceKind::Branch],
+        ..Default::default()
+    });
+    assert_eq!(branches.len(), 6);
+    assert!(branches[3].starts_with("5: call 7"));
+
+    let memory = run(TraceFilter {
+        kinds: vec![TraceKind::Memory],
+        pcs: Some(program.labels["done"] as i32..program.labels["end"] as i32),
+    });
+    assert_eq!(memory, ["7: ld r2, 0[r0] ; r2=1 ; -"]);
+}
+
+#[test]
+fn test_atomics() {
+    let code = "
+        mov r1, 0x100
+        mov r2, 5
+        st r2, 0[r1]
+        mov r3, 9
+        swap r3, [r1]       @ r3 = 5, [0x100] = 9
+        mov r4, 7
+        mov r5, 1
+        cas r4, r5, [r1]    @ Fails, r4 = 9
+        mov r6, 0
+        beq end
+        cas r4, r5, [r1]    @ Succeeds, [0x100] = 1
+        mov r6, 1
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 5);
+    assert_eq!(emul.get_reg_val(4), 9);
+    assert_eq!(emul.get_reg_val(6), 1);
+    assert_eq!(emul.read_word(0x100).unwrap(), 1);
+    assert_eq!(emul.flags(), (true, false));
+
+    let bincode = parse_and_assemble("mov r1, 2\nswap r2, [r1]\n").unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::UnalignedMemAddr
+    ));
+}
+
+#[test]
+fn test_xor() {
+    let code = "
+        mov r1, 0b1100
+        mov r2, 0b1010
+        xor r3, r1, r2
+        xor r4, r3, r3
+        movh r5, 0xffff
+        xor r5, r5, r1
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 0b0110);
+    assert_eq!(emul.get_reg_val(4), 0);
+    assert_eq!(emul.get_reg_val(5), 0xffff000cu32 as i32);
+}
+
+#[test]
+fn test_mulh() {
+    // 64-bit product of 0xffffffff and 0x10 by parts
+    let code = "
+        mov r1, -1
+        mov r2, 16
+        mul r3, r1, r2
+        mulh r4, r1, r2
+        mulhu r5, r1, r2
+        movh r6, 0x4000
+        mulh r7, r6, r6
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), -16);
+    assert_eq!(emul.get_reg_val(4), -1);
+    assert_eq!(emul.get_reg_val(5), 15);
+    assert_eq!(emul.get_reg_val(7), 0x1000_0000);
+
+    // With an immediate it is still mul with the 'h' modifier
+    let bincode = parse_and_assemble("mov r2, 100\nmulh r3, r2, 1\n").unwrap();
+    assert_eq!(info::opcode_of(bincode[1]), Opcode::MUL as u8);
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 6553600);
+}
+
+#[test]
+fn test_xlen64() {
+    let code = "
+        movw r1, 0x1234
+        orh r1, r1, 0x5678
+        add r2, r1, r1
+        mov r3, -1
+        lsr r4, r3, 32
+        mulhu r5, r3, r3
+        st r1, 8[r0]
+        ld r6, 8[r0]
+        ldb r7, 12[r0]
+        cmp r1, r4
+        mov r8, 1
+        lsl r8, r8, 63
+        mov r12, 1
+        subs r9, r8, r12
+        bvs overflow
+        mov r10, 1
+    overflow:
+        csrr r11, 7
+    ";
+    let bincode = assemble_for(code, Endian::Little, Xlen::X64).unwrap().text;
+    // The 'w' modifier needs the 64-bit mode
+    let err = parse_and_assemble(code).unwrap_err();
+    assert_eq!(err.kind(), &ErrKind::WideModifier("movw".into()));
+    assert_eq!(err.line(), 2);
+    let config = EmulatorConfig {
+        xlen: Xlen::X64,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config.clone());
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val64(1), 0x1234_5678_0000);
+    assert_eq!(emul.get_reg_val64(2), 0x2468_acf0_0000);
+    assert_eq!(emul.get_reg_val64(4), 0xffff_ffff);
+    assert_eq!(emul.get_reg_val64(5), -2);
+    assert_eq!(emul.get_reg_val64(6), 0x1234_5678_0000);
+    assert_eq!(emul.get_reg_val64(7), 0x34);
+    assert_eq!(emul.flags(), (false, true));
+    assert_eq!(emul.get_reg_val64(9), i64::MAX);
+    assert_eq!(emul.get_reg_val64(10), 0);
+    assert_eq!(emul.get_reg_val(11), 0b0011);
+    assert_eq!(
+        emul.read_mem(8..16).unwrap(),
+        [0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0]
+    );
+    assert_eq!(emul.get_reg_val(1), 0x5678_0000);
+    assert!(emul.machine_state().to_string().starts_with(
+        "r0  = 0                    (0x0000000000000000)\nr1  = 20015998304256       (0x0000123456780000)\n"
+    ));
+
+    // The snapshot keeps the upper halves
+    let state = emul.snapshot();
+    emul.reset();
+    assert_eq!(emul.get_reg_val64(1), 0);
+    emul.restore(&state).unwrap();
+    assert_eq!(emul.get_reg_val64(1), 0x1234_5678_0000);
+
+    // The same program wraps around at 32 bits, where the 'w' modifier is invalid
+    let mut emul = Emulator::new(&bincode);
+    let err = emul.exec().unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::InvalidModbits));
+    let mut emul = Emulator::new(&bincode[1..]);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val64(2), -0x5310_0000);
+    assert_eq!(emul.get_reg_val64(4), -1);
+
+    // Misaligned doublewords
+    let bincode = parse_and_assemble("ld r1, 4[r0]\n").unwrap();
+    let mut emul = Emulator::with_config(&bincode, config);
+    let err = emul.exec().unwrap_err();
+    assert!(matches!(err.kind(), EmulatorErr::UnalignedMemAddr));
+}
+
+#[test]
+fn test_unsigned() {
+    let code = "
+        movh r1, 0x8000
+        mov r2, 3
+        divu r3, r1, r2
+        modu r4, r1, r2
+        div r5, r1, r2
+        mov r6, 0
+        cmpu r1, r2
+        bgt above
+        mov r6, 1
+    above:
+        mov r7, 0
+        cmp r1, r2
+        bgt end
+        mov r7, 1
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 0x2aaa_aaaa);
+    assert_eq!(emul.get_reg_val(4), 2);
+    assert_eq!(emul.get_reg_val(5), -0x2aaa_aaaa);
+    assert_eq!(emul.get_reg_val(6), 0);
+    assert_eq!(emul.get_reg_val(7), 1);
+
+    let bincode = parse_and_assemble("mov r1, 1\nmodu r2, r1, r0\n").unwrap();
+    let mut emul = Emulator::new(&bincode);
+    assert!(matches!(
+        emul.exec().unwrap_err().kind(),
+        EmulatorErr::DivideByZero
+    ));
+
+    // With an immediate they are the instructions with the 'u' modifier, as before
+    let code = "
+        movh r1, 1
+        divu r2, r1, 40000
+        modu r3, r1, 40000
+        movu r4, 0xFFFF
+        mov r5, 0
+        cmpu r4, 0xFFFF
+        beq end
+        mov r5, 1
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    assert_eq!(info::opcode_of(bincode[1]), Opcode::DIV as u8);
+    assert_eq!(info::opcode_of(bincode[5]), Opcode::CMP as u8);
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(2), 1);
+    assert_eq!(emul.get_reg_val(3), 25536);
+    assert_eq!(emul.get_reg_val(5), 0);
+}
+
+#[test]
+fn test_mla() {
+    // Dot product of [1, 2, 3] and [4, 5, 6]
+    let code = "
+        mov r1, 0x100
+        mov r2, 1
+        mov r3, 7
+    fill:
+        st r2, 0[r1]
+        add r1, r1, 4
+        add r2, r2, 1
+        cmp r2, r3
+        beq dot
+        b fill
+    dot:
+        mov r1, 0x100
+        mov r4, 0
+    loop:
+        ld r5, 0[r1]
+        ld r6, 12[r1]
+        mla r4, r5, r6
+        add r1, r1, 4
+        cmp r1, 0x10c
+        beq end
+        b loop
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(4), 4 + 10 + 18);
+}
+
+#[test]
+fn test_bit_counts() {
+    let code = "
+        mov r1, 0x0f00
+        clz r2, r1
+        ctz r3, r1
+        popcnt r4, r1
+        clz r5, r0
+        ctz r6, r0
+        mov r7, -1
+        popcnt r7, r7
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    let regs: Vec<i32> = (2..8).map(|r| emul.get_reg_val(r)).collect();
+    assert_eq!(regs, [20, 8, 4, 32, 32, 32]);
+}
+
+#[test]
+fn test_extend() {
+    let code = "
+        movu r1, 0x8180
+        sxtb r2, r1
+        sxth r3, r1
+        uxtb r4, r1
+        uxth r5, r1
+        mov r6, -1
+        uxth r6, r6
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    let regs: Vec<i32> = (2..7).map(|r| emul.get_reg_val(r)).collect();
+    assert_eq!(regs, [-0x80, -0x7e80, 0x80, 0x8180, 0xffff]);
+}
+
+#[test]
+fn test_min_max() {
+    let code = "
+        mov r1, -5
+        mov r2, 3
+        min r3, r1, r2
+        max r4, r1, r2
+        minu r5, r1, r2
+        maxu r6, r1, r2
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    let regs: Vec<i32> = (3..7).map(|r| emul.get_reg_val(r)).collect();
+    assert_eq!(regs, [-5, 3, 3, -5]);
+}
+
+#[test]
+fn test_nzcv_flags() {
+    // Adds the 64-bit numbers r2:r1 and r4:r3 by propagating the carry through FLAGS
+    let code = "
+        mov r1, -1
+        mov r2, 1
+        mov r3, 1
+        mov r4, 2
+        adds r5, r1, r3
+        csrr r7, 7
+        and r8, r7, 2
+        lsr r8, r8, 1
+        add r6, r2, r4
+        add r6, r6, r8
+        mov r9, 1
+        lsl r9, r9, 31
+        sub r9, r9, 1
+        adds r10, r9, r3
+        csrr r11, 7
+        subs r12, r3, r3
+        csrr r13, 7
+        cmp r3, r4
+        csrr r14, 7
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!((emul.get_reg_val(5), emul.get_reg_val(6)), (0, 4));
+    // Z and C
+    assert_eq!(emul.get_reg_val(7), 0b0110);
+    // N and V
+    assert_eq!(emul.get_reg_val(11), 0b1001);
+    // Z and C, subtracting equal numbers does not borrow
+    assert_eq!(emul.get_reg_val(13), 0b0110);
+    // N, 1 - 2 borrows
+    assert_eq!(emul.get_reg_val(14), 0b1000);
+}
+
+#[test]
+fn test_nzcv_branches() {
+    // Runs in user mode, branches expected to be taken skip setting r3 to 1 and
+    // the others jump to setting it to 2
+    let code = "
+        mov r1, user
+        csrw r1, 1          @ EPC
+        mov r1, 2
+        csrw r1, 0          @ STATUS, supervisor now and user after reti
+        reti
+    user:
+        mov r1, -1
+        mov r2, 1
+        cmp r1, r2          @ N and C, -1 < 1 but 0xffffffff > 1
+        blt lt
+        mov r3, 1
+    lt:
+        bhi hi
+        mov r3, 1
+    hi:
+        bge wrong
+        bls wrong
+        adds r4, r1, r2     @ Z and C
+        bne wrong
+        blo wrong
+        bmi wrong
+        subs r5, r4, r2     @ N, 0 - 1 borrows
+        bhs wrong
+        bpl wrong
+        ble le
+        mov r3, 1
+    le:
+        mov r6, 1
+        lsl r6, r6, 31
+        subs r7, r6, r2     @ V and C
+        bvc wrong
+        bvs vs
+        mov r3, 1
+    vs:
+        csrr r8, 7          @ FLAGS can be accessed in user mode
+        csrw r0, 7
+        csrr r9, 7
+        b end
+    wrong:
+        mov r3, 2
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.exec().unwrap();
+    assert_eq!(emul.get_reg_val(3), 0);
+    assert_eq!(emul.get_reg_val(8), 0b0011);
+    assert_eq!(emul.get_reg_val(9), 0);
+    assert!(emul
+        .machine_state()
+        .to_string()
+        .ends_with("E = 0, GT = 0, N = 0, Z = 0, C = 0, V = 0\n"));
+}
+
+#[test]
+fn test_multicore() {
+    // Each hart increments a counter protected by a spinlock, one using cas and one
+    // with a plain load and store, whose updates are lost when the harts interleave
+    let code = "
+        mov r1, 0x100       @ Lock, followed by the counters
+        mov r2, 50
+    loop:
+        mov r3, 1
+    acquire:
+        swap r3, [r1]
+        cmp r3, 0
+        bgt acquire
+        ld r4, 4[r1]
+        add r4, r4, 1
+        st r4, 4[r1]
+        st r0, 0[r1]        @ Release
+        add r5, r1, 8
+    retry:
+        ld r4, 0[r5]
+        add r6, r4, 1
+        cas r4, r6, [r5]
+        beq racy
+        b retry
+    racy:
+        ld r4, 12[r1]
+        add r4, r4, 1
+        st r4, 12[r1]
+        sub r2, r2, 1
+        cmp r2, 0
+        bgt loop
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let schedules = [
+        Schedule::RoundRobin { quantum: 1 },
+        Schedule::RoundRobin { quantum: 7 },
+        Schedule::Random {
+            seed: 3,
+            max_quantum: 5,
+        },
+    ];
+    let mut lost = false;
+    for schedule in schedules {
+        let mut multicore = Multicore::new(&bincode, 3, schedule);
+        assert_eq!(multicore.exec().unwrap(), [ExitStatus::Finished; 3]);
+        let hart = multicore.hart_mut(0);
+        assert_eq!(hart.read_word(0x104).unwrap(), 150);
+        assert_eq!(hart.read_word(0x108).unwrap(), 150);
+        let racy = hart.read_word(0x10c).unwrap();
+        assert!(racy <= 150);
+        lost |= racy < 150;
+    }
+    assert!(lost);
+
+    let bincode =
+        parse_and_assemble("csrr r1, 5\ncmp r1, 1\nbeq bad\nb end\nbad: div r1, r1, 0\nend:\n");
+    let mut multicore = Multicore::new(&bincode.unwrap(), 2, Schedule::default());
+    let fault = multicore.exec().unwrap_err();
+    assert_eq!(fault.hart, 1);
+    assert!(matches!(fault.err.kind(), EmulatorErr::DivideByZero));
+}
+
+#[test]
+fn test_host_input() {
+    let code = "
+        mov r0, 13      @ input_ready
+        sys
+        mov r5, r0
+        mov r0, 12      @ try_getchar
+        sys
+        mov r6, r0
+        mov r0, 6       @ read_int
+        sys
+        mov r1, r0
+        mov r0, 4       @ print_int
+        sys
+        mov r0, 0       @ getchar
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let out = SharedBuf::default();
+    let (input, sender) = HostInput::new();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_input(Box::new(input));
+    emul.set_output(Box::new(out.clone()));
+
+    // read_int waits for the rest of the number without blocking
+    assert_eq!(emul.run_for(100).unwrap(), RunStatus::Running);
+    assert_eq!((emul.get_reg_val(5), emul.get_reg_val(6)), (0, -2));
+    sender.send(b'-').unwrap();
+    assert_eq!(emul.run_for(100).unwrap(), RunStatus::Running);
+    assert_eq!((emul.pc(), emul.get_reg_val(0)), (7, 6));
+    for &byte in b"17\n" {
+        sender.send(byte).unwrap();
+    }
+    assert_eq!(emul.run_for(100).unwrap(), RunStatus::Running);
+    assert_eq!(*out.0.borrow(), b"-17");
+    assert_eq!(emul.pc(), 12);
+    drop(sender);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!(emul.get_reg_val(0), -1);
+}
+
+#[test]
+fn test_heatmap() {
+    let code = "
+        mov r1, 0
+    loop:
+        st r1, 0x100[r1]
+        ld r2, 0x100[r1]
+        add r1, r1, 4
+        cmp r1, 32
+        bgt end
+        b loop
+    end:
+        ldb r3, 0x200[r0]
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let mut emul = Emulator::new(&bincode);
+    emul.set_heatmap(Heatmap::new(16));
+    emul.exec().unwrap();
+    let buckets = emul.heatmap().unwrap().buckets();
+    let counts: Vec<_> = buckets
+        .iter()
+        .map(|(range, counts)| (range.start, counts.loads, counts.stores))
+        .collect();
+    assert_eq!(
+        counts,
+        [(0x100, 4, 4), (0x110, 4, 4), (0x120, 1, 1), (0x200, 1, 0)]
+    );
+}
+
+#[test]
+fn test_deterministic() {
+    let code = "
+        mov r0, 9       @ random
+        sys
+        mov r5, r0
+        mov r0, 10      @ time_ms
+        sys
+        mov r6, r0
+        mov r0, 12      @ try_getchar
+        sys
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        deterministic: true,
+        ..Default::default()
+    };
+    let run = || {
+        let (input, sender) = HostInput::new();
+        // The byte arrives long after try_getchar, which waits for it
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            sender.send(b'a').unwrap();
+        });
+        let mut emul = Emulator::with_config(&bincode, config.clone());
+        emul.set_input(Box::new(input));
+        emul.exec().unwrap();
+        (0..16).map(|r| emul.get_reg_val(r)).collect::<Vec<_>>()
+    };
+    let regs = run();
+    assert_eq!((regs[0], regs[6]), (b'a' as i32, 0));
+    assert_eq!(run(), regs);
+}
+
+#[test]
+fn test_rollback() {
+    let code = "
+        mov r1, 0
+    loop:
+        add r1, r1, 1
+        st r1, 0[r0]
+        cmp r1, 10
+        bgt end
+        b loop
+    end:
+    ";
+    let bincode = parse_and_assemble(code).unwrap();
+    let config = EmulatorConfig {
+        checkpoint_interval: 10,
+        checkpoints_kept: 3,
+        ..Default::default()
+    };
+    let mut emul = Emulator::with_config(&bincode, config);
+    assert_eq!(emul.run_for(45).unwrap(), RunStatus::Running);
+    let steps: Vec<u64> = emul.checkpoints().map(|s| s.steps).collect();
+    assert_eq!(steps, [20, 30, 40]);
+    assert!(!emul.rollback_to(10));
+
+    assert!(emul.rollback_to(30));
+    assert_eq!(emul.steps(), 30);
+    // 1 + 5 * 5 steps reach the 6th iteration
+    assert_eq!(emul.get_reg_val(1), 6);
+    assert_eq!(emul.read_word(0).unwrap(), 6);
+    assert_eq!(emul.checkpoints().count(), 2);
+    assert_eq!(emul.exec().unwrap(), ExitStatus::Finished);
+    assert_eq!(emul.get_reg_val(1), 11);
+}